@@ -1,75 +1,1569 @@
 import argparse
+import fnmatch
+import gzip
 import logging
+import re
+import shlex
+import shutil
+import subprocess
 import sys
 import os
+import tarfile
+import zipfile
+from collections import Counter, deque
+from concurrent.futures import FIRST_COMPLETED, ThreadPoolExecutor, wait
 from typing import Optional
+from urllib.parse import urlparse
 
 import internetarchive
+import requests
+
+from ia_common import (
+    DEFAULT_BASE_URL,
+    EXIT_ERROR,
+    EXIT_INTERRUPTED,
+    EXIT_NOTHING,
+    PART_SUFFIX,
+    ConfigArgumentParser,
+    Pacer,
+    PauseFile,
+    add_exit_code_args,
+    add_filename_args,
+    add_free_space_arg,
+    add_head_bytes_arg,
+    add_log_file_args,
+    add_pause_arg,
+    add_session_args,
+    add_shuffle_args,
+    base_url,
+    build_download_url,
+    fetch_files_xml,
+    fetch_head,
+    file_md5,
+    format_size,
+    friendly_error,
+    head_path,
+    is_dark_item,
+    is_derivative,
+    is_local_sidecar,
+    pace_requests,
+    parse_args_with_config,
+    parse_size_field,
+    pin_transport,
+    print_files_table,
+    record_original_name,
+    render_output_path,
+    resolve_s3_keys,
+    run_exit_code,
+    safe_filename,
+    save_original_names,
+    screen_rows,
+    session_from_args,
+    set_base_url,
+    setup_logging,
+    shuffled,
+    size_summary,
+    space_shortfall,
+    thumbnail_url,
+    unique_path,
+    validate_output_template,
+    write_json_atomic,
+    write_text_atomic,
+)
 
 DEFAULT_DEST = "S:/Linux-FUCKIN-ISOs"
 
+# The capture data and its URL index in web-archive (mediatype: web) items
+WARC_SUFFIXES = (".warc.gz", ".warc")
+CDX_SUFFIXES = (".cdx.gz", ".cdx")
+# What --write-m3u lists as tracks
+AUDIO_EXTENSIONS = (".mp3", ".flac", ".ogg", ".oga", ".opus", ".m4a", ".aac", ".wav", ".aif", ".aiff", ".shn", ".ape",
+                    ".wma")
+# Marks a kept file whose md5 never matched the metadata
+BADSUM_SUFFIX = ".badsum"
+# Where such a download goes instead when a local copy is already in its place
+BAD_COPY_SUFFIX = ".bad"
+DOWNLOAD_ORDERS = ("default", "size-asc", "size-desc", "name")
+ARCHIVE_FORMATS = ("tar", "zip")
+CAS_LINKS = ("hardlink", "symlink")
+FILE_LISTS = ("json", "xml", "auto")
+# Files hashed at once by --manifest-verify and --verify-after
+DEFAULT_VERIFY_CONCURRENCY = 4
+# What archive.org accepts as an item identifier
+IDENTIFIER_PATTERN = re.compile(r"[A-Za-z0-9][A-Za-z0-9._-]{0,99}")
+# archive.org pages whose path starts with the identifier: /details/<id>, /download/<id>/..., ...
+ITEM_PATH_PREFIXES = ("details", "download", "metadata")
+# An archive.org URL pasted without its scheme: archive.org/details/<id>
+SCHEMELESS_HOST = re.compile(r"(?:www\.)?archive\.org/", re.IGNORECASE)
 
-def setup_logging(verbosity: int, log_file: Optional[str] = None):
-    level = logging.WARNING
-    if verbosity == 1:
-        level = logging.INFO
-    elif verbosity >= 2:
-        level = logging.DEBUG
 
-    handlers = [logging.StreamHandler(sys.stdout)]
-    if log_file:
-        handlers.append(logging.FileHandler(log_file, encoding="utf-8"))
+def _metadata_values(metadata: dict, field: str) -> list:
+    value = metadata.get(field)
+    if value is None:
+        return []
+    values = value if isinstance(value, list) else [value]
+    return [str(v).strip() for v in values if str(v).strip()]
 
-    logging.basicConfig(
-        level=level,
-        format="%(asctime)s | %(levelname)-8s | %(message)s",
-        datefmt="%H:%M:%S",
-        handlers=handlers,
-    )
 
+def metadata_mismatch(metadata: dict, required: Optional[list], matches: Optional[list], year: Optional[int]) -> Optional[str]:
+    """Why an item's top-level metadata fails the item filters, or None if it passes.
 
-def main():
-    p = argparse.ArgumentParser(description="Download an entire Internet Archive item/collection (v2)")
-    p.add_argument("identifier", help="Archive.org item identifier")
+    `required` fields must be present and non-empty; each `matches` entry is (field, glob)
+    and passes if any of the field's values matches case-insensitively; `year` is compared
+    with the `year` field, else the start of `date`.
+    """
+    for field in required or []:
+        if not _metadata_values(metadata, field):
+            return f"no {field!r} metadata"
+    for field, pattern in matches or []:
+        values = _metadata_values(metadata, field)
+        if not any(fnmatch.fnmatch(v.lower(), pattern.lower()) for v in values):
+            return f"{field} is {', '.join(values) or 'unset'}, not {pattern!r}"
+    if year is not None:
+        years = _metadata_values(metadata, "year") or [d[:4] for d in _metadata_values(metadata, "date")]
+        if str(year) not in years:
+            return f"year is {', '.join(years) or 'unknown'}, not {year}"
+    return None
+
+
+def metadata_match(value: str) -> tuple:
+    """argparse type for --metadata FIELD=GLOB."""
+    field, sep, pattern = value.partition("=")
+    if not sep or not field.strip():
+        raise argparse.ArgumentTypeError(f"expected FIELD=VALUE, got {value!r}")
+    return field.strip(), pattern.strip()
+
+
+def select_files(files: list, glob: Optional[str], include: Optional[re.Pattern], exclude: Optional[re.Pattern],
+                 include_derivatives: bool = False, names: Optional[list] = None,
+                 originals_only: bool = False) -> list:
+    """Return the metadata file entries whose names pass the glob/include/exclude filters.
+
+    Generated metadata/derivative files are dropped first unless include_derivatives is set;
+    with originals_only, everything archive.org doesn't list with source 'original' is.
+    Files listed in `names` are always selected; when names are given, other files are only
+    added if they match a glob/include pattern.
+    """
+    explicit = set(names or [])
+    selected = []
+    for f in files:
+        name = f.get("name") or ""
+        if not name:
+            continue
+        if name in explicit:
+            selected.append(f)
+            continue
+        if explicit and not (glob or include):
+            continue
+        if originals_only and f.get("source") != "original":
+            continue
+        if not include_derivatives and is_derivative(f):
+            continue
+        if glob and not fnmatch.fnmatch(name, glob):
+            continue
+        if include and not include.search(name):
+            continue
+        if exclude and exclude.search(name):
+            continue
+        selected.append(f)
+    return selected
+
+
+def is_web_capture_file(name: str) -> bool:
+    return name.lower().endswith(WARC_SUFFIXES + CDX_SUFFIXES)
+
+
+def cdx_urls(path: str):
+    """Yield the original URL of each capture listed in a (optionally gzipped) CDX index.
+
+    The ' CDX ...' header names the fields; 'a' is the original URL. Without a header the
+    standard 11-field layout (N b a m s k r M S V g) is assumed.
+    """
+    opener = gzip.open if path.lower().endswith(".gz") else open
+    url_field = 2
+    with opener(path, "rt", encoding="utf-8", errors="replace") as f:
+        for line in f:
+            fields = line.split()
+            if not fields:
+                continue
+            if fields[0] == "CDX":
+                if "a" in fields[1:]:
+                    url_field = fields[1:].index("a")
+                continue
+            if len(fields) > url_field:
+                yield fields[url_field]
+
+
+def resolve_originals(selected: list, files: list) -> list:
+    """Swap each derived file for the original it was made from (its `original` field), once each.
+
+    Files without an `original` pointer are kept as they are; pointers to names missing from
+    the metadata are reported and the derivative is kept instead.
+    """
+    by_name = {f.get("name"): f for f in files}
+    resolved, seen = [], set()
+    for f in selected:
+        target = f
+        original = f.get("original")
+        if isinstance(original, list):
+            original = original[0] if original else None
+        if original:
+            if original in by_name:
+                target = by_name[original]
+            else:
+                logging.warning(f"{f['name']}: original {original!r} is not listed in the item metadata; keeping the derivative")
+        if target["name"] not in seen:
+            seen.add(target["name"])
+            resolved.append(target)
+    return resolved
+
+
+def is_current(path: str, entry: dict, checksum: bool) -> bool:
+    """Whether a local copy still matches its metadata entry (for --update).
+
+    Compares size, the upstream mtime (the internetarchive library stamps downloads
+    with it) and, with --checksum, the md5. A copy kept under a .badsum marker stays
+    current while both it and the upstream md5 are the ones the marker records.
+    """
+    if checksum and entry.get("md5"):
+        marker = read_badsum(path)
+        if marker and marker.get("expected") == entry["md5"] and marker.get("actual") == file_md5(path):
+            return True
+    if not size_matches(path, entry):
+        return False
+    remote_mtime = str(entry.get("mtime") or "")
+    if remote_mtime.isdigit() and int(remote_mtime) > os.path.getmtime(path) + 1:
+        return False
+    if checksum and entry.get("md5") and file_md5(path) != entry["md5"]:
+        return False
+    return True
+
+
+def read_badsum(path: str) -> Optional[dict]:
+    """The expected and actual md5 recorded in path's .badsum marker, if it has one."""
+    try:
+        with open(path + BADSUM_SUFFIX, "r", encoding="utf-8") as fh:
+            return dict(line.strip().partition(" ")[::2] for line in fh if line.strip())
+    except OSError:
+        return None
+
+
+def write_badsum(path: str, expected: str, actual: str):
+    with open(path + BADSUM_SUFFIX, "w", encoding="utf-8") as fh:
+        fh.write(f"expected {expected}\nactual {actual}\n")
+
+
+def archive_session(args, config: Optional[dict], verify=True):
+    """The internetarchive session every library call of the run goes through, with --proxy/--no-proxy
+    and the tls_verify() value pinned on it."""
+    session = internetarchive.get_session(config=config)
+    pin_transport(session, args, verify)
+    return session
+
+
+def library_kwargs(args, config: Optional[dict]) -> dict:
+    """Keyword arguments for internetarchive.get_item()/search_items(): the run's session once main() opened it."""
+    return {"archive_session": args.ia_session} if args.ia_session else {"config": config}
+
+
+def open_item(identifier: str, args, config: Optional[dict]):
+    """internetarchive.get_item() through the run's session, so its downloads share the transport settings."""
+    return internetarchive.get_item(identifier, **library_kwargs(args, config))
+
+
+def search_identifiers(query: str, args, config: Optional[dict]) -> list:
+    """Identifiers of the items matching an advanced search query, sorted."""
+    results = internetarchive.search_items(query, fields=["identifier"], **library_kwargs(args, config))
+    return sorted({r["identifier"] for r in results if r.get("identifier")})
+
+
+def collection_members(identifier: str, args, config: Optional[dict]) -> list:
+    """Identifiers of the items in a collection, sorted, from an advanced search for collection:<identifier>."""
+    return search_identifiers(f"collection:{identifier}", args, config)
+
+
+def add_search_matches(identifiers: list, args, config: Optional[dict]) -> list:
+    """identifiers followed by the items matching each --search query that aren't already among them."""
+    combined = dict.fromkeys(identifiers)
+    for query in args.search or []:
+        found = search_identifiers(query, args, config)
+        new = [identifier for identifier in found if identifier not in combined]
+        listed = len(found) - len(new)
+        print(f"{query}: {len(found)} matching items" + (f", {listed} already listed" if listed else ""))
+        combined.update(dict.fromkeys(new))
+    return list(combined)
+
+
+def is_restricted(error: Exception) -> bool:
+    """Listed-but-not-served files: the download was refused with 401/403 rather than failing transiently."""
+    response = getattr(error, "response", None)
+    return getattr(response, "status_code", None) in (401, 403)
+
+
+def build_ia_config(args) -> Optional[dict]:
+    """internetarchive library config carrying S3 keys and any --base-url/IA_BASE_URL host override."""
+    config = {}
+    keys = resolve_s3_keys(args.access_key, args.secret_key)
+    if keys:
+        config["s3"] = {"access": keys[0], "secret": keys[1]}
+    if args.base_url:
+        set_base_url(args.base_url)
+    if base_url() != DEFAULT_BASE_URL:
+        parsed = urlparse(base_url())
+        config["general"] = {"host": parsed.netloc, "secure": parsed.scheme == "https"}
+    return config or None
+
+
+def read_identifiers_file(path: str) -> list:
+    """One identifier per line; blank lines and '#' comments are ignored."""
+    identifiers = []
+    with open(path, "r", encoding="utf-8") as f:
+        for line in f:
+            line = line.split("#", 1)[0].strip()
+            if line:
+                identifiers.append(line)
+    return identifiers
+
+
+def normalize_identifier(value: str) -> str:
+    """The bare identifier in a pasted value: an archive.org URL, a 'details/<id>' path or the identifier itself.
+
+    Whitespace is trimmed and the URL may lack its https://; raises ValueError when what remains isn't a valid identifier.
+    """
+    text = value.strip()
+    parsed = urlparse("https://" + text if SCHEMELESS_HOST.match(text) else text)
+    path = parsed.path if parsed.scheme in ("http", "https") and parsed.netloc else text
+    parts = [part for part in path.split("/") if part]
+    # /details/<id>/page/n5 and /download/<id>/<file> still name the item first
+    if len(parts) > 1 and parts[0] in ITEM_PATH_PREFIXES:
+        parts = parts[1:2]
+    if len(parts) != 1 or not IDENTIFIER_PATTERN.fullmatch(parts[0]):
+        raise ValueError(f"{text!r} isn't an archive.org identifier or item URL")
+    return parts[0]
+
+
+def normalize_identifiers(values: list) -> list:
+    """normalize_identifier() over a list, dropping repeats; the ValueError names every bad value."""
+    identifiers, bad = [], []
+    for value in values:
+        try:
+            identifiers.append(normalize_identifier(value))
+        except ValueError:
+            bad.append(repr(value.strip()))
+    if bad:
+        raise ValueError(f"not archive.org identifiers or item URLs: {', '.join(bad)} (an identifier is up to 100 "
+                         f"letters, digits, '.', '_' and '-', like the ubuntu-22.04 in "
+                         f"https://archive.org/details/ubuntu-22.04)")
+    return list(dict.fromkeys(identifiers))
+
+
+def bdecode(data: bytes):
+    """Decode a bencoded value (torrent files); strings come back as bytes."""
+    def decode(pos: int):
+        token = data[pos:pos + 1]
+        if token == b"i":
+            end = data.index(b"e", pos)
+            return int(data[pos + 1:end]), end + 1
+        if token == b"l":
+            pos += 1
+            items = []
+            while data[pos:pos + 1] != b"e":
+                value, pos = decode(pos)
+                items.append(value)
+            return items, pos + 1
+        if token == b"d":
+            pos += 1
+            result = {}
+            while data[pos:pos + 1] != b"e":
+                key, pos = decode(pos)
+                result[key], pos = decode(pos)
+            return result, pos + 1
+        if token.isdigit():
+            colon = data.index(b":", pos)
+            start = colon + 1
+            end = start + int(data[pos:colon])
+            if end > len(data):
+                raise ValueError("truncated string")
+            return data[start:end], end
+        raise ValueError(f"unexpected byte {token!r} at offset {pos}")
+
+    try:
+        value, end = decode(0)
+    except (IndexError, ValueError) as e:
+        raise ValueError(f"not a valid bencoded file: {e}") from e
+    if end != len(data):
+        raise ValueError("trailing data after bencoded value")
+    return value
+
+
+def torrent_files(meta: dict) -> list:
+    """(path, length) for each file described by a decoded torrent's info dict."""
+    info = meta.get(b"info") if isinstance(meta, dict) else None
+    if not isinstance(info, dict) or b"name" not in info:
+        raise ValueError("torrent has no info dictionary")
+    name = info[b"name"].decode("utf-8", "replace")
+    if b"files" not in info:
+        return [(name, info.get(b"length", 0))]
+    return [("/".join([name] + [p.decode("utf-8", "replace") for p in f.get(b"path", [])]), f.get(b"length", 0))
+            for f in info[b"files"]]
+
+
+def download_via_torrent(identifier: str, args, config: Optional[dict]) -> Counter:
+    """Fetch and validate the item's _archive.torrent, list its files and optionally hand it to a client."""
+    counts = Counter()
+    item = open_item(identifier, args, config)
+    names = [f.get("name") for f in item.files if f.get("format") == "Archive BitTorrent"]
+    torrent_name = names[0] if names else f"{identifier}_archive.torrent"
+    torrent_path = os.path.join(args.destdir, os.path.basename(torrent_name))
+    os.makedirs(args.destdir, exist_ok=True)
+
+    try:
+        item.get_file(torrent_name).download(file_path=torrent_path + PART_SUFFIX, verbose=args.v >= 1,
+                                             ignore_existing=False, checksum=False, retries=args.retries)
+        with open(torrent_path + PART_SUFFIX, "rb") as f:
+            entries = torrent_files(bdecode(f.read()))
+        os.replace(torrent_path + PART_SUFFIX, torrent_path)
+    except Exception as e:
+        if is_restricted(e):
+            counts["restricted"] += 1
+        else:
+            counts["failed"] += 1
+        logging.error(f"{identifier}: could not fetch a usable torrent ({torrent_name}): {e}")
+        return counts
+
+    for path, length in entries:
+        print(f"{path}\t{length}")
+    print(f"{torrent_path}: {size_summary([length for _, length in entries])}")
+    counts["downloaded"] += 1
+
+    if args.torrent_client and not args.dry_run:
+        command = shlex.split(args.torrent_client) + [torrent_path]
+        logging.info(f"Running {' '.join(command)}")
+        result = subprocess.run(command)
+        if result.returncode != 0:
+            logging.error(f"{identifier}: torrent client exited with status {result.returncode}")
+            counts["failed"] += 1
+    return counts
+
+
+def download_thumbnail(session: requests.Session, identifier: str, args) -> Counter:
+    """--thumbnail: save just the item's primary image as <identifier>.jpg in destdir."""
+    counts = Counter()
+    name = safe_filename(f"{identifier}.jpg", args.max_filename_length, args.invalid_char_replacement,
+                         args.portable_names)
+    dest_path = os.path.join(args.destdir, name)
+    url = thumbnail_url(identifier)
+    if os.path.exists(dest_path):
+        if args.on_exists == "skip":
+            logging.info(f"Skipping existing: {dest_path}")
+            counts["skipped"] += 1
+            return counts
+        if args.on_exists == "rename":
+            dest_path = unique_path(dest_path)
+    if args.dry_run:
+        print(f"{url} -> {dest_path}")
+        counts["planned"] += 1
+        return counts
+
+    part_path = dest_path + PART_SUFFIX
+    try:
+        resp = session.get(url)
+        resp.raise_for_status()
+        with open(part_path, "wb") as f:
+            f.write(resp.content)
+        os.replace(part_path, dest_path)
+    except (requests.RequestException, OSError) as e:
+        if os.path.exists(part_path):
+            os.remove(part_path)
+        if is_restricted(e):
+            counts["restricted"] += 1
+        else:
+            counts["failed"] += 1
+        logging.error(f"{identifier}: could not fetch the thumbnail: {friendly_error(e)}")
+        return counts
+    logging.info(f"Saved {dest_path} ({len(resp.content)} bytes)")
+    counts["downloaded"] += 1
+    return counts
+
+
+def cas_blob(args, entry: dict) -> Optional[str]:
+    """Where --cas-store keeps the content of a file entry: <store>/<md5[:2]>/<md5>; None without an md5."""
+    md5 = (entry.get("md5") or "").lower()
+    if not args.cas_store or not re.fullmatch(r"[0-9a-f]{32}", md5):
+        return None
+    return os.path.join(args.cas_store, md5[:2], md5)
+
+
+def link_blob(blob: str, dest_path: str, kind: str = "hardlink"):
+    """Point dest_path at a stored blob, replacing whatever is there.
+
+    A hardlink that the filesystem refuses (another device, no link support) falls back to a symlink.
+    """
+    os.makedirs(os.path.dirname(dest_path), exist_ok=True)
+    tmp_path = dest_path + PART_SUFFIX
+    if os.path.lexists(tmp_path):
+        os.remove(tmp_path)
+    try:
+        if kind != "hardlink":
+            raise OSError("symlink requested")
+        os.link(blob, tmp_path)
+    except OSError:
+        os.symlink(os.path.abspath(blob), tmp_path)
+    os.replace(tmp_path, dest_path)
+
+
+class ItemArchive:
+    """--archive: one uncompressed tar or zip per item that downloaded files are moved into as they complete.
+
+    Members are copied from disk in small blocks, so no file is ever held in memory whole.
+    An existing archive is appended to (and the members it already holds are listed in
+    `members`) unless `overwrite` is set.
+    """
+
+    def __init__(self, path: str, kind: str, overwrite: bool = False):
+        self.path = path
+        mode = "a" if os.path.exists(path) and not overwrite else "w"
+        if kind == "tar":
+            self._tar, self._zip = tarfile.open(path, mode), None
+            self.members = set(self._tar.getnames())
+        else:
+            # ZIP_STORED: the payloads are mostly ISOs and archives that wouldn't shrink
+            self._tar, self._zip = None, zipfile.ZipFile(path, mode, zipfile.ZIP_STORED)
+            self.members = set(self._zip.namelist())
+
+    def add(self, file_path: str, member: str):
+        if self._tar:
+            self._tar.add(file_path, arcname=member)
+        else:
+            self._zip.write(file_path, member)
+        self.members.add(member)
+
+    def close(self):
+        (self._tar or self._zip).close()
+
+
+def archive_path(args, identifier: str) -> str:
+    return os.path.join(args.destdir, safe_filename(identifier) + "." + args.archive)
+
+
+def archive_member(args, identifier: str, name: str) -> str:
+    """Member name of a file in its item's --archive: the file name, sanitized like a local path.
+
+    Members always follow the --portable-names rules, since the archive may be unpacked anywhere.
+    """
+    file_name = name.replace("/", "_") if args.flatten else name
+    return render_output_path("{file_name}", identifier=identifier, file_name=file_name,
+                              max_length=args.max_filename_length,
+                              replacement=args.invalid_char_replacement, portable=True).replace(os.sep, "/")
+
+
+def item_title(item) -> str:
+    title = (item.metadata or {}).get("title") or ""
+    if isinstance(title, list):
+        title = title[0] if title else ""
+    return title
+
+
+def local_path(args, identifier: str, name: str, title: str) -> str:
+    """Where a file of the item lands under destdir, per --output-template and --flatten."""
+    # Names may carry subdirectories (disc1/track01.flac); --flatten folds them into one level
+    file_name = name.replace("/", "_") if args.flatten else name
+    return os.path.join(args.destdir, render_output_path(args.output_template, identifier=identifier,
+                                                         file_name=file_name, title=title,
+                                                         max_length=args.max_filename_length,
+                                                         replacement=args.invalid_char_replacement,
+                                                         portable=args.portable_names))
+
+
+def saved_as(args, identifier: str, title: str):
+    """unique_local_names() key: the local path a name lands on, case-folded where the filesystem ignores case."""
+    return lambda name: os.path.normcase(local_path(args, identifier, name, title))
+
+
+def size_matches(path: str, entry: dict) -> bool:
+    """Whether a local file has the size the metadata lists; files with no listed size always match."""
+    expected = parse_size_field(entry.get("size"))
+    return expected is None or os.path.getsize(path) == expected
+
+
+def planned_action(args, dest_path: str, entry: dict) -> str:
+    """What a real run would do with one file: download, skip, update, overwrite, rename or resume."""
+    if not os.path.exists(dest_path):
+        return "download"
+    if args.update:
+        return "skip" if is_current(dest_path, entry, args.checksum) else "update"
+    if args.on_exists == "skip" and not size_matches(dest_path, entry):
+        return "overwrite"
+    return args.on_exists
+
+
+def plan_entry(args, identifier: str, entry: dict, local_name: str, title: str, raw: Optional[dict]) -> dict:
+    """One --plan record: where a file would come from and go to, its size and md5, and the action."""
+    dest_path = local_path(args, identifier, local_name, title)
+    return {
+        "identifier": identifier,
+        "name": entry["name"],
+        "url": build_download_url(identifier, entry["name"]),
+        "dest_path": os.path.abspath(dest_path),
+        "size": parse_size_field(entry.get("size")),
+        "md5": entry.get("md5"),
+        "action": planned_action(args, dest_path, entry),
+    }
+
+
+def unique_local_names(identifier: str, files: list, local_key=None) -> list:
+    """Local name for each file entry, keeping names listed more than once in an item apart.
+
+    The first entry keeps its name; each later one with the same name gets its `source`
+    ('name (derivative).ext') or else a number ('name (2).ext') before the extension.
+    `local_key` maps a name to the path it is saved as, so names that only become equal
+    once sanitized or flattened ('a:b.iso' and 'a?b.iso') are kept apart too.
+    """
+    key = local_key or (lambda name: name)
+    listed = Counter(key(f["name"]) for f in files)
+    used = set()
+    names = []
+    for f in files:
+        name = f["name"]
+        if key(name) in used:
+            stem, ext = os.path.splitext(name)
+            source = f.get("source")
+            candidate = f"{stem} ({source}){ext}" if source else None
+            number = 2
+            while candidate is None or key(candidate) in used or key(candidate) in listed:
+                candidate = f"{stem} ({number}){ext}"
+                number += 1
+            logging.warning(f"{identifier}: {name!r} would be saved over another file of the item; "
+                            f"saving this copy as {candidate!r}")
+            name = candidate
+        used.add(key(name))
+        names.append(name)
+    return names
+
+
+def order_files(files: list, local_names: list, order: str) -> tuple:
+    """Reorder file entries and their local names together for --download-order.
+
+    Sorting is stable, so ties keep the metadata order; files without a listed size count as 0,
+    first for size-asc and last for size-desc.
+    """
+    pairs = list(zip(files, local_names))
+    if order in ("size-asc", "size-desc"):
+        pairs.sort(key=lambda pair: parse_size_field(pair[0].get("size")) or 0, reverse=order == "size-desc")
+    elif order == "name":
+        pairs.sort(key=lambda pair: pair[0]["name"])
+    return [f for f, _ in pairs], [local_name for _, local_name in pairs]
+
+
+def parse_selection(answer: str, files: list) -> list:
+    """The files an --interactive answer picks: numbers and ranges from the listing (1,3-5), "all", or
+    words matched against the names (case-insensitively, every word must appear). Raises ValueError."""
+    answer = answer.strip()
+    if answer.lower() in ("all", "*"):
+        return list(files)
+    if re.fullmatch(r"[\d,\s-]+", answer):
+        picked = set()
+        for part in filter(None, re.split(r"[,\s]+", answer)):
+            first, _, last = part.partition("-")
+            if not first.isdigit() or not (last or first).isdigit():
+                raise ValueError(f"can't read {part!r}; use numbers like 1,3-5")
+            low, high = int(first), int(last or first)
+            if not 1 <= low <= high <= len(files):
+                raise ValueError(f"{part} is outside 1-{len(files)}")
+            picked.update(range(low - 1, high))
+        return [f for number, f in enumerate(files) if number in picked]
+    words = answer.lower().split()
+    return [f for f in files if all(word in f["name"].lower() for word in words)]
+
+
+def choose_files(identifier: str, files: list) -> list:
+    """--interactive: list an item's files and ask which of them to fetch; an empty answer picks none."""
+    print(f"{identifier}:")
+    print_files_table(files, sort=None, labels=[f"{number}) {f['name']}" for number, f in enumerate(files, 1)],
+                      page_rows=screen_rows())
+    while True:
+        try:
+            answer = input("Files to download (e.g. 1,3-5, all, or words from the names; empty for none): ")
+        except EOFError:
+            answer = ""
+        if not answer.strip():
+            return []
+        try:
+            chosen = parse_selection(answer, files)
+        except ValueError as e:
+            print(e)
+            continue
+        if chosen:
+            sizes = [parse_size_field(f.get("size")) for f in chosen]
+            print(f"Chose {len(chosen)} of {len(files)} files ({size_summary(sizes)})")
+            return chosen
+        print(f"Nothing matches {answer.strip()!r}")
+
+
+def item_files(session: Optional[requests.Session], identifier: str, item, raw: Optional[dict], source: str) -> list:
+    """An item's file entries from its JSON metadata or its _files.xml (--file-list).
+
+    "auto" keeps the JSON list unless it is empty or an original file in it has no md5, and
+    then prefers the XML one when that can be read.
+    """
+    files = item.files or []
+    if source == "json" or (source == "auto" and files
+                            and all(f.get("md5") for f in files if f.get("source") == "original")):
+        return files
+    try:
+        return fetch_files_xml(session, identifier, raw)
+    except (requests.RequestException, RuntimeError) as e:
+        if source == "xml":
+            raise
+        logging.warning(f"{identifier}: {friendly_error(e)}; using the JSON file list")
+        return files
+
+
+def verify_item(identifier: str, args, config: Optional[dict], session: Optional[requests.Session] = None) -> Counter:
+    """Check local copies of an item's selected files against its metadata (for --manifest-verify and
+    --verify-after).
+
+    Prints one line per missing or corrupt (wrong size or md5) file, and per extra file found
+    in the item's directory (neither a download tool's sidecar nor one of the item's files
+    the filters leave out); returns counts of ok/missing/corrupt/extra files. Up to
+    args.verify_concurrency files are hashed at once; the lines still come in listing order.
+    """
+    counts = Counter()
+    item = open_item(identifier, args, config)
+    raw = getattr(item, "item_metadata", None)
+    if raw is not None and is_dark_item(raw):
+        logging.warning(f"{identifier}: item is dark (taken down or unavailable); nothing to verify")
+        counts["dark"] += 1
+        return counts
+    files = item_files(session, identifier, item, raw, args.file_list)
+    selected = select_files(files, args.glob, args.include, args.exclude, args.include_derivatives, args.file,
+                            args.originals_only)
+    title = item_title(item)
+
+    expected = {}
+    with ThreadPoolExecutor(max_workers=args.verify_concurrency) as pool:
+        # (file, "missing" / "corrupt" / "ok", why it's corrupt, pending md5)
+        checks = []
+        local_names = unique_local_names(identifier, selected, saved_as(args, identifier, title))
+        for f, local_name in zip(selected, local_names):
+            path = local_path(args, identifier, local_name, title)
+            expected[os.path.normpath(path)] = f
+            if not os.path.isfile(path):
+                checks.append((f, "missing", None, None))
+                continue
+            size = parse_size_field(f.get("size"))
+            actual_size = os.path.getsize(path)
+            if size is not None and actual_size != size:
+                checks.append((f, "corrupt", f"size {actual_size}, expected {size}", None))
+            else:
+                checks.append((f, "ok", None, pool.submit(file_md5, path) if f.get("md5") else None))
+        for f, status, reason, digest in checks:
+            if digest is not None and digest.result() != f["md5"]:
+                status, reason = "corrupt", "md5 mismatch"
+            if status == "missing":
+                print(f"MISSING  {f['name']}")
+            elif status == "corrupt":
+                print(f"CORRUPT  {f['name']} ({reason})")
+            counts[status] += 1
+
+    # Extra files can only be told apart when each item has a directory of its own
+    if "{identifier}" in args.output_template and expected:
+        root = os.path.commonpath([os.path.dirname(path) for path in expected])
+        listed = {os.path.normpath(local_path(args, identifier, f["name"], title)) for f in files if f.get("name")}
+        for dirpath, _, filenames in os.walk(root):
+            for filename in sorted(filenames):
+                path = os.path.normpath(os.path.join(dirpath, filename))
+                if path not in expected and path not in listed and not is_local_sidecar(filename):
+                    print(f"EXTRA    {os.path.relpath(path, args.destdir)}")
+                    counts["extra"] += 1
+    logging.info(f"{identifier}: {counts['ok']} ok, {counts['missing']} missing, {counts['corrupt']} corrupt, "
+                 f"{counts['extra']} extra")
+    return counts
+
+
+def verify_items(identifiers: list, args, config: Optional[dict], session: Optional[requests.Session] = None) -> Counter:
+    """verify_item() over several items, then a totals line.
+
+    An item that can't be checked counts as an error; "bad_items" counts those and the items
+    with missing or corrupt files.
+    """
+    totals = Counter()
+    for identifier in identifiers:
+        try:
+            counts = verify_item(identifier, args, config, session)
+        except Exception as e:
+            logging.error(f"{identifier}: {friendly_error(e)}")
+            counts = Counter(errors=1)
+        totals.update(counts)
+        if counts["errors"] or counts["missing"] or counts["corrupt"]:
+            totals["bad_items"] += 1
+    print(f"Verified: {totals['ok']} ok, {totals['missing']} missing, {totals['corrupt']} corrupt, "
+          f"{totals['extra']} extra")
+    return totals
+
+
+def track_number(entry: dict) -> Optional[int]:
+    """The number at the start of a file entry's `track` field ('3', '03', '3/12'), or None."""
+    match = re.match(r"\s*(\d+)", str(entry.get("track") or ""))
+    return int(match[1]) if match else None
+
+
+def track_seconds(entry: dict) -> int:
+    """A file entry's `length` ('245.32' or '4:05') in whole seconds, -1 when unknown (as #EXTINF wants)."""
+    seconds = 0.0
+    try:
+        for part in str(entry.get("length") or "").split(":"):
+            seconds = seconds * 60 + float(part)
+    except ValueError:
+        return -1
+    return int(seconds) if seconds > 0 else -1
+
+
+def playlist_tracks(files: list, selected: list) -> list:
+    """The audio files of selected in play order, for --write-m3u.
+
+    Files with a track number come first, by that number; the rest follow, all in the
+    order the metadata lists them. A derivative (an MP3 made from a FLAC) is left out when
+    the file it was made from is in the list too, so no track plays twice.
+    """
+    position = {f["name"]: i for i, f in enumerate(files)}
+    audio = [f for f in selected if f["name"].lower().endswith(AUDIO_EXTENSIONS)]
+    names = {f["name"] for f in audio}
+    audio = [f for f in audio if f.get("original") not in names]
+
+    def play_order(f):
+        track = track_number(f)
+        return track is None, track or 0, position.get(f["name"], len(position))
+
+    return sorted(audio, key=play_order)
+
+
+def write_playlist(path: str, tracks: list) -> None:
+    """An extended M3U (UTF-8, so .m3u8) of (file entry, local path) tracks, paths relative to the playlist."""
+    directory = os.path.dirname(os.path.abspath(path))
+    lines = ["#EXTM3U"]
+    for f, track_path in tracks:
+        title = f.get("title") or os.path.splitext(os.path.basename(f["name"]))[0]
+        if f.get("creator"):
+            title = f"{f['creator']} - {title}"
+        lines.append(f"#EXTINF:{track_seconds(f)},{title}")
+        lines.append(os.path.relpath(os.path.abspath(track_path), directory).replace(os.sep, "/"))
+    write_text_atomic(path, "".join(f"{line}\n" for line in lines))
+
+
+def fetch_file(item, name: str, raw: Optional[dict], file_path: str, args):
+    """Download one file of the item to file_path through the internetarchive library.
+
+    When the metadata names the item's data node (server/dir), the file is fetched from it
+    directly instead of through the /download/ redirect, falling back to the redirect on 404.
+    There is no Content-Length check here as in Download-From-JSON: the library never exposes
+    the header, and urllib3 already raises when the body ends before it.
+    """
+    ia_file = item.get_file(name)
+    generic_url = ia_file.url
+    exact_url = build_download_url(item.identifier, name, raw)
+    if raw and exact_url != build_download_url(item.identifier, name):
+        ia_file.url = exact_url
+
+    def download():
+        ia_file.download(file_path=file_path, verbose=args.v >= 1, ignore_existing=False, checksum=False,
+                         retries=args.retries)
+    try:
+        download()
+    except requests.HTTPError as e:
+        if ia_file.url == generic_url or getattr(e.response, "status_code", None) != 404:
+            raise
+        logging.info(f"{name}: {ia_file.url} answered 404; retrying via {generic_url}")
+        ia_file.url = generic_url
+        download()
+
+
+def fetch_heads(session: requests.Session, identifier: str, args, selected: list, local_names: list, title: str,
+                raw: Optional[dict]) -> Counter:
+    """--head-bytes: save the first args.head_bytes of each selected file, next to where the whole file would go."""
+    counts = Counter()
+    for f, local_name in zip(selected, local_names):
+        name = f["name"]
+        dest_path = head_path(local_path(args, identifier, local_name, title), args.head_bytes)
+        if os.path.exists(dest_path):
+            if args.on_exists == "skip":
+                logging.info(f"Skipping existing {os.path.basename(dest_path)}")
+                counts["skipped"] += 1
+                continue
+            if args.on_exists == "rename":
+                dest_path = unique_path(dest_path)
+        os.makedirs(os.path.dirname(dest_path), exist_ok=True)
+        try:
+            written = fetch_head(session, build_download_url(identifier, name, raw), dest_path, args.head_bytes)
+        except (requests.RequestException, OSError) as e:
+            if is_restricted(e):
+                counts["restricted"] += 1
+                logging.warning(f"Restricted {name}: server refused access ({e})")
+            else:
+                counts["failed"] += 1
+                logging.error(f"Failed {name}: {friendly_error(e)}")
+            continue
+        logging.info(f"Saved the first {written} bytes of {name} as {dest_path}")
+        counts["downloaded"] += 1
+    return counts
+
+
+def download_item(identifier: str, args, config: Optional[dict], plan: Optional[list] = None,
+                  original_names: Optional[dict] = None, members: Optional[list] = None,
+                  session: Optional[requests.Session] = None) -> Counter:
+    """Download the selected files of one item; returns counts of downloaded/skipped/failed files.
+
+    Items that are dark, fail the item-level metadata filters, or have no files left after
+    filtering count once as "dark"/"excluded"/"unmatched". With --dry-run and a `plan`
+    list, a plan_entry() per selected file is appended to it. Files downloaded under another
+    name than listed are noted in `original_names` when it is given. Given a `members`
+    list, a collection (mediatype collection) downloads nothing: its member identifiers
+    are added to the list instead and it counts once as "collections". With --head-bytes,
+    only the start of each file is fetched, through `session`.
+    """
+    counts = Counter()
+    logging.info(f"Starting download for '{identifier}' -> {args.destdir}")
+
+    filtered = bool(args.glob or args.include or args.exclude or args.file)
+
+    item = open_item(identifier, args, config)
+    raw = getattr(item, "item_metadata", None)
+    if raw is not None and is_dark_item(raw):
+        logging.warning(f"{identifier}: item is dark (taken down or unavailable); nothing to download")
+        counts["dark"] += 1
+        return counts
+    if members is not None and (item.metadata or {}).get("mediatype") == "collection":
+        found = collection_members(identifier, args, config)
+        logging.info(f"{identifier}: collection of {len(found)} items")
+        members.extend(found)
+        counts["collections"] += 1
+        return counts
+    # Item filters come first so unwanted items cost no file listing work
+    mismatch = metadata_mismatch(item.metadata or {}, args.require_metadata, args.metadata, args.year)
+    if mismatch:
+        logging.info(f"{identifier}: skipped by item filters ({mismatch})")
+        counts["excluded"] += 1
+        return counts
+    files = item_files(session, identifier, item, raw, args.file_list)
+    selected = select_files(files, args.glob, args.include, args.exclude, args.include_derivatives, args.file,
+                            args.originals_only)
+    for missing in sorted(set(args.file or []) - {f["name"] for f in selected}):
+        logging.warning(f"{identifier}: requested file {missing!r} is not listed in the item metadata")
+    if args.follow_originals:
+        selected = resolve_originals(selected, files)
+    if args.web_captures:
+        mediatype = (item.metadata or {}).get("mediatype")
+        if mediatype == "web":
+            selected = [f for f in selected if is_web_capture_file(f["name"])]
+        else:
+            logging.info(f"{identifier}: mediatype is {mediatype!r}, not a web capture; --web-captures ignored")
+    if args.interactive and selected:
+        selected = choose_files(identifier, selected)
+        filtered = True
+    title = item_title(item)
+    # Local names are settled in metadata order, so the order doesn't change which duplicate gets renamed
+    local_names = unique_local_names(identifier, selected, saved_as(args, identifier, title))
+    selected, local_names = order_files(selected, local_names, args.download_order)
+    if args.shuffle:
+        # Seeded per item, so an item's order doesn't depend on where it falls in the identifier list
+        pairs = shuffled(zip(selected, local_names), None if args.seed is None else f"{args.seed}/{identifier}")
+        selected, local_names = [f for f, _ in pairs], [local_name for _, local_name in pairs]
+    names = [f["name"] for f in selected]
+
+    if args.dry_run:
+        counts["planned"] += len(selected)
+        if plan is not None:
+            plan.extend(plan_entry(args, identifier, f, local_name, title, raw)
+                        for f, local_name in zip(selected, local_names))
+        labels = [name if local_name == name else f"{name} -> {local_name}" for name, local_name in zip(names, local_names)]
+        if args.archive:
+            print(f"Into {archive_path(args, identifier)}:")
+        if args.compact:
+            print_files_table(selected, sort=None, labels=labels, page_rows=screen_rows())
+            return counts
+        for label in labels:
+            print(label)
+        print(size_summary([parse_size_field(f.get("size")) for f in selected]))
+        return counts
+
+    if not names:
+        logging.warning(f"{identifier}: " + ("no files matched the given filters" if filtered else "item lists no files"))
+        counts["unmatched"] += 1
+        return counts
+
+    if args.head_bytes:
+        return fetch_heads(session, identifier, args, selected, local_names, title, raw)
+    missing_sizes = [parse_size_field(f.get("size")) for f, local_name in zip(selected, local_names)
+                     if not os.path.exists(local_path(args, identifier, local_name, title))]
+    shortfall = space_shortfall(args.destdir, sum(s for s in missing_sizes if s), args.min_free_space)
+    if shortfall:
+        raise RuntimeError(f"not downloading: {shortfall}")
+
+    archive = ItemArchive(archive_path(args, identifier), args.archive, args.on_exists == "overwrite") \
+        if args.archive else None
+    for done, f in enumerate(selected):
+        name = f["name"]
+        dest_path = local_path(args, identifier, local_names[done], title)
+        # Transfer into <name>.part and only move it into place once it's complete (and verified)
+        part_path = dest_path + PART_SUFFIX
+        if archive:
+            member = archive_member(args, identifier, local_names[done])
+            part_path = archive.path + PART_SUFFIX
+            if member in archive.members:
+                logging.info(f"Skipping {name}: already in {archive.path}")
+                counts["skipped"] += 1
+                continue
+        elif os.path.exists(dest_path):
+            if args.update:
+                if is_current(dest_path, f, args.checksum):
+                    logging.info(f"Skipping {name}: up to date")
+                    counts["skipped"] += 1
+                    continue
+                logging.info(f"Updating {name}: changed upstream")
+            elif args.on_exists == "skip":
+                # A file cut short by an interrupted run is fetched again; the library can't resume it
+                if size_matches(dest_path, f):
+                    logging.info(f"Skipping existing {name}")
+                    counts["skipped"] += 1
+                    continue
+                logging.info(f"Re-downloading {name}: local copy is {os.path.getsize(dest_path)} bytes, "
+                             f"metadata lists {f.get('size')}")
+            elif args.on_exists == "rename":
+                dest_path = unique_path(dest_path)
+                part_path = dest_path + PART_SUFFIX
+                logging.info(f"{name} exists; saving as {os.path.basename(dest_path)}")
+            if args.on_exists != "rename" and args.checksum and f.get("md5") and file_md5(dest_path) == f["md5"]:
+                logging.info(f"Skipping {name}: checksum matches")
+                counts["skipped"] += 1
+                continue
+        blob = None if archive else cas_blob(args, f)
+        if blob and os.path.exists(blob):
+            link_blob(blob, dest_path, args.cas_link)
+            logging.info(f"{name}: already in the store as {blob}; linked")
+            counts["deduped"] += 1
+            counts["deduped_bytes"] += os.path.getsize(blob)
+            continue
+        if blob:
+            part_path = blob + PART_SUFFIX
+        os.makedirs(os.path.dirname(part_path), exist_ok=True)
+        if args.pause:
+            # The library fetches whole files, so a pause takes effect between them
+            args.pause.wait()
+        try:
+            # A wrong md5 is re-fetched a limited number of times, separately from network retries;
+            # some items list a bad md5 upstream and would otherwise never verify
+            for attempt in range(args.retry_on_checksum_fail + 1):
+                fetch_file(item, name, raw, part_path, args)
+                # The store is keyed by md5, so what goes into it is always verified
+                actual = file_md5(part_path) if (args.checksum or blob) and f.get("md5") else None
+                if actual is None or actual == f["md5"]:
+                    break
+                if attempt < args.retry_on_checksum_fail:
+                    logging.info(f"{name}: checksum mismatch (expected {f['md5']}, got {actual}); re-downloading")
+            bad = actual is not None and actual != f["md5"]
+            if bad and blob:
+                # It stays out of the store, which may be on another filesystem than the item tree
+                staged = dest_path + PART_SUFFIX
+                os.makedirs(os.path.dirname(staged), exist_ok=True)
+                shutil.move(part_path, staged)
+                part_path = staged
+            if bad and not size_matches(part_path, f):
+                # Probably cut short rather than listed with a wrong md5; nothing worth keeping
+                raise RuntimeError(f"checksum mismatch and {os.path.getsize(part_path)} bytes where the metadata "
+                                   f"lists {f.get('size')}; partial download left at {part_path}")
+            kept = None
+            if archive:
+                archive.add(part_path, member)
+                os.remove(part_path)
+            elif bad and os.path.exists(dest_path):
+                # A copy that fails its md5 never takes the place of the file already there
+                kept = dest_path + BAD_COPY_SUFFIX
+                os.replace(part_path, kept)
+            else:
+                if blob and actual == f["md5"]:
+                    os.replace(part_path, blob)
+                    link_blob(blob, dest_path, args.cas_link)
+                else:
+                    os.makedirs(os.path.dirname(dest_path), exist_ok=True)
+                    os.replace(part_path, dest_path)
+                if original_names is not None:
+                    record_original_name(original_names, args.destdir, dest_path, identifier, name)
+            if bad:
+                if archive:
+                    where = f"in {archive.path}"
+                elif kept:
+                    where = f"as {os.path.basename(kept)}, leaving the existing file alone"
+                else:
+                    write_badsum(dest_path, f["md5"], actual)
+                    where = f"with a {BADSUM_SUFFIX} marker"
+                logging.warning(f"{name}: checksum still wrong after {args.retry_on_checksum_fail + 1} downloads "
+                                f"(expected {f['md5']}, got {actual}); kept {where}")
+                counts["badsum"] += 1
+            else:
+                if os.path.exists(dest_path + BADSUM_SUFFIX):
+                    os.remove(dest_path + BADSUM_SUFFIX)
+                counts["downloaded"] += 1
+        except KeyboardInterrupt:
+            if os.path.exists(part_path):
+                logging.warning(f"Interrupted: partial download left at {part_path}")
+            logging.warning(f"{identifier}: interrupted after {done} of {len(names)} files")
+            if archive:
+                archive.close()
+            raise
+        except Exception as e:
+            if is_restricted(e):
+                counts["restricted"] += 1
+                logging.warning(f"Restricted {name}: server refused access ({e})")
+            else:
+                counts["failed"] += 1
+                logging.error(f"Failed {name}: {friendly_error(e)}")
+    if archive:
+        archive.close()
+
+    if args.write_m3u:
+        local = {f["name"]: local_path(args, identifier, local_name, title)
+                 for f, local_name in zip(selected, local_names)}
+        tracks = [(f, local[f["name"]]) for f in playlist_tracks(files, selected) if os.path.isfile(local[f["name"]])]
+        if tracks:
+            playlist = local_path(args, identifier, f"{identifier}.m3u8", title)
+            write_playlist(playlist, tracks)
+            logging.info(f"{identifier}: wrote {playlist} ({len(tracks)} tracks)")
+        else:
+            logging.info(f"{identifier}: no audio files downloaded; no playlist written")
+
+    if args.list_captures:
+        for name, local_name in zip(names, local_names):
+            path = local_path(args, identifier, local_name, title)
+            if name.lower().endswith(CDX_SUFFIXES) and os.path.isfile(path):
+                for url in cdx_urls(path):
+                    print(url)
+
+    if counts["failed"]:
+        logging.warning(f"{identifier}: {counts['failed']} of {len(names)} files failed")
+    if counts["restricted"]:
+        logging.warning(f"{identifier}: {counts['restricted']} of {len(names)} files are access-restricted")
+    if counts["badsum"]:
+        logging.warning(f"{identifier}: {counts['badsum']} of {len(names)} files kept despite a checksum mismatch")
+    if counts["skipped"]:
+        logging.info(f"{identifier}: skipped {counts['skipped']} existing files")
+    if counts["deduped"]:
+        logging.info(f"{identifier}: linked {counts['deduped']} files already in {args.cas_store}, "
+                     f"saving {format_size(counts['deduped_bytes'])}")
+    return counts
+
+
+def item_outcome(counts: Counter) -> str:
+    """One item's download counts as a short phrase, for the --item-concurrency progress lines."""
+    for key, outcome in (("dark", "dark"), ("collections", "collection"), ("excluded", "excluded by item filters"),
+                         ("unmatched", "no matching files")):
+        if counts[key]:
+            return outcome
+    outcome = f"{counts['downloaded']} downloaded, {counts['skipped'] + counts['deduped']} skipped"
+    for key in ("failed", "restricted", "badsum"):
+        if counts[key]:
+            outcome += f", {counts[key]} {'bad checksum' if key == 'badsum' else key}"
+    return outcome
+
+
+def download_concurrently(queue: deque, fetch, record, workers: int) -> tuple:
+    """Run fetch(identifier) for the queued (identifier, depth) pairs, up to `workers` items at once.
+
+    Each finished item is passed to record(identifier, depth, counts, members), which may queue
+    more, and gets a progress line. Returns (items started, items whose fetch raised, interrupted).
+    On Ctrl-C the queued items are dropped and the running ones are still finished and recorded.
+    """
+    processed = errors = finished = 0
+    running = {}
+    interrupted = False
+
+    def collect(future, identifier: str, depth: int):
+        nonlocal errors, finished
+        finished += 1
+        try:
+            counts, members = future.result()
+        except Exception as e:
+            logging.error(f"{identifier}: {friendly_error(e)}")
+            errors += 1
+            return
+        record(identifier, depth, counts, members)
+        print(f"[{finished}/{processed + len(queue)}] {identifier}: {item_outcome(counts)}")
+
+    with ThreadPoolExecutor(max_workers=workers) as pool:
+        try:
+            while queue or running:
+                while queue and len(running) < workers:
+                    identifier, depth = queue.popleft()
+                    processed += 1
+                    running[pool.submit(fetch, identifier)] = (identifier, depth)
+                done, _ = wait(running, return_when=FIRST_COMPLETED)
+                for future in done:
+                    collect(future, *running.pop(future))
+        except KeyboardInterrupt:
+            interrupted = True
+            queue.clear()
+            print(f"Interrupted; waiting for the {len(running)} running items to finish")
+    for future, (identifier, depth) in running.items():
+        collect(future, identifier, depth)
+    return processed, errors, interrupted
+
+
+def build_parser() -> argparse.ArgumentParser:
+    p = ConfigArgumentParser(description="Download an entire Internet Archive item/collection (v2)")
+    p.add_argument("identifier", nargs="?", help="Archive.org item identifier")
+    p.add_argument("--identifiers-file", help="File with one identifier per line ('#' comments allowed); each item gets its own subdirectory")
+    p.add_argument("--normalize-identifiers", action="store_true",
+                   help="Accept pasted archive.org URLs (https://archive.org/details/ID, .../download/ID/...) and "
+                        "'details/ID' paths for the identifier and --identifiers-file lines, reducing them to the "
+                        "bare identifier; anything that isn't a valid identifier is rejected before downloading")
+    p.add_argument("--search", action="append", metavar="QUERY",
+                   help="Download every item matching this advanced search query (repeatable), e.g. "
+                        "'mediatype:software AND subject:linux'; each whole item is fetched, narrowed only by the "
+                        "file and item filters, unlike IA-Advanced-Search's ISO-like files")
+    p.add_argument("--item-concurrency", type=int, default=1, metavar="N",
+                   help="Download up to N items at the same time, each into its own directory, with a progress line "
+                        "as each one finishes; the files of one item still come one at a time (default: 1)")
+    p.add_argument("--sleep", type=float, default=0.0, metavar="SECONDS",
+                   help="Seconds between the starts of consecutive requests, the library's and this tool's, counted "
+                        "across all --item-concurrency items together (default: 0)")
     p.add_argument("--destdir", "-o", default=DEFAULT_DEST, help="Destination directory")
-    p.add_argument("--ignore-existing", action="store_true", default=True, help="Skip files that already exist (default: true)")
-    p.add_argument("--no-ignore-existing", action="store_false", dest="ignore_existing", help="Do not skip existing files")
+    p.add_argument("--on-exists", choices=("skip", "overwrite", "rename"), default="skip",
+                   help="What to do when a file already exists: skip it (default), overwrite it, or save the new "
+                        "download as 'name (1).ext'. Resuming is not offered: the library always fetches whole files")
+    p.add_argument("--ignore-existing", action="store_const", dest="on_exists", const="skip",
+                   help="Same as --on-exists skip")
+    p.add_argument("--no-ignore-existing", action="store_const", dest="on_exists", const="overwrite",
+                   help="Same as --on-exists overwrite")
     p.add_argument("--checksum", action="store_true", help="Verify checksums after download")
-    p.add_argument("--retries", type=int, default=5, help="Number of retries")
+    p.add_argument("--update", action="store_true",
+                   help="Re-download existing files whose size/mtime (and md5 with --checksum) no longer match the metadata")
+    p.add_argument("--retry-on-checksum-fail", type=int, default=2, metavar="N",
+                   help="With --checksum, re-download a file whose md5 doesn't match at most N times, then keep it "
+                        f"with a {BADSUM_SUFFIX} marker (default: 2)")
     p.add_argument("--glob", help="Only download files matching this glob pattern (e.g. *.iso)")
-    p.add_argument("--log-file", help="Optional path to a log file")
+    p.add_argument("--include", help="Regex; only download files whose name matches (case-insensitive)")
+    p.add_argument("--exclude", help="Regex; skip files whose name matches (case-insensitive)")
+    p.add_argument("--file", action="append", metavar="NAME",
+                   help="Download this exact file name (repeatable); combined with --glob/--include as a union")
+    p.add_argument("--require-metadata", action="append", metavar="FIELD",
+                   help="Skip items whose metadata lacks this field or has it empty, e.g. licenseurl (repeatable)")
+    p.add_argument("--metadata", action="append", type=metadata_match, metavar="FIELD=GLOB",
+                   help="Skip items unless a value of item metadata FIELD matches GLOB, case-insensitively "
+                        "(e.g. language=eng, subject=*linux*; repeatable, all must match)")
+    p.add_argument("--year", type=int, help="Skip items whose metadata year (or date) is not this year")
+    p.add_argument("--follow-derivative-originals", action="store_true", dest="follow_originals",
+                   help="For matched derivative files (e.g. --glob '*.mp3'), download the originals they were derived "
+                        "from instead, via each file's 'original' field")
+    p.add_argument("--include-derivatives", action="store_true",
+                   help="Also download generated files (format 'Metadata', _files.xml, _meta.sqlite, __ia_thumb.jpg, ...)")
+    p.add_argument("--file-list", choices=FILE_LISTS, default="json",
+                   help="Where an item's file list and checksums come from: its JSON metadata (default), its "
+                        "<identifier>_files.xml, or auto (the XML when the JSON lists no files or an original "
+                        "without an md5)")
+    p.add_argument("--originals-only", action="store_true",
+                   help="Only download files archive.org lists as uploaded originals (source 'original'), not the "
+                        "derivatives and metadata files it generates from them")
+    p.add_argument("--web-captures", action="store_true",
+                   help="For web-archive items (mediatype web), only fetch the capture files (.warc.gz) and their "
+                        "CDX indexes (.cdx/.cdx.gz); other items are unaffected")
+    p.add_argument("--list-captures", action="store_true",
+                   help="After downloading, print the captured URL of every entry in the item's CDX files")
+    p.add_argument("--write-m3u", action="store_true",
+                   help="After downloading an item, write <identifier>.m3u8 next to its files listing the audio files "
+                        "on disk in track order (the metadata's track numbers, else its file order)")
+    p.add_argument("--output-template", default="{identifier}/{file_name}",
+                   help="Destination path relative to destdir; placeholders {identifier}, {title}, {file_name}")
+    add_filename_args(p)
+    add_free_space_arg(p)
+    add_head_bytes_arg(p)
+    add_pause_arg(p)
+    p.add_argument("--download-order", choices=DOWNLOAD_ORDERS, default="default",
+                   help="Order to fetch each item's files in: metadata order (default), size-asc to fail fast on "
+                        "small files, size-desc for the big payload first, or name. Files without a size count as 0")
+    add_shuffle_args(p, "the items, and each item's files,")
+    p.add_argument("--archive", choices=ARCHIVE_FORMATS,
+                   help="Put each item's files into one uncompressed <identifier>.tar or .zip in destdir instead of "
+                        "loose files; a later run adds the files still missing from it (--on-exists overwrite "
+                        "starts it afresh). Files are not streamed in: each is saved whole as a .part beside the "
+                        "archive first, then copied in, so the disk needs room for the largest file twice")
+    p.add_argument("--cas-store", metavar="DIR",
+                   help="Content-addressed storage: keep each file once in DIR under its metadata md5 and link the "
+                        "item paths in destdir to it; a file whose md5 is already stored isn't downloaded again")
+    p.add_argument("--cas-link", choices=CAS_LINKS, default="hardlink",
+                   help="How --cas-store links item paths to stored files (default: hardlink, falling back to a "
+                        "symlink where hardlinks aren't possible)")
+    p.add_argument("--flatten", action="store_true",
+                   help="Replace '/' in nested file names with '_' so each item's files share one directory")
+    p.add_argument("--preserve-paths", action="store_false", dest="flatten",
+                   help="Recreate subdirectories from nested file names (default)")
+    add_session_args(p)
+    p.add_argument("--resolve-collections", action="store_true",
+                   help="When an identifier is a collection (mediatype collection), download its member items "
+                        "(found by search) instead, each into its own subdirectory as usual")
+    p.add_argument("--max-depth", type=int, default=2, metavar="N",
+                   help="With --resolve-collections, expand collections nested at most N levels deep; deeper "
+                        "ones are reported and skipped (default: 2)")
+    add_log_file_args(p)
     p.add_argument("-v", action="count", default=0, help="Increase verbosity (-v info, -vv debug)")
     p.add_argument("--dry-run", action="store_true", help="List files without downloading")
-    args = p.parse_args()
+    p.add_argument("--plan", metavar="PATH",
+                   help="With --dry-run, also write the files a real run would handle to PATH as JSON: identifier, "
+                        "name, url, dest_path, size, md5 and action (download, skip, update, overwrite, ...)")
+    p.add_argument("--interactive", action="store_true",
+                   help="List each item's files (after the filters) with numbers and ask which to download; with "
+                        "--dry-run the choice is only printed. Ignored when not run in a terminal")
+    p.add_argument("--compact", action="store_true",
+                   help="With --dry-run, list files as an aligned table of name, size and format with a totals line; "
+                        "on a terminal the header repeats every screen")
+    p.add_argument("--manifest-verify", action="store_true",
+                   help="Download nothing; check the local copies of the selected files against the item metadata "
+                        "(size and md5) and report missing, corrupt and extra files. Exits 2 on any missing or corrupt file")
+    p.add_argument("--verify-after", action="store_true",
+                   help="Once the downloads are done, check every downloaded item's files against its metadata (size "
+                        "and md5) as --manifest-verify does and report missing and corrupt ones, which count as "
+                        "failures; for items fetched without --checksum")
+    p.add_argument("--verify-concurrency", type=int, default=DEFAULT_VERIFY_CONCURRENCY, metavar="N",
+                   help=f"Files hashed at the same time by --manifest-verify and --verify-after "
+                        f"(default: {DEFAULT_VERIFY_CONCURRENCY})")
+    p.add_argument("--via-torrent", action="store_true",
+                   help="Fetch each item's _archive.torrent into destdir, validate it and list its files instead of "
+                        "downloading over HTTP")
+    p.add_argument("--thumbnail", action="store_true",
+                   help="Only fetch each item's primary image (/services/img/<identifier>) and save it as "
+                        "<identifier>.jpg in destdir, e.g. for a catalog; the item's files are not downloaded")
+    p.add_argument("--torrent-client", metavar="CMD",
+                   help="With --via-torrent, run this command with the .torrent path appended (e.g. 'transmission-cli -w D:/isos')")
+    p.add_argument("--fail-on-error", action="store_true", help=argparse.SUPPRESS)
+    add_exit_code_args(p)
+    return p
+
 
-    setup_logging(args.v, args.log_file)
+def main():
+    p = build_parser()
+    args = parse_args_with_config(p, "download-collections")
+
+    if args.retry_on_checksum_fail < 0:
+        p.error("--retry-on-checksum-fail must not be negative")
+    if args.plan and not args.dry_run:
+        p.error("--plan needs --dry-run")
+    if args.seed is not None and not args.shuffle:
+        p.error("--seed needs --shuffle")
+    if args.shuffle and args.download_order != "default":
+        p.error("--shuffle replaces --download-order; use one or the other")
+    if args.archive:
+        clash = [flag for flag, used in (("--cas-store", args.cas_store), ("--update", args.update),
+                                         ("--on-exists rename", args.on_exists == "rename"),
+                                         ("--plan", args.plan), ("--list-captures", args.list_captures),
+                                         ("--manifest-verify", args.manifest_verify),
+                                         ("--via-torrent", args.via_torrent)) if used]
+        if clash:
+            p.error(f"--archive can't be combined with {', '.join(clash)}")
+    if args.resolve_collections:
+        clash = [flag for flag, used in (("--thumbnail", args.thumbnail), ("--manifest-verify", args.manifest_verify),
+                                         ("--via-torrent", args.via_torrent)) if used]
+        if clash:
+            p.error(f"--resolve-collections can't be combined with {', '.join(clash)}")
+    if args.originals_only:
+        clash = [flag for flag, used in (("--include-derivatives", args.include_derivatives),
+                                         ("--follow-derivative-originals", args.follow_originals)) if used]
+        if clash:
+            p.error(f"--originals-only can't be combined with {', '.join(clash)}")
+    if args.head_bytes is not None:
+        clash = [flag for flag, used in (("--archive", args.archive), ("--cas-store", args.cas_store),
+                                         ("--update", args.update), ("--thumbnail", args.thumbnail),
+                                         ("--manifest-verify", args.manifest_verify),
+                                         ("--via-torrent", args.via_torrent)) if used]
+        if clash:
+            p.error(f"--head-bytes can't be combined with {', '.join(clash)}")
+        if args.head_bytes < 1:
+            p.error("--head-bytes must be at least 1")
+    if args.cas_store:
+        clash = [flag for flag, used in (("--thumbnail", args.thumbnail), ("--via-torrent", args.via_torrent)) if used]
+        if clash:
+            p.error(f"--cas-store can't be combined with {', '.join(clash)}")
+    if args.max_depth < 1:
+        p.error("--max-depth must be at least 1")
+    if args.item_concurrency < 1:
+        p.error("--item-concurrency must be at least 1")
+    if args.sleep < 0:
+        p.error("--sleep can't be negative")
+    if args.item_concurrency > 1:
+        clash = [flag for flag, used in (("--dry-run", args.dry_run), ("--interactive", args.interactive),
+                                         ("--cas-store", args.cas_store)) if used]
+        if clash:
+            p.error(f"--item-concurrency can't be combined with {', '.join(clash)}")
+        if not (args.archive or args.thumbnail or args.via_torrent) and "{identifier}" not in args.output_template:
+            p.error("--item-concurrency needs {identifier} in --output-template, so items don't share a directory")
+    if args.verify_concurrency < 1:
+        p.error("--verify-concurrency must be at least 1")
+    if args.verify_after:
+        clash = [flag for flag, used in (("--dry-run", args.dry_run), ("--manifest-verify", args.manifest_verify),
+                                         ("--archive", args.archive), ("--head-bytes", args.head_bytes is not None),
+                                         ("--interactive", args.interactive), ("--thumbnail", args.thumbnail),
+                                         ("--via-torrent", args.via_torrent)) if used]
+        if clash:
+            p.error(f"--verify-after can't be combined with {', '.join(clash)}")
+    if args.write_m3u:
+        clash = [flag for flag, used in (("--dry-run", args.dry_run), ("--archive", args.archive),
+                                         ("--head-bytes", args.head_bytes is not None), ("--thumbnail", args.thumbnail),
+                                         ("--manifest-verify", args.manifest_verify),
+                                         ("--via-torrent", args.via_torrent)) if used]
+        if clash:
+            p.error(f"--write-m3u can't be combined with {', '.join(clash)}")
+    if args.interactive:
+        clash = [flag for flag, used in (("--thumbnail", args.thumbnail), ("--manifest-verify", args.manifest_verify),
+                                         ("--via-torrent", args.via_torrent)) if used]
+        if clash:
+            p.error(f"--interactive can't be combined with {', '.join(clash)}")
+    if args.thumbnail:
+        clash = [flag for flag, used in (("--archive", args.archive), ("--update", args.update), ("--plan", args.plan),
+                                         ("--list-captures", args.list_captures),
+                                         ("--manifest-verify", args.manifest_verify),
+                                         ("--via-torrent", args.via_torrent)) if used]
+        if clash:
+            p.error(f"--thumbnail can't be combined with {', '.join(clash)}")
+    template_error = validate_output_template(args.output_template)
+    if template_error:
+        p.error(template_error)
+    try:
+        # Compiled once for every item, so a bad pattern stops the run here instead of failing each item
+        args.include = re.compile(args.include, re.IGNORECASE) if args.include else None
+        args.exclude = re.compile(args.exclude, re.IGNORECASE) if args.exclude else None
+    except re.error as e:
+        p.error(f"invalid --include/--exclude pattern: {e}")
+
+    identifiers = [args.identifier] if args.identifier else []
+    if args.identifiers_file:
+        identifiers.extend(read_identifiers_file(args.identifiers_file))
+    if not identifiers and not args.search:
+        p.error("an identifier, --identifiers-file or --search is required")
+    if args.normalize_identifiers:
+        try:
+            identifiers = normalize_identifiers(identifiers)
+        except ValueError as e:
+            p.error(str(e))
+
+    setup_logging(args.v, args.log_file, max_size=args.log_max_size, backups=args.log_backups,
+                  log_format=args.log_format)
+    args.pause = PauseFile(args.pause_file) if args.pause_file else None
+
+    if args.compact and not args.dry_run:
+        logging.warning("--compact only changes the --dry-run listing; ignoring it")
+    if args.interactive and not (sys.stdin.isatty() and sys.stdout.isatty()):
+        logging.warning("--interactive needs a terminal; taking every file that passes the filters")
+        args.interactive = False
+
+    config = build_ia_config(args)
+    # Direct requests (thumbnails, --head-bytes, _files.xml) go through this session, the library's through ia_session
+    session = session_from_args(args)
+    args.ia_session = archive_session(args, config, session.verify)
+    if args.sleep > 0:
+        # One pace for the whole run, however many items are in flight
+        pacer = Pacer(args.sleep)
+        pace_requests(session, pacer)
+        pace_requests(args.ia_session, pacer)
+    if args.search:
+        try:
+            identifiers = add_search_matches(identifiers, args, config)
+        except Exception as e:
+            logging.error(f"--search: {friendly_error(e)}")
+            sys.exit(EXIT_ERROR)
+        if not identifiers:
+            print("No items matched --search")
+            sys.exit(EXIT_NOTHING)
+    if args.shuffle:
+        identifiers = shuffled(identifiers, args.seed)
+    if len(identifiers) > 1 and "{identifier}" not in args.output_template:
+        logging.warning("--output-template has no {identifier}; files from different items share one directory")
+
+    if args.manifest_verify:
+        totals = verify_items(identifiers, args, config, session)
+        sys.exit(run_exit_code(totals["missing"] + totals["corrupt"] + totals["errors"],
+                               totals["ok"] + totals["missing"] + totals["corrupt"]))
 
     os.makedirs(args.destdir, exist_ok=True)
+    plan = [] if args.plan else None
+    original_names = {} if args.preserve_original_names and not args.dry_run else None
+    totals = Counter()
+    items_failed = 0
+    interrupted = False
+    # Items whose download ran through, for --verify-after
+    completed = []
+    # (identifier, nesting depth); --resolve-collections appends the members of collections met on the way
+    queue = deque((identifier, 0) for identifier in identifiers)
+    queued = set(identifiers)
+    processed = 0
 
-    logging.info(f"Starting download for '{args.identifier}' -> {args.destdir}")
+    def fetch(identifier: str):
+        """(counts, member identifiers or None) for one item."""
+        members = [] if args.resolve_collections else None
+        if args.thumbnail:
+            return download_thumbnail(session, identifier, args), members
+        if args.via_torrent:
+            return download_via_torrent(identifier, args, config), members
+        return download_item(identifier, args, config, plan, original_names, members, session), members
 
-    if args.dry_run:
-        # internetarchive library supports listing files via get_item
-        item = internetarchive.get_item(args.identifier)
-        for f in item.files:
-            name = f.get("name")
-            if args.glob and not item.session.matches(name, args.glob):
-                continue
-            print(name)
-        return
-
-    # Perform download
-    internetarchive.download(
-        args.identifier,
-        destdir=args.destdir,
-        verbose=args.v >= 1,
-        ignore_existing=args.ignore_existing,
-        checksum=args.checksum,
-        retries=args.retries,
-        glob_pattern=args.glob,
-    )
+    def record(identifier: str, depth: int, counts: Counter, members: Optional[list]):
+        nonlocal items_failed
+        totals.update(counts)
+        if counts["failed"] or counts["restricted"] or counts["badsum"]:
+            items_failed += 1
+        elif not counts["collections"] and not counts["dark"]:
+            completed.append(identifier)
+        if counts["collections"]:
+            # A collection listing itself, or two collections sharing items, must not loop or repeat
+            found = [member for member in members if member not in queued]
+            if depth >= args.max_depth:
+                logging.warning(f"{identifier}: collection nested deeper than --max-depth {args.max_depth}; "
+                                f"skipping its {len(found)} items")
+                return
+            if args.shuffle:
+                found = shuffled(found, None if args.seed is None else f"{args.seed}/{identifier}")
+            print(f"{identifier}: collection; queued {len(found)} member items")
+            queued.update(found)
+            queue.extend((member, depth + 1) for member in found)
+
+    if args.item_concurrency > 1:
+        processed, errors, interrupted = download_concurrently(queue, fetch, record, args.item_concurrency)
+        items_failed += errors
+    else:
+        try:
+            while queue:
+                identifier, depth = queue.popleft()
+                processed += 1
+                try:
+                    counts, members = fetch(identifier)
+                except KeyboardInterrupt:
+                    raise
+                except Exception as e:
+                    logging.error(f"{identifier}: {friendly_error(e)}")
+                    items_failed += 1
+                    continue
+                record(identifier, depth, counts, members)
+        except KeyboardInterrupt:
+            interrupted = True
 
+    if processed > 1 and not args.dry_run:
+        collections = f", {totals['collections']} collections expanded" if totals["collections"] else ""
+        print(f"Items: {processed} ({items_failed} with failures, {totals['dark']} dark{collections}, "
+              f"{totals['excluded']} excluded by item filters, {totals['unmatched']} with no matching files). "
+              f"Files downloaded: {totals['downloaded']}, skipped: {totals['skipped']}, failed: {totals['failed']}, "
+              f"restricted: {totals['restricted']}, bad checksum: {totals['badsum']}")
+    if totals["deduped"]:
+        print(f"Deduplicated {totals['deduped']} files from {args.cas_store}, saving {format_size(totals['deduped_bytes'])}")
+    if plan is not None:
+        write_json_atomic(args.plan, plan, indent=2, ensure_ascii=False)
+        print(f"Plan of {len(plan)} files written to {args.plan}")
+    if original_names:
+        save_original_names(args.destdir, original_names)
+    if interrupted:
+        sys.exit(EXIT_INTERRUPTED)
     logging.info("Download finished")
+    if args.verify_after and completed:
+        verified = verify_items(completed, args, config, session)
+        items_failed += verified["bad_items"]
+    matched = sum(totals[key] for key in ("downloaded", "skipped", "failed", "restricted", "badsum", "deduped",
+                                          "planned"))
+    sys.exit(run_exit_code(items_failed, matched, matched - totals["skipped"], args.exit_code_on_skip))
 
 
 if __name__ == "__main__":
@@ -1,105 +1,1418 @@
+import argparse
+import hashlib
 import os
 import json
+import logging
+import re
 import requests
+import shlex
+import statistics
+import subprocess
 import sys
 import time
+from collections import Counter
+from datetime import datetime, timezone
+from itertools import islice
+from urllib.parse import urlparse
 
-# Load the ISO metadata (explicit UTF-8 to avoid Windows cp1252 decode issues)
-with open("misc.json", "r", encoding="utf-8") as f:
-    iso_list = json.load(f)
+import urllib3
 
-# Make sure the output directory exists
-output_dir = "G:/Linux-ISOs/"
-os.makedirs(output_dir, exist_ok=True)
+from ia_common import (
+    DEFAULT_MAX_BACKOFF,
+    EXIT_ERROR,
+    EXIT_FAILED,
+    EXIT_INTERRUPTED,
+    EXIT_USAGE,
+    PART_SUFFIX,
+    REQUEST_ID_HEADER,
+    ConfigArgumentParser,
+    MetricsWriter,
+    PauseFile,
+    RateLimiter,
+    TransferStatus,
+    add_color_arg,
+    add_exit_code_args,
+    add_filename_args,
+    add_free_space_arg,
+    add_head_bytes_arg,
+    add_log_file_args,
+    add_metrics_args,
+    add_pause_arg,
+    add_session_args,
+    add_shuffle_args,
+    backoff_delay,
+    build_download_url,
+    colored,
+    compression_for,
+    fetch_head,
+    file_md5,
+    format_size,
+    friendly_error,
+    head_path,
+    install_status_signal,
+    last_request_id,
+    older_than,
+    open_text,
+    parse_args_with_config,
+    parse_duration,
+    parse_rate,
+    parse_size_field,
+    record_original_name,
+    render_output_path,
+    run_exit_code,
+    save_original_names,
+    session_from_args,
+    setup_logging,
+    shuffled,
+    size_summary,
+    space_shortfall,
+    unique_path,
+    use_color,
+    validate_output_template,
+    warn_without_contact,
+    with_attempts,
+    write_json_atomic,
+)
+
+DEFAULT_INPUT = "misc.json"
+DEFAULT_OUTPUT_DIR = "G:/Linux-ISOs/"
 
 BAR_WIDTH = 40
 CHUNK_SIZE = 1024 * 256  # 256 KiB chunks for smoother progress
+# --adaptive-buffer: reads start at the minimum and double while they fill in under
+# ADAPTIVE_FAST_READ seconds, up to the maximum; one slower than ADAPTIVE_SLOW_READ halves them
+ADAPTIVE_MIN_CHUNK = 1024 * 64
+ADAPTIVE_MAX_CHUNK = 1024 * 1024 * 8
+ADAPTIVE_FAST_READ = 0.1
+ADAPTIVE_SLOW_READ = 1.0
 REQUEST_TIMEOUT = 60
+RETRIES = 5
+BACKOFF = 1.0  # seconds; doubles with each attempt up to the max backoff
+
+
+# Success green, failure red, skipped/duplicate/warning yellow (see --color)
+MARKER_COLORS = {"[✔]": "green", "[✗]": "red", "[✓]": "yellow", "[=]": "yellow", "[!]": "yellow"}
 
 
-def _format_size(num_bytes: int | None) -> str:
-    if num_bytes is None:
-        return "?"
-    units = ["B", "KB", "MB", "GB", "TB"]
-    size = float(num_bytes)
-    for unit in units:
-        if size < 1024 or unit == units[-1]:
-            return f"{size:.1f}{unit}"
-        size /= 1024
-    return f"{num_bytes}B"
+def mark(marker: str) -> str:
+    return colored(marker, MARKER_COLORS[marker])
 
 
-def _print_bar(prefix: str, downloaded: int, total: int | None):
+# (known total, unknown total) line templates per --progress-style
+PROGRESS_STYLES = {
+    "bar": ("{name} [{bar}] {percent} ({current}/{total})", "{name} [{bar}] {current}"),
+    "bytes": ("{name} {current} of {total} ({percent})", "{name} {current}"),
+    "minimal": ("{name} {percent}", "{name} {current}"),
+    "spinner": ("{name} {spinner} {current} of {total}", "{name} {spinner} {current}"),
+}
+PROGRESS_FIELDS = ("name", "bar", "percent", "current", "total", "spinner")
+SPINNER = "|/-\\"
+
+
+def progress_template(value: str) -> str:
+    """argparse type for --progress-template: a str.format template over PROGRESS_FIELDS."""
+    try:
+        value.format(**{field: "" for field in PROGRESS_FIELDS})
+    except (KeyError, IndexError, ValueError) as e:
+        raise argparse.ArgumentTypeError(f"{value!r}: {e!r} (use {', '.join('{' + f + '}' for f in PROGRESS_FIELDS)})")
+    return value
+
+
+def _print_bar(prefix: str, downloaded: int, total: int | None, templates: tuple = PROGRESS_STYLES["bar"]):
+    fields = {"name": prefix, "current": format_size(downloaded),
+              "spinner": SPINNER[int(time.monotonic() * 8) % len(SPINNER)]}
     if total and total > 0:
         frac = min(1.0, downloaded / total)
         filled = int(BAR_WIDTH * frac)
-        bar = "#" * filled + "-" * (BAR_WIDTH - filled)
-        percent = int(frac * 100)
-        total_s = _format_size(total)
-        cur_s = _format_size(downloaded)
-        line = f"{prefix} [{bar}] {percent:3d}% ({cur_s}/{total_s})"
+        fields.update(bar="#" * filled + "-" * (BAR_WIDTH - filled), percent=f"{int(frac * 100):3d}%",
+                      total=format_size(total))
+        template = templates[0]
     else:
         # Unknown total size
-        cur_s = _format_size(downloaded)
         bar = "#" * (downloaded // (10 * 1024 * 1024))  # one # per ~10MB as a rough indicator
-        bar = bar[-BAR_WIDTH:]
-        line = f"{prefix} [{bar:<{BAR_WIDTH}}] {cur_s}"
-    print("\r" + line, end="", flush=True)
+        fields.update(bar=f"{bar[-BAR_WIDTH:]:<{BAR_WIDTH}}", percent="?", total="?")
+        template = templates[1]
+    print("\r" + template.format(**fields), end="", flush=True)
 
 
-def download_file(url: str, dest_path: str, display_name: str | None = None):
-    """Download a URL to dest_path with a simple progress bar."""
-    display_name = display_name or os.path.basename(dest_path)
+class IncompleteDownload(IOError):
+    """The body broke off or ended before the advertised Content-Length was received.
+
+    Only these are retried by download_file; connection and status errors on the
+    initial request are already retried by the session's urllib3 Retry policy.
+    """
+
+
+class NotModified(Exception):
+    """A conditional GET answered 304: the local copy is current."""
+
+
+class FileTimeout(Exception):
+    """The --timeout-per-file budget for one file ran out; its `.part` file is kept for a later resume."""
+
+
+# Sidecar --write-checksums puts next to each download, in sha256sum's format
+CHECKSUM_SUFFIX = ".sha256"
+
+
+class StreamHashes:
+    """md5 and sha256 of a download, updated chunk by chunk as it is written.
+
+    A resumed transfer seeds them from the bytes already in its `.part` file; only those
+    are read back, never the bytes fetched in this run.
+    """
+
+    def __init__(self):
+        self.reset()
+
+    def reset(self):
+        self.md5, self.sha256 = hashlib.md5(), hashlib.sha256()
+
+    def update(self, data: bytes):
+        self.md5.update(data)
+        self.sha256.update(data)
+
+    def seed(self, path: str):
+        self.reset()
+        with open(path, "rb") as f:
+            for block in iter(lambda: f.read(CHUNK_SIZE), b""):
+                self.update(block)
+
+
+def write_checksum_file(dest_path: str, sha256: str):
+    """Write <dest_path>.sha256 so `sha256sum -c` can check the download from its directory."""
+    with open(dest_path + CHECKSUM_SUFFIX, "w", encoding="utf-8", newline="\n") as f:
+        f.write(f"{sha256}  {os.path.basename(dest_path)}\n")
+
+
+META_SUFFIX = ".meta"
+
+ON_EXISTS_POLICIES = ("skip", "overwrite", "resume", "rename")
+
+
+def read_validators(dest_path: str) -> dict:
+    """ETag/Last-Modified recorded next to a previous download, if any."""
     try:
-        with requests.get(url, stream=True, timeout=REQUEST_TIMEOUT) as r:
-            r.raise_for_status()
-            total_str = r.headers.get("Content-Length") or r.headers.get("content-length")
-            total = int(total_str) if total_str and total_str.isdigit() else None
+        with open(dest_path + META_SUFFIX, "r", encoding="utf-8") as f:
+            meta = json.load(f)
+    except (OSError, json.JSONDecodeError):
+        return {}
+    return {k: meta[k] for k in ("etag", "last_modified") if meta.get(k)}
+
+
+def write_validators(dest_path: str, url: str, headers, served_url: str | None = None) -> None:
+    """Save the ETag/Last-Modified of a download for --update, with the URL asked for and the one that served it."""
+    meta = {"url": url, "served_url": served_url or url, "etag": headers.get("ETag"),
+            "last_modified": headers.get("Last-Modified")}
+    if meta["etag"] or meta["last_modified"]:
+        write_json_atomic(dest_path + META_SUFFIX, meta, indent=2)
+
+
+def discard_download(dest_path: str):
+    """Delete a download that failed its checks, with any .meta that would describe it to --update."""
+    for path in (dest_path, dest_path + META_SUFFIX):
+        if os.path.exists(path):
+            os.remove(path)
+
+
+def if_range_header(validators: dict) -> dict:
+    """If-Range for resuming a file saved with these validators: its strong ETag, else its Last-Modified."""
+    etag = validators.get("etag")
+    if etag and not etag.startswith("W/"):
+        return {"If-Range": etag}
+    if validators.get("last_modified"):
+        return {"If-Range": validators["last_modified"]}
+    return {}
+
+
+def continue_short_file(dest_path: str, display_name: str) -> dict:
+    """Turn a short existing file into the .part to resume; returns the headers for that resume.
+
+    A .part already there is never overwritten: it is resumed instead, and the short file
+    is replaced once it completes. Otherwise the file's saved validators go out as If-Range,
+    so a file that changed upstream since comes back whole instead of spliced onto old bytes.
+    """
+    part_path = dest_path + PART_SUFFIX
+    if os.path.exists(part_path):
+        print(f"{mark('[!]')} {display_name}: resuming the existing {os.path.basename(part_path)} instead; "
+              "the short file is left until it completes")
+        return {}
+    headers = if_range_header(read_validators(dest_path))
+    os.replace(dest_path, part_path)
+    return headers
+
+
+def conditional_headers(validators: dict) -> dict:
+    headers = {}
+    if validators.get("etag"):
+        headers["If-None-Match"] = validators["etag"]
+    if validators.get("last_modified"):
+        headers["If-Modified-Since"] = validators["last_modified"]
+    return headers
+
+
+# archive.org's record of the length the file was served with upstream
+ORIG_LENGTH_HEADER = "X-Archive-Orig-Content-Length"
+CONTENT_RANGE_PATTERN = re.compile(r"bytes (\d+)-(\d+)/(\d+|\*)")
+
 
+def parse_content_range(value: str | None) -> tuple | None:
+    """(first, last, total) from a `bytes N-M/Total` Content-Range, total None for `*`; None if malformed."""
+    match = CONTENT_RANGE_PATTERN.fullmatch((value or "").strip())
+    if not match or int(match[2]) < int(match[1]):
+        return None
+    return int(match[1]), int(match[2]), None if match[3] == "*" else int(match[3])
+
+
+def range_problem(content_range: str | None, offset: int, length: int | None, expected_size: int | None) -> str | None:
+    """Why a 206 answering `Range: bytes=offset-` can't be appended to the .part, or None if it can.
+
+    The range has to start at offset and, where the sizes are known, run to the end of a file of
+    expected_size with as many bytes as Content-Length says; a different total means the file
+    changed upstream since the .part was started.
+    """
+    parsed = parse_content_range(content_range)
+    if parsed is None:
+        return f"unreadable Content-Range {content_range!r}"
+    first, last, total = parsed
+    if first != offset:
+        return f"asked for bytes {offset}- but got Content-Range {content_range!r}"
+    if length is not None and last - first + 1 != length:
+        return f"Content-Range {content_range!r} doesn't match Content-Length {length}"
+    if total is not None and expected_size is not None and total != expected_size:
+        return f"file is now {total} bytes, not {expected_size} (changed upstream)"
+    if total is not None and last != total - 1:
+        return f"Content-Range {content_range!r} stops short of the end of the file"
+    return None
+
+
+def _adaptive_chunks(raw, start: int, cap: int):
+    """Yield a response body in reads sized to the link, from `start` up to `cap` bytes.
+
+    A fast link soon reads (and writes) in few large chunks; a slow one stays at small
+    reads, so little is held in memory and progress keeps moving.
+    """
+    size = start
+    while True:
+        clock = time.monotonic()
+        try:
+            chunk = raw.read(size, decode_content=True)
+        except urllib3.exceptions.HTTPError as e:
+            # Surface like iter_content() does, so the caller's handling stays the same
+            raise requests.exceptions.ChunkedEncodingError(e) from e
+        if not chunk:
+            return
+        elapsed = time.monotonic() - clock
+        filled = len(chunk) >= size
+        yield chunk
+        if filled and elapsed < ADAPTIVE_FAST_READ:
+            size = min(cap, size * 2)
+        elif elapsed > ADAPTIVE_SLOW_READ:
+            size = max(start, size // 2)
+
+
+def _download_once(session: requests.Session, url: str, dest_path: str, display_name: str, offset: int = 0,
+                   extra_headers: dict | None = None, rate_limiter: RateLimiter | None = None,
+                   progress: bool | tuple = True, status: TransferStatus | None = None,
+                   file_limiter: RateLimiter | None = None, deadline: float | None = None,
+                   adaptive: bool = False, pause: PauseFile | None = None, hashes: StreamHashes | None = None,
+                   expected_size: int | None = None):
+    """Single GET of url into dest_path, continuing from offset if the server honours Range.
+
+    Returns the response headers and the URL that answered (after redirects). With a
+    rate_limiter (shared by all transfers) and/or a file_limiter (this file only), reads
+    are paced to both byte rates; with a status, every chunk is counted towards it. Past
+    the time.monotonic() deadline the transfer stops with FileTimeout after the chunk in
+    hand is written. progress may be a pair of PROGRESS_STYLES templates instead of True.
+    With `adaptive`, read sizes follow the throughput (see _adaptive_chunks) instead of
+    staying at CHUNK_SIZE. With a pause, reading stops between chunks while its sentinel
+    file exists. hashes, when given, end up covering the whole of dest_path. A 206 is only
+    appended after its Content-Range checks out against offset and expected_size (see
+    range_problem); otherwise the .part is discarded.
+    """
+    templates = PROGRESS_STYLES["bar"] if progress is True else progress
+    headers = dict(extra_headers or {})
+    if offset > 0:
+        headers["Range"] = f"bytes={offset}-"
+    with session.get(url, stream=True, headers=headers) as r:
+        if r.status_code == 304:
+            raise NotModified()
+        if offset > 0 and r.status_code == 416:
+            # Nothing left to fetch if the server says the file is exactly `offset` bytes long
+            if r.headers.get("Content-Range", "").endswith(f"/{offset}"):
+                if hashes:
+                    hashes.seed(dest_path)
+                return r.headers, r.url
+            os.remove(dest_path)
+            raise IncompleteDownload(f"Range {offset}- not satisfiable; restarting from zero")
+        r.raise_for_status()
+        total_str = r.headers.get("Content-Length") or r.headers.get("content-length")
+        length = int(total_str) if total_str and total_str.isdigit() else None
+        # A proxy that cuts the body short may rewrite Content-Length to match; the
+        # archive's copy of the original length still gives the intended size. It only
+        # ever raises the expectation: a lower one would let a short body pass as complete
+        orig_length = parse_size_field(r.headers.get(ORIG_LENGTH_HEADER))
+        if r.status_code == 200 and orig_length is not None and (length is None or orig_length > length):
+            if length is not None:
+                print(f"{mark('[!]')} {display_name}: Content-Length {length} but {ORIG_LENGTH_HEADER} "
+                      f"{orig_length}; expecting {orig_length} bytes")
+            length = orig_length
+        # Content-Length describes the encoded body; only compare it against
+        # bytes written when the body is not transfer-compressed.
+        expected = length if not r.headers.get("Content-Encoding") else None
+
+        if offset > 0 and r.status_code == 206:
+            # Appending anything but the rest of the same file would splice two copies together
+            problem = range_problem(r.headers.get("Content-Range"), offset, expected, expected_size)
+            if problem:
+                os.remove(dest_path)
+                raise IncompleteDownload(f"{problem}; restarting from zero")
+            mode = "ab"
+            downloaded = offset
+        else:
+            # Server ignored the Range header: the body is the whole file, so start over
+            if offset > 0:
+                print(f"{mark('[!]')} {display_name}: server ignored the Range and sent the whole file; "
+                      f"discarding {offset} bytes and restarting from zero")
+            mode = "wb"
             downloaded = 0
-            last_update = 0.0
-            prefix = f"[↓] {display_name}"
-            _print_bar(prefix, downloaded, total)
-            with open(dest_path, "wb") as f:
-                for chunk in r.iter_content(chunk_size=CHUNK_SIZE):
+        if hashes and mode == "ab":
+            hashes.seed(dest_path)
+        elif hashes:
+            hashes.reset()
+        total = downloaded + length if length is not None else None
+
+        received = 0
+        last_update = 0.0
+        prefix = f"[↓] {display_name}"
+        # Smaller reads under a rate limit keep the pacing smooth instead of bursty
+        rates = [limiter.rate for limiter in (rate_limiter, file_limiter) if limiter]
+        chunk_size = min(CHUNK_SIZE, max(1024, int(min(rates) / 8))) if rates else CHUNK_SIZE
+        if adaptive:
+            cap = chunk_size if rates else ADAPTIVE_MAX_CHUNK
+            chunk_size = min(ADAPTIVE_MIN_CHUNK, cap)
+            chunks = _adaptive_chunks(r.raw, chunk_size, cap)
+        else:
+            chunks = r.iter_content(chunk_size=chunk_size)
+        if progress:
+            _print_bar(prefix, downloaded, total, templates)
+        # Each chunk goes straight into a file buffer of one (first) chunk, so memory stays
+        # bounded at roughly the largest read no matter how bursty the stream is
+        with open(dest_path, mode, buffering=chunk_size) as f:
+            try:
+                for chunk in chunks:
                     if not chunk:
                         continue
+                    if pause:
+                        pause.check()
+                    if rate_limiter:
+                        rate_limiter.consume(len(chunk))
+                    if file_limiter:
+                        file_limiter.consume(len(chunk))
                     f.write(chunk)
+                    if hashes:
+                        hashes.update(chunk)
+                    if status:
+                        status.advance(display_name, len(chunk))
+                    received += len(chunk)
                     downloaded += len(chunk)
+                    if deadline is not None and time.monotonic() > deadline:
+                        raise FileTimeout(f"stopped after {received} bytes")
                     # Throttle refresh rate to reduce flicker/CPU
                     now = time.time()
-                    if now - last_update >= 0.05:
-                        _print_bar(prefix, downloaded, total)
+                    if progress and now - last_update >= 0.05:
+                        _print_bar(prefix, downloaded, total, templates)
                         last_update = now
+            except requests.RequestException as e:
+                raise IncompleteDownload(f"Stream interrupted after {received} bytes: {e}") from e
+        if expected is not None and received != expected:
+            if received > expected:
+                os.remove(dest_path)
+            raise IncompleteDownload(f"Incomplete download: received {received} of {expected} bytes")
+        if progress:
             # Finalize bar at 100%
-            _print_bar(prefix, downloaded, total)
+            _print_bar(prefix, downloaded, total, templates)
             print()  # newline after bar
-    except Exception:
-        # Ensure the progress line doesn't stick on errors
-        print()
-        raise
+        return r.headers, r.url
 
 
-def main():
-    total_items = len(iso_list)
-    for idx, iso in enumerate(iso_list, start=1):
-        file_name = iso.get("file_name")
-        url = iso.get("download_url")
-        if not file_name or not url:
+def download_file(session: requests.Session, url: str, dest_path: str, display_name: str | None = None,
+                  retries: int = RETRIES, backoff: float = BACKOFF, max_backoff: float = DEFAULT_MAX_BACKOFF,
+                  resume: bool = False, extra_headers: dict | None = None,
+                  rate_limiter: RateLimiter | None = None, progress: bool | tuple = True,
+                  status: TransferStatus | None = None, fallback_url: str | None = None,
+                  file_rate: float | None = None, time_limit: float | None = None,
+                  adaptive_buffer: bool = False, pause: PauseFile | None = None,
+                  hashes: StreamHashes | None = None, expected_size: int | None = None):
+    """Download a URL to dest_path with a simple progress bar.
+
+    Returns the response headers and the URL that finally served the file, after redirects
+    to a data node and any fallback_url.
+
+    Data is written to `<dest_path>.part` and renamed once the transfer completes, so
+    an interrupted run never leaves a truncated file under the real name. Connection
+    drops mid-body are retried up to `retries` times with exponential backoff,
+    continuing from the bytes already on disk. With `resume`, an existing `.part`
+    file from an earlier run is continued as well. Raises NotModified when
+    `extra_headers` carry validators and the server answers 304. With progress=False
+    no progress bar is drawn (e.g. when several downloads share the terminal); a pair of
+    templates draws it in that style (see PROGRESS_STYLES). If the
+    url answers 404 and a fallback_url is given (the generic /download/ URL behind an
+    exact data-node URL, which goes stale when items move), that one is tried instead.
+    file_rate caps this transfer alone (bytes/sec), on top of any shared rate_limiter.
+    time_limit is a ceiling in seconds on the whole file, retries included; when it runs
+    out FileTimeout is raised and the `.part` file stays for a later resume. adaptive_buffer
+    grows reads and writes with the throughput, up to ADAPTIVE_MAX_CHUNK. pause holds the
+    transfer while its sentinel file exists; a server that drops the idle connection in the
+    meantime is handled like any other drop. A StreamHashes passed as hashes is left holding
+    the finished file's checksums, computed as it streamed in. expected_size, the listed size
+    of the file, lets a resumed range be checked against it (see range_problem).
+    """
+    display_name = display_name or os.path.basename(dest_path)
+    part_path = dest_path + PART_SUFFIX
+    offset = os.path.getsize(part_path) if resume and os.path.exists(part_path) else 0
+    attempt = 0
+    # One bucket for the whole file, so retries don't get a fresh burst
+    file_limiter = RateLimiter(file_rate) if file_rate else None
+    deadline = time.monotonic() + time_limit if time_limit else None
+    while True:
+        try:
+            headers, served_url = _download_once(session, url, part_path, display_name, offset, extra_headers,
+                                                 rate_limiter, progress, status, file_limiter, deadline,
+                                                 adaptive_buffer, pause, hashes, expected_size)
+            os.replace(part_path, dest_path)
+            return headers, served_url
+        except IncompleteDownload as e:
+            # Ensure the progress line doesn't stick on errors
+            if progress:
+                print()
+            attempt += 1
+            if attempt > retries:
+                raise
+            offset = os.path.getsize(part_path) if os.path.exists(part_path) else 0
+            delay = backoff_delay(attempt, backoff, max_backoff)
+            if deadline is not None and time.monotonic() + delay > deadline:
+                raise FileTimeout(f"no time left to retry after: {e}; {timeout_note(time_limit, part_path)}") from e
+            print(f"{mark('[!]')} {display_name}: {e} - retrying ({attempt}/{retries}) from byte {offset}")
+            time.sleep(delay)
+        except FileTimeout as e:
+            if progress:
+                print()
+            raise FileTimeout(f"{e}; {timeout_note(time_limit, part_path)}") from None
+        except NotModified:
+            raise
+        except requests.HTTPError as e:
+            if progress:
+                print()
+            if not fallback_url or fallback_url == url or getattr(e.response, "status_code", None) != 404:
+                raise
+            print(f"{mark('[!]')} {display_name}: {url} answered 404 - retrying via {fallback_url}")
+            url, fallback_url = fallback_url, None
+        except KeyboardInterrupt:
+            if progress:
+                print()
+            if os.path.exists(part_path):
+                print(f"{mark('[!]')} Interrupted: partial download kept at {part_path}")
+            raise
+        except Exception:
+            if progress:
+                print()
+            raise
+
+
+def timeout_note(time_limit: float, part_path: str) -> str:
+    kept = f"partial download kept at {part_path}" if os.path.exists(part_path) else "nothing downloaded"
+    return f"--timeout-per-file {time_limit:g}s reached, {kept}"
+
+
+# (name field, URL field) of the entries IA-Advanced-Search-v2.py writes
+DEFAULT_FIELDS = ("file_name", "download_url")
+
+
+def json_path(value, path: str):
+    """Follow a dotted path such as files.0.url through nested objects and arrays; None where it leads nowhere."""
+    for key in path.split("."):
+        if isinstance(value, dict):
+            value = value.get(key)
+        elif isinstance(value, list) and key.isdigit() and int(key) < len(value):
+            value = value[int(key)]
+        else:
+            return None
+    return value
+
+
+def json_path_arg(value: str) -> str:
+    if not value or "" in value.split("."):
+        raise argparse.ArgumentTypeError(f"invalid field path {value!r}; expected names joined by dots, e.g. files.0.url")
+    return value
+
+
+def entry_problem(item, fields: tuple = DEFAULT_FIELDS) -> str | None:
+    """Why an input entry can't be downloaded, or None if it's usable."""
+    if not isinstance(item, dict):
+        return f"expected an object, got {type(item).__name__}"
+    missing = [path for path in fields if not isinstance(json_path(item, path), str) or not json_path(item, path)]
+    if missing:
+        return "missing " + " and ".join(missing)
+    return None
+
+
+def with_fields(item: dict, fields: tuple) -> dict:
+    """A valid entry with file_name and download_url copied in from the --name-field/--url-field paths."""
+    if fields == DEFAULT_FIELDS:
+        return item
+    return dict(item, **{key: json_path(item, path) for key, path in zip(DEFAULT_FIELDS, fields)})
+
+
+def _iter_ndjson(lines, fields: tuple = DEFAULT_FIELDS):
+    """Yield (line_no, item, problem) per non-blank line; item is None if the line isn't valid JSON."""
+    for line_no, line in enumerate(lines, start=1):
+        line = line.strip()
+        if not line:
+            continue
+        try:
+            item = json.loads(line)
+        except json.JSONDecodeError as e:
+            yield line_no, None, f"invalid JSON ({e})"
             continue
+        yield line_no, item, entry_problem(item, fields)
 
-        dest_path = os.path.join(output_dir, file_name)
-        prefix = f"[{idx}/{total_items} {(idx/total_items*100):.1f}%]"
 
-        if os.path.exists(dest_path):
-            print(f"{prefix} [✓] Already exists: {file_name}")
+def _file_lines(path: str):
+    with open_text(path) as f:
+        yield from f
+
+
+def load_items(path: str, ndjson: bool, fields: tuple = DEFAULT_FIELDS):
+    """Return (item_count, iterable of valid items, problems) for a JSON array or NDJSON input file.
+
+    A path of '-' reads standard input. `problems` lists (location, reason) for every unusable
+    entry, located by array index or NDJSON line number. Raises ValueError if the file isn't
+    a JSON array at all. `fields` are the (name, URL) paths to read from each entry; valid
+    items carry them as file_name and download_url.
+    """
+    # stdin can only be read once, so it is held in memory for both NDJSON passes
+    stdin_text = sys.stdin.read() if path == "-" else None
+    if ndjson:
+        def lines():
+            return stdin_text.splitlines() if stdin_text is not None else _file_lines(path)
+
+        # Validation pass up front; the download pass streams the file again
+        problems, count = [], 0
+        for line_no, _, problem in _iter_ndjson(lines(), fields):
+            if problem:
+                problems.append((f"line {line_no}", problem))
+            else:
+                count += 1
+        return count, (with_fields(item, fields) for _, item, problem in _iter_ndjson(lines(), fields)
+                       if not problem), problems
+    try:
+        if stdin_text is not None:
+            items = json.loads(stdin_text)
+        else:
+            # Explicit UTF-8 (inside open_text) to avoid Windows cp1252 decode issues
+            with open_text(path) as f:
+                items = json.load(f)
+    except json.JSONDecodeError as e:
+        raise ValueError(f"{path} is not valid JSON: {e}") from e
+    if not isinstance(items, list):
+        raise ValueError(f"{path}: expected a JSON array of objects, got {type(items).__name__}"
+                         + (" (use --ndjson for one object per line)" if isinstance(items, dict) else ""))
+    problems = [(f"index {i}", entry_problem(item, fields)) for i, item in enumerate(items)
+                if entry_problem(item, fields)]
+    valid = [with_fields(item, fields) for item in items if not entry_problem(item, fields)]
+    return len(valid), valid, problems
+
+
+def _now() -> str:
+    return datetime.now(timezone.utc).isoformat(timespec="seconds")
+
+
+def generic_url(iso: dict) -> str | None:
+    """The item's /download/ URL for an entry, which redirects to wherever the file lives now."""
+    if not iso.get("identifier") or not iso.get("file_name"):
+        return None
+    return build_download_url(iso["identifier"], iso["file_name"])
+
+
+def _prefix(idx: int, total_items: int | None) -> str:
+    """'[3/40 7.5%]' progress prefix, or just '[3]' when the total isn't known yet."""
+    if not total_items:
+        return f"[{idx}]"
+    return f"[{idx}/{total_items} {(idx/total_items*100):.1f}%]"
+
+
+def resolve_dest_path(args, iso: dict) -> str:
+    rel_path = render_output_path(args.output_template, identifier=iso.get("identifier") or "",
+                                  file_name=iso.get("file_name"), title=iso.get("title") or "",
+                                  max_length=args.max_filename_length, replacement=args.invalid_char_replacement,
+                                  portable=args.portable_names)
+    return os.path.join(args.output_dir, rel_path)
+
+
+def save_renamed(args, results: list):
+    """For --preserve-original-names: record the downloaded files whose local name differs from the listed one."""
+    names = {}
+    for r in results:
+        if r["status"] == "downloaded":
+            record_original_name(names, args.output_dir, r["dest_path"], r["identifier"] or "", r["file_name"])
+    save_original_names(args.output_dir, names)
+
+
+def partial_entries(args, items) -> tuple:
+    """Split out the entries with a `.part` file under the output dir (for --resume-all).
+
+    Returns (entries, orphans): the entries to resume, in input order, and the `.part`
+    files that no input entry maps to.
+    """
+    parts = set()
+    for dirpath, _, filenames in os.walk(args.output_dir):
+        parts.update(os.path.abspath(os.path.join(dirpath, name)) for name in filenames if name.endswith(PART_SUFFIX))
+    entries = []
+    for iso in items:
+        part_path = os.path.abspath(resolve_dest_path(args, iso)) + PART_SUFFIX
+        if part_path in parts:
+            parts.discard(part_path)
+            entries.append(iso)
+    return entries, sorted(parts)
+
+
+def has_mediatype(iso: dict, wanted: list) -> bool:
+    """Whether the entry's mediatype field is one of wanted (case-insensitively); entries without one never are."""
+    value = iso.get("mediatype")
+    values = value if isinstance(value, list) else [value]
+    return any(isinstance(v, str) and v.lower() in wanted for v in values)
+
+
+def remaining_bytes(args, items: list) -> int:
+    """Known bytes still to fetch for items: listed sizes minus what is already on disk."""
+    total = 0
+    for iso in items:
+        expected = parse_size_field(iso.get("size"))
+        if expected is None:
             continue
+        dest_path = resolve_dest_path(args, iso)
+        have = 0
+        if os.path.exists(dest_path):
+            size = os.path.getsize(dest_path)
+            stale = older_than(dest_path, args.refresh_older_than)
+            if args.update or (args.on_exists == "skip" and size == expected and not stale):
+                have = expected
+            elif args.on_exists in ("skip", "resume") and size < expected and not stale:
+                have = size
+        elif args.on_exists == "resume" and os.path.exists(dest_path + PART_SUFFIX):
+            have = os.path.getsize(dest_path + PART_SUFFIX)
+        total += max(0, expected - have)
+    return total
+
+
+def dedupe_key(iso: dict) -> str:
+    """Identical content is recognised by md5 when the entry carries one, else by URL."""
+    md5 = iso.get("md5")
+    return f"md5:{md5.lower()}" if md5 else f"url:{iso.get('download_url')}"
+
+
+def handle_duplicate(args, iso: dict, original_path: str, idx: int, total_items: int) -> dict:
+    """Skip or hardlink an entry whose content was already fetched to original_path."""
+    file_name = iso.get("file_name")
+    dest_path = resolve_dest_path(args, iso)
+    prefix = _prefix(idx, total_items)
+    result = {
+        "index": idx,
+        "identifier": iso.get("identifier"),
+        "file_name": file_name,
+        "download_url": iso.get("download_url"),
+        "dest_path": os.path.abspath(dest_path),
+        "size": parse_size_field(iso.get("size")),
+        "status": "duplicate",
+        "bytes": 0,
+        "error": None,
+        "duplicate_of": os.path.abspath(original_path),
+        "saved_bytes": (os.path.getsize(original_path) if os.path.exists(original_path)
+                        else parse_size_field(iso.get("size")) or 0),
+        "started_at": _now(),
+    }
+    if args.dedupe == "hardlink" and not args.dry_run and os.path.abspath(dest_path) != os.path.abspath(original_path):
+        try:
+            if not os.path.exists(dest_path):
+                os.makedirs(os.path.dirname(dest_path), exist_ok=True)
+                os.link(original_path, dest_path)
+            print(f"{prefix} {mark('[=]')} Linked duplicate: {file_name} -> {original_path}")
+        except OSError as e:
+            result["error"] = f"hardlink failed: {e}"
+            print(f"{prefix} {mark('[=]')} Duplicate of {original_path}, hardlink failed ({e}): {file_name}")
+    else:
+        print(f"{prefix} {mark('[=]')} Duplicate of {original_path}, skipping: {file_name}")
+    result["finished_at"] = _now()
+    return result
+
+
+def add_per_file_args(parser):
+    parser.add_argument("--limit-rate-per-file", type=parse_rate, metavar="RATE",
+                        help="Cap each single transfer at RATE, e.g. 1MB/s; with --max-rate both apply, so a "
+                             "file gets at most the smaller of RATE and its share of --max-rate")
+    parser.add_argument("--timeout-per-file", type=float, metavar="SECONDS",
+                        help="Give up on a file after SECONDS in total, retries included, and move on; it counts "
+                             "as failed and its .part file is kept for --resume")
+    parser.add_argument("--adaptive-buffer", action="store_true",
+                        help=f"Grow each transfer's reads and writes from {ADAPTIVE_MIN_CHUNK // 1024} KiB up to "
+                             f"{ADAPTIVE_MAX_CHUNK // (1024 * 1024)} MiB while the link keeps up, instead of fixed "
+                             f"{CHUNK_SIZE // 1024} KiB chunks: fewer system calls on fast links, small buffers on "
+                             "slow ones")
+    parser.add_argument("--refresh-older-than", type=parse_duration, metavar="DURATION",
+                        help="With --on-exists skip, fetch an existing file again when it was last modified more "
+                             "than DURATION ago (e.g. 30d, 12h, 2w), to pick up upstream fixes; newer files are "
+                             "still skipped")
+    parser.add_argument("--write-checksums", action="store_true",
+                        help=f"Hash each file as it streams in and write <file>{CHECKSUM_SUFFIX} next to it (for "
+                             "sha256sum -c); an entry's md5, when it has one, is checked on the way, and a "
+                             "mismatching download is deleted and counted as failed")
+
 
+HOOK_PLACEHOLDERS = ("path", "identifier", "filename")
+
+
+def add_hook_args(parser):
+    parser.add_argument("--on-complete", metavar="CMD",
+                        help="Run CMD after each successful download, e.g. 'clamscan {path}'; placeholders {path}, "
+                             "{identifier} and {filename}. The command is split like a shell line but not run "
+                             "through a shell")
+    parser.add_argument("--hook-strict", action="store_true",
+                        help="Count a file as failed when its --on-complete command exits non-zero")
+
+
+def hook_template_error(template: str) -> str | None:
+    """Return an error message if an --on-complete template can't be split or uses unknown placeholders."""
+    try:
+        for token in shlex.split(template):
+            token.format(**{name: "x" for name in HOOK_PLACEHOLDERS})
+    except (KeyError, IndexError, ValueError) as e:
+        return f"Invalid --on-complete {template!r}: {e!r} (use {{path}}, {{identifier}}, {{filename}})"
+    return None
+
+
+def run_hook(template: str, path: str, iso: dict) -> int:
+    """Run the --on-complete command for one downloaded file and return its exit status.
+
+    Placeholders are filled per argument after splitting, so file names with spaces or
+    quotes reach the command as a single argument. 127 means the command wasn't found.
+    """
+    values = {"path": path, "identifier": iso.get("identifier") or "", "filename": iso.get("file_name") or ""}
+    argv = [token.format(**values) for token in shlex.split(template)]
+    try:
+        return subprocess.run(argv).returncode
+    except OSError as e:
+        print(f"{mark('[!]')} --on-complete could not start {argv[0]!r}: {e}")
+        return 127
+
+
+def process_item(session: requests.Session, args, iso: dict, idx: int, total_items: int | None,
+                 rate_limiter: RateLimiter | None = None, status: TransferStatus | None = None) -> dict | None:
+    """Download (or skip/plan) one input entry and return its result record, or None if unusable."""
+    file_name = iso.get("file_name")
+    url = iso.get("download_url")
+    if not file_name or not url:
+        return None
+
+    dest_path = resolve_dest_path(args, iso)
+    prefix = _prefix(idx, total_items)
+    result = {
+        "index": idx,
+        "identifier": iso.get("identifier"),
+        "file_name": file_name,
+        "download_url": url,
+        "dest_path": os.path.abspath(dest_path),
+        "size": parse_size_field(iso.get("size")),
+        "status": None,
+        "bytes": 0,
+        "error": None,
+        "started_at": _now(),
+    }
+    earlier_request = last_request_id()
+
+    def finish(status: str, error: str | None = None) -> dict:
+        result["status"] = status
+        result["error"] = error
+        result["finished_at"] = _now()
+        # One line per entry for --log-file, naming the entry's last request for support tickets;
+        # the terminal shows the printed line instead
+        request_id = last_request_id()
+        logging.info(f"{status} {result['identifier']}/{file_name} -> {result['dest_path']}, {result['bytes']} bytes"
+                     + (f" ({REQUEST_ID_HEADER} {request_id})" if request_id != earlier_request else "")
+                     + (f": {error}" if error else ""))
+        return result
+
+    if args.head_bytes:
+        dest_path = head_path(dest_path, args.head_bytes)
+        if os.path.exists(dest_path) and args.on_exists != "overwrite":
+            if args.on_exists != "rename":
+                print(f"{prefix} {mark('[✓]')} Already exists: {os.path.basename(dest_path)}")
+                return finish("skipped")
+            dest_path = unique_path(dest_path)
+        result["dest_path"] = os.path.abspath(dest_path)
+        if args.dry_run:
+            print(f"{prefix} [~] Would fetch the first {format_size(args.head_bytes)}: {file_name} -> {dest_path}")
+            return finish("planned")
+        clock = time.monotonic()
         try:
-            download_file(url, dest_path, display_name=file_name)
-            print(f"{prefix} [✔] Done: {file_name}")
-        except Exception as e:
-            print(f"{prefix} [✗] Failed: {file_name} - {e}")
+            os.makedirs(os.path.dirname(dest_path), exist_ok=True)
+            result["bytes"] = result["transferred_bytes"] = fetch_head(session, url, dest_path, args.head_bytes)
+        except (requests.RequestException, OSError) as e:
+            error = with_attempts(friendly_error(e), e)
+            print(f"{prefix} {mark('[✗]')} Failed: {file_name} - {error}")
+            return finish("failed", error)
+        result["elapsed_seconds"] = round(time.monotonic() - clock, 3)
+        print(f"{prefix} {mark('[✔]')} Saved the first {format_size(result['bytes'])}: {dest_path}")
+        return finish("downloaded")
+
+    validators = {}
+    resume_headers = {}
+    expected = parse_size_field(iso.get("size"))
+    resume = args.on_exists == "resume"
+    if os.path.exists(dest_path):
+        if args.update:
+            validators = read_validators(dest_path)
+            if not validators and (expected is None or os.path.getsize(dest_path) == expected):
+                print(f"{prefix} {mark('[✓]')} Up to date (size): {file_name}")
+                return finish("skipped")
+        elif args.on_exists == "skip" and older_than(dest_path, args.refresh_older_than):
+            print(f"{prefix} [~] Older than --refresh-older-than, re-downloading: {file_name}")
+        elif args.on_exists == "skip":
+            size = os.path.getsize(dest_path)
+            # A file left short by an interrupted run isn't done; only a size match (or no size to go by) is
+            if expected is None or size == expected:
+                print(f"{prefix} {mark('[✓]')} Already exists: {file_name}")
+                return finish("skipped")
+            if size < expected:
+                print(f"{prefix} [~] Incomplete ({format_size(size)} of {format_size(expected)}), resuming: {file_name}")
+                resume = True
+                if not args.dry_run:
+                    resume_headers = continue_short_file(dest_path, file_name)
+            else:
+                print(f"{prefix} [~] Larger than listed ({format_size(size)} > {format_size(expected)}), "
+                      f"re-downloading: {file_name}")
+        elif args.on_exists == "rename":
+            dest_path = unique_path(dest_path)
+            result["dest_path"] = os.path.abspath(dest_path)
+        elif args.on_exists == "resume":
+            if expected is None or os.path.getsize(dest_path) >= expected:
+                print(f"{prefix} {mark('[✓]')} Already complete: {file_name}")
+                return finish("skipped")
+            if not args.dry_run:
+                # Treat the short file as a partial download and continue it with Range
+                resume_headers = continue_short_file(dest_path, file_name)
+
+    if args.dry_run:
+        print(f"{prefix} [~] Would download: {file_name} -> {dest_path}")
+        return finish("planned")
+
+    part_path = dest_path + PART_SUFFIX
+    resumed_from = os.path.getsize(part_path) if resume and os.path.exists(part_path) else 0
+    if expected is not None:
+        shortfall = space_shortfall(args.output_dir, max(0, expected - resumed_from), args.min_free_space)
+        if shortfall:
+            print(f"{prefix} {mark('[✗]')} Skipped: {file_name} - {shortfall}")
+            return finish("failed", shortfall)
+    if args.pause:
+        args.pause.wait()
+    clock = time.monotonic()
+    if status:
+        status.start(file_name, expected)
+    hashes = StreamHashes() if args.write_checksums else None
+    try:
+        os.makedirs(os.path.dirname(dest_path), exist_ok=True)
+        headers, result["served_url"] = download_file(
+            session, url, dest_path, display_name=file_name, retries=args.retries, backoff=args.backoff,
+            max_backoff=args.max_backoff, resume=resume,
+            extra_headers={**conditional_headers(validators), **resume_headers},
+            rate_limiter=rate_limiter, progress=args.progress, status=status, fallback_url=generic_url(iso),
+            file_rate=args.limit_rate_per_file, time_limit=args.timeout_per_file,
+            adaptive_buffer=args.adaptive_buffer, pause=args.pause, hashes=hashes, expected_size=expected)
+        listed_md5 = iso.get("md5").lower() if isinstance(iso.get("md5"), str) else None
+        if hashes:
+            result["md5"], result["sha256"] = hashes.md5.hexdigest(), hashes.sha256.hexdigest()
+            if listed_md5 and listed_md5 != result["md5"]:
+                discard_download(dest_path)
+                error = f"md5 mismatch (expected {listed_md5}, got {result['md5']}); download deleted"
+                print(f"{prefix} {mark('[✗]')} Failed: {file_name} - {error}")
+                return finish("failed", error)
+            write_checksum_file(dest_path, result["sha256"])
+        elif resumed_from and listed_md5:
+            # Bytes from an earlier run were kept; make sure they belong to the same file
+            actual = file_md5(dest_path)
+            if actual != listed_md5:
+                discard_download(dest_path)
+                error = (f"md5 mismatch after resuming from byte {resumed_from} (expected {listed_md5}, "
+                         f"got {actual}); download deleted")
+                print(f"{prefix} {mark('[✗]')} Failed: {file_name} - {error}")
+                return finish("failed", error)
+        # Only a download that passed its checks gets validators for later --update runs
+        if args.update:
+            write_validators(dest_path, url, headers, result["served_url"])
+        print(f"{prefix} {mark('[✔]')} Done: {file_name}")
+        result["bytes"] = os.path.getsize(dest_path)
+        # Speed counts only what crossed the wire this run, not bytes resumed from disk
+        result["elapsed_seconds"] = round(time.monotonic() - clock, 3)
+        result["transferred_bytes"] = max(0, result["bytes"] - resumed_from)
+        if args.on_complete:
+            result["hook_exit"] = run_hook(args.on_complete, os.path.abspath(dest_path), iso)
+            if result["hook_exit"] != 0:
+                print(f"{prefix} {mark('[!]' if not args.hook_strict else '[✗]')} "
+                      f"--on-complete exited {result['hook_exit']}: {file_name}")
+                if args.hook_strict:
+                    return finish("failed", f"--on-complete exited {result['hook_exit']}")
+        return finish("downloaded")
+    except NotModified:
+        print(f"{prefix} {mark('[✓]')} Not modified: {file_name}")
+        return finish("skipped")
+    except Exception as e:
+        error = with_attempts(friendly_error(e), e)
+        print(f"{prefix} {mark('[✗]')} Failed: {file_name} - {error}")
+        return finish("failed", error)
+    finally:
+        if status:
+            status.end(file_name)
+
+
+def check_item(session: requests.Session, iso: dict, idx: int, total_items: int) -> dict | None:
+    """HEAD one entry's download_url (for --check-only) and return its result record.
+
+    A reachable link whose Content-Length differs from the entry's `size` is a "mismatch".
+    """
+    file_name = iso.get("file_name")
+    url = iso.get("download_url")
+    if not file_name or not url:
+        return None
+    prefix = _prefix(idx, total_items)
+    result = {
+        "index": idx,
+        "identifier": iso.get("identifier"),
+        "file_name": file_name,
+        "download_url": url,
+        "size": parse_size_field(iso.get("size")),
+        "status": None,
+        "http_status": None,
+        "content_length": None,
+        "bytes": 0,
+        "error": None,
+        "started_at": _now(),
+    }
+    try:
+        r = session.head(url, allow_redirects=True)
+        if r.status_code == 405:
+            # Some mirrors refuse HEAD; a streamed GET that is closed unread gives the same answer
+            with session.get(url, stream=True) as r:
+                pass
+        result["http_status"] = r.status_code
+        # The length of a compressed body says nothing about the file's size
+        if not r.headers.get("Content-Encoding"):
+            result["content_length"] = parse_size_field(r.headers.get("Content-Length"))
+        result["status"] = "reachable" if r.status_code < 400 else "broken"
+        if (result["status"] == "reachable" and None not in (result["size"], result["content_length"])
+                and result["size"] != result["content_length"]):
+            result["status"] = "mismatch"
+            result["error"] = f"size {result['size']} in the input, Content-Length {result['content_length']}"
+    except requests.RequestException as e:
+        result["status"] = "broken"
+        result["error"] = with_attempts(friendly_error(e), e)
+    result["finished_at"] = _now()
+    if result["status"] == "reachable":
+        length = result["content_length"]
+        size = format_size(length) if length is not None else "?"
+        print(f"{prefix} {colored('[✓]', 'green')} {result['http_status']} {size}: {file_name}")
+    elif result["status"] == "mismatch":
+        print(f"{prefix} {mark('[!]')} {result['http_status']} {result['error']}: {file_name}")
+    else:
+        print(f"{prefix} {mark('[✗]')} {result['http_status'] or result['error']}: {file_name} ({url})")
+    return result
+
+
+def left_alone_without_size(args, iso: dict) -> bool:
+    """Whether process_item() passes over an entry with no listed size without fetching it.
+
+    That's an entry it can't use, and one whose file already exists when any size counts as
+    done: --on-exists skip (unless --refresh-older-than says it's stale) or resume, and
+    --update with no saved validators.
+    """
+    if not iso.get("file_name") or not iso.get("download_url"):
+        return True
+    dest_path = resolve_dest_path(args, iso)
+    if not os.path.exists(dest_path):
+        return False
+    if args.update:
+        return not read_validators(dest_path)
+    if args.on_exists == "skip":
+        return not older_than(dest_path, args.refresh_older_than)
+    return args.on_exists == "resume"
+
+
+def preflight_sizes(session: requests.Session, items: list, left_alone=None) -> int:
+    """HEAD every entry without a listed size and fill in its `size` from Content-Length (for --preflight-sizes).
+
+    Entries `left_alone` says won't be fetched (see left_alone_without_size) aren't sent a HEAD.
+    The filled-in sizes are used from then on (progress totals, free-space checks), so no
+    file's headers are fetched twice. Returns how many sizes stayed unknown.
+    """
+    unknown = 0
+    for iso in items:
+        if parse_size_field(iso.get("size")) is not None:
+            continue
+        if left_alone and left_alone(iso):
+            continue
+        try:
+            r = session.head(iso["download_url"], allow_redirects=True)
+            length = parse_size_field(r.headers.get("Content-Length"))
+            # A transfer-encoded length says nothing about the file size
+            if r.status_code >= 400 or r.headers.get("Content-Encoding"):
+                length = None
+        except requests.RequestException:
+            length = None
+        if length is None:
+            unknown += 1
+        else:
+            iso["size"] = str(length)
+    return unknown
+
+
+def count_statuses(results: list) -> Counter:
+    """Entries per result status ("downloaded", "skipped", "failed", ...).
+
+    Totals are always counted from the finished results, never kept as running counters,
+    so they stay exact however many transfers ran at once.
+    """
+    return Counter(r["status"] for r in results)
+
+
+def throughput_stats(results: list, elapsed: float) -> dict:
+    """Batch totals plus min/median/max per-file speed (bytes/sec) over the files downloaded this run."""
+    timed = [r for r in results if r["status"] == "downloaded" and r.get("elapsed_seconds")]
+    speeds = sorted(r["transferred_bytes"] / r["elapsed_seconds"] for r in timed)
+    transferred = sum(r.get("transferred_bytes", 0) for r in results)
+    return {
+        "transferred_bytes": transferred,
+        "elapsed_seconds": round(elapsed, 3),
+        "average_bytes_per_sec": transferred / elapsed if elapsed > 0 else None,
+        "min_file_bytes_per_sec": speeds[0] if speeds else None,
+        "median_file_bytes_per_sec": statistics.median(speeds) if speeds else None,
+        "max_file_bytes_per_sec": speeds[-1] if speeds else None,
+    }
+
+
+def _format_speed(bytes_per_sec: float | None) -> str:
+    return "?" if bytes_per_sec is None else f"{bytes_per_sec / 1_000_000:.2f} MB/s"
+
+
+def slowest_downloads(results: list, count: int) -> list:
+    """The count files downloaded this run with the lowest throughput, slowest first.
+
+    Each is {"file_name", "url", "host", "elapsed_seconds", "bytes_per_sec"}; url is the one
+    that served the file (the data node after redirects) where it is known.
+    """
+    timed = [r for r in results if r["status"] == "downloaded" and r.get("elapsed_seconds")]
+    timed.sort(key=lambda r: r["transferred_bytes"] / r["elapsed_seconds"])
+    slowest = []
+    for r in timed[:count]:
+        url = r.get("served_url") or r["download_url"]
+        slowest.append({"file_name": r["file_name"], "url": url, "host": urlparse(url).hostname,
+                        "elapsed_seconds": r["elapsed_seconds"],
+                        "bytes_per_sec": r["transferred_bytes"] / r["elapsed_seconds"]})
+    return slowest
+
+
+def print_slowest(results: list, count: int) -> None:
+    slowest = slowest_downloads(results, count)
+    if not slowest:
+        return
+    print(f"Slowest {len(slowest)} downloads:")
+    for rank, entry in enumerate(slowest, 1):
+        print(f"  {rank:>2}. {_format_speed(entry['bytes_per_sec']):>12} {entry['elapsed_seconds']:>9.1f}s  "
+              f"{entry['host']}  {entry['file_name']}  {entry['url']}")
+
+
+def write_report(path: str, args, started_at: str, results: list, interrupted: bool,
+                 throughput: dict | None = None) -> None:
+    totals = {status: sum(1 for r in results if r["status"] == status)
+              for status in ("downloaded", "skipped", "failed", "planned", "duplicate", "reachable", "broken",
+                             "mismatch")}
+    totals["bytes"] = sum(r["bytes"] for r in results)
+    totals["dedupe_saved_bytes"] = sum(r.get("saved_bytes", 0) for r in results)
+    report = {
+        "input": args.input if args.input == "-" else os.path.abspath(args.input),
+        "output_dir": os.path.abspath(args.output_dir),
+        "started_at": started_at,
+        "finished_at": _now(),
+        "dry_run": args.dry_run,
+        "check_only": args.check_only,
+        "interrupted": interrupted,
+        "totals": totals,
+        "throughput": throughput,
+        "items": results,
+    }
+    write_json_atomic(path, report, indent=2, ensure_ascii=False)
+
+
+# Extra field on --failures-out entries; load_items ignores keys it doesn't know
+LAST_ERROR_FIELD = "last_error"
+
+
+def write_failures(path: str, failures: list) -> None:
+    """Write (entry, error) pairs as an input file for a rerun: each original entry plus its last error."""
+    entries = [dict(iso, **{LAST_ERROR_FIELD: error}) for iso, error in failures]
+    write_json_atomic(path, entries, indent=2, ensure_ascii=False)
+
+
+def build_parser() -> argparse.ArgumentParser:
+    p = ConfigArgumentParser(description="Download files listed in a JSON (or NDJSON) file")
+    p.add_argument("--input", "-i", default=DEFAULT_INPUT, help="Input JSON array or NDJSON file ('-' for stdin); *.gz and *.zst files are decompressed")
+    p.add_argument("--output-dir", "-o", default=DEFAULT_OUTPUT_DIR, help="Destination directory")
+    p.add_argument("--ndjson", action="store_true", help="Treat input as one JSON object per line (implied by .ndjson/.jsonl)")
+    p.add_argument("--url-field", type=json_path_arg, default="download_url", metavar="PATH",
+                   help="Where each entry's download URL is, as a dotted path into nested objects and arrays "
+                        "(e.g. files.0.url; default: download_url)")
+    p.add_argument("--name-field", type=json_path_arg, default="file_name", metavar="PATH",
+                   help="Where each entry's file name is, as a dotted path like --url-field (default: file_name)")
+    p.add_argument("--skip-invalid", action="store_true",
+                   help="Continue with the valid entries when some input entries are malformed (default: stop)")
+    p.add_argument("--mediatype", action="append", metavar="TYPE",
+                   help="Only download entries whose mediatype field is TYPE, e.g. texts (repeatable); entries "
+                        "without the field are skipped. IA-Advanced-Search writes the field into every entry")
+    p.add_argument("--output-template", default="{file_name}",
+                   help="Destination path relative to the output dir; placeholders {identifier}, {title}, {file_name} "
+                        "(e.g. '{identifier}/{file_name}')")
+    add_filename_args(p)
+    add_free_space_arg(p)
+    p.add_argument("--dry-run", action="store_true", help="List what would be downloaded and the total size")
+    p.add_argument("--on-exists", choices=ON_EXISTS_POLICIES, default="skip",
+                   help="What to do when the destination already exists: skip it (default), overwrite it with a fresh "
+                        "download, resume a short file or leftover .part with HTTP Range, or rename the new download "
+                        "to 'name (1).ext'")
+    p.add_argument("--resume", action="store_const", dest="on_exists", const="resume",
+                   help="Same as --on-exists resume")
+    p.add_argument("--resume-all", action="store_true",
+                   help="Only continue the partial downloads (.part files) found under the output dir, each from its "
+                        "input entry's URL; implies --on-exists resume")
+    p.add_argument("--update", action="store_true",
+                   help="Re-check existing files: conditional GET using the ETag/Last-Modified saved in <file>.meta, "
+                        "or a size comparison when no validators were saved")
+    p.add_argument("--dedupe", nargs="?", const="skip", choices=("skip", "hardlink"),
+                   help="Fetch entries with the same md5 (or download_url) only once; duplicates are skipped "
+                        "or hardlinked to the first copy (default: skip)")
+    p.add_argument("--check-only", "--verify-only", action="store_true",
+                   help="Only send a HEAD request per download_url and report reachable, broken and size-mismatched "
+                        "links (with --report for a JSON listing); nothing is downloaded. Exits with status "
+                        f"{EXIT_FAILED} if any link is broken or mismatched")
+    add_hook_args(p)
+    p.add_argument("--report", help="Write a JSON summary (per-item status, bytes, errors, totals) to this path")
+    p.add_argument("--show-slowest", type=int, metavar="N",
+                   help="End the summary with the N downloads of this run that had the lowest throughput, with "
+                        "their time, speed and the host and URL that served them (to spot a slow data node)")
+    p.add_argument("--failures-out", metavar="PATH",
+                   help="Write the input entries that failed to PATH as a JSON array, each with a last_error "
+                        "field, so '--input PATH' retries just those")
+    p.add_argument("--preflight-sizes", action="store_true",
+                   help="Before downloading, HEAD each entry that has no size to learn its Content-Length, so the "
+                        "batch total and free-space check are exact (one extra request per such file)")
+    p.add_argument("--no-progress", action="store_false", dest="progress",
+                   help="Don't draw per-file progress bars (e.g. when logging to a file)")
+    p.add_argument("--progress-style", choices=sorted(PROGRESS_STYLES), default="bar",
+                   help="Progress line look: bar (default), bytes (sizes and percent, no bar), minimal (percent "
+                        "only, for narrow terminals) or spinner")
+    p.add_argument("--progress-template", type=progress_template, metavar="TEMPLATE",
+                   help="Custom progress line instead of --progress-style, with the placeholders {name}, {bar}, "
+                        "{percent}, {current}, {total} and {spinner}, e.g. '{name} {percent} {current}'")
+    p.add_argument("--max-rate", type=parse_rate,
+                   help="Cap download speed across all transfers, e.g. 2MB/s or 500KiB/s (KB/MB decimal, KiB/MiB binary)")
+    add_shuffle_args(p, "the entries")
+    p.add_argument("--max", type=int, metavar="N",
+                   help="Only process the first N entries of the input (drawn after --shuffle, so the two take a "
+                        "random sample)")
+    add_per_file_args(p)
+    add_head_bytes_arg(p)
+    add_pause_arg(p)
+    add_session_args(p, timeout=REQUEST_TIMEOUT)
+    add_log_file_args(p)
+    add_metrics_args(p)
+    p.add_argument("-v", action="count", default=0,
+                   help="Increase verbosity (-v info, -vv debug, including each redirect hop and request ID)")
+    add_color_arg(p)
+    add_exit_code_args(p)
+    return p
+
+
+def main():
+    p = build_parser()
+    args = parse_args_with_config(p, "download-from-json")
+    use_color(args.color)
+    # Progress goes to the terminal with print(); the log only adds warnings there unless -v, and everything
+    # to --log-file
+    setup_logging(args.v, args.log_file, max_size=args.log_max_size, backups=args.log_backups,
+                  log_format=args.log_format)
+
+    template_error = validate_output_template(args.output_template)
+    if template_error:
+        p.error(template_error)
+    hook_error = hook_template_error(args.on_complete) if args.on_complete else None
+    if hook_error:
+        p.error(hook_error)
+    if args.timeout_per_file is not None and args.timeout_per_file <= 0:
+        p.error("--timeout-per-file must be greater than 0")
+    if args.refresh_older_than is not None and (args.on_exists != "skip" or args.update):
+        p.error("--refresh-older-than only applies with --on-exists skip (the default), not with --update")
+    if args.metrics_interval <= 0:
+        p.error("--metrics-interval must be greater than 0")
+    if args.seed is not None and not args.shuffle:
+        p.error("--seed needs --shuffle")
+    if args.max is not None and args.max < 0:
+        p.error("--max must not be negative")
+    if args.show_slowest is not None and args.show_slowest < 1:
+        p.error("--show-slowest must be at least 1")
+    if args.head_bytes is not None:
+        clash = [flag for flag, used in (("--check-only", args.check_only), ("--update", args.update),
+                                         ("--resume-all", args.resume_all), ("--dedupe", args.dedupe),
+                                         ("--on-complete", args.on_complete),
+                                         ("--write-checksums", args.write_checksums)) if used]
+        if clash:
+            p.error(f"--head-bytes can't be combined with {', '.join(clash)}")
+        if args.head_bytes < 1:
+            p.error("--head-bytes must be at least 1")
+    if args.progress:
+        args.progress = (args.progress_template,) * 2 if args.progress_template else PROGRESS_STYLES[args.progress_style]
+
+    # .gz/.zst inputs are decompressed on the fly, so look at the name underneath
+    base_name = args.input[:-len(os.path.splitext(args.input)[1])] if compression_for(args.input) else args.input
+    ndjson = args.ndjson or base_name.lower().endswith((".ndjson", ".jsonl"))
+    try:
+        total_items, iso_list, problems = load_items(args.input, ndjson, (args.name_field, args.url_field))
+    except (OSError, ValueError) as e:
+        print(f"{mark('[✗]')} {e}")
+        sys.exit(EXIT_USAGE)
+    for location, problem in problems:
+        print(f"{mark('[!]')} Invalid entry at {location}: {problem}")
+    if problems:
+        if not args.skip_invalid:
+            print(f"{mark('[✗]')} {len(problems)} invalid entries in {args.input}; fix them or rerun with --skip-invalid")
+            sys.exit(EXIT_USAGE)
+        print(f"{mark('[!]')} Skipping {len(problems)} invalid entries; continuing with {total_items}")
+
+    if args.resume_all:
+        iso_list, orphans = partial_entries(args, iso_list)
+        for path in orphans:
+            print(f"{mark('[!]')} No input entry for partial download {path}; leaving it alone")
+        total_items = len(iso_list)
+        args.on_exists = "resume"
+        print(f"[~] Resuming {total_items} partial downloads found under {args.output_dir}")
+    if args.mediatype:
+        # Reads a streamed NDJSON input in whole, so the count of entries left is known up front
+        wanted = [mediatype.lower() for mediatype in args.mediatype]
+        iso_list = [iso for iso in iso_list if has_mediatype(iso, wanted)]
+        print(f"[~] {len(iso_list)} of {total_items} entries have mediatype {' or '.join(args.mediatype)}")
+        total_items = len(iso_list)
+    if args.shuffle:
+        # Reads a streamed NDJSON input in whole, as the order can't be drawn before every entry is known
+        iso_list = shuffled(iso_list, args.seed)
+    if args.max is not None and args.max < total_items:
+        iso_list = iso_list[:args.max] if isinstance(iso_list, list) else islice(iso_list, args.max)
+        total_items = args.max
+    warn_without_contact(args, total_items)
+
+    session = session_from_args(args)
+    # After the entry filters, and only for files that will be fetched; --head-bytes only wants a prefix
+    if args.preflight_sizes and not (args.check_only or args.head_bytes):
+        iso_list = list(iso_list)
+        unknown = preflight_sizes(session, iso_list, lambda iso: left_alone_without_size(args, iso))
+        sizes = [parse_size_field(iso.get("size")) for iso in iso_list]
+        print(f"[~] Preflight: {size_summary(sizes)}" + (f"; {unknown} HEAD requests gave no size" if unknown else ""))
+
+    # Make sure the output directory exists
+    if not (args.dry_run or args.check_only):
+        os.makedirs(args.output_dir, exist_ok=True)
+        # NDJSON entries are streamed, so their sizes are only checked file by file
+        if isinstance(iso_list, list) and not args.head_bytes:
+            shortfall = space_shortfall(args.output_dir, remaining_bytes(args, iso_list), args.min_free_space)
+            if shortfall:
+                print(f"{mark('[✗]')} Not starting: {shortfall}")
+                sys.exit(EXIT_ERROR)
+
+    rate_limiter = RateLimiter(args.max_rate) if args.max_rate else None
+    args.pause = PauseFile(args.pause_file) if args.pause_file else None
+    # The byte total is only meaningful once every size is known (e.g. after --preflight-sizes)
+    sized = isinstance(iso_list, list) and all(parse_size_field(iso.get("size")) is not None for iso in iso_list)
+    status = TransferStatus(total_items, remaining_bytes(args, iso_list) if sized else None)
+    # kill -USR1 <pid> prints a one-line snapshot to stderr, even with --no-progress
+    install_status_signal(status)
+    metrics = MetricsWriter(args.metrics_file, status, args.metrics_interval).start() if args.metrics_file else None
+    started_at = _now()
+    clock = time.monotonic()
+    results = []
+    failures = []  # (input entry, error) for --failures-out
+    seen = {}  # dedupe key -> path of the first copy
+    interrupted = False
+    try:
+        for idx, iso in enumerate(iso_list, start=1):
+            if args.check_only:
+                result = check_item(session, iso, idx, total_items)
+                if result is not None:
+                    results.append(result)
+                    if result["status"] in ("broken", "mismatch"):
+                        failures.append((iso, result["error"] or f"HTTP {result['http_status']}"))
+                continue
+            key = dedupe_key(iso) if args.dedupe else None
+            if key in seen:
+                results.append(handle_duplicate(args, iso, seen[key], idx, total_items))
+                continue
+            result = process_item(session, args, iso, idx, total_items, rate_limiter, status)
+            if result is not None:
+                status.record(result["status"])
+                results.append(result)
+                if result["status"] == "failed":
+                    failures.append((iso, result["error"]))
+                if key and result["status"] in ("downloaded", "skipped", "planned"):
+                    seen[key] = result["dest_path"]
+    except KeyboardInterrupt:
+        # Stop starting new downloads; the current file stays behind as .part
+        interrupted = True
+    if metrics:
+        metrics.stop()
+
+    throughput = None if args.dry_run or args.check_only else throughput_stats(results, time.monotonic() - clock)
+    if args.check_only:
+        broken = sum(1 for r in results if r["status"] == "broken")
+        mismatched = sum(1 for r in results if r["status"] == "mismatch")
+        print(f"Checked {len(results)} links. Reachable: {len(results) - broken - mismatched}, Broken: {broken}, "
+              f"Size mismatch: {mismatched}")
+    elif args.dry_run:
+        planned_sizes = [r["size"] for r in results if r["status"] == "planned"]
+        print(f"Dry run: {size_summary(planned_sizes)}")
+    else:
+        counts = count_statuses(results)
+        status = "Interrupted" if interrupted else "Completed"
+        print(f"{status}. Success: {counts['downloaded']}, Skipped: {counts['skipped']}, Failed: {counts['failed']}")
+        if counts["downloaded"]:
+            print(f"Transferred {format_size(throughput['transferred_bytes'])} in {throughput['elapsed_seconds']:.1f}s "
+                  f"({_format_speed(throughput['average_bytes_per_sec'])} average); per file "
+                  f"min {_format_speed(throughput['min_file_bytes_per_sec'])}, "
+                  f"median {_format_speed(throughput['median_file_bytes_per_sec'])}, "
+                  f"max {_format_speed(throughput['max_file_bytes_per_sec'])}")
+        if args.show_slowest:
+            print_slowest(results, args.show_slowest)
+    if args.dedupe:
+        duplicates = [r for r in results if r["status"] == "duplicate"]
+        saved = sum(r["saved_bytes"] for r in duplicates)
+        print(f"Deduplicated {len(duplicates)} entries, saving {format_size(saved)}")
+    if args.report:
+        write_report(args.report, args, started_at, results, interrupted, throughput)
+        print(f"Report written to {args.report}")
+    if args.failures_out and not args.dry_run:
+        write_failures(args.failures_out, failures)
+        print(f"{len(failures)} failed entries written to {args.failures_out}")
+    if args.preserve_original_names:
+        save_renamed(args, results)
+    if interrupted:
+        sys.exit(EXIT_INTERRUPTED)
+    counts = count_statuses(results)
+    failed = counts["broken"] + counts["mismatch"] if args.check_only else counts["failed"]
+    done = len(results) - failed - counts["skipped"] - counts["duplicate"]
+    sys.exit(run_exit_code(failed, len(results), done, args.exit_code_on_skip))
 
 
 if __name__ == "__main__":
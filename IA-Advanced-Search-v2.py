@@ -1,76 +1,168 @@
 import argparse
+import difflib
+import heapq
 import json
 import logging
+import os
+import re
+import shutil
+import sqlite3
 import sys
+import tempfile
+import threading
 import time
-from typing import List, Optional
+from collections import Counter, deque
+from concurrent.futures import ThreadPoolExecutor
+from datetime import datetime
+from typing import Callable, Iterable, Iterator, List, Optional
+from urllib.parse import parse_qs, urlparse
 
 import requests
-from requests.adapters import HTTPAdapter
-from urllib3.util.retry import Retry
 
-SEARCH_URL = "https://archive.org/advancedsearch.php"
-METADATA_BASE_URL = "https://archive.org/metadata/"
-DOWNLOAD_BASE_URL = "https://archive.org/download"
+from ia_common import (
+    EXIT_ERROR,
+    EXIT_FAILED,
+    EXIT_INTERRUPTED,
+    EXIT_NOTHING,
+    EXIT_OK,
+    ConfigArgumentParser,
+    adaptive_sleep_error,
+    add_adaptive_sleep_args,
+    add_exit_code_args,
+    add_log_file_args,
+    add_session_args,
+    as_pacer,
+    build_download_url,
+    changes_url,
+    compression_for,
+    file_extension,
+    format_size,
+    friendly_error,
+    fulltext_search_url,
+    get_json,
+    has_extension,
+    is_dark_item,
+    metadata_url,
+    open_text,
+    pacer_from_args,
+    parse_args_with_config,
+    parse_extensions,
+    parse_size_field,
+    render_output_path,
+    require_compression,
+    search_url,
+    session_from_args,
+    setup_logging,
+    warn_without_contact,
+    with_attempts,
+    write_json_atomic,
+    write_text_atomic,
+)
 
+DEFAULT_QUERY = '(format:ISO OR format:IMG) AND mediatype:software AND description:"linux, distribution"'
 DEFAULT_FIELDS = ["identifier", "title", "date", "creator"]
 
+# Fields advancedsearch.php can return (fl[]) and sort on, as listed on archive.org/advancedsearch.php.
+# An unknown name isn't an error there: it just comes back missing from every doc
+SEARCH_FIELDS = {
+    "addeddate": "date the item was added",
+    "avg_rating": "average review rating",
+    "backup_location": "backup storage location",
+    "btih": "BitTorrent info hash",
+    "call_number": "library call number",
+    "collection": "collections the item belongs to",
+    "contributor": "contributing institution or person",
+    "coverage": "place or period covered",
+    "createdate": "date the item was created",
+    "creator": "author or maker",
+    "creatorSorter": "creator, normalized for sorting",
+    "date": "date of the work",
+    "description": "item description (HTML)",
+    "downloads": "all-time download count",
+    "external-identifier": "identifiers in other systems",
+    "foldoutcount": "number of foldout pages",
+    "format": "file formats in the item",
+    "genre": "genre",
+    "headerImage": "collection header image",
+    "identifier": "item identifier",
+    "identifierSorter": "identifier, normalized for sorting",
+    "imagecount": "number of page images",
+    "indexdate": "date the item was last indexed",
+    "indexflag": "indexing flags",
+    "item_size": "total size of the item's files in bytes",
+    "language": "language",
+    "languageSorter": "language, normalized for sorting",
+    "licenseurl": "license URL",
+    "mediatype": "media type (texts, software, movies, ...)",
+    "members": "number of members (collections)",
+    "month": "downloads in the last 30 days",
+    "name": "uploader name",
+    "nav_order": "position in its collection's ordering",
+    "noindex": "excluded from search listings",
+    "num_reviews": "number of reviews",
+    "oai_updatedate": "OAI-PMH update dates",
+    "publicdate": "date the item was made public",
+    "publisher": "publisher",
+    "related-external-id": "related identifiers in other systems",
+    "reviewdate": "date of the latest review",
+    "rights": "rights statement",
+    "scanningcentre": "scanning centre",
+    "source": "source of the material",
+    "stripped_tags": "tags without markup",
+    "subject": "subjects and keywords",
+    "title": "title",
+    "titleSorter": "title, normalized for sorting",
+    "type": "item type",
+    "updatedate": "dates the item was updated",
+    "uploader": "account that uploaded the item",
+    "volume": "volume",
+    "week": "downloads in the last 7 days",
+    "year": "year of the work",
+}
 
-def setup_logging(verbosity: int, log_file: Optional[str] = None):
-    level = logging.WARNING
-    if verbosity == 1:
-        level = logging.INFO
-    elif verbosity >= 2:
-        level = logging.DEBUG
-
-    handlers = [logging.StreamHandler(sys.stdout)]
-    if log_file:
-        handlers.append(logging.FileHandler(log_file, encoding="utf-8"))
-
-    logging.basicConfig(
-        level=level,
-        format="%(asctime)s | %(levelname)-8s | %(message)s",
-        datefmt="%H:%M:%S",
-        handlers=handlers,
-    )
-
-    # Tame noisy urllib3 retry warnings unless user asked for very verbose logs
-    u3_level = logging.DEBUG if verbosity >= 2 else logging.ERROR
-    for name in ("urllib3", "urllib3.connectionpool", "requests.packages.urllib3"):
-        logging.getLogger(name).setLevel(u3_level)
-
-
-def build_session(timeout: int, retries: int, backoff: float, user_agent: Optional[str]) -> requests.Session:
-    session = requests.Session()
-    session.headers.update({
-        "User-Agent": user_agent or "Internet-Archive-API/2.0 (+https://example.local) Python-requests"
-    })
-    retry = Retry(
-        total=retries,
-        connect=retries,
-        read=retries,
-        backoff_factor=backoff,
-        status_forcelist=(429, 500, 502, 503, 504),
-        allowed_methods=("HEAD", "GET", "OPTIONS"),
-        raise_on_status=False,
-    )
-    adapter = HTTPAdapter(max_retries=retry)
-    session.mount("https://", adapter)
-    session.mount("http://", adapter)
-    # attach default timeout wrapper
-    session.request = _timeout_wrapper(session.request, timeout)
-    return session
-
-
-def _timeout_wrapper(request_func, default_timeout: int):
-    def wrapped(method, url, **kwargs):
-        if "timeout" not in kwargs:
-            kwargs["timeout"] = default_timeout
-        return request_func(method, url, **kwargs)
-    return wrapped
-
-
-def search_page(session: requests.Session, query: str, fields: List[str], rows: int, page: int) -> dict:
+# Syncing the state file to disk after every page would slow down a fast crawl
+STATE_SAVE_SECONDS = 10.0
+
+MEDIATYPES = ("account", "audio", "collection", "data", "etree", "image", "movies", "software", "texts", "web")
+
+# advancedsearch.php silently clamps larger page sizes, which would throw off the page math
+MAX_ROWS = 1000
+DEFAULT_ROWS = 500
+
+# File names collected into the output list
+ISO_EXTENSIONS = (".iso", ".img", ".zip")
+
+FIELD_PRESETS = {
+    "minimal": ["identifier", "title"],
+    "standard": DEFAULT_FIELDS,
+    "full": DEFAULT_FIELDS + ["publicdate", "mediatype", "collection", "downloads", "item_size", "description"],
+}
+
+
+def search_error(data: dict) -> Optional[str]:
+    """The server's message when an advanced search response reports an error, else None.
+
+    A malformed query still gets a 200, with the parser error in "error" (a string, or a
+    Solr-style {"msg": ..., "code": ...}) and sometimes an empty result set beside it.
+    """
+    err = data.get("error") if isinstance(data, dict) else None
+    if not err:
+        return None
+    if isinstance(err, dict):
+        return str(err.get("msg") or json.dumps(err)[:500])
+    return str(err)
+
+
+def save_raw_page(directory: Optional[str], page: int, resp: requests.Response):
+    """Write a search response body, as received, to directory/page-N.json (no-op without a directory)."""
+    if not directory:
+        return
+    with open(os.path.join(directory, f"page-{page}.json"), "wb") as f:
+        f.write(resp.content)
+
+
+def search_page(session: requests.Session, query: str, fields: List[str], rows: int, page: int,
+                sort: Optional[List[str]] = None, save_raw: Optional[str] = None) -> dict:
     params = {
         "q": query,
         "fl[]": fields,
@@ -78,108 +170,1659 @@ def search_page(session: requests.Session, query: str, fields: List[str], rows:
         "page": page,
         "output": "json",
     }
-    resp = session.get(SEARCH_URL, params=params)
+    if sort:
+        params["sort[]"] = sort
+    try:
+        resp = session.get(search_url(), params=params)
+    except requests.RequestException as e:
+        raise RuntimeError(with_attempts(f"Advanced search request failed: {friendly_error(e)}", e)) from e
     if resp.status_code != 200:
-        raise RuntimeError(f"Advanced search failed with status {resp.status_code}: {resp.text[:300]}")
+        raise RuntimeError(with_attempts(f"Advanced search failed with status {resp.status_code}: {resp.text[:300]}", resp))
+    save_raw_page(save_raw, page, resp)
     try:
-        return resp.json()
+        data = resp.json()
     except json.JSONDecodeError as e:
         raise RuntimeError(f"Failed to parse JSON from advanced search: {e}\nBody: {resp.text[:300]}") from e
+    message = search_error(data)
+    if message:
+        raise RuntimeError(f"Advanced search rejected the query: {message}")
+    return data
+
+
+def make_entry(item: dict, f: dict, meta: Optional[dict] = None) -> dict:
+    """Output entry for one file entry `f` of the search result `item`; `meta` is the item's metadata.
+
+    The download_url is always the generic /download/ one: the data node in `meta` changes as
+    items move, which would make the same crawl give different results from run to run.
+    """
+    identifier = item["identifier"]
+    entry = {
+        "identifier": identifier,
+        "title": item.get("title", ""),
+        "file_name": f["name"],
+        "download_url": build_download_url(identifier, f["name"]),
+        "size": f.get("size", "unknown"),
+    }
+    if item.get("snippet"):
+        entry["snippet"] = item["snippet"]
+    # Carry the other requested search fields along (date, creator, downloads, ...)
+    for key, value in item.items():
+        entry.setdefault(key, value)
+    # Lets Download-From-JSON --mediatype filter on it even when the search didn't ask for the field
+    mediatype = ((meta or {}).get("metadata") or {}).get("mediatype")
+    if mediatype:
+        entry.setdefault("mediatype", mediatype)
+    return entry
+
+
+# Which files --max-files-per-item keeps of an item with more
+FILE_CAP_ORDERS = ("largest", "first")
+# What --format writes to --out
+OUTPUT_FORMATS = ("json", "urls", "aria2", "sqlite")
+# --out when none is given, by --format
+DEFAULT_OUT = {"json": "pear.json", "urls": "pear.txt", "aria2": "pear.aria2", "sqlite": "pear.db"}
+# --out names that get --format sqlite unless another format is given
+SQLITE_SUFFIXES = (".db", ".sqlite", ".sqlite3")
+# One row per (identifier, file_name); `entry` holds the whole entry as JSON for json_extract()
+SQLITE_SCHEMA = """
+CREATE TABLE IF NOT EXISTS files (
+    identifier TEXT NOT NULL,
+    file_name TEXT NOT NULL,
+    title TEXT,
+    download_url TEXT,
+    size INTEGER,
+    entry TEXT NOT NULL,
+    PRIMARY KEY (identifier, file_name)
+);
+CREATE INDEX IF NOT EXISTS files_file_name ON files (file_name);
+"""
+SQLITE_UPSERT = """
+INSERT INTO files (identifier, file_name, title, download_url, size, entry) VALUES (?, ?, ?, ?, ?, ?)
+ON CONFLICT (identifier, file_name) DO UPDATE SET
+    title = excluded.title, download_url = excluded.download_url, size = excluded.size, entry = excluded.entry
+"""
+# Entries --merge sorts in memory before spilling a sorted run to --temp-dir
+MERGE_CHUNK_ENTRIES = 200_000
+
+
+def cap_files(files: list, limit: int, order: str = "largest") -> list:
+    """At most `limit` of an item's files, still in their listed order.
+
+    "largest" keeps the biggest ones (files without a size rank last, ties go to the
+    alphabetically first name); "first" keeps the first ones as the metadata lists them.
+    """
+    if len(files) <= limit:
+        return files
+    if order == "first":
+        return files[:limit]
+
+    def rank(f):
+        size = parse_size_field(f.get("size"))
+        return -(size if size is not None else -1), (f.get("name") or "").lower()
+
+    kept = {id(f) for f in sorted(files, key=rank)[:limit]}
+    return [f for f in files if id(f) in kept]
+
+
+def pick_primary(files: list, prefer: tuple = (), strict: bool = False) -> Optional[dict]:
+    """The one file of an item to keep for --one-per-item.
+
+    Files whose extension comes earliest in `prefer` win; other extensions rank after
+    all preferred ones, unless `strict` (--prefer), where an item without any of them
+    gives None. Ties go to the largest size (files without a size rank last), then to
+    the alphabetically first name so reruns pick the same file.
+    """
+    def rank(f):
+        name = (f.get("name") or "").lower()
+        ext = file_extension(name)
+        priority = prefer.index(ext) if ext in prefer else len(prefer)
+        size = parse_size_field(f.get("size"))
+        return priority, -(size if size is not None else -1), name
+
+    best = min(files, key=rank)
+    if strict and rank(best)[0] == len(prefer):
+        return None
+    return best
 
 
 def fetch_metadata(session: requests.Session, identifier: str) -> Optional[dict]:
-    url = f"{METADATA_BASE_URL}{identifier}"
     try:
-        resp = session.get(url)
-        if resp.status_code != 200:
-            return None
-        return resp.json()
+        return get_json(session, metadata_url(identifier))[1]
     except Exception:
         return None
 
 
-def main():
-    parser = argparse.ArgumentParser(description="Internet Archive Advanced Search (v2)")
-    parser.add_argument("--query", "-q", default='(format:ISO OR format:IMG) AND mediatype:software AND description:"linux, distribution"', help="Advanced search query string")
-    parser.add_argument("--rows", type=int, default=500, help="Rows per page (<=1000)")
-    parser.add_argument("--max-pages", type=int, help="Limit number of pages to fetch")
-    parser.add_argument("--sleep", type=float, default=1.0, help="Sleep seconds between requests")
-    parser.add_argument("--fields", nargs="*", default=DEFAULT_FIELDS, help="Fields to fetch in search results")
-    parser.add_argument("--out", "-o", default="pear.json", help="Output JSON file for results")
-    parser.add_argument("--timeout", type=int, default=30, help="Request timeout seconds")
-    parser.add_argument("--retries", type=int, default=5, help="HTTP retries for transient errors")
-    parser.add_argument("--backoff", type=float, default=1.0, help="Retry backoff factor")
-    parser.add_argument("--user-agent", help="Custom User-Agent header")
-    parser.add_argument("--log-file", help="Optional log file path")
-    parser.add_argument("-v", action="count", default=0, help="Increase verbosity (-v info, -vv debug)")
-    parser.add_argument("--dry-run", action="store_true", help="Do not fetch per-item metadata, only list identifiers")
-    args = parser.parse_args()
+class MetadataCache:
+    """On-disk cache of metadata responses, one JSON file per identifier (for --cache-dir).
+
+    Each entry records when it was fetched; entries older than `ttl` seconds are ignored.
+    """
+
+    def __init__(self, directory: str, ttl: float):
+        self.directory = directory
+        self.ttl = ttl
+        os.makedirs(directory, exist_ok=True)
+
+    def _path(self, identifier: str) -> str:
+        return os.path.join(self.directory, identifier.replace("/", "_") + ".json")
+
+    def get(self, identifier: str) -> Optional[dict]:
+        try:
+            with open(self._path(identifier), "r", encoding="utf-8") as f:
+                entry = json.load(f)
+        except (OSError, json.JSONDecodeError):
+            return None
+        if not isinstance(entry, dict) or time.time() - entry.get("fetched_at", 0) > self.ttl:
+            return None
+        return entry.get("metadata")
+
+    def put(self, identifier: str, metadata: dict):
+        entry = {"identifier": identifier, "fetched_at": time.time(), "metadata": metadata}
+        write_json_atomic(self._path(identifier), entry, ensure_ascii=False)
+
 
-    setup_logging(args.v, args.log_file)
-    session = build_session(args.timeout, args.retries, args.backoff, args.user_agent)
+class CrawlState:
+    """Checkpoint of a crawl kept in --state-file, so an interrupted run resumes instead of starting over.
+
+    The file holds the crawl's settings, the last query and page whose docs were fully
+    processed and how many entries were collected up to there; the entries themselves are
+    appended to <path>.entries, one JSON line each, so a save only writes what is new.
+    It is only reused by a run with the same settings, and saved at most every
+    STATE_SAVE_SECONDS.
+    """
+
+    def __init__(self, path: str, settings: dict):
+        self.path = path
+        self.entries_path = path + ".entries"
+        self.settings = settings
+        self.saved_at = None
+        # Entries already in entries_path
+        self.written = 0
+
+    def load(self) -> Optional[dict]:
+        """The saved progress, or None if there is none yet; ValueError if it belongs to another crawl."""
+        try:
+            with open(self.path, "r", encoding="utf-8") as f:
+                state = json.load(f)
+        except FileNotFoundError:
+            return None
+        except (OSError, json.JSONDecodeError) as e:
+            raise ValueError(f"can't read state file {self.path}: {e}") from e
+        if not isinstance(state, dict) or not isinstance(state.get("progress"), dict):
+            raise ValueError(f"{self.path} is not a crawl state file")
+        saved = state.get("settings") or {}
+        changed = sorted(key for key in set(saved) | set(self.settings) if saved.get(key) != self.settings.get(key))
+        if changed:
+            raise ValueError(f"state file {self.path} was saved by a different crawl ({', '.join(changed)} differ); "
+                             "remove it or use another --state-file")
+        progress = state["progress"]
+        progress["entries"] = self._read_entries(progress.get("entries", 0))
+        return progress
 
-    logging.info(f"Query: {args.query}")
+    def _read_entries(self, count: int) -> list:
+        """The first `count` entries of entries_path, cutting off any appended after the last state save."""
+        entries = []
+        beyond = False
+        try:
+            with open(self.entries_path, "r", encoding="utf-8") as f:
+                while len(entries) < count:
+                    line = f.readline()
+                    if not line:
+                        break
+                    entries.append(json.loads(line))
+                beyond = bool(f.readline())
+        except FileNotFoundError:
+            pass
+        except (OSError, json.JSONDecodeError) as e:
+            raise ValueError(f"can't read {self.entries_path}: {e}") from e
+        if len(entries) < count:
+            raise ValueError(f"{self.entries_path} holds {len(entries)} of the {count} entries {self.path} "
+                             "counts; remove both to start over")
+        if beyond:
+            write_text_atomic(self.entries_path, [json.dumps(e, ensure_ascii=False) + "\n" for e in entries])
+        self.written = count
+        return entries
 
-    iso_entries = []
+    def due(self) -> bool:
+        return self.saved_at is None or time.monotonic() - self.saved_at >= STATE_SAVE_SECONDS
+
+    def save(self, progress: dict, entries: list):
+        """Record a checkpoint: `progress` and the first progress["entries"] of `entries`."""
+        count = progress["entries"]
+        if count > self.written:
+            with open(self.entries_path, "a", encoding="utf-8") as f:
+                for entry in entries[self.written:count]:
+                    f.write(json.dumps(entry, ensure_ascii=False) + "\n")
+                f.flush()
+                os.fsync(f.fileno())
+        self.written = count
+        state = {"settings": self.settings, "saved_at": datetime.now().isoformat(timespec="seconds"),
+                 "progress": progress}
+        write_json_atomic(self.path, state, ensure_ascii=False)
+        self.saved_at = time.monotonic()
+
+    def clear(self):
+        for path in (self.path, self.entries_path):
+            try:
+                os.remove(path)
+            except FileNotFoundError:
+                pass
+
+
+def search_url_params(value: str) -> dict:
+    """argparse type for --from-url: the q, fl[], sort[] and rows of an advancedsearch.php URL.
+
+    Returns a dict with 'query' and, when present in the URL, 'fields', 'sort' and 'rows',
+    plus 'output' and 'page' when they ask for something the crawl won't do (another output
+    format than JSON, or a start past page 1).
+    """
+    url = urlparse(value.strip())
+    if not url.path.endswith("/advancedsearch.php"):
+        raise argparse.ArgumentTypeError(f"not an advancedsearch.php URL: {value}")
+    params = parse_qs(url.query)
+    query = (params.get("q") or [""])[0].strip()
+    if not query:
+        raise argparse.ArgumentTypeError(f"search URL has no q= query: {value}")
+    found = {"query": query}
+    # The website sends empty sort[]/fl[] slots for unused form fields
+    fields = [f for f in params.get("fl[]", []) if f]
+    if fields:
+        found["fields"] = fields
+    sort = [s for s in params.get("sort[]", []) if s.strip()]
+    if sort:
+        found["sort"] = sort
+    rows = (params.get("rows") or [""])[0]
+    if rows.isdigit() and int(rows) > 0:
+        found["rows"] = int(rows)
+    output = (params.get("output") or ["json"])[0]
+    if output.lower() != "json":
+        found["output"] = output
+    page = (params.get("page") or [""])[0]
+    if page.isdigit() and int(page) > 1:
+        found["page"] = int(page)
+    return found
+
+
+def with_mediatype(query: str, mediatype: Optional[str]) -> str:
+    return f"({query}) AND mediatype:{mediatype}" if mediatype else query
+
+
+def iso_date(value: str) -> str:
+    """argparse type for YYYY-MM-DD dates."""
+    try:
+        datetime.strptime(value, "%Y-%m-%d")
+    except ValueError:
+        raise argparse.ArgumentTypeError(f"invalid date {value!r}, expected YYYY-MM-DD")
+    return value
+
+
+def with_date_range(query: str, since: Optional[str], until: Optional[str]) -> str:
+    """AND a publicdate range onto the query; a missing bound is left open with '*'."""
+    if not since and not until:
+        return query
+    return f"({query}) AND publicdate:[{since or '*'} TO {until or '*'}]"
+
+
+def timestamp(value: str) -> str:
+    """argparse type for --only-new-since: YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS[Z], as UTC 'YYYY-MM-DDTHH:MM:SSZ'."""
+    for fmt in ("%Y-%m-%dT%H:%M:%SZ", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d"):
+        try:
+            return datetime.strptime(value, fmt).strftime("%Y-%m-%dT%H:%M:%SZ")
+        except ValueError:
+            continue
+    raise argparse.ArgumentTypeError(f"invalid timestamp {value!r}, expected YYYY-MM-DD or YYYY-MM-DDTHH:MM:SSZ")
 
+
+class NewSince:
+    """--only-new-since: keeps items published after `threshold` and notes the newest publicdate seen.
+
+    The search range is inclusive, so items published exactly at the threshold (the newest
+    of the previous run) come back and are dropped by is_new(). Items without a publicdate
+    are kept, since they can't be judged. mark() is where the next run may start: the
+    newest publicdate only once every result was visited, since results come in any
+    sort order and an unvisited one older than the newest would otherwise never be seen.
+    """
+
+    def __init__(self, threshold: Optional[str]):
+        self.threshold = threshold
+        self.newest = threshold
+        self.complete = True
+
+    def missed(self, reason: str):
+        """Note that some results went unvisited, so the mark has to stay where it was."""
+        if self.complete:
+            logging.warning(f"{reason}; the --since-state mark stays at {self.threshold or 'its start'}")
+        self.complete = False
+
+    def mark(self) -> Optional[str]:
+        return self.newest if self.complete else self.threshold
+
+    def query(self, query: str) -> str:
+        return f"({query}) AND publicdate:[{self.threshold} TO *]" if self.threshold else query
+
+    def see(self, docs: list):
+        for doc in docs:
+            published = doc.get("publicdate")
+            if isinstance(published, str) and (self.newest is None or published > self.newest):
+                self.newest = published
+
+    def is_new(self, doc: dict) -> bool:
+        published = doc.get("publicdate")
+        return not self.threshold or not isinstance(published, str) or published > self.threshold
+
+
+def read_since_state(path: str) -> Optional[str]:
+    """The timestamp a --since-state file holds, or None if there is no file yet."""
+    try:
+        with open(path, encoding="utf-8") as f:
+            return timestamp(json.load(f)["only_new_since"])
+    except FileNotFoundError:
+        return None
+    except (OSError, ValueError, KeyError, TypeError, argparse.ArgumentTypeError) as e:
+        raise ValueError(f"{path} is not a --since-state file: {e}") from e
+
+
+def write_since_state(path: str, newest: Optional[str]):
+    if newest:
+        write_json_atomic(path, {"only_new_since": timestamp(newest),
+                                 "saved_at": datetime.now().isoformat(timespec="seconds")})
+
+
+def new_since_from_args(args) -> Optional[NewSince]:
+    """NewSince for --only-new-since, or the timestamp stored in --since-state; None without either.
+
+    Raises ValueError for an unreadable state file.
+    """
+    if not args.only_new_since and not args.since_state:
+        return None
+    return NewSince(args.only_new_since or read_since_state(args.since_state))
+
+
+def add_new_since_args(parser):
+    parser.add_argument("--only-new-since", type=timestamp, metavar="TIMESTAMP",
+                        help="Only items published (publicdate) after TIMESTAMP, YYYY-MM-DD or YYYY-MM-DDTHH:MM:SSZ")
+    parser.add_argument("--since-state", metavar="PATH",
+                        help="Incremental runs: take --only-new-since from PATH when it isn't given, and store the "
+                             "newest publicdate seen there after a run that visited every result (not one cut "
+                             "short by --max-pages or with failed metadata fetches)")
+
+
+def unvisited_results(num_found: int, total_pages: int, rows: int) -> int:
+    """Results beyond the pages --max-pages lets a crawl walk."""
+    return max(0, num_found - total_pages * rows)
+
+
+def page_count(num_found: int, rows: int, max_pages: Optional[int] = None) -> int:
+    """Number of result pages to walk for num_found hits at `rows` per page: exactly ceil(num_found / rows)."""
+    total_pages = -(-num_found // rows)
+    if max_pages is not None:
+        total_pages = min(total_pages, max_pages)
+    return total_pages
+
+
+# Extra requests for a later page answered with a 200 that holds no response.docs, before the crawl stops
+PAGE_RETRIES = 2
+
+
+def fetch_page_docs(session: requests.Session, query: str, fields: List[str], rows: int, page: int, sleep,
+                    sort: Optional[List[str]] = None, save_raw: Optional[str] = None) -> list:
+    """The docs of result page `page`, requested again while the answer carries no response.docs.
+
+    advancedsearch.php now and then answers a page with a 200 whose body is something other
+    than results (a transient backend error without the usual error field). Such a page is
+    logged and re-requested PAGE_RETRIES more times; after that RuntimeError stops the crawl,
+    so the page's docs are never silently missing or taken from another page. `sleep` is
+    seconds between requests or a Pacer.
+    """
+    pacer = as_pacer(sleep)
+    for attempt in range(PAGE_RETRIES + 1):
+        pacer.wait()
+        data = search_page(session, query, fields, rows, page, sort, save_raw)
+        response_obj = data.get("response") if isinstance(data, dict) else None
+        docs = response_obj.get("docs") if isinstance(response_obj, dict) else None
+        if isinstance(docs, list):
+            return docs
+        logging.warning(f"Page {page} came back without response.docs"
+                        + (f"; requesting it again ({attempt + 1}/{PAGE_RETRIES})" if attempt < PAGE_RETRIES else ""))
+    raise RuntimeError(f"Page {page} came back without response.docs {PAGE_RETRIES + 1} times; stopping the crawl. "
+                       f"Details: {json.dumps(data)[:500]}")
+
+
+def iter_search_pages(session: requests.Session, query: str, fields: List[str], rows: int,
+                      max_pages: Optional[int], sleep, on_start: Optional[Callable] = None,
+                      workers: int = 1, sort: Optional[List[str]] = None, start_page: int = 1,
+                      save_raw: Optional[str] = None):
+    """Yield (page, docs) for every result page of an advanced search query.
+
+    on_start(num_found, total_pages, first_docs) is called once the first page is in;
+    returning False stops the crawl before anything is yielded. With workers > 1 the
+    remaining pages are fetched concurrently (see _windowed_pages). `sort` holds
+    sort[] values such as 'downloads desc'. A start_page above 1 skips the pages
+    before it, for resuming a crawl. `sleep` is seconds between requests or a Pacer.
+    With save_raw each page's response body is also written to save_raw/page-N.json.
+    """
+    pacer = as_pacer(sleep)
     # Fetch first page to get numFound
-    first = search_page(session, args.query, args.fields, args.rows, 1)
+    pacer.wait()
+    first = search_page(session, query, fields, rows, start_page, sort, save_raw)
     response_obj = first.get("response")
     if not isinstance(response_obj, dict) or "docs" not in response_obj:
         err = first.get("error") or first
         raise RuntimeError(f"Unexpected search response structure, missing 'response.docs'. Details: {json.dumps(err)[:500]}")
 
     num_found = int(response_obj.get("numFound", 0))
-    total_pages = max(1, (num_found + args.rows - 1) // args.rows)
-    if args.max_pages is not None:
-        total_pages = min(total_pages, args.max_pages)
+    total_pages = page_count(num_found, rows, max_pages)
 
     logging.info(f"numFound={num_found}, pages={total_pages}")
+    if on_start and on_start(num_found, total_pages, response_obj.get("docs") or []) is False:
+        return
+
+    docs = response_obj["docs"]
+    if not isinstance(docs, list):
+        raise RuntimeError(f"Unexpected search response structure, 'response.docs' is not a list. "
+                           f"Details: {json.dumps(first)[:500]}")
+    if workers > 1 and total_pages > start_page:
+        yield start_page, docs
+        yield from _windowed_pages(session, query, fields, rows, total_pages, pacer, workers, sort, start_page + 1,
+                                   save_raw)
+        return
+
+    for page in range(start_page, total_pages + 1):
+        if page > start_page:
+            docs = fetch_page_docs(session, query, fields, rows, page, pacer, sort, save_raw)
+        yield page, docs
+        if len(docs) < rows and page < total_pages:
+            logging.warning(f"Page {page} returned {len(docs)} of {rows} docs before the last page; "
+                            "the result set changed during the crawl, not requesting later pages")
+            return
+
+
+def _windowed_pages(session: requests.Session, query: str, fields: List[str], rows: int, total_pages: int,
+                    sleep, workers: int, sort: Optional[List[str]] = None, first_page: int = 2,
+                    save_raw: Optional[str] = None):
+    """Yield (page, docs) for pages first_page..total_pages in order, keeping up to `workers` requests in flight.
+
+    Requests are still paced by `sleep` (seconds or a Pacer) overall. A page with fewer than `rows`
+    docs before the last one means the result set shrank under us, so no further pages
+    are requested; those already in flight are still yielded.
+    """
+    pacer = as_pacer(sleep)
+
+    def fetch(page):
+        return fetch_page_docs(session, query, fields, rows, page, pacer, sort, save_raw)
+
+    with ThreadPoolExecutor(max_workers=workers) as pool:
+        window = deque()
+        next_page = first_page
+        exhausted = False
+        while window or (next_page <= total_pages and not exhausted):
+            while not exhausted and next_page <= total_pages and len(window) < workers:
+                window.append((next_page, pool.submit(fetch, next_page)))
+                next_page += 1
+            page, future = window.popleft()
+            docs = future.result()
+            if len(docs) < rows and page < total_pages and not exhausted:
+                logging.info(f"Page {page} returned {len(docs)} of {rows} docs; not requesting later pages")
+                exhausted = True
+            yield page, docs
+
+
+def count_results(session: requests.Session, query: str) -> int:
+    """numFound for a query, from a single rows=0 request that returns no docs."""
+    data = search_page(session, query, ["identifier"], 0, 1)
+    response_obj = data.get("response")
+    if not isinstance(response_obj, dict) or "numFound" not in response_obj:
+        err = data.get("error") or data
+        raise RuntimeError(f"Unexpected search response structure, missing 'response.numFound'. Details: {json.dumps(err)[:500]}")
+    return int(response_obj["numFound"])
+
+
+def changes_page(session: requests.Session, token: Optional[str] = None, start_date: Optional[str] = None) -> dict:
+    """One page of the changes API: items modified after `token`, or since start_date (YYYY-MM-DD) without one."""
+    params = {"token": token} if token else {"start_date": (start_date or "").replace("-", "")}
+    try:
+        resp = session.get(changes_url(), params=params)
+    except requests.RequestException as e:
+        raise RuntimeError(with_attempts(f"Changes request failed: {friendly_error(e)}", e)) from e
+    if resp.status_code != 200:
+        raise RuntimeError(with_attempts(f"Changes request failed with status {resp.status_code}: {resp.text[:300]}", resp))
+    try:
+        data = resp.json()
+    except json.JSONDecodeError as e:
+        raise RuntimeError(f"Failed to parse JSON from the changes API: {e}\nBody: {resp.text[:300]}") from e
+    if not isinstance(data, dict) or not isinstance(data.get("changes"), list):
+        raise RuntimeError(f"Unexpected changes response, missing 'changes'. Details: {json.dumps(data)[:500]}")
+    return data
 
-    for page in range(1, total_pages + 1):
-        if page > 1:
-            time.sleep(args.sleep)
-            data = search_page(session, args.query, args.fields, args.rows, page)
-            response_obj = data.get("response", {})
-        docs = response_obj.get("docs", [])
-        if not isinstance(docs, list):
+
+def fetch_changes(session: requests.Session, token: Optional[str], start_date: Optional[str],
+                  max_pages: Optional[int], sleep) -> tuple:
+    """(changed identifiers in first-seen order, token to continue from next time).
+
+    Follows next_token until the API reports no tokens remaining, a page comes back empty,
+    or max_pages pages were read; the returned token then picks up where this run stopped.
+    """
+    pacer = as_pacer(sleep)
+    identifiers = {}
+    pages = 0
+    while True:
+        pacer.wait()
+        data = changes_page(session, token, start_date)
+        pages += 1
+        changes = [c.get("identifier") for c in data["changes"] if isinstance(c, dict)]
+        identifiers.update((identifier, None) for identifier in changes if identifier)
+        token = data.get("next_token") or token
+        if not changes or not data.get("estimated_tokens_remaining") or (max_pages and pages >= max_pages):
+            break
+    logging.info(f"Changes: {len(identifiers)} items over {pages} pages")
+    return list(identifiers), token
+
+
+def in_collection(session: requests.Session, identifiers: List[str], collection: str, sleep,
+                  batch: int = 100) -> List[str]:
+    """The identifiers that belong to the collection, keeping their order; asked in batches of advanced search."""
+    pacer = as_pacer(sleep)
+    members = set()
+    for start in range(0, len(identifiers), batch):
+        chunk = identifiers[start:start + batch]
+        terms = " OR ".join(f'"{identifier}"' for identifier in chunk)
+        pacer.wait()
+        docs = (search_page(session, f"collection:{collection} AND identifier:({terms})", ["identifier"],
+                            len(chunk), 1).get("response") or {}).get("docs") or []
+        members.update(doc.get("identifier") for doc in docs if isinstance(doc, dict))
+    return [identifier for identifier in identifiers if identifier in members]
+
+
+def read_changes_state(path: str) -> Optional[str]:
+    """The token a --changes state file holds, or None if there is no file yet."""
+    try:
+        with open(path, encoding="utf-8") as f:
+            token = json.load(f)["changes_token"]
+    except FileNotFoundError:
+        return None
+    except (OSError, ValueError, KeyError, TypeError) as e:
+        raise ValueError(f"{path} is not a --changes state file: {e}") from e
+    if not isinstance(token, str) or not token:
+        raise ValueError(f"{path} is not a --changes state file: no token")
+    return token
+
+
+def write_changes_state(path: str, token: Optional[str]):
+    if token:
+        write_json_atomic(path, {"changes_token": token, "saved_at": datetime.now().isoformat(timespec="seconds")})
+
+
+def fulltext_page(session: requests.Session, query: str, rows: int, page: int,
+                  save_raw: Optional[str] = None) -> dict:
+    params = {
+        "user_query": query,
+        "service_backend": "fts",
+        "hits_per_page": rows,
+        "page": page,
+    }
+    try:
+        resp = session.get(fulltext_search_url(), params=params)
+    except requests.RequestException as e:
+        raise RuntimeError(with_attempts(f"Full-text search request failed: {friendly_error(e)}", e)) from e
+    if resp.status_code != 200:
+        raise RuntimeError(with_attempts(f"Full-text search failed with status {resp.status_code}: {resp.text[:300]}", resp))
+    save_raw_page(save_raw, page, resp)
+    try:
+        return resp.json()
+    except json.JSONDecodeError as e:
+        raise RuntimeError(f"Failed to parse JSON from full-text search: {e}\nBody: {resp.text[:300]}") from e
+
+
+def _fulltext_hits(data: dict) -> dict:
+    hits = (((data.get("response") or {}).get("body") or {}).get("hits"))
+    if not isinstance(hits, dict):
+        raise RuntimeError(f"Unexpected full-text response structure, missing 'response.body.hits'. Details: {json.dumps(data)[:500]}")
+    return hits
+
+
+def _fulltext_doc(hit: dict) -> dict:
+    """Flatten a full-text hit into the same shape as an advanced search doc, plus a snippet."""
+    fields = hit.get("fields") or {}
+    title = fields.get("title", "")
+    if isinstance(title, list):
+        title = title[0] if title else ""
+    highlight = hit.get("highlight") or {}
+    snippets = highlight.get("text") or []
+    return {
+        "identifier": fields.get("identifier"),
+        "title": title,
+        "snippet": " ... ".join(snippets) if isinstance(snippets, list) else str(snippets),
+    }
+
+
+def iter_fulltext_pages(session: requests.Session, query: str, rows: int, max_pages: Optional[int], sleep,
+                        on_start: Optional[Callable] = None, start_page: int = 1, save_raw: Optional[str] = None):
+    """Yield (page, docs) for a full-text (book/text contents) search; sleep, on_start, start_page and save_raw
+    as for iter_search_pages."""
+    pacer = as_pacer(sleep)
+    pacer.wait()
+    hits = _fulltext_hits(fulltext_page(session, query, rows, start_page, save_raw))
+    total = hits.get("total", 0)
+    if isinstance(total, dict):
+        total = total.get("value", 0)
+    num_found = int(total or 0)
+    total_pages = page_count(num_found, rows, max_pages)
+
+    logging.info(f"full-text numFound={num_found}, pages={total_pages}")
+    if on_start and on_start(num_found, total_pages, [_fulltext_doc(h) for h in hits.get("hits", []) or []
+                                                     if isinstance(h, dict)]) is False:
+        return
+
+    for page in range(start_page, total_pages + 1):
+        if page > start_page:
+            pacer.wait()
+            hits = _fulltext_hits(fulltext_page(session, query, rows, page, save_raw))
+        yield page, [_fulltext_doc(h) for h in hits.get("hits", []) or [] if isinstance(h, dict)]
+
+
+def count_facets(pages, facets: List[str]) -> dict:
+    """Tally the values of each facet field over (page, docs) pairs: {field: Counter(value -> items)}.
+
+    advancedsearch.php returns no aggregations, so the buckets are counted here from a crawl
+    that only requests the facet fields. Multi-valued fields count once per value; items
+    without the field land in '(none)'. An identifier seen twice is counted once.
+    """
+    counts = {field: Counter() for field in facets}
+    seen = set()
+    for _, docs in pages:
+        for doc in docs:
+            identifier = doc.get("identifier")
+            if identifier in seen:
+                continue
+            seen.add(identifier)
+            for field in facets:
+                value = doc.get(field)
+                values = value if isinstance(value, list) else [value]
+                for v in values or [None]:
+                    counts[field]["(none)" if v in (None, "") else str(v)] += 1
+    return counts
+
+
+def print_facets(counts: dict, as_json: bool = False):
+    """Print facet buckets, most common first, as one table per field or as a JSON object."""
+    ordered = {field: dict(sorted(buckets.items(), key=lambda kv: (-kv[1], kv[0]))) for field, buckets in counts.items()}
+    if as_json:
+        print(json.dumps(ordered, indent=2, ensure_ascii=False))
+        return
+    for field, buckets in ordered.items():
+        width = max([len(field)] + [len(value) for value in buckets])
+        print(f"{field:<{width}}  items")
+        print(f"{'-' * width}  -----")
+        for value, count in buckets.items():
+            print(f"{value:<{width}}  {count:>5}")
+        print()
+
+
+def tally_formats(tally: dict, files: list):
+    """Add an item's metadata files to `tally`: {extension: [files, total bytes]}."""
+    for f in files:
+        counts = tally.setdefault(file_extension(f.get("name") or "") or "(none)", [0, 0])
+        counts[0] += 1
+        counts[1] += parse_size_field(f.get("size")) or 0
+
+
+def print_formats(tally: dict, out=None):
+    """Print the --list-formats table, largest total size first, with a totals line."""
+    rows = sorted(tally.items(), key=lambda kv: (-kv[1][1], kv[0]))
+    width = max([len("extension")] + [len(ext) for ext in tally])
+    print(f"{'extension':<{width}}  {'files':>7}  {'total size':>10}", file=out)
+    print(f"{'-' * width}  {'-' * 7}  {'-' * 10}", file=out)
+    for ext, (files, size) in rows:
+        print(f"{ext:<{width}}  {files:>7}  {format_size(size):>10}", file=out)
+    print(f"{'total':<{width}}  {sum(c[0] for c in tally.values()):>7}  "
+          f"{format_size(sum(c[1] for c in tally.values())):>10}", file=out)
+
+
+def estimate_total_size(num_found: int, sample_docs: list) -> Optional[int]:
+    """Extrapolate total item_size from the docs seen so far; None if none carry a size."""
+    sizes = [doc["item_size"] for doc in sample_docs if isinstance(doc.get("item_size"), (int, float))]
+    if not sizes:
+        return None
+    return int(sum(sizes) / len(sizes) * num_found)
+
+
+def confirm_crawl(num_found: int, threshold: int, assume_yes: bool) -> bool:
+    """Ask before crawling more than `threshold` items unless --yes was given."""
+    if assume_yes or num_found <= threshold:
+        return True
+    # Prompt on stderr so it never ends up in JSON written to stdout
+    sys.stderr.write(f"Crawl {num_found} items (more than {threshold})? [y/N] ")
+    sys.stderr.flush()
+    try:
+        answer = input()
+    except EOFError:
+        answer = ""
+    return answer.strip().lower() in ("y", "yes")
+
+
+class ProgressReporter:
+    """Emits one JSON object per line describing crawl progress (for --progress-json).
+
+    Counters are updated under a lock so several workers can share one reporter.
+    """
+
+    def __init__(self, stream):
+        self.stream = stream
+        self.started = time.monotonic()
+        self.pages_done = 0
+        self.items_seen = 0
+        self.entries_collected = 0
+        self._lock = threading.Lock()
+
+    def update(self, pages: int = 0, items: int = 0, entries: int = 0):
+        with self._lock:
+            self.pages_done += pages
+            self.items_seen += items
+            self.entries_collected += entries
+
+    def emit(self, done: bool = False):
+        with self._lock:
+            record = {
+                "pages_done": self.pages_done,
+                "items_seen": self.items_seen,
+                "entries_collected": self.entries_collected,
+                "elapsed": round(time.monotonic() - self.started, 1),
+                "done": done,
+            }
+            self.stream.write(json.dumps(record) + "\n")
+            self.stream.flush()
+
+
+def identifier_filter(pattern: Optional[str], case_sensitive: bool = False) -> Optional[re.Pattern]:
+    """Compile --identifier-regex, case-insensitive unless asked otherwise; ValueError if it's invalid."""
+    if not pattern:
+        return None
+    try:
+        return re.compile(pattern, 0 if case_sensitive else re.IGNORECASE)
+    except re.error as e:
+        raise ValueError(f"invalid --identifier-regex {pattern!r}: {e}") from e
+
+
+def unknown_fields(fields: List[str]) -> List[str]:
+    """A warning per field name advancedsearch.php doesn't know, with the closest known name when there is one."""
+    warnings = []
+    for field in dict.fromkeys(fields):
+        if field in SEARCH_FIELDS:
             continue
+        close = difflib.get_close_matches(field, SEARCH_FIELDS, n=1)
+        hint = f"; did you mean {close[0]!r}?" if close else "; see --list-fields"
+        warnings.append(f"{field!r} is not a known search field, so results won't carry it and sorting or "
+                        f"faceting on it does nothing{hint}")
+    return warnings
+
+
+def print_fields(out=None):
+    """--list-fields: every known search field and what it holds."""
+    out = out or sys.stdout
+    width = max(len(name) for name in SEARCH_FIELDS)
+    for name, description in sorted(SEARCH_FIELDS.items()):
+        print(f"{name:<{width}}  {description}", file=out)
+
+
+def sort_spec(value: str) -> str:
+    """argparse type for --sort: 'FIELD' or 'FIELD asc|desc' (default asc), as sent in sort[]."""
+    parts = value.split()
+    if len(parts) == 1:
+        parts.append("asc")
+    if len(parts) != 2 or parts[1].lower() not in ("asc", "desc"):
+        raise argparse.ArgumentTypeError(f"expected FIELD or 'FIELD asc|desc', got {value!r}")
+    return f"{parts[0]} {parts[1].lower()}"
+
 
-        logging.debug(f"Processing page {page} with {len(docs)} docs")
+def enough_downloads(doc: dict, minimum: Optional[int]) -> bool:
+    """Whether a doc's `downloads` count reaches minimum; docs without one count as 0."""
+    return minimum is None or (parse_size_field(doc.get("downloads")) or 0) >= minimum
 
-        for item in docs:
-            identifier = item.get("identifier")
-            if not identifier:
+
+def matching_docs(docs: list, pattern: Optional[re.Pattern], min_downloads: Optional[int] = None) -> list:
+    """Docs whose identifier the pattern finds a match in (anywhere; anchor with ^ and $) and
+    that have at least min_downloads downloads; all of them without either filter."""
+    if pattern is None and min_downloads is None:
+        return docs
+    return [doc for doc in docs
+            if (pattern is None or pattern.search(doc.get("identifier") or "")) and enough_downloads(doc, min_downloads)]
+
+
+def list_collection_members(session: requests.Session, query: str, collection: str, rows: int,
+                            max_pages: Optional[int], sleep, workers: int = 1,
+                            id_pattern: Optional[re.Pattern] = None, sort: Optional[List[str]] = None,
+                            min_downloads: Optional[int] = None, new_since: Optional[NewSince] = None):
+    """Print member identifiers of a collection, suitable for feeding to Download-Collections-v2.py."""
+    logging.info(f"Query: {query}")
+    count = 0
+    fields = ["identifier"] if min_downloads is None else ["identifier", "downloads"]
+    on_start = None
+    if new_since:
+        fields.append("publicdate")
+
+        def on_start(num_found, total_pages, first_docs):
+            unvisited = unvisited_results(num_found, total_pages, rows)
+            if unvisited:
+                new_since.missed(f"--max-pages {max_pages} leaves {unvisited} results unlisted")
+    for _, docs in iter_search_pages(session, query, fields, rows, max_pages, sleep, on_start, workers=workers,
+                                     sort=sort):
+        if new_since:
+            new_since.see(docs)
+            docs = [doc for doc in docs if new_since.is_new(doc)]
+        for doc in matching_docs(docs, id_pattern, min_downloads):
+            identifier = doc.get("identifier")
+            if identifier:
+                print(identifier)
+                count += 1
+    logging.info(f"Listed {count} identifiers in collection '{collection}'")
+
+
+def iter_results_file(path: str) -> Iterator[dict]:
+    """The entries of a results file: a JSON array (read whole), or NDJSON streamed one object per line when
+    the name ends in .ndjson or .jsonl (before any .gz/.zst).
+
+    Raises ValueError for a file that is neither.
+    """
+    base_name = os.path.splitext(path)[0] if compression_for(path) else path
+    with open_text(path) as f:
+        if not base_name.lower().endswith((".ndjson", ".jsonl")):
+            try:
+                entries = json.load(f)
+            except json.JSONDecodeError as e:
+                raise ValueError(f"{path} is not valid JSON: {e}") from e
+            if not isinstance(entries, list) or not all(isinstance(entry, dict) for entry in entries):
+                raise ValueError(f"{path}: expected a JSON array of result objects")
+            yield from entries
+            return
+        for number, line in enumerate(f, start=1):
+            if not line.strip():
                 continue
-            title = item.get("title", "")
+            try:
+                entry = json.loads(line)
+            except json.JSONDecodeError as e:
+                raise ValueError(f"{path}:{number}: not valid JSON: {e}") from e
+            if not isinstance(entry, dict):
+                raise ValueError(f"{path}:{number}: expected a result object")
+            yield entry
+
+
+def entry_sort_key(entry: dict) -> tuple:
+    return str(entry.get("identifier") or ""), str(entry.get("file_name") or "")
+
+
+def _unique_sorted(records: Iterable[tuple], stats: Counter) -> Iterator[dict]:
+    """Entries from (key, seq, entry) records in key order, the lowest seq of each key only."""
+    last = None
+    for key, _, entry in records:
+        if key == last:
+            stats["duplicates"] += 1
+            continue
+        last = key
+        stats["entries"] += 1
+        yield entry
+
+
+def _read_run(path: str) -> Iterator[tuple]:
+    with open(path, encoding="utf-8") as f:
+        for line in f:
+            identifier, file_name, seq, entry = json.loads(line)
+            yield (identifier, file_name), seq, entry
+
 
-            if args.dry_run:
-                print(identifier, "-", title)
+def iter_merged(paths: List[str], stats: Counter, temp_dir: Optional[str] = None,
+                chunk_entries: int = MERGE_CHUNK_ENTRIES) -> Iterator[dict]:
+    """The entries of several results files, sorted by (identifier, file_name) with repeats dropped.
+
+    The first entry for each (identifier, file_name) is kept, so list the file whose copies
+    should win first. At most chunk_entries entries are held at once (plus one whole input
+    that is a JSON array rather than NDJSON): beyond that, sorted runs are spilled to NDJSON
+    files under temp_dir (the system temp directory by default) and merged from there.
+    stats counts "entries" yielded and "duplicates" dropped. Raises ValueError for a file
+    that isn't a results file.
+    """
+    def records():
+        seq = 0
+        for path in paths:
+            count = 0
+            for entry in iter_results_file(path):
+                yield entry_sort_key(entry), seq, entry
+                seq += 1
+                count += 1
+            logging.info(f"Read {count} entries from {path}")
+
+    chunk, runs, spill_dir = [], [], None
+    try:
+        for record in records():
+            chunk.append(record)
+            if len(chunk) >= chunk_entries:
+                if spill_dir is None:
+                    spill_dir = tempfile.mkdtemp(prefix="ia-merge-", dir=temp_dir)
+                chunk.sort(key=lambda r: (r[0], r[1]))
+                run = os.path.join(spill_dir, f"run-{len(runs)}.ndjson")
+                with open(run, "w", encoding="utf-8") as f:
+                    for key, seq, entry in chunk:
+                        f.write(json.dumps([key[0], key[1], seq, entry], ensure_ascii=False) + "\n")
+                runs.append(run)
+                chunk = []
+        chunk.sort(key=lambda r: (r[0], r[1]))
+        if not runs:
+            yield from _unique_sorted(chunk, stats)
+            return
+        logging.info(f"Merging {len(runs) + 1} sorted runs from {spill_dir}")
+        merged = heapq.merge(*(_read_run(run) for run in runs), iter(chunk), key=lambda r: (r[0], r[1]))
+        yield from _unique_sorted(merged, stats)
+    finally:
+        if spill_dir:
+            shutil.rmtree(spill_dir, ignore_errors=True)
+
+
+def merge_results(paths: List[str]) -> tuple:
+    """iter_merged() collected into a list; returns (entries, duplicates)."""
+    stats = Counter()
+    entries = list(iter_merged(paths, stats))
+    return entries, stats["duplicates"]
+
+
+# Leading keys of every --stable-output entry; any others follow alphabetically
+ENTRY_KEY_ORDER = ("identifier", "title", "file_name", "download_url", "size")
+
+
+def stable_keys(entry: dict) -> dict:
+    """The entry with its keys in the --stable-output order."""
+    keys = [key for key in ENTRY_KEY_ORDER if key in entry]
+    return {key: entry[key] for key in keys + sorted(key for key in entry if key not in ENTRY_KEY_ORDER)}
+
+
+def stable_entries(entries: list) -> list:
+    """Entries sorted by (identifier, file_name), each with its keys in one fixed order (for --stable-output).
+
+    Two crawls that find the same files then serialize to the same bytes, whatever order
+    the pages, queries or metadata fields came back in.
+    """
+    return [stable_keys(entry) for entry in sorted(entries, key=entry_sort_key)]
+
+
+def format_urls(entries: list) -> str:
+    """One download_url per line (for --format urls), ready for wget -i or aria2c -i."""
+    return "".join(f"{entry['download_url']}\n" for entry in entries if entry.get("download_url"))
+
+
+def format_aria2(entries: list, directory: Optional[str] = None) -> str:
+    """An aria2c input file (for --format aria2): each download_url with its out= file_name indented below.
+
+    directory becomes each entry's dir= option, with {identifier} filled in per entry.
+    Both names are cleaned the way the downloaders clean a destination path (no line
+    breaks, no '..', nothing absolute), and an entry whose URL holds a line break is
+    skipped, so a crafted file name can't add aria2c options or write outside the tree.
+    """
+    lines = []
+    for entry in entries:
+        url = entry.get("download_url")
+        if not url:
+            continue
+        if "\r" in url or "\n" in url:
+            logging.warning(f"Skipping {entry.get('identifier')}/{entry.get('file_name')}: "
+                            "its download_url holds a line break")
+            continue
+        lines.append(url)
+        if entry.get("file_name"):
+            out = render_output_path("{file_name}", "", str(entry["file_name"]), portable=True)
+            lines.append(f" out={out.replace(os.sep, '/')}")
+        if directory:
+            identifier = str(entry.get("identifier") or "")
+            if identifier:
+                identifier = render_output_path("{identifier}", identifier, "", portable=True)
+            lines.append(f" dir={directory.replace('{identifier}', identifier)}")
+    return "".join(f"{line}\n" for line in lines)
+
+
+def json_array_chunks(entries: Iterable[dict]) -> Iterator[str]:
+    """The text json.dump(list(entries), indent=2, ensure_ascii=False) writes, one entry at a time."""
+    first = True
+    for entry in entries:
+        body = json.dumps(entry, indent=2, ensure_ascii=False).replace("\n", "\n  ")
+        yield ("[\n  " if first else ",\n  ") + body
+        first = False
+    yield "[]" if first else "\n]"
+
+
+def write_sqlite(entries: Iterable[dict], path: str) -> None:
+    """Upsert entries into the `files` table of the SQLite database at path (for --format sqlite).
+
+    The database and its schema are created if needed. Rows are keyed by (identifier,
+    file_name), so a rerun into the same file updates the rows it finds again and keeps the
+    rest; everything goes in as one transaction, streamed from the iterable.
+    """
+    def rows():
+        for entry in entries:
+            yield (entry.get("identifier"), entry.get("file_name"), entry.get("title"), entry.get("download_url"),
+                   parse_size_field(entry.get("size")), json.dumps(entry, ensure_ascii=False))
+
+    conn = sqlite3.connect(path)
+    try:
+        with conn:
+            conn.executescript(SQLITE_SCHEMA)
+            conn.executemany(SQLITE_UPSERT, rows())
+    finally:
+        conn.close()
+
+
+def write_entries(entries: Iterable[dict], out: str, output_format: str = "json", aria2_dir: Optional[str] = None):
+    """Write result entries to `out` (compressed by extension), or to stdout for '-'.
+
+    output_format is one of OUTPUT_FORMATS: the JSON array, a plain download URL list, an
+    aria2c input file whose downloads go under aria2_dir, or rows upserted into a SQLite
+    database (see write_sqlite). entries may be any iterable; it is written as it is
+    consumed (--merge streams its results through here).
+    """
+    if output_format == "sqlite":
+        write_sqlite(entries, out)
+        return
+    if output_format == "json":
+        chunks = json_array_chunks(entries)
+        if out == "-":
+            for chunk in chunks:
+                sys.stdout.write(chunk)
+            sys.stdout.write("\n")
+        else:
+            write_text_atomic(out, chunks)
+        return
+    if output_format == "aria2":
+        chunks = (format_aria2([entry], aria2_dir) for entry in entries)
+    else:
+        chunks = (format_urls([entry]) for entry in entries)
+    if out == "-":
+        for chunk in chunks:
+            sys.stdout.write(chunk)
+    else:
+        write_text_atomic(out, chunks)
+
+
+def main():
+    parser = ConfigArgumentParser(description="Internet Archive Advanced Search (v2)")
+    parser.add_argument("--query", "-q", action="append",
+                        help="Advanced search query string; repeat to run several queries into one deduplicated output "
+                             "(default: Linux distribution ISOs)")
+    parser.add_argument("--from-url", type=search_url_params, metavar="URL",
+                        help="Run the query of an archive.org advancedsearch.php URL copied from the browser: its q, "
+                             "fl[], sort[] and rows replace --query, --fields and --rows")
+    parser.add_argument("--rows", type=int,
+                        help=f"Rows per page (default: {DEFAULT_ROWS}, or the rows of --from-url; capped at {MAX_ROWS})")
+    parser.add_argument("--max-pages", type=int, help="Limit number of pages to fetch")
+    parser.add_argument("--sleep", type=float, default=1.0,
+                        help="Seconds between the starts of consecutive requests; time a request took counts "
+                             "towards it (default: 1.0)")
+    parser.add_argument("--sleep-min", type=float, metavar="SECONDS",
+                        help="Lower bound of a random gap between requests (defaults to --sleep)")
+    parser.add_argument("--sleep-max", type=float, metavar="SECONDS",
+                        help="Upper bound of a random gap between requests (defaults to --sleep, or --sleep-min "
+                             "when that is larger)")
+    add_adaptive_sleep_args(parser)
+    parser.add_argument("--fields", nargs="*",
+                        help="Fields to fetch in search results; overrides --fields-preset")
+    parser.add_argument("--list-fields", action="store_true",
+                        help="Print the search fields usable with --fields, --sort and --facet, and exit")
+    parser.add_argument("--fields-preset", choices=sorted(FIELD_PRESETS), default="standard",
+                        help="Curated field list: minimal (identifier, title), standard (adds date, creator; default), "
+                             "full (adds publicdate, mediatype, collection, downloads, item_size, description)")
+    parser.add_argument("--out", "-o",
+                        help="Output JSON file for results (compressed when named *.gz or *.zst), a SQLite database "
+                             "when named *.db or *.sqlite, or '-' for stdout (logs and status then go to stderr); "
+                             "default pear.json, or pear.txt, pear.aria2 and pear.db for the other --format values")
+    parser.add_argument("--format", choices=OUTPUT_FORMATS, dest="output_format",
+                        help="What --out holds: the JSON array of entries (default), or 'urls', one download_url per "
+                             "line for wget -i / aria2c -i (after every filter, as the JSON would be), 'aria2', "
+                             "an aria2c input file that also names each download after its file_name, or 'sqlite', "
+                             "rows of a 'files' table upserted by (identifier, file_name) (the default for *.db, "
+                             "*.sqlite and *.sqlite3)")
+    parser.add_argument("--aria2-dir", metavar="DIR",
+                        help="With --format aria2, the directory aria2c saves each file in (its dir= option); "
+                             "{identifier} is replaced per entry, e.g. 'isos/{identifier}'")
+    add_session_args(parser)
+    add_log_file_args(parser)
+    parser.add_argument("-v", action="count", default=0, help="Increase verbosity (-v info, -vv debug)")
+    parser.add_argument("--dry-run", action="store_true", help="Do not fetch per-item metadata, only list identifiers")
+    parser.add_argument("--page-workers", type=int, default=1, metavar="N",
+                        help="With --dry-run or --collection, fetch up to N advanced search pages at once; --sleep "
+                             "still spaces the requests overall (default: 1)")
+    parser.add_argument("--fulltext", action="store_true",
+                        help="Search inside item texts (full-text search API) instead of metadata; entries gain a 'snippet'")
+    parser.add_argument("--save-raw", metavar="DIR",
+                        help="Also write each search page's response body, before it is parsed, to DIR/page-N.json "
+                             "(DIR/qN/page-N.json with several --query); serve them back with --base-url to replay "
+                             "a crawl")
+    parser.add_argument("--list-formats", action="store_true",
+                        help="Also tally every file of every fetched item by extension and print the count and total "
+                             f"size per extension, to tune the extension filter ({', '.join(ISO_EXTENSIONS)})")
+    parser.add_argument("--one-per-item", action="store_true",
+                        help="Emit a single entry per item: the file with the most preferred extension "
+                             "(see --prefer-extension), then the largest, then the first by name")
+    parser.add_argument("--max-files-per-item", type=int, metavar="N",
+                        help="Emit at most N entries per item, so items with dozens of discs don't crowd out the rest")
+    parser.add_argument("--max-files-order", choices=FILE_CAP_ORDERS, default="largest",
+                        help="Which files --max-files-per-item keeps: the largest (default) or the first N as listed")
+    parser.add_argument("--prefer-extension", type=parse_extensions, default=(), metavar="EXTS",
+                        help="With --one-per-item, comma-separated extension priority, e.g. .iso,.img")
+    parser.add_argument("--prefer", type=parse_extensions, default=(), metavar="EXTS",
+                        help="With --one-per-item, a fallback chain such as iso,img,zip: take the first extension "
+                             "the item has, and skip items with none of them")
+    parser.add_argument("--prefer-fallback-largest", action="store_true",
+                        help="With --prefer, take the largest file of an item with none of the extensions instead "
+                             "of skipping it")
+    parser.add_argument("--since", type=iso_date, help="Only items published on or after this date (YYYY-MM-DD)")
+    parser.add_argument("--until", type=iso_date, help="Only items published on or before this date (YYYY-MM-DD)")
+    parser.add_argument("--mediatype", choices=MEDIATYPES,
+                        help="Only items of this media type (ANDed onto every query; the default query already "
+                             "implies software)")
+    parser.add_argument("--count-only", action="store_true",
+                        help="Print how many items match each query (or --collection) and exit, without fetching any "
+                             "results or metadata")
+    parser.add_argument("--validate-query", action="store_true",
+                        help="Only check that each query (or --collection) is accepted by the server, print its "
+                             "result count, and exit; fails on a query the server rejects")
+    parser.add_argument("--facet", action="append", metavar="FIELD",
+                        help="Print how many matching items have each value of FIELD (repeatable, e.g. year, format) "
+                             "and exit; combine with --collection to analyse a collection")
+    parser.add_argument("--facet-json", action="store_true", help="With --facet, print the buckets as JSON")
+    parser.add_argument("--sort", type=sort_spec, action="append", metavar="'FIELD [asc|desc]'",
+                        help="Order results by FIELD (repeatable for tie-breaks), e.g. 'downloads desc' for the most "
+                             "downloaded first")
+    parser.add_argument("--min-downloads", type=int, metavar="N",
+                        help="Only keep items downloaded at least N times (the downloads search field); applied "
+                             "before any metadata is fetched")
+    parser.add_argument("--identifier-regex", metavar="REGEX",
+                        help="Only keep items whose identifier matches, e.g. '^debian-'; applied to the search "
+                             "results before any metadata is fetched (case-insensitive)")
+    parser.add_argument("--case-sensitive", action="store_true", help="Match --identifier-regex case-sensitively")
+    parser.add_argument("--collection", help="Print the identifiers of every item in this collection (one per line) and exit")
+    parser.add_argument("--changes", metavar="STATE",
+                        help="Print the identifiers of items changed since the last run (one per line), from the "
+                             "changes API, and keep its token in STATE for the next run; with --collection only that "
+                             "collection's items")
+    parser.add_argument("--changes-since", type=iso_date, metavar="YYYY-MM-DD",
+                        help="With --changes, list changes from this date instead of from the stored token "
+                             "(needed on the first run)")
+    add_new_since_args(parser)
+    parser.add_argument("--merge", nargs="+", metavar="FILE",
+                        help="Search nothing; combine these results files into --out, dropping repeated "
+                             "(identifier, file_name) entries (the first one wins) and sorting the rest. Inputs are "
+                             "JSON arrays or NDJSON (.ndjson/.jsonl); large sets are sorted on disk under --temp-dir")
+    parser.add_argument("--temp-dir", metavar="DIR",
+                        help="Where --merge keeps its sorted runs when the inputs don't fit in memory, about as "
+                             "much space as the uncompressed inputs (default: the system temp directory, TMPDIR)")
+    parser.add_argument("--stable-output", action="store_true",
+                        help="Write deterministic JSON for diffing: entries sorted by (identifier, file_name) and "
+                             "keys in a fixed order, so identical results give byte-identical files")
+    parser.add_argument("--progress-json", nargs="?", const="-", metavar="PATH",
+                        help="After each page, write a JSON progress line (pages_done, items_seen, entries_collected, "
+                             "elapsed) to PATH, or to stderr if no path is given")
+    parser.add_argument("--confirm-above", type=int, default=10000, metavar="N",
+                        help="Ask for confirmation before crawling a query with more than N results (default: 10000)")
+    parser.add_argument("--yes", "-y", action="store_true", help="Never ask for confirmation (for scripts)")
+    parser.add_argument("--state-file", metavar="PATH",
+                        help="Save crawl progress here and, when the file exists, resume from its last page "
+                             "(the query and options must match); removed once the crawl completes")
+    parser.add_argument("--cache-dir", help="Reuse per-item metadata stored here instead of refetching it")
+    parser.add_argument("--cache-ttl", type=float, default=86400,
+                        help="Seconds a cached metadata entry stays valid (default: 86400)")
+    add_exit_code_args(parser, skips=False)
+    args = parse_args_with_config(parser, "search")
+    if args.list_fields:
+        print_fields()
+        return
+    if args.since and args.until and args.since > args.until:
+        parser.error(f"--since {args.since} is after --until {args.until}")
+    if args.rows is not None and args.rows < 1:
+        parser.error("--rows must be at least 1")
+    if args.prefer and not args.one_per_item:
+        parser.error("--prefer needs --one-per-item")
+    if args.prefer and args.prefer_extension:
+        parser.error("--prefer replaces --prefer-extension; use one or the other")
+    if args.prefer_fallback_largest and not args.prefer:
+        parser.error("--prefer-fallback-largest needs --prefer")
+    if args.page_workers < 1:
+        parser.error("--page-workers must be at least 1")
+    if args.max_files_per_item is not None:
+        if args.max_files_per_item < 1:
+            parser.error("--max-files-per-item must be at least 1")
+        if args.one_per_item:
+            parser.error("--one-per-item already keeps one file per item; drop --max-files-per-item")
+    sleep_min = args.sleep if args.sleep_min is None else args.sleep_min
+    sleep_max = max(sleep_min, args.sleep) if args.sleep_max is None else args.sleep_max
+    if sleep_min < 0 or sleep_min > sleep_max:
+        parser.error(f"--sleep-min {sleep_min} must be between 0 and --sleep-max {sleep_max}")
+    if args.adaptive_sleep and (args.sleep_min is not None or args.sleep_max is not None):
+        parser.error("--adaptive-sleep sets the gap itself; drop --sleep-min/--sleep-max")
+    sleep_error = adaptive_sleep_error(args)
+    if sleep_error:
+        parser.error(sleep_error)
+    if args.from_url:
+        if args.query:
+            parser.error("--from-url already carries a query; drop --query")
+        if args.fulltext:
+            parser.error("--from-url takes an advanced search URL, which --fulltext can't run")
+        args.query = [args.from_url["query"]]
+        args.fields = args.from_url.get("fields", args.fields)
+        if args.rows is not None and "rows" in args.from_url:
+            parser.error("--from-url already carries rows; drop --rows")
+        args.rows = args.from_url.get("rows", args.rows)
+    if args.rows is None:
+        args.rows = DEFAULT_ROWS
+    if args.sort and args.from_url and args.from_url.get("sort"):
+        parser.error("--from-url already carries a sort; drop --sort")
+    sort = args.sort or (args.from_url.get("sort") if args.from_url else None)
+    if (args.sort or args.min_downloads is not None) and args.fulltext:
+        parser.error("--sort and --min-downloads work with advanced search only, not --fulltext")
+    if args.state_file and (args.dry_run or args.collection or args.facet or args.count_only):
+        parser.error("--state-file only applies to a crawl that saves results, "
+                     "not --dry-run, --collection, --facet or --count-only")
+    if args.output_format is None:
+        args.output_format = "sqlite" if args.out and args.out.lower().endswith(SQLITE_SUFFIXES) else "json"
+    if args.out is None:
+        args.out = DEFAULT_OUT[args.output_format]
+    if args.output_format == "sqlite" and (args.out == "-" or compression_for(args.out)):
+        parser.error("--format sqlite writes a database file; give --out a path without .gz/.zst, not '-'")
+    if args.aria2_dir and args.output_format != "aria2":
+        parser.error("--aria2-dir only applies with --format aria2")
+    changes_token = None
+    if args.changes:
+        clash = [flag for flag, used in (("--query", args.query), ("--from-url", args.from_url),
+                                         ("--fulltext", args.fulltext), ("--facet", args.facet),
+                                         ("--count-only", args.count_only), ("--validate-query", args.validate_query),
+                                         ("--merge", args.merge), ("--state-file", args.state_file)) if used]
+        if clash:
+            parser.error(f"--changes can't be combined with {', '.join(clash)}")
+        try:
+            changes_token = read_changes_state(args.changes)
+        except ValueError as e:
+            parser.error(str(e))
+        if not changes_token and not args.changes_since:
+            parser.error(f"{args.changes} holds no changes token yet; start with --changes-since YYYY-MM-DD")
+    elif args.changes_since:
+        parser.error("--changes-since only applies with --changes")
+    if args.save_raw and (args.changes or args.collection or args.facet or args.count_only or args.validate_query
+                          or args.merge):
+        parser.error("--save-raw only applies to a crawl, not --changes, --collection, --facet, --count-only, "
+                     "--validate-query or --merge")
+    if args.merge and (args.query or args.from_url or args.collection or args.facet or args.count_only
+                       or args.fulltext or args.dry_run or args.state_file):
+        parser.error("--merge only combines existing results files; it can't be combined with a search")
+    if args.temp_dir and not args.merge:
+        parser.error("--temp-dir only applies with --merge")
+    if args.list_formats and (args.dry_run or args.collection or args.facet or args.count_only):
+        parser.error("--list-formats needs the item metadata, which --dry-run, --collection, --facet and "
+                     "--count-only don't fetch")
+    if (args.only_new_since or args.since_state) and args.fulltext:
+        parser.error("--only-new-since and --since-state work with advanced search only, not --fulltext")
+    try:
+        new_since = new_since_from_args(args)
+    except ValueError as e:
+        parser.error(str(e))
+    if (args.count_only or args.validate_query) and args.fulltext:
+        parser.error("--count-only and --validate-query work with advanced search only, not --fulltext")
+    if args.facet and args.fulltext:
+        parser.error("--facet works with advanced search only, not --fulltext")
+    try:
+        id_pattern = identifier_filter(args.identifier_regex, args.case_sensitive)
+    except ValueError as e:
+        parser.error(str(e))
+    fields = args.fields or FIELD_PRESETS[args.fields_preset]
+    if "identifier" not in fields:
+        fields = ["identifier"] + fields
+    if args.min_downloads is not None and "downloads" not in fields:
+        fields = fields + ["downloads"]
+    if new_since and "publicdate" not in fields:
+        fields = fields + ["publicdate"]
+
+    def scope(query: str) -> str:
+        """A query narrowed by --mediatype, --since/--until and --only-new-since."""
+        query = with_date_range(with_mediatype(query, args.mediatype), args.since, args.until)
+        return new_since.query(query) if new_since else query
+
+    to_stdout = args.out == "-"
+    if not to_stdout:
+        try:
+            require_compression(args.out)
+        except ValueError as e:
+            parser.error(str(e))
+    # Keep stdout a clean JSON stream when the results are written there
+    status = sys.stderr if to_stdout else sys.stdout
+    setup_logging(args.v, args.log_file, stream=status, max_size=args.log_max_size, backups=args.log_backups,
+                  log_format=args.log_format)
+    if args.merge:
+        stats = Counter()
+        try:
+            # Already in (identifier, file_name) order, so --stable-output only has the keys to reorder
+            entries = iter_merged(args.merge, stats, args.temp_dir)
+            write_entries(map(stable_keys, entries) if args.stable_output else entries, args.out,
+                          args.output_format, args.aria2_dir)
+        except (OSError, ValueError) as e:
+            logging.error(str(e))
+            sys.exit(EXIT_ERROR)
+        print(f"Merged {len(args.merge)} files into {stats['entries']} entries "
+              f"({stats['duplicates']} duplicates dropped)"
+              + ("." if to_stdout else f". Saved to {args.out}."), file=status)
+        return
+
+    if args.rows > MAX_ROWS:
+        logging.warning(f"--rows {args.rows} exceeds the API maximum; using {MAX_ROWS} per page")
+        args.rows = MAX_ROWS
+    if args.from_url and args.from_url.get("output"):
+        logging.warning(f"--from-url asks for output={args.from_url['output']}; results are always fetched as JSON")
+    if args.from_url and args.from_url.get("page"):
+        logging.warning(f"--from-url starts at page={args.from_url['page']}; the crawl walks every page from 1 "
+                        "(use --max-pages to stop early)")
+    # Full-text hits carry their own fixed fields, so only advanced search requests are checked
+    if not args.fulltext:
+        requested = (args.fields or []) + [spec.split()[0] for spec in sort or []] + (args.facet or [])
+        for warning in unknown_fields(requested):
+            logging.warning(warning)
+    session = session_from_args(args)
+    # One pacer for every search, listing and metadata request of the run
+    pacer = pacer_from_args(args, session, sleep_min, sleep_max)
+    # Only listing modes are light enough per item for concurrent pages to pay off
+    page_workers = args.page_workers if args.dry_run or args.collection or args.facet else 1
+    if args.page_workers > 1 and page_workers == 1:
+        logging.warning("--page-workers only applies with --dry-run, --collection or --facet; "
+                        "fetching pages one at a time")
+
+    if args.count_only:
+        raw_queries = [f"collection:{args.collection}"] if args.collection else args.query or [DEFAULT_QUERY]
+        for number, raw_query in enumerate(raw_queries, start=1):
+            query = scope(raw_query)
+            try:
+                num_found = count_results(session, query)
+            except RuntimeError as e:
+                logging.error(str(e))
+                sys.exit(EXIT_ERROR)
+            print(num_found if len(raw_queries) == 1 else f"[q{number}] {num_found}")
+        return
+
+    if args.validate_query:
+        raw_queries = [f"collection:{args.collection}"] if args.collection else args.query or [DEFAULT_QUERY]
+        rejected = 0
+        for number, raw_query in enumerate(raw_queries, start=1):
+            query = scope(raw_query)
+            try:
+                num_found = count_results(session, query)
+            except RuntimeError as e:
+                rejected += 1
+                print(f"[q{number}] Invalid: {query}\n  {e}")
                 continue
+            print(f"[q{number}] OK ({num_found} results): {query}")
+        sys.exit(EXIT_FAILED if rejected else EXIT_OK)
+
+    if args.facet:
+        raw_queries = [f"collection:{args.collection}"] if args.collection else args.query or [DEFAULT_QUERY]
+        queries = [scope(q) for q in raw_queries]
+        facet_fields = ["identifier"] + [f for f in args.facet if f != "identifier"]
+        if args.min_downloads is not None and "downloads" not in facet_fields:
+            facet_fields.append("downloads")
+        pages = ((page, matching_docs(docs, id_pattern, args.min_downloads)) for query in queries
+                 for page, docs in iter_search_pages(session, query, facet_fields, args.rows, args.max_pages,
+                                                     pacer, workers=page_workers, sort=sort))
+        try:
+            counts = count_facets(pages, args.facet)
+        except RuntimeError as e:
+            logging.error(str(e))
+            sys.exit(EXIT_ERROR)
+        print_facets(counts, args.facet_json)
+        return
+
+    if args.changes:
+        try:
+            identifiers, token = fetch_changes(session, None if args.changes_since else changes_token,
+                                               args.changes_since, args.max_pages, pacer)
+            if args.collection:
+                identifiers = in_collection(session, identifiers, args.collection, pacer)
+        except RuntimeError as e:
+            logging.error(str(e))
+            sys.exit(EXIT_ERROR)
+        for identifier in identifiers:
+            if not id_pattern or id_pattern.search(identifier):
+                print(identifier)
+        write_changes_state(args.changes, token)
+        return
+
+    if args.collection:
+        query = scope(f"collection:{args.collection}")
+        list_collection_members(session, query, args.collection, args.rows, args.max_pages, pacer,
+                                page_workers, id_pattern, sort, args.min_downloads, new_since)
+        if args.since_state:
+            write_since_state(args.since_state, new_since.mark())
+        return
+
+    queries = args.query or [DEFAULT_QUERY]
+    resolved = [scope(q) for q in queries]
+
+    state = None
+    resume = {}
+    if args.state_file:
+        state = CrawlState(args.state_file, {
+            "queries": resolved, "fulltext": args.fulltext, "fields": fields, "rows": args.rows, "sort": sort,
+            "one_per_item": args.one_per_item, "prefer_extension": list(args.prefer_extension),
+            "max_files_per_item": args.max_files_per_item, "max_files_order": args.max_files_order,
+            "prefer": list(args.prefer), "prefer_fallback_largest": args.prefer_fallback_largest,
+            "identifier_regex": args.identifier_regex, "case_sensitive": args.case_sensitive,
+            "min_downloads": args.min_downloads, "list_formats": args.list_formats,
+        })
+        try:
+            resume = state.load() or {}
+        except ValueError as e:
+            parser.error(str(e))
+    resume_query, resume_page = resume.get("query", 1), resume.get("page", 0)
+    if resume:
+        print(f"Resuming from {args.state_file}: q{resume_query} after page {resume_page}, "
+              f"{len(resume.get('entries', []))} entries collected so far", file=status)
 
-            time.sleep(args.sleep)
-            meta_json = fetch_metadata(session, identifier)
-            if not meta_json:
-                logging.debug(f"No metadata for {identifier}")
+    def query_pages():
+        """(number, label, page, docs) across every --query in turn; labels are q1, q2, ... for the logs."""
+        for number, query in enumerate(resolved, start=1):
+            if number < resume_query:
                 continue
+            start_page = resume_page + 1 if number == resume_query else 1
+            label = f"q{number}"
+            logging.info(f"[{label}] Query: {query}")
+
+            def announce(num_found, total_pages, first_docs):
+                line = f"[{label}] {num_found} results across {total_pages} pages"
+                estimated = estimate_total_size(num_found, first_docs)
+                if estimated is not None:
+                    line += f", roughly {format_size(estimated)} in total (item_size)"
+                print(line, file=status)
+                # --max-pages may limit how much of a huge result set is actually walked; a
+                # resumed query was confirmed by the run that started it
+                if start_page == 1 and not confirm_crawl(min(num_found, total_pages * args.rows), args.confirm_above, args.yes):
+                    print(f"[{label}] Skipped", file=status)
+                    declined.append(label)
+                    if new_since:
+                        new_since.missed(f"[{label}] was skipped")
+                    return False
+                unvisited = unvisited_results(num_found, total_pages, args.rows)
+                if new_since and unvisited:
+                    new_since.missed(f"[{label}] --max-pages {args.max_pages} leaves {unvisited} results unvisited")
+                # Roughly one metadata request per item walked
+                warn_without_contact(args, min(num_found, total_pages * args.rows))
+                return True
+
+            raw_dir = args.save_raw
+            if raw_dir and len(resolved) > 1:
+                raw_dir = os.path.join(raw_dir, label)
+            if raw_dir:
+                os.makedirs(raw_dir, exist_ok=True)
+            if args.fulltext:
+                pages = iter_fulltext_pages(session, query, args.rows, args.max_pages, pacer, announce,
+                                            start_page, raw_dir)
+            else:
+                pages = iter_search_pages(session, query, fields, args.rows, args.max_pages, pacer, announce,
+                                          page_workers, sort, start_page, raw_dir)
+            for page, docs in pages:
+                yield number, label, page, docs
+
+    iso_entries = resume.get("entries", [])
+    # Every identifier+file_name pair appears a single time in the output, however
+    # many queries (or pages) match its item
+    collected = {(entry["identifier"], entry["file_name"]) for entry in iso_entries}
+    listed = set()
+    dark_items = resume.get("dark_items", 0)
+    unmatched_items = resume.get("unmatched_items", 0)
+    filtered_items = resume.get("filtered_items", 0)
+    unpopular_items = resume.get("unpopular_items", 0)
+    capped_files = resume.get("capped_files", 0)
+    formats = resume.get("formats") or {} if args.list_formats else None
+    if new_since and resume.get("newest"):
+        new_since.see([{"publicdate": resume["newest"]}])
+    if new_since and resume.get("since_complete") is False:
+        new_since.complete = False
+    entries_per_query = Counter(resume.get("entries_per_query", {}))
+    declined = resume.get("declined", [])
+    checkpoint = None
+    cache = MetadataCache(args.cache_dir, args.cache_ttl) if args.cache_dir else None
+    prefer = args.prefer or args.prefer_extension
+    strict_prefer = bool(args.prefer) and not args.prefer_fallback_largest
+    progress_file = None
+    progress = None
+    if args.progress_json:
+        progress_file = sys.stderr if args.progress_json == "-" else open(args.progress_json, "w", encoding="utf-8")
+        progress = ProgressReporter(progress_file)
+
+    try:
+        for number, label, page, docs in query_pages():
+            logging.debug(f"[{label}] Processing page {page} with {len(docs)} docs")
+            entries_before = len(iso_entries)
+            if new_since:
+                new_since.see(docs)
+
+            for item in docs:
+                identifier = item.get("identifier")
+                if not identifier:
+                    continue
+                if new_since and not new_since.is_new(item):
+                    logging.debug(f"[{label}] {identifier} was published at the --only-new-since mark; skipping")
+                    continue
+                if id_pattern and not id_pattern.search(identifier):
+                    filtered_items += 1
+                    continue
+                if not enough_downloads(item, args.min_downloads):
+                    unpopular_items += 1
+                    continue
+                title = item.get("title", "")
+
+                if args.dry_run:
+                    if identifier in listed:
+                        continue
+                    listed.add(identifier)
+                    if item.get("snippet"):
+                        print(identifier, "-", title, "-", item["snippet"])
+                    else:
+                        print(identifier, "-", title)
+                    continue
+
+                meta_json = cache.get(identifier) if cache else None
+                if meta_json is not None:
+                    logging.debug(f"Metadata cache hit for {identifier}")
+                else:
+                    # No batch alternative: fl[] only returns item-level fields, never the files list
+                    pacer.wait()
+                    meta_json = fetch_metadata(session, identifier)
+                    if meta_json and cache:
+                        cache.put(identifier, meta_json)
+                if not meta_json:
+                    logging.debug(f"No metadata for {identifier}")
+                    if new_since and meta_json is None:
+                        new_since.missed(f"Metadata for {identifier} could not be fetched")
+                    continue
+
+                if is_dark_item(meta_json):
+                    logging.warning(f"{identifier}: item is dark (taken down or unavailable); skipping")
+                    dark_items += 1
+                    continue
+
+                if formats is not None:
+                    tally_formats(formats, meta_json.get("files", []) or [])
+                files = [f for f in meta_json.get("files", []) or []
+                         if has_extension(f.get("name") or "", ISO_EXTENSIONS)]
+                primary = pick_primary(files, prefer, strict_prefer) if files and args.one_per_item else None
+                if not files or (args.one_per_item and primary is None):
+                    logging.debug(f"{identifier}: no matching files" + (" with a --prefer extension" if files else ""))
+                    unmatched_items += 1
+                else:
+                    if args.one_per_item:
+                        files = [primary]
+                    elif args.max_files_per_item is not None:
+                        kept = cap_files(files, args.max_files_per_item, args.max_files_order)
+                        capped_files += len(files) - len(kept)
+                        files = kept
+                    for f in files:
+                        if (identifier, f["name"]) in collected:
+                            logging.debug(f"[{label}] {identifier}/{f['name']} already collected")
+                            continue
+                        collected.add((identifier, f["name"]))
+                        iso_entries.append(make_entry(item, f, meta_json))
+
+            entries_per_query[label] += len(iso_entries) - entries_before
+            if progress:
+                progress.update(pages=1, items=len(docs), entries=len(iso_entries) - entries_before)
+                progress.emit()
+            if state:
+                checkpoint = {"query": number, "page": page, "entries": len(iso_entries), "dark_items": dark_items,
+                              "unmatched_items": unmatched_items, "filtered_items": filtered_items,
+                              "unpopular_items": unpopular_items, "capped_files": capped_files,
+                              "formats": {ext: list(c) for ext, c in formats.items()} if formats is not None else None,
+                              "newest": new_since.newest if new_since else None,
+                              "since_complete": new_since.complete if new_since else None,
+                              "entries_per_query": dict(entries_per_query), "declined": list(declined)}
+                if state.due():
+                    state.save(checkpoint, iso_entries)
+    except KeyboardInterrupt:
+        if not state:
+            raise
+        if checkpoint:
+            state.save(checkpoint, iso_entries)
+        print(f"Interrupted; progress is saved in {args.state_file}, run the same command again to resume",
+              file=status)
+        sys.exit(EXIT_INTERRUPTED)
+
+    if len(queries) > 1:
+        for number in range(1, len(queries) + 1):
+            logging.info(f"[q{number}] contributed {entries_per_query[f'q{number}']} entries")
+
+    if progress:
+        progress.emit(done=True)
+        if progress_file is not sys.stderr:
+            progress_file.close()
+
+    if len(declined) == len(queries):
+        print(f"Nothing crawled; {args.out} left unchanged.", file=status)
+        if state:
+            state.clear()
+        return
+
+    if filtered_items:
+        print(f"Left out {filtered_items} items whose identifier doesn't match --identifier-regex.", file=status)
+    if unpopular_items:
+        print(f"Left out {unpopular_items} items with fewer than {args.min_downloads} downloads.", file=status)
+    if capped_files:
+        print(f"Left out {capped_files} files beyond --max-files-per-item {args.max_files_per_item} "
+              f"({args.max_files_order}).", file=status)
+    if dark_items or unmatched_items:
+        print(f"Skipped {dark_items} dark items and {unmatched_items} items with no matching files.", file=status)
 
-            files = meta_json.get("files", []) or []
-            for f in files:
-                name = (f.get("name", "") or "")
-                lname = name.lower()
-                if lname.endswith((".iso", ".img", ".zip")):
-                    iso_entries.append({
-                        "identifier": identifier,
-                        "title": title,
-                        "file_name": name,
-                        "download_url": f"{DOWNLOAD_BASE_URL}/{identifier}/{name}",
-                        "size": f.get("size", "unknown"),
-                    })
-
-    with open(args.out, "w", encoding="utf-8") as f:
-        json.dump(iso_entries, f, indent=2, ensure_ascii=False)
-
-    print(f"Found {len(iso_entries)} ISO-like files. Saved to {args.out}.")
+    if formats is not None:
+        print_formats(formats, status)
+    write_entries(stable_entries(iso_entries) if args.stable_output else iso_entries, args.out,
+                  args.output_format, args.aria2_dir)
+    if to_stdout:
+        print(f"Found {len(iso_entries)} ISO-like files.", file=status)
+    else:
+        print(f"Found {len(iso_entries)} ISO-like files. Saved to {args.out}.")
+    # A dry run lists items without collecting them, so it mustn't move the mark past them
+    if new_since and args.since_state and not args.dry_run:
+        write_since_state(args.since_state, new_since.mark())
+    if state:
+        state.clear()
+    if not iso_entries and not args.dry_run:
+        sys.exit(EXIT_NOTHING)
 
 
 if __name__ == "__main__":
@@ -0,0 +1,205 @@
+import json
+import logging
+import os
+import re
+import sys
+from typing import Optional
+
+from ia_common import (
+    ConfigArgumentParser,
+    add_log_file_args,
+    add_session_args,
+    file_md5,
+    format_size,
+    is_derivative,
+    is_local_sidecar,
+    load_tool,
+    parse_args_with_config,
+    parse_size_field,
+    session_from_args,
+    setup_logging,
+)
+
+# The metadata fetch is IA-Metadata's
+metadata = load_tool("IA-Metadata.py")
+
+# Files archive.org rewrites whenever anything about an item changes, so they'd always differ
+GENERATED_SOURCES = {"metadata"}
+GENERATED_FORMATS = {"Metadata"}
+
+
+def item_listing(files: list, include_generated: bool = False) -> dict:
+    """name -> {"size", "md5"} for an item's metadata file entries."""
+    listing = {}
+    for f in files:
+        name = f.get("name")
+        if not name:
+            continue
+        if not include_generated and (f.get("source") in GENERATED_SOURCES or f.get("format") in GENERATED_FORMATS):
+            continue
+        listing[name] = {"size": parse_size_field(f.get("size")), "md5": f.get("md5")}
+    return listing
+
+
+def local_listing(root: str) -> dict:
+    """name -> {"size", "md5", "path"} for the files under root, named by their '/'-separated relative path.
+
+    The md5 is left as None; same_file() only computes it where it can make a difference.
+    """
+    listing = {}
+    for dirpath, _, filenames in os.walk(root):
+        for filename in filenames:
+            if is_local_sidecar(filename):
+                continue
+            path = os.path.join(dirpath, filename)
+            name = os.path.relpath(path, root).replace(os.sep, "/")
+            listing[name] = {"size": os.path.getsize(path), "md5": None, "path": path}
+    return listing
+
+
+def downloaded_files(files: list, include: Optional[re.Pattern] = None, exclude: Optional[re.Pattern] = None,
+                     include_derivatives: bool = False) -> list:
+    """The metadata file entries a download with the same filters saves, to compare with a local copy.
+
+    Like Download-Collections-v2.py, the generated derivative files are left out unless
+    include_derivatives is set, so a copy made without them doesn't list them as removed.
+    """
+    kept = []
+    for f in files:
+        name = f.get("name") or ""
+        if not include_derivatives and is_derivative(f):
+            continue
+        if include and not include.search(name):
+            continue
+        if exclude and exclude.search(name):
+            continue
+        kept.append(f)
+    return kept
+
+
+def _md5(entry: dict, other: dict) -> Optional[str]:
+    if entry["md5"] is None and "path" in entry and other["md5"]:
+        entry["md5"] = file_md5(entry["path"])
+    return entry["md5"]
+
+
+def same_file(a: dict, b: dict) -> bool:
+    """Whether two listing entries hold the same content, by size and then md5.
+
+    Entries that can't be told apart (no md5 on one side, sizes equal or unknown) count as the same.
+    """
+    if a["size"] is not None and b["size"] is not None and a["size"] != b["size"]:
+        return False
+    md5_a, md5_b = _md5(a, b), _md5(b, a)
+    if md5_a and md5_b:
+        return md5_a.lower() == md5_b.lower()
+    return True
+
+
+def diff_listings(old: dict, new: dict) -> dict:
+    """Names added in new, removed from old, and changed between them, plus the unchanged count."""
+    common = sorted(set(old) & set(new))
+    changed = [name for name in common if not same_file(old[name], new[name])]
+    return {
+        "added": sorted(set(new) - set(old)),
+        "removed": sorted(set(old) - set(new)),
+        "changed": changed,
+        "unchanged": len(common) - len(changed),
+    }
+
+
+def describe(entry: dict) -> str:
+    size = format_size(entry["size"]) if entry["size"] is not None else "size unknown"
+    return f"{size}, md5 {entry['md5']}" if entry["md5"] else size
+
+
+def diff_json(diff: dict, old: dict, new: dict, old_label: str, new_label: str) -> dict:
+    def fields(entry):
+        return {"size": entry["size"], "md5": entry["md5"]}
+
+    return {
+        "old": old_label,
+        "new": new_label,
+        "added": [{"name": name, **fields(new[name])} for name in diff["added"]],
+        "removed": [{"name": name, **fields(old[name])} for name in diff["removed"]],
+        "changed": [{"name": name, "old": fields(old[name]), "new": fields(new[name])} for name in diff["changed"]],
+        "unchanged": diff["unchanged"],
+    }
+
+
+def print_diff(diff: dict, old: dict, new: dict, old_label: str, new_label: str):
+    for name in diff["added"]:
+        print(f"+ {name} ({describe(new[name])})")
+    for name in diff["removed"]:
+        print(f"- {name} ({describe(old[name])})")
+    for name in diff["changed"]:
+        print(f"~ {name}: {describe(old[name])} -> {describe(new[name])}")
+    print(f"{old_label} -> {new_label}: {len(diff['added'])} added, {len(diff['removed'])} removed, "
+          f"{len(diff['changed'])} changed, {diff['unchanged']} unchanged")
+
+
+def main():
+    p = ConfigArgumentParser(description="Compare the file listings of two Internet Archive items, or of an item "
+                                         "and a local copy")
+    p.add_argument("identifier", help="Archive.org item identifier")
+    p.add_argument("other", nargs="?", help="Identifier of the item to compare it with")
+    p.add_argument("--local", metavar="DIR",
+                   help="Compare the item with the files under DIR (e.g. its download directory) instead of another "
+                        "item; local md5s are only computed for files whose size matches")
+    p.add_argument("--include", help="With --local, regex; only compare the item's files whose name matches "
+                                     "(case-insensitive), as the download that made the copy did")
+    p.add_argument("--exclude", help="With --local, regex; leave out the item's files whose name matches "
+                                     "(case-insensitive)")
+    p.add_argument("--include-derivatives", action="store_true",
+                   help="With --local, also expect the generated files the downloaders skip by default "
+                        "(__ia_thumb.jpg, _archive.torrent, ...)")
+    p.add_argument("--include-generated", action="store_true",
+                   help="Also compare the metadata files archive.org regenerates on every change (_files.xml, "
+                        "_meta.xml, ...), which otherwise always show as changed")
+    p.add_argument("--json", action="store_true", help="Print the differences as JSON instead of a +/-/~ listing")
+    add_session_args(p)
+    add_log_file_args(p)
+    p.add_argument("-v", action="count", default=0, help="Increase verbosity (-v info, -vv debug)")
+    args = parse_args_with_config(p, "diff")
+    if bool(args.other) == bool(args.local):
+        p.error("give either a second identifier or --local DIR")
+    if args.local and not os.path.isdir(args.local):
+        p.error(f"--local: {args.local} is not a directory")
+    if not args.local and (args.include or args.exclude or args.include_derivatives):
+        p.error("--include, --exclude and --include-derivatives only apply with --local")
+    try:
+        include = re.compile(args.include, re.IGNORECASE) if args.include else None
+        exclude = re.compile(args.exclude, re.IGNORECASE) if args.exclude else None
+    except re.error as e:
+        p.error(f"invalid --include/--exclude pattern: {e}")
+
+    setup_logging(args.v, args.log_file, max_size=args.log_max_size, backups=args.log_backups,
+                  log_format=args.log_format)
+    session = session_from_args(args)
+
+    try:
+        files = metadata.get_metadata(session, args.identifier).get("files", []) or []
+        if args.local:
+            files = downloaded_files(files, include, exclude, args.include_derivatives or args.include_generated)
+        old = item_listing(files, args.include_generated)
+        if args.local:
+            new, new_label = local_listing(args.local), args.local
+        else:
+            new = item_listing(metadata.get_metadata(session, args.other).get("files", []) or [],
+                               args.include_generated)
+            new_label = args.other
+    except Exception as e:
+        logging.error(str(e))
+        sys.exit(2)
+
+    diff = diff_listings(old, new)
+    if args.json:
+        print(json.dumps(diff_json(diff, old, new, args.identifier, new_label), indent=2, ensure_ascii=False))
+    else:
+        print_diff(diff, old, new, args.identifier, new_label)
+    # Like diff(1): 0 when identical, 1 when they differ, 2 on errors
+    sys.exit(1 if diff["added"] or diff["removed"] or diff["changed"] else 0)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,150 @@
+import json
+import logging
+import sys
+import time
+
+import requests
+
+from ia_common import (
+    FILE_SORT_KEYS,
+    ConfigArgumentParser,
+    add_log_file_args,
+    add_session_args,
+    encode_path_segment,
+    fetch_files_xml,
+    friendly_error,
+    get_json,
+    metadata_url,
+    parse_args_with_config,
+    print_files_table,
+    session_from_args,
+    setup_logging,
+    with_attempts,
+    write_json_atomic,
+)
+
+
+def get_metadata(session, identifier: str) -> dict:
+    url = metadata_url(identifier)
+    try:
+        resp, data = get_json(session, url)
+    except json.JSONDecodeError as e:
+        raise RuntimeError(f"Failed to parse metadata JSON for '{identifier}': {e}") from e
+    except requests.RequestException as e:
+        raise RuntimeError(with_attempts(f"Metadata request failed: {friendly_error(e)}", e)) from e
+    if resp.status_code != 200:
+        raise RuntimeError(with_attempts(f"Metadata request failed with status {resp.status_code}: {resp.text[:300]}", resp))
+    # The endpoint answers unknown identifiers with 200 and an empty object
+    if not data:
+        raise RuntimeError(f"No metadata found for '{identifier}'")
+    return data
+
+
+# Metadata fields naming the data nodes that hold an item: the primary and its two copies
+NODE_FIELDS = ("server", "d1", "d2")
+
+
+def data_nodes(meta: dict) -> list:
+    """(host, [fields]) for each distinct data node in the item's metadata, primary first."""
+    nodes = {}
+    for field in NODE_FIELDS:
+        host = meta.get(field)
+        if host:
+            nodes.setdefault(host, []).append(field)
+    return list(nodes.items())
+
+
+def node_urls(meta: dict, name: str) -> list:
+    """The direct https://{node}{dir}/{name} URL of a file on each of the item's data nodes."""
+    return [f"https://{host}{meta['dir']}/{encode_path_segment(name)}" for host, _ in data_nodes(meta)]
+
+
+def ping_node(session, url: str) -> str:
+    """How a HEAD request for url went: the status and round trip time, or why it failed."""
+    started = time.monotonic()
+    try:
+        resp = session.head(url, allow_redirects=False)
+    except requests.RequestException as e:
+        return f"unreachable ({friendly_error(e)})"
+    return f"HTTP {resp.status_code} in {(time.monotonic() - started) * 1000:.0f} ms"
+
+
+def list_servers(session, meta: dict, ping: bool = False):
+    names = [f["name"] for f in meta.get("files", []) or [] if f.get("name")]
+    print(f"Data nodes (dir {meta['dir']}):")
+    for host, fields in data_nodes(meta):
+        line = f"  {host} ({', '.join(fields)})"
+        if ping:
+            # The first file stands in for the node; any file there answers the same way
+            url = f"https://{host}{meta['dir']}/{encode_path_segment(names[0])}" if names else f"https://{host}/"
+            line += f": {ping_node(session, url)}"
+        print(line)
+    for name in names:
+        print(name)
+        for url in node_urls(meta, name):
+            print(f"  {url}")
+
+
+def main():
+    p = ConfigArgumentParser(description="Fetch raw Internet Archive metadata for an identifier")
+    p.add_argument("identifier", help="Archive.org item identifier")
+    p.add_argument("--out", "-o", help="Write the metadata JSON to this file instead of stdout")
+    p.add_argument("--files-only", "--ls", action="store_true",
+                   help="Only list the item's files as a table of name, size and format")
+    p.add_argument("--files-xml", action="store_true",
+                   help="Read the file list (with md5, sha1 and crc32) from the item's <identifier>_files.xml "
+                        "instead of the JSON metadata; the output is then just {\"files\": [...]}")
+    p.add_argument("--sort", choices=sorted(FILE_SORT_KEYS), default="size",
+                   help="Table order for --files-only (default: size, largest first)")
+    p.add_argument("--list-servers", action="store_true",
+                   help="Print the data nodes holding the item (its server, d1 and d2 fields) and each file's "
+                        "direct URL on every one of them, e.g. to tell whether a slow download is a node problem")
+    p.add_argument("--ping", action="store_true",
+                   help="With --list-servers, send each node a HEAD request and print its status and round trip time")
+    add_session_args(p)
+    add_log_file_args(p)
+    p.add_argument("-v", action="count", default=0, help="Increase verbosity (-v info, -vv debug)")
+    args = parse_args_with_config(p, "metadata")
+    if args.ping and not args.list_servers:
+        p.error("--ping needs --list-servers")
+    if args.list_servers:
+        clash = [flag for flag, used in (("--files-only", args.files_only), ("--files-xml", args.files_xml),
+                                         ("--out", args.out)) if used]
+        if clash:
+            p.error(f"--list-servers can't be combined with {', '.join(clash)}")
+
+    setup_logging(args.v, args.log_file, max_size=args.log_max_size, backups=args.log_backups,
+                  log_format=args.log_format)
+    session = session_from_args(args)
+
+    logging.info(f"Fetching metadata for '{args.identifier}'")
+    try:
+        if args.files_xml:
+            meta = {"files": fetch_files_xml(session, args.identifier)}
+        else:
+            meta = get_metadata(session, args.identifier)
+    except Exception as e:
+        logging.error(friendly_error(e))
+        sys.exit(1)
+
+    if args.list_servers:
+        if not meta.get("dir") or not data_nodes(meta):
+            logging.error(f"'{args.identifier}' lists no data nodes (no server/d1/d2 and dir fields); "
+                          "it may be dark or still being archived")
+            sys.exit(1)
+        list_servers(session, meta, args.ping)
+        return
+
+    if args.files_only:
+        print_files_table(meta.get("files", []) or [], args.sort)
+        return
+
+    if args.out:
+        write_json_atomic(args.out, meta, indent=2, ensure_ascii=False)
+        print(f"Saved metadata for '{args.identifier}' to {args.out}.")
+    else:
+        print(json.dumps(meta, indent=2, ensure_ascii=False))
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,506 @@
+import argparse
+import logging
+import os
+import re
+import sys
+import threading
+import time
+from concurrent.futures import FIRST_COMPLETED, ThreadPoolExecutor, wait
+from typing import Iterable, Iterator, Optional
+
+import requests
+
+from ia_common import (
+    EXIT_ERROR,
+    EXIT_INTERRUPTED,
+    ConfigArgumentParser,
+    MetricsWriter,
+    PauseFile,
+    RateLimiter,
+    TransferStatus,
+    adaptive_sleep_error,
+    add_adaptive_sleep_args,
+    add_color_arg,
+    add_exit_code_args,
+    add_filename_args,
+    add_free_space_arg,
+    add_log_file_args,
+    add_metrics_args,
+    add_pause_arg,
+    add_session_args,
+    add_throttle_handler,
+    format_size,
+    has_extension,
+    install_status_signal,
+    is_dark_item,
+    load_tool,
+    pacer_from_args,
+    parse_args_with_config,
+    parse_extensions,
+    parse_rate,
+    parse_size,
+    parse_size_field,
+    run_exit_code,
+    session_from_args,
+    setup_logging,
+    size_summary,
+    use_color,
+    validate_output_template,
+    warn_without_contact,
+)
+
+# Search and download logic comes from the two standalone tools this one chains together
+search = load_tool("IA-Advanced-Search-v2.py")
+dfj = load_tool("Download-From-JSON.py")
+
+
+def file_matches(f: dict, args, include: Optional[re.Pattern], exclude: Optional[re.Pattern]) -> bool:
+    """Whether a metadata file entry passes the extension, name and size filters.
+
+    Files without a listed size pass the size bounds, since they can't be judged.
+    """
+    name = f.get("name") or ""
+    if not name or not has_extension(name, args.extensions):
+        return False
+    if include and not include.search(name):
+        return False
+    if exclude and exclude.search(name):
+        return False
+    size = parse_size_field(f.get("size"))
+    if size is not None:
+        if args.min_size is not None and size < args.min_size:
+            return False
+        if args.max_size is not None and size > args.max_size:
+            return False
+    return True
+
+
+def iter_matching_entries(session: requests.Session, args, include: Optional[re.Pattern] = None,
+                          exclude: Optional[re.Pattern] = None, new_since=None) -> Iterator[dict]:
+    """Walk every query's result pages and yield a download entry per matching file, as found.
+
+    include/exclude are the compiled --include/--exclude patterns. With a search.NewSince,
+    only items published after its threshold are expanded, and the newest publicdate of
+    the results is noted on it.
+    """
+    seen = set()
+    pacer = pacer_from_args(args, session, args.sleep)
+    for number, raw_query in enumerate(args.query or [search.DEFAULT_QUERY], start=1):
+        label = f"q{number}"
+        query = search.with_date_range(search.with_mediatype(raw_query, args.mediatype), args.since, args.until)
+        fields = search.FIELD_PRESETS["standard"]
+        if new_since:
+            query = new_since.query(query)
+            fields = fields + ["publicdate"]
+        logging.info(f"[{label}] Query: {query}")
+
+        def announce(num_found, total_pages, first_docs):
+            print(f"[{label}] {num_found} results across {total_pages} pages")
+            warn_without_contact(args, min(num_found, total_pages * args.rows))
+            unvisited = search.unvisited_results(num_found, total_pages, args.rows)
+            if new_since and unvisited:
+                new_since.missed(f"[{label}] --max-pages {args.max_pages} leaves {unvisited} results unvisited")
+
+        pages = search.iter_search_pages(session, query, fields, args.rows, args.max_pages, pacer, announce)
+        for _, docs in pages:
+            if new_since:
+                new_since.see(docs)
+            for item in docs:
+                identifier = item.get("identifier")
+                if not identifier or identifier in seen or (new_since and not new_since.is_new(item)):
+                    continue
+                seen.add(identifier)
+                pacer.wait()
+                meta_json = search.fetch_metadata(session, identifier)
+                if not meta_json:
+                    logging.debug(f"No metadata for {identifier}")
+                    if new_since and meta_json is None:
+                        new_since.missed(f"Metadata for {identifier} could not be fetched")
+                    continue
+                if is_dark_item(meta_json):
+                    logging.warning(f"{identifier}: item is dark (taken down or unavailable); skipping")
+                    continue
+                for f in meta_json.get("files", []) or []:
+                    if file_matches(f, args, include, exclude):
+                        yield search.make_entry(item, f, meta_json)
+
+
+# Seconds between the -v reports on the search -> download queue
+PIPELINE_REPORT_SECONDS = 10.0
+
+
+class Pipeline:
+    """Depth of the search -> download queue, reported under -v to show which side holds the other up.
+
+    Time the search spends blocked on a full queue means the downloads (network or disk) are
+    the bottleneck; time spent finding the next entry while jobs sit idle means the search is.
+    """
+
+    def __init__(self, jobs: int, capacity: int):
+        self.jobs = jobs
+        self.capacity = capacity
+        self.queued = 0
+        self.running = 0
+        self.done = 0
+        self.blocked = 0.0
+        self.starved = 0.0
+        self._lock = threading.Lock()
+        self._next_report = time.monotonic() + PIPELINE_REPORT_SECONDS
+
+    def submitted(self):
+        with self._lock:
+            self.queued += 1
+
+    def started(self):
+        with self._lock:
+            self.queued -= 1
+            self.running += 1
+
+    def finished(self):
+        with self._lock:
+            self.running -= 1
+            self.done += 1
+
+    def idle(self) -> bool:
+        """Whether a job is free with nothing queued for it."""
+        with self._lock:
+            return self.queued == 0 and self.running < self.jobs
+
+    def summary(self) -> str:
+        with self._lock:
+            return (f"{self.queued} queued, {self.running} running, {self.done} done (queue holds {self.capacity}); "
+                    f"search waited {self.blocked:.1f}s on a full queue, jobs waited {self.starved:.1f}s on the search")
+
+    def report(self, force: bool = False):
+        if force or time.monotonic() >= self._next_report:
+            self._next_report = time.monotonic() + PIPELINE_REPORT_SECONDS
+            logging.info(f"Pipeline: {self.summary()}")
+
+
+# Seconds of transfer --auto-jobs measures each setting over before it takes the next step
+AUTO_JOBS_INTERVAL = 15.0
+# How much an extra job has to raise the aggregate throughput to be kept (0.1 = 10%)
+AUTO_JOBS_GAIN = 0.1
+DEFAULT_AUTO_JOBS_MAX = 8
+
+
+class JobTuner:
+    """How many downloads may run at once under --auto-jobs, steered by their aggregate throughput.
+
+    Every interval the bytes received through `status` are turned into a rate. While that
+    beats the best rate so far by more than AUTO_JOBS_GAIN another job is let in, up to high;
+    once an added job doesn't pay off, the limit goes back to the best setting and stays
+    there. A 429 halves the limit (not below low), caps later probing below the setting that
+    drew it, and starts the search again. Intervals in which jobs sat idle waiting on the
+    search say nothing about the downloads and are skipped.
+    """
+
+    def __init__(self, start: int, low: int, high: int, status: TransferStatus, interval: float = AUTO_JOBS_INTERVAL):
+        self.low, self.high = low, high
+        self.limit = min(max(start, low), high)
+        self.status = status
+        self.interval = interval
+        self.running = 0
+        self.best_rate, self.best_limit = 0.0, self.limit
+        self.settled = False
+        self._cut_at = None
+        self._cond = threading.Condition()
+        self._stop = threading.Event()
+        self._thread = threading.Thread(target=self._run, name="auto-jobs", daemon=True)
+
+    def acquire(self):
+        """Wait for a free job slot under the current limit."""
+        with self._cond:
+            while self.running >= self.limit:
+                self._cond.wait()
+            self.running += 1
+
+    def release(self):
+        with self._cond:
+            self.running -= 1
+            self._cond.notify()
+
+    def _set(self, limit: int, reason: str) -> Optional[str]:
+        """Change the limit; the caller holds _cond. Returns the line to log if it changed."""
+        changed, self.limit = limit != self.limit, limit
+        self._cond.notify_all()
+        return f"--auto-jobs: {limit} jobs ({reason})" if changed else None
+
+    def sample(self, rate: float, busy: bool = True):
+        """Take a step after an interval in which the downloads received `rate` bytes/sec."""
+        message = None
+        # Download threads call throttled() on a 429 while the tuner thread samples
+        with self._cond:
+            if self.settled or not busy:
+                return
+            if rate > self.best_rate * (1 + AUTO_JOBS_GAIN):
+                self.best_rate, self.best_limit = rate, self.limit
+                if self.limit < self.high:
+                    message = self._set(self.limit + 1,
+                                        f"{format_size(int(rate))}/s with {self.limit}, trying one more")
+                else:
+                    self.settled = True
+            else:
+                self.settled = True
+                message = self._set(self.best_limit, f"{format_size(int(rate))}/s with {self.limit} is no better than "
+                                                     f"{format_size(int(self.best_rate))}/s with {self.best_limit}")
+        if message:
+            logging.info(message)
+
+    def throttled(self):
+        """Back off after a 429; the ones that follow within an interval belong to the same burst."""
+        with self._cond:
+            now = time.monotonic()
+            if self._cut_at is not None and now - self._cut_at < self.interval:
+                return
+            self._cut_at = now
+            limit = max(self.low, self.limit // 2)
+            self.high = max(self.low, self.limit - 1)
+            self.best_rate, self.best_limit, self.settled = 0.0, limit, False
+            message = self._set(limit, "429 Too Many Requests")
+        if message:
+            logging.info(message)
+
+    def attach(self, session: requests.Session) -> "JobTuner":
+        """Have the 429s session meets call throttled(), alongside any --adaptive-sleep pacer."""
+        add_throttle_handler(session, self.throttled)
+        return self
+
+    def start(self) -> "JobTuner":
+        self._thread.start()
+        return self
+
+    def _run(self):
+        last_time, last_bytes = time.monotonic(), self.status.bytes
+        while not self._stop.wait(self.interval):
+            now, transferred = time.monotonic(), self.status.bytes
+            with self._cond:
+                busy = self.running >= self.limit
+            self.sample((transferred - last_bytes) / (now - last_time), busy)
+            last_time, last_bytes = now, transferred
+
+    def stop(self):
+        self._stop.set()
+        if self._thread.is_alive():
+            self._thread.join()
+
+
+def run_downloads(session: requests.Session, args, entries: Iterable[dict],
+                  rate_limiter: Optional[RateLimiter] = None, status: Optional[TransferStatus] = None,
+                  pipeline: Optional[Pipeline] = None, tuner: Optional[JobTuner] = None) -> tuple:
+    """Download entries as they arrive on up to args.jobs threads; returns (results, interrupted).
+
+    At most two entries per job are queued ahead, so the search only runs as far ahead of
+    the downloads as needed to keep every job busy. The queue's depth and the time either
+    side spent waiting on the other are kept in `pipeline` and logged under -v. With a
+    tuner, only as many of the threads transfer at once as it allows.
+    """
+    capacity = args.jobs * 2
+    pipeline = pipeline or Pipeline(args.jobs, capacity)
+
+    def run(entry, idx):
+        if tuner:
+            tuner.acquire()
+        pipeline.started()
+        try:
+            result = dfj.process_item(session, args, entry, idx, None, rate_limiter, status)
+        finally:
+            pipeline.finished()
+            if tuner:
+                tuner.release()
+        if result is not None and status:
+            status.record(result["status"])
+        return result
+
+    futures = []
+    pending = set()
+    interrupted = False
+    with ThreadPoolExecutor(max_workers=args.jobs) as pool:
+        try:
+            entries = iter(entries)
+            while True:
+                # Finding the next entry (search pages, item metadata) while a job has nothing to do
+                clock, idle = time.monotonic(), pipeline.idle()
+                entry = next(entries, None)
+                if idle and futures:
+                    pipeline.starved += time.monotonic() - clock
+                if entry is None:
+                    break
+                while len(pending) >= capacity:
+                    clock = time.monotonic()
+                    _, pending = wait(pending, timeout=PIPELINE_REPORT_SECONDS, return_when=FIRST_COMPLETED)
+                    pipeline.blocked += time.monotonic() - clock
+                    pipeline.report()
+                pipeline.submitted()
+                future = pool.submit(run, entry, len(futures) + 1)
+                futures.append(future)
+                pending.add(future)
+                pipeline.report()
+        except KeyboardInterrupt:
+            # Transfers already running finish; queued ones are dropped
+            interrupted = True
+            for future in pending:
+                future.cancel()
+            print(f"{dfj.mark('[!]')} Interrupted; waiting for running transfers to finish")
+    results = [future.result() for future in futures if not future.cancelled()]
+    pipeline.report(force=True)
+    return [r for r in results if r is not None], interrupted
+
+
+def build_parser() -> argparse.ArgumentParser:
+    p = ConfigArgumentParser(description="Search Internet Archive and download the matching files in one pass")
+    p.add_argument("--query", "-q", action="append",
+                   help="Advanced search query (repeatable; results are combined and deduplicated by identifier). "
+                        "Defaults to the Linux ISO query of IA-Advanced-Search-v2.py")
+    p.add_argument("--mediatype", choices=search.MEDIATYPES, help="Only items of this media type (ANDed onto every query)")
+    p.add_argument("--since", type=search.iso_date, help="Only items published on or after this date (YYYY-MM-DD)")
+    p.add_argument("--until", type=search.iso_date, help="Only items published on or before this date (YYYY-MM-DD)")
+    search.add_new_since_args(p)
+    p.add_argument("--rows", type=int, default=500, help=f"Search rows per page (capped at {search.MAX_ROWS})")
+    p.add_argument("--max-pages", type=int, help="Limit number of search pages per query")
+    p.add_argument("--sleep", type=float, default=1.0, help="Sleep seconds between search/metadata requests")
+    add_adaptive_sleep_args(p)
+    p.add_argument("--extensions", type=parse_extensions, default=search.ISO_EXTENSIONS,
+                   help=f"Comma-separated file extensions to fetch (default: {','.join(search.ISO_EXTENSIONS)})")
+    p.add_argument("--include", help="Regex; only files whose name matches (case-insensitive)")
+    p.add_argument("--exclude", help="Regex; skip files whose name matches (case-insensitive)")
+    p.add_argument("--min-size", type=parse_size, help="Skip files smaller than this, e.g. 100MB")
+    p.add_argument("--max-size", type=parse_size, help="Skip files larger than this, e.g. 4GiB")
+    p.add_argument("--output-dir", "-o", default=dfj.DEFAULT_OUTPUT_DIR, help="Destination directory")
+    p.add_argument("--output-template", default="{identifier}/{file_name}",
+                   help="Destination path relative to the output dir; placeholders {identifier}, {title}, {file_name}")
+    add_filename_args(p)
+    add_free_space_arg(p)
+    p.add_argument("--on-exists", choices=dfj.ON_EXISTS_POLICIES, default="skip",
+                   help="What to do when a destination file already exists (default: skip)")
+    p.add_argument("--resume", action="store_const", dest="on_exists", const="resume",
+                   help="Same as --on-exists resume")
+    p.add_argument("--jobs", "-j", type=int, default=1, help="Files downloaded at the same time (default: 1)")
+    p.add_argument("--auto-jobs", action="store_true",
+                   help="Pick the number of simultaneous downloads from the measured throughput: start at --jobs "
+                        "and add one every 15s while the total speed keeps improving, settle when it stops, "
+                        "and halve it on a 429")
+    p.add_argument("--no-auto-jobs", action="store_false", dest="auto_jobs",
+                   help="Keep --jobs fixed (turns off an auto-jobs set in the config file)")
+    p.add_argument("--auto-jobs-min", type=int, default=1, metavar="N",
+                   help="Fewest simultaneous downloads --auto-jobs goes down to (default: 1)")
+    p.add_argument("--auto-jobs-max", type=int, default=DEFAULT_AUTO_JOBS_MAX, metavar="N",
+                   help=f"Most simultaneous downloads --auto-jobs tries (default: {DEFAULT_AUTO_JOBS_MAX})")
+    p.add_argument("--max-rate", type=parse_rate,
+                   help="Cap download speed across all transfers, e.g. 2MB/s or 500KiB/s")
+    dfj.add_per_file_args(p)
+    add_pause_arg(p)
+    dfj.add_hook_args(p)
+    p.add_argument("--dry-run", action="store_true", help="List what would be downloaded and the total size")
+    p.add_argument("--show-slowest", type=int, metavar="N",
+                   help="End the summary with the N slowest downloads by throughput and the host that served each")
+    add_log_file_args(p)
+    add_metrics_args(p)
+    p.add_argument("-v", action="count", default=0, help="Increase verbosity (-v info, -vv debug)")
+    add_session_args(p, timeout=dfj.REQUEST_TIMEOUT)
+    add_color_arg(p)
+    add_exit_code_args(p)
+    return p
+
+
+def main():
+    p = build_parser()
+    args = parse_args_with_config(p, "sync")
+    use_color(args.color)
+    if args.jobs < 1:
+        p.error("--jobs must be at least 1")
+    if not 1 <= args.auto_jobs_min <= args.auto_jobs_max:
+        p.error(f"--auto-jobs-min {args.auto_jobs_min} must be between 1 and --auto-jobs-max {args.auto_jobs_max}")
+    if args.rows < 1:
+        p.error("--rows must be at least 1")
+    sleep_error = adaptive_sleep_error(args)
+    if sleep_error:
+        p.error(sleep_error)
+    if args.timeout_per_file is not None and args.timeout_per_file <= 0:
+        p.error("--timeout-per-file must be greater than 0")
+    if args.refresh_older_than is not None and args.on_exists != "skip":
+        p.error("--refresh-older-than only applies with --on-exists skip (the default)")
+    if args.metrics_interval <= 0:
+        p.error("--metrics-interval must be greater than 0")
+    if args.show_slowest is not None and args.show_slowest < 1:
+        p.error("--show-slowest must be at least 1")
+    try:
+        new_since = search.new_since_from_args(args)
+    except ValueError as e:
+        p.error(str(e))
+    try:
+        include = re.compile(args.include, re.IGNORECASE) if args.include else None
+        exclude = re.compile(args.exclude, re.IGNORECASE) if args.exclude else None
+    except re.error as e:
+        p.error(f"invalid --include/--exclude pattern: {e}")
+    template_error = validate_output_template(args.output_template) or (
+        dfj.hook_template_error(args.on_complete) if args.on_complete else None)
+    if template_error:
+        p.error(template_error)
+    args.rows = min(args.rows, search.MAX_ROWS)
+    # Fields process_item expects from Download-From-JSON's command line
+    args.update = False
+    args.head_bytes = None
+    first_jobs = args.jobs
+    if args.auto_jobs:
+        # Threads for the most the tuner may allow; it holds back the ones beyond its limit
+        args.jobs = args.auto_jobs_max
+    # Concurrent progress bars would overwrite each other on one terminal line
+    args.progress = args.jobs == 1
+
+    setup_logging(args.v, args.log_file, max_size=args.log_max_size, backups=args.log_backups,
+                  log_format=args.log_format)
+    # Every job holds a connection; a smaller pool would close and reopen them between files
+    args.pool_size = max(args.pool_size, args.jobs)
+    session = session_from_args(args)
+    rate_limiter = RateLimiter(args.max_rate) if args.max_rate else None
+    args.pause = PauseFile(args.pause_file) if args.pause_file else None
+    status = TransferStatus()
+    install_status_signal(status)
+    metrics = MetricsWriter(args.metrics_file, status, args.metrics_interval).start() if args.metrics_file else None
+    tuner = None
+    if args.auto_jobs and not args.dry_run:
+        tuner = JobTuner(first_jobs, args.auto_jobs_min, args.auto_jobs_max, status).attach(session).start()
+    if not args.dry_run:
+        os.makedirs(args.output_dir, exist_ok=True)
+
+    clock = time.monotonic()
+    try:
+        entries = iter_matching_entries(session, args, include, exclude, new_since)
+        results, interrupted = run_downloads(session, args, entries, rate_limiter, status, tuner=tuner)
+    except RuntimeError as e:
+        logging.error(str(e))
+        sys.exit(EXIT_ERROR)
+    finally:
+        if metrics:
+            metrics.stop()
+        if tuner:
+            tuner.stop()
+
+    if args.preserve_original_names:
+        dfj.save_renamed(args, results)
+    counts = dfj.count_statuses(results)
+    if args.dry_run:
+        print(f"Dry run: {size_summary([r['size'] for r in results if r['status'] == 'planned'])}")
+    else:
+        throughput = dfj.throughput_stats(results, time.monotonic() - clock)
+        status = "Interrupted" if interrupted else "Completed"
+        print(f"{status}. Success: {counts['downloaded']}, Skipped: {counts['skipped']}, Failed: {counts['failed']}; "
+              f"transferred {format_size(throughput['transferred_bytes'])} in {throughput['elapsed_seconds']:.1f}s")
+        if args.show_slowest:
+            dfj.print_slowest(results, args.show_slowest)
+    if new_since and args.since_state and not args.dry_run:
+        # Moving the mark past failed files would leave them out of every later run
+        if interrupted or counts["failed"]:
+            print(f"{dfj.mark('[!]')} {args.since_state} left at {new_since.threshold or 'no mark'}; "
+                  "rerun to retry what didn't finish")
+        else:
+            search.write_since_state(args.since_state, new_since.mark())
+    if interrupted:
+        sys.exit(EXIT_INTERRUPTED)
+    done = len(results) - counts["failed"] - counts["skipped"]
+    sys.exit(run_exit_code(counts["failed"], len(results), done, args.exit_code_on_skip))
+
+
+if __name__ == "__main__":
+    main()
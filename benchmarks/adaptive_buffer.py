@@ -0,0 +1,88 @@
+"""Count the write() calls Download-From-JSON makes for one large file, with and without --adaptive-buffer.
+
+A local server sends --size MiB of zeros; each run downloads it through download_file()
+and reads the process's write syscall count (syscw) from /proc/self/io, so it only runs
+on Linux. The figures in the README's --adaptive-buffer entry come from the defaults:
+
+    python benchmarks/adaptive_buffer.py
+"""
+import argparse
+import importlib.util
+import os
+import socket
+import sys
+import tempfile
+import threading
+import time
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+ROOT = os.path.join(os.path.dirname(os.path.abspath(__file__)), "..")
+sys.path.insert(0, ROOT)
+
+import ia_common  # noqa: E402
+
+SEND_CHUNK = b"\0" * (1024 * 1024)
+
+
+def load_dfj():
+    spec = importlib.util.spec_from_file_location("download_from_json", os.path.join(ROOT, "Download-From-JSON.py"))
+    module = importlib.util.module_from_spec(spec)
+    spec.loader.exec_module(module)
+    return module
+
+
+def start_server(size_mib: int) -> ThreadingHTTPServer:
+    class Handler(BaseHTTPRequestHandler):
+        protocol_version = "HTTP/1.1"
+
+        def setup(self):
+            super().setup()
+            self.connection.setsockopt(socket.IPPROTO_TCP, socket.TCP_NODELAY, 1)
+
+        def log_message(self, *args):
+            pass
+
+        def do_GET(self):
+            self.send_response(200)
+            self.send_header("Content-Length", str(size_mib * len(SEND_CHUNK)))
+            self.end_headers()
+            for _ in range(size_mib):
+                self.wfile.write(SEND_CHUNK)
+
+    server = ThreadingHTTPServer(("127.0.0.1", 0), Handler)
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    return server
+
+
+def write_calls() -> int:
+    with open("/proc/self/io") as f:
+        return next(int(line.split()[1]) for line in f if line.startswith("syscw:"))
+
+
+def main():
+    p = argparse.ArgumentParser(description=__doc__.split("\n\n")[0])
+    p.add_argument("--size", type=int, default=256, help="File size in MiB (default: 256)")
+    args = p.parse_args()
+    dfj = load_dfj()
+    parser = argparse.ArgumentParser()
+    ia_common.add_session_args(parser)
+    session = ia_common.session_from_args(parser.parse_args([]))
+    server = start_server(args.size)
+    url = f"http://127.0.0.1:{server.server_address[1]}/download/item/big.bin"
+    try:
+        with tempfile.TemporaryDirectory() as tmp:
+            for label, adaptive in (("fixed chunks", False), ("--adaptive-buffer", True)):
+                dest = os.path.join(tmp, "big.bin")
+                # syscw covers the whole process, but the server thread's socket send() calls aren't in it
+                before, start = write_calls(), time.perf_counter()
+                dfj.download_file(session, url, dest, progress=False, adaptive_buffer=adaptive)
+                elapsed, calls = time.perf_counter() - start, write_calls() - before
+                print(f"{label:<18} {calls:>6} write calls, {elapsed:.1f} s")
+                os.remove(dest)
+    finally:
+        server.shutdown()
+        server.server_close()
+
+
+if __name__ == "__main__":
+    main()
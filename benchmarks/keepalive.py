@@ -0,0 +1,79 @@
+"""Time many small GETs through the shared session with and without connection reuse.
+
+A local server stands in for archive.org and waits --setup-ms before answering on each
+new connection, standing in for the TCP/TLS handshake of a real one. The figures in the
+README's Connections note come from the defaults:
+
+    python benchmarks/keepalive.py
+"""
+import argparse
+import os
+import socket
+import sys
+import threading
+import time
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+sys.path.insert(0, os.path.join(os.path.dirname(os.path.abspath(__file__)), ".."))
+
+import ia_common  # noqa: E402
+
+BODY = b"x" * 512
+
+
+def start_server(setup_seconds: float) -> ThreadingHTTPServer:
+    class Handler(BaseHTTPRequestHandler):
+        protocol_version = "HTTP/1.1"
+
+        def setup(self):
+            super().setup()
+            # Headers and body go out as separate writes; like a real server, don't hold the second back
+            self.connection.setsockopt(socket.IPPROTO_TCP, socket.TCP_NODELAY, 1)
+            time.sleep(setup_seconds)
+
+        def log_message(self, *args):
+            pass
+
+        def do_GET(self):
+            self.send_response(200)
+            self.send_header("Content-Length", str(len(BODY)))
+            if self.close_connection:
+                # Say so, as real servers do, or the client tries to reuse the connection
+                self.send_header("Connection", "close")
+            self.end_headers()
+            self.wfile.write(BODY)
+
+    server = ThreadingHTTPServer(("127.0.0.1", 0), Handler)
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    return server
+
+
+def time_gets(url: str, count: int, flags: list) -> float:
+    parser = argparse.ArgumentParser()
+    ia_common.add_session_args(parser)
+    session = ia_common.session_from_args(parser.parse_args(flags))
+    start = time.perf_counter()
+    for n in range(count):
+        session.get(f"{url}/download/item/file{n}.txt").raise_for_status()
+    elapsed = time.perf_counter() - start
+    session.close()
+    return elapsed
+
+
+def main():
+    p = argparse.ArgumentParser(description=__doc__.split("\n\n")[0])
+    p.add_argument("--requests", type=int, default=300, help="GETs per run (default: 300)")
+    p.add_argument("--setup-ms", type=float, default=20, help="Delay per new connection (default: 20)")
+    args = p.parse_args()
+    server = start_server(args.setup_ms / 1000)
+    url = f"http://127.0.0.1:{server.server_address[1]}"
+    try:
+        for label, flags in (("reuse", []), ("--no-keepalive", ["--no-keepalive"])):
+            print(f"{label:<15} {args.requests} GETs in {time_gets(url, args.requests, flags):.1f} s")
+    finally:
+        server.shutdown()
+        server.server_close()
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,135 @@
+"""Helpers shared by the test suite: loading the hyphenated scripts and a scriptable HTTP server."""
+import argparse
+import contextlib
+import importlib.util
+import io
+import logging
+import os
+import sys
+import threading
+import unittest.mock
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+ROOT = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
+if ROOT not in sys.path:
+    sys.path.insert(0, ROOT)
+
+import ia_common  # noqa: E402
+
+
+def load_script(file_name: str):
+    """Import one of the top-level CLI scripts (whose names aren't valid module names)."""
+    path = os.path.join(ROOT, file_name)
+    module_name = os.path.splitext(file_name)[0].replace("-", "_").lower()
+    spec = importlib.util.spec_from_file_location(module_name, path)
+    module = importlib.util.module_from_spec(spec)
+    spec.loader.exec_module(module)
+    return module
+
+
+def run_main(module, *argv) -> tuple:
+    """Run a script's main() with argv as its command line: (exit status, stdout, stderr).
+
+    The config file is pinned to an empty one, and main() gets a root logger without
+    handlers to set up; the handlers it adds are closed and the old ones restored afterwards.
+    """
+    root = logging.getLogger()
+    handlers, level = root.handlers[:], root.level
+    root.handlers = []
+    out, err = io.StringIO(), io.StringIO()
+    argv = [module.__name__, *argv, "--config", os.devnull]
+    try:
+        with unittest.mock.patch.object(sys, "argv", argv), contextlib.redirect_stdout(out), \
+                contextlib.redirect_stderr(err):
+            module.main()
+        code = 0
+    except SystemExit as e:
+        code = e.code or 0
+    finally:
+        for handler in root.handlers:
+            handler.close()
+        root.handlers, root.level = handlers, level
+    return code, out.getvalue(), err.getvalue()
+
+
+def session_args(**overrides) -> argparse.Namespace:
+    """Parsed add_session_args() defaults, tuned for fast tests."""
+    parser = argparse.ArgumentParser()
+    ia_common.add_session_args(parser)
+    args = parser.parse_args([])
+    args.timeout = 5
+    args.backoff = 0.0
+    for key, value in overrides.items():
+        setattr(args, key, value)
+    return args
+
+
+class Response:
+    def __init__(self, status=200, body=b"", headers=None, truncate_at=None):
+        self.status = status
+        self.body = body if isinstance(body, bytes) else body.encode("utf-8")
+        self.headers = dict(headers or {})
+        # Advertise the full length but close the connection after this many bytes
+        self.truncate_at = truncate_at
+
+
+class MockServer:
+    """Threaded HTTP server that answers each path from a queue of scripted Responses.
+
+    A handler callable may be registered instead of a queue for request-dependent answers.
+    Every request is recorded in `requests` as (method, path, headers).
+    """
+
+    def __init__(self):
+        self.routes = {}
+        self.requests = []
+        server = self
+
+        class Handler(BaseHTTPRequestHandler):
+            def log_message(self, *args):
+                pass
+
+            def _serve(self):
+                server.requests.append((self.command, self.path, dict(self.headers)))
+                path = self.path.split("?", 1)[0]
+                route = server.routes.get(path)
+                if callable(route):
+                    resp = route(self)
+                elif route:
+                    resp = route.pop(0) if len(route) > 1 else route[0]
+                else:
+                    resp = Response(404, b"not found")
+                self.send_response(resp.status)
+                headers = {"Content-Length": str(len(resp.body)), **resp.headers}
+                for key, value in headers.items():
+                    self.send_header(key, value)
+                self.end_headers()
+                if self.command == "HEAD":
+                    return
+                if resp.truncate_at is not None:
+                    self.wfile.write(resp.body[:resp.truncate_at])
+                    self.wfile.flush()
+                    self.close_connection = True
+                    return
+                self.wfile.write(resp.body)
+
+            do_GET = _serve
+            do_HEAD = _serve
+
+        self.httpd = ThreadingHTTPServer(("127.0.0.1", 0), Handler)
+        self.url = f"http://127.0.0.1:{self.httpd.server_address[1]}"
+        self.thread = threading.Thread(target=self.httpd.serve_forever, daemon=True)
+
+    def add(self, path: str, *responses):
+        self.routes[path] = list(responses)
+
+    def __enter__(self):
+        self.thread.start()
+        self.previous_base_url = ia_common.base_url()
+        ia_common.set_base_url(self.url)
+        return self
+
+    def __exit__(self, *exc):
+        ia_common.set_base_url(self.previous_base_url)
+        self.httpd.shutdown()
+        self.httpd.server_close()
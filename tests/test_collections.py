@@ -0,0 +1,862 @@
+import contextlib
+import errno
+import hashlib
+import io
+import os
+import re
+import sys
+import tarfile
+import tempfile
+import threading
+import time
+import types
+import unittest
+import unittest.mock
+import zipfile
+from collections import Counter, deque
+
+import requests
+
+from support import MockServer, Response, ia_common, load_script, run_main
+
+# The internetarchive library is replaced by a minimal stand-in whose File.download()
+# fetches from the mock server, so download_item runs against scripted responses.
+fake_ia = types.ModuleType("internetarchive")
+sys.modules.setdefault("internetarchive", fake_ia)
+collections_v2 = load_script("Download-Collections-v2.py")
+
+GOOD = b"good iso contents"
+GOOD_MD5 = hashlib.md5(GOOD).hexdigest()
+# Same size as GOOD, so only the md5 tells them apart
+BAD = b"evil iso contents"
+
+
+class FakeFile:
+    def __init__(self, identifier, name, session=None):
+        self.url = f"{ia_common.download_base_url()}/{identifier}/{name}"
+        self.session = session or requests
+
+    def download(self, file_path, **kwargs):
+        resp = self.session.get(self.url, timeout=5)
+        resp.raise_for_status()
+        with open(file_path, "wb") as f:
+            f.write(resp.content)
+
+
+class FakeItem:
+    def __init__(self, identifier, files, session=None):
+        self.identifier = identifier
+        self.files = files
+        self.metadata = {"title": identifier}
+        self.session = session
+
+    def get_file(self, name):
+        return FakeFile(self.identifier, name, self.session)
+
+
+def make_args(destdir, **overrides):
+    args = collections_v2.build_parser().parse_args(["-o", destdir, "--retries", "0", "--retry-on-checksum-fail", "0"])
+    # What main() sets after parsing
+    args.pause, args.ia_session = None, None
+    for key, value in overrides.items():
+        setattr(args, key, value)
+    return args
+
+
+def fake_library(test, **fakes):
+    """Stand in for internetarchive functions until the test ends."""
+    for name, fake in fakes.items():
+        patcher = unittest.mock.patch.object(collections_v2.internetarchive, name, fake, create=True)
+        patcher.start()
+        test.addCleanup(patcher.stop)
+
+
+class DownloadItemTest(unittest.TestCase):
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+        self.files = [{"name": "disc.iso", "size": str(len(GOOD)), "md5": GOOD_MD5}]
+        self.dest = os.path.join(self.tmp.name, "item", "disc.iso")
+        fake_library(self, get_item=lambda identifier, config=None: FakeItem(identifier, self.files))
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def write_local(self, data):
+        os.makedirs(os.path.dirname(self.dest), exist_ok=True)
+        with open(self.dest, "wb") as f:
+            f.write(data)
+
+    def read_local(self):
+        with open(self.dest, "rb") as f:
+            return f.read()
+
+    def test_mismatched_local_copy_is_redownloaded(self):
+        self.write_local(b"corrupt iso contents")
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, GOOD))
+            counts = collections_v2.download_item("item", make_args(self.tmp.name, update=True, checksum=True), None)
+        self.assertEqual(counts["downloaded"], 1)
+        self.assertEqual(self.read_local(), GOOD)
+
+    def test_matching_local_copy_is_skipped(self):
+        self.write_local(GOOD)
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, GOOD))
+            counts = collections_v2.download_item("item", make_args(self.tmp.name, update=True, checksum=True), None)
+        self.assertEqual(counts["skipped"], 1)
+        self.assertEqual(server.requests, [])
+
+    def test_skip_refetches_file_shorter_than_listed_size(self):
+        self.write_local(GOOD[:4])
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, GOOD))
+            counts = collections_v2.download_item("item", make_args(self.tmp.name), None)
+        self.assertEqual(counts["downloaded"], 1)
+        self.assertEqual(self.read_local(), GOOD)
+
+    def test_corrupt_download_does_not_replace_file(self):
+        self.write_local(b"old")
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, b"truncated"))
+            with self.assertLogs(level="ERROR"):
+                counts = collections_v2.download_item(
+                    "item", make_args(self.tmp.name, on_exists="overwrite", checksum=True), None)
+        self.assertEqual(counts["failed"], 1)
+        self.assertEqual(self.read_local(), b"old")
+        self.assertTrue(os.path.exists(self.dest + ia_common.PART_SUFFIX))
+
+    def test_full_size_mismatch_is_kept_beside_an_existing_file(self):
+        self.write_local(b"old")
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, BAD))
+            with self.assertLogs(level="WARNING"):
+                counts = collections_v2.download_item(
+                    "item", make_args(self.tmp.name, on_exists="overwrite", checksum=True), None)
+        self.assertEqual(counts["badsum"], 1)
+        self.assertEqual(self.read_local(), b"old")
+        with open(self.dest + collections_v2.BAD_COPY_SUFFIX, "rb") as f:
+            self.assertEqual(f.read(), BAD)
+        self.assertFalse(os.path.exists(self.dest + collections_v2.BADSUM_SUFFIX))
+
+    def test_checksum_mismatch_is_refetched_then_kept_with_marker(self):
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, BAD))
+            with self.assertLogs(level="WARNING"):
+                counts = collections_v2.download_item(
+                    "item", make_args(self.tmp.name, checksum=True, retry_on_checksum_fail=2), None)
+        self.assertEqual(len(server.requests), 3)
+        self.assertEqual(counts["badsum"], 1)
+        self.assertEqual(counts["downloaded"], 0)
+        self.assertEqual(self.read_local(), BAD)
+        self.assertEqual(collections_v2.read_badsum(self.dest)["actual"], hashlib.md5(BAD).hexdigest())
+        # The kept copy counts as current on the next --update run instead of being fetched again
+        with MockServer() as server:
+            counts = collections_v2.download_item("item", make_args(self.tmp.name, update=True, checksum=True), None)
+        self.assertEqual(counts["skipped"], 1)
+        self.assertEqual(server.requests, [])
+
+    def test_checksum_retry_that_verifies_counts_as_downloaded(self):
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, b"bad"), Response(200, GOOD))
+            counts = collections_v2.download_item(
+                "item", make_args(self.tmp.name, checksum=True, retry_on_checksum_fail=1), None)
+        self.assertEqual(counts["downloaded"], 1)
+        self.assertEqual(self.read_local(), GOOD)
+        self.assertFalse(os.path.exists(self.dest + collections_v2.BADSUM_SUFFIX))
+
+    def test_head_bytes_saves_only_the_prefix_beside_the_file(self):
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, GOOD))
+            counts = collections_v2.download_item("item", make_args(self.tmp.name, head_bytes=4), None,
+                                                  session=ia_common.build_session(5, 0, 0, None))
+        self.assertEqual(counts["downloaded"], 1)
+        self.assertEqual(server.requests[0][2].get("Range"), "bytes=0-3")
+        with open(self.dest + ".head-4", "rb") as f:
+            self.assertEqual(f.read(), GOOD[:4])
+        self.assertFalse(os.path.exists(self.dest))
+
+    def test_auto_file_list_takes_checksums_from_files_xml(self):
+        self.files = [{"name": "disc.iso", "size": str(len(GOOD)), "source": "original"}]
+        xml = (f'<files><file name="disc.iso" source="original"><size>{len(GOOD)}</size><md5>{GOOD_MD5}</md5>'
+               '</file></files>')
+        with MockServer() as server:
+            server.add("/download/item/item_files.xml", Response(200, xml))
+            server.add("/download/item/disc.iso", Response(200, b"bad"), Response(200, GOOD))
+            counts = collections_v2.download_item(
+                "item", make_args(self.tmp.name, file_list="auto", checksum=True, retry_on_checksum_fail=1), None,
+                session=ia_common.build_session(5, 0, 0, None))
+        # Only the md5 from the XML list could catch the bad first copy
+        self.assertEqual(counts["downloaded"], 1)
+        self.assertEqual(self.read_local(), GOOD)
+        self.assertEqual(len(server.requests), 3)
+
+    def test_forbidden_file_counts_as_restricted(self):
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(403, b"forbidden"))
+            with self.assertLogs(level="WARNING"):
+                counts = collections_v2.download_item("item", make_args(self.tmp.name), None)
+        self.assertEqual(counts["restricted"], 1)
+        self.assertEqual(counts["failed"], 0)
+
+    def test_body_cut_short_is_not_saved(self):
+        # Neither a listed size nor an md5 to catch it: the short read itself fails the file
+        self.files = [{"name": "disc.iso"}]
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, GOOD, truncate_at=5))
+            with self.assertLogs(level="ERROR"):
+                counts = collections_v2.download_item("item", make_args(self.tmp.name), None)
+        self.assertEqual(counts["failed"], 1)
+        self.assertFalse(os.path.exists(self.dest))
+
+    def test_dark_item_is_counted_apart_from_unmatched(self):
+        def dark_item(identifier, config=None):
+            item = FakeItem(identifier, [])
+            item.item_metadata = {"is_dark": True}
+            return item
+        fake_library(self, get_item=dark_item)
+        with self.assertLogs(level="WARNING") as logs:
+            counts = collections_v2.download_item("item", make_args(self.tmp.name), None)
+        self.assertEqual((counts["dark"], counts["unmatched"]), (1, 0))
+        self.assertIn("dark", logs.output[0])
+
+        fake_library(self, get_item=lambda identifier, config=None: FakeItem(identifier, self.files))
+        with self.assertLogs(level="WARNING") as logs:
+            counts = collections_v2.download_item("item2", make_args(self.tmp.name, glob="*.zip"), None)
+        self.assertEqual((counts["dark"], counts["unmatched"]), (0, 1))
+        self.assertIn("no files matched", logs.output[0])
+
+    def test_dry_run_plan_lists_urls_paths_and_actions(self):
+        self.files = self.files + [{"name": "notes.txt", "size": "5"}]
+        self.write_local(GOOD)
+        plan = []
+        with contextlib.redirect_stdout(io.StringIO()):
+            collections_v2.download_item("item", make_args(self.tmp.name, dry_run=True), None, plan)
+        self.assertEqual([(p["name"], p["action"], p["size"]) for p in plan],
+                         [("disc.iso", "skip", len(GOOD)), ("notes.txt", "download", 5)])
+        self.assertEqual(plan[0]["dest_path"], os.path.abspath(self.dest))
+        self.assertTrue(plan[1]["url"].endswith("/download/item/notes.txt"))
+
+
+class MainTest(unittest.TestCase):
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+        self.addCleanup(self.tmp.cleanup)
+
+    def run_main(self, server, files, *argv) -> int:
+        def get_item(identifier, archive_session=None, **kwargs):
+            return FakeItem(identifier, files, archive_session)
+
+        with unittest.mock.patch.object(collections_v2.internetarchive, "get_session", create=True,
+                                        side_effect=lambda config=None: requests.Session()), \
+                unittest.mock.patch.object(collections_v2.internetarchive, "get_item", create=True,
+                                           side_effect=get_item):
+            return run_main(collections_v2, *argv, "-o", self.tmp.name)[0]
+
+    def test_sleep_paces_requests_across_concurrent_items(self):
+        files = [{"name": "a.iso", "size": str(len(GOOD))}, {"name": "b.iso", "size": str(len(GOOD))}]
+        identifiers = os.path.join(self.tmp.name, "ids.txt")
+        with open(identifiers, "w", encoding="utf-8") as f:
+            f.write("one\ntwo\n")
+        starts = []
+
+        def handle(request):
+            starts.append(time.monotonic())
+            return Response(200, GOOD)
+
+        with MockServer() as server:
+            for path in ("/download/one/a.iso", "/download/one/b.iso", "/download/two/a.iso", "/download/two/b.iso"):
+                server.routes[path] = handle
+            code = self.run_main(server, files, "--identifiers-file", identifiers, "--item-concurrency", "2",
+                                 "--sleep", "0.2")
+        self.assertEqual((code, len(starts)), (0, 4))
+        # Two items at a time, but one shared pace: no two downloads start closer than --sleep
+        gaps = [later - earlier for earlier, later in zip(starts, starts[1:])]
+        self.assertGreater(min(gaps), 0.15, gaps)
+
+    def test_bad_checksums_fail_the_run(self):
+        files = [{"name": "a.iso", "size": str(len(GOOD)), "md5": GOOD_MD5},
+                 {"name": "b.iso", "size": str(len(GOOD)), "md5": GOOD_MD5}]
+        with MockServer() as server:
+            server.add("/download/item/a.iso", Response(200, BAD))
+            server.add("/download/item/b.iso", Response(200, BAD))
+            self.assertEqual(self.run_main(server, files, "item", "--checksum", "--retry-on-checksum-fail", "0"),
+                             ia_common.EXIT_FAILED)
+            # One good file beside a bad one still fails the run
+            server.add("/download/other/a.iso", Response(200, GOOD))
+            server.add("/download/other/b.iso", Response(200, BAD))
+            self.assertEqual(self.run_main(server, files, "other", "--checksum", "--retry-on-checksum-fail", "0"),
+                             ia_common.EXIT_FAILED)
+
+    def test_nothing_matched(self):
+        with MockServer() as server:
+            code = self.run_main(server, [{"name": "a.iso", "size": "1"}], "item", "--glob", "*.flac")
+        self.assertEqual(code, ia_common.EXIT_NOTHING)
+        self.assertEqual(server.requests, [])
+
+    def test_usage_error(self):
+        with MockServer() as server:
+            code = self.run_main(server, [], "item", "--dry-run", "--write-m3u")
+        self.assertEqual(code, ia_common.EXIT_USAGE)
+
+    def test_invalid_filter_pattern_is_a_usage_error(self):
+        with MockServer() as server:
+            code = self.run_main(server, [{"name": "disc.iso"}], "item", "--include", "(")
+        self.assertEqual(code, ia_common.EXIT_USAGE)
+        self.assertEqual(server.requests, [])
+
+
+class DuplicateNamesTest(unittest.TestCase):
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def test_local_names_use_source_then_number(self):
+        files = [{"name": "disc.iso", "source": "original"}, {"name": "disc.iso", "source": "derivative"},
+                 {"name": "disc.iso"}, {"name": "disc (2).iso"}]
+        with self.assertLogs(level="WARNING") as logs:
+            names = collections_v2.unique_local_names("item", files)
+        self.assertEqual(names, ["disc.iso", "disc (derivative).iso", "disc (3).iso", "disc (2).iso"])
+        self.assertEqual(len(logs.output), 2)
+
+    def test_names_equal_once_sanitized_are_kept_apart(self):
+        files = [{"name": "a:b.iso"}, {"name": "a?b.iso"}]
+        args = make_args(self.tmp.name, portable_names=True)
+        with self.assertLogs(level="WARNING"):
+            names = collections_v2.unique_local_names("item", files, collections_v2.saved_as(args, "item", ""))
+        paths = [collections_v2.local_path(args, "item", name, "") for name in names]
+        self.assertEqual([os.path.basename(path) for path in paths], ["a_b.iso", "a_b (2).iso"])
+
+    def test_second_listing_does_not_clobber_first(self):
+        files = [{"name": "disc.iso", "source": "original"}, {"name": "disc.iso", "source": "derivative"}]
+        fake_library(self, get_item=lambda identifier, config=None: FakeItem(identifier, files))
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, b"first"), Response(200, b"second"))
+            with self.assertLogs(level="WARNING"):
+                counts = collections_v2.download_item("item", make_args(self.tmp.name), None)
+        self.assertEqual(counts["downloaded"], 2)
+        with open(os.path.join(self.tmp.name, "item", "disc.iso"), "rb") as f:
+            self.assertEqual(f.read(), b"first")
+        with open(os.path.join(self.tmp.name, "item", "disc (derivative).iso"), "rb") as f:
+            self.assertEqual(f.read(), b"second")
+
+
+class DownloadOrderTest(unittest.TestCase):
+    FILES = [{"name": "b.iso", "size": "300"}, {"name": "c.txt"}, {"name": "a.md5", "size": "10"},
+             {"name": "d.iso", "size": "300"}]
+
+    def order(self, order):
+        files, local_names = collections_v2.order_files(self.FILES, [f["name"] for f in self.FILES], order)
+        self.assertEqual([f["name"] for f in files], local_names)
+        return local_names
+
+    def test_orders(self):
+        self.assertEqual(self.order("default"), ["b.iso", "c.txt", "a.md5", "d.iso"])
+        self.assertEqual(self.order("size-asc"), ["c.txt", "a.md5", "b.iso", "d.iso"])
+        self.assertEqual(self.order("size-desc"), ["b.iso", "d.iso", "a.md5", "c.txt"])
+        self.assertEqual(self.order("name"), ["a.md5", "b.iso", "c.txt", "d.iso"])
+
+    def test_seeded_shuffle_repeats_per_item(self):
+        files = [{"name": f"f{i}.iso"} for i in range(20)]
+        fake_library(self, get_item=lambda identifier, config=None: FakeItem(identifier, files))
+
+        def listing(identifier):
+            out = io.StringIO()
+            with tempfile.TemporaryDirectory() as tmp, contextlib.redirect_stdout(out):
+                collections_v2.download_item(identifier, make_args(tmp, dry_run=True, shuffle=True, seed=7), None)
+            return [line.strip() for line in out.getvalue().splitlines() if line.strip().startswith("f")]
+
+        first = listing("item")
+        self.assertEqual(listing("item"), first)
+        self.assertEqual(sorted(first), sorted(f["name"] for f in files))
+        self.assertNotEqual(first, [f["name"] for f in files])
+        self.assertNotEqual(listing("other"), first)
+
+
+class ArchiveTest(unittest.TestCase):
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+        files = [{"name": "disc.iso"}, {"name": "extras/notes:1.txt"}]
+        fake_library(self, get_item=lambda identifier, config=None: FakeItem(identifier, files))
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def run_item(self, kind, *responses):
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", *responses)
+            server.add("/download/item/extras/notes:1.txt", Response(200, b"notes"))
+            counts = collections_v2.download_item("item", make_args(self.tmp.name, archive=kind), None)
+        return counts, server
+
+    def test_tar_collects_files_and_later_runs_add_the_missing_ones(self):
+        with self.assertLogs(level="ERROR"):
+            counts, _ = self.run_item("tar", Response(404))
+        self.assertEqual((counts["downloaded"], counts["failed"]), (1, 1))
+        counts, server = self.run_item("tar", Response(200, GOOD))
+        self.assertEqual((counts["downloaded"], counts["skipped"]), (1, 1))
+        self.assertEqual([path for _, path, _ in server.requests], ["/download/item/disc.iso"])
+        path = os.path.join(self.tmp.name, "item.tar")
+        with tarfile.open(path) as tar:
+            self.assertEqual(sorted(tar.getnames()), ["disc.iso", "extras/notes_1.txt"])
+            self.assertEqual(tar.extractfile("disc.iso").read(), GOOD)
+        self.assertEqual(sorted(os.listdir(self.tmp.name)), ["item.tar"])
+
+    def test_zip(self):
+        counts, _ = self.run_item("zip", Response(200, GOOD))
+        self.assertEqual(counts["downloaded"], 2)
+        with zipfile.ZipFile(os.path.join(self.tmp.name, "item.zip")) as archive:
+            self.assertEqual(archive.read("extras/notes_1.txt"), b"notes")
+
+
+class CasStoreTest(unittest.TestCase):
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+        self.store = os.path.join(self.tmp.name, "store")
+        self.files = [{"name": "disc.iso", "size": str(len(GOOD)), "md5": GOOD_MD5}]
+        fake_library(self, get_item=lambda identifier, config=None: FakeItem(identifier, self.files))
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def run_item(self, identifier, *responses):
+        with MockServer() as server:
+            server.add(f"/download/{identifier}/disc.iso", *responses)
+            counts = collections_v2.download_item(identifier, make_args(self.tmp.name, cas_store=self.store), None)
+        return counts, server
+
+    def test_second_item_links_stored_copy_without_downloading(self):
+        counts, _ = self.run_item("first", Response(200, GOOD))
+        self.assertEqual(counts["downloaded"], 1)
+        counts, server = self.run_item("second", Response(200, GOOD))
+        self.assertEqual(server.requests, [])
+        self.assertEqual((counts["deduped"], counts["deduped_bytes"]), (1, len(GOOD)))
+        blob = os.path.join(self.store, GOOD_MD5[:2], GOOD_MD5)
+        for identifier in ("first", "second"):
+            path = os.path.join(self.tmp.name, identifier, "disc.iso")
+            self.assertTrue(os.path.samefile(path, blob))
+
+    def test_mismatched_download_stays_out_of_the_store(self):
+        with self.assertLogs(level="WARNING"):
+            counts, _ = self.run_item("first", Response(200, BAD))
+        self.assertEqual(counts["badsum"], 1)
+        self.assertFalse(os.path.exists(os.path.join(self.store, GOOD_MD5[:2], GOOD_MD5)))
+        with open(os.path.join(self.tmp.name, "first", "disc.iso"), "rb") as f:
+            self.assertEqual(f.read(), BAD)
+
+    def test_mismatched_download_is_not_renamed_out_of_another_filesystem(self):
+        real_replace = os.replace
+
+        def replace(src, dst):
+            # As if the store were a separate mount: renames between it and the item tree fail
+            if (os.path.commonpath([src, self.store]) == self.store) != (os.path.commonpath([dst, self.store]) == self.store):
+                raise OSError(errno.EXDEV, "Invalid cross-device link")
+            real_replace(src, dst)
+
+        with unittest.mock.patch.object(collections_v2.os, "replace", replace), self.assertLogs(level="WARNING"):
+            counts, _ = self.run_item("first", Response(200, BAD))
+        self.assertEqual((counts["badsum"], counts["failed"]), (1, 0))
+        with open(os.path.join(self.tmp.name, "first", "disc.iso"), "rb") as f:
+            self.assertEqual(f.read(), BAD)
+
+    def test_file_without_md5_is_saved_in_place_of_the_store(self):
+        self.files = [{"name": "disc.iso", "size": str(len(GOOD))}]
+        counts, _ = self.run_item("first", Response(200, GOOD))
+        self.assertEqual(counts["downloaded"], 1)
+        path = os.path.join(self.tmp.name, "first", "disc.iso")
+        self.assertEqual((os.path.isfile(path), os.stat(path).st_nlink), (True, 1))
+        self.assertEqual(os.listdir(self.tmp.name), ["first"])
+
+
+class ResolveCollectionsTest(unittest.TestCase):
+    def test_collection_yields_members_instead_of_files(self):
+        collection = FakeItem("shelf", [{"name": "shelf_logo.jpg"}])
+        collection.metadata["mediatype"] = "collection"
+        fake_library(self, get_item=lambda identifier, config=None: collection)
+        queries = []
+
+        def search_items(query, fields=None, config=None, **kwargs):
+            queries.append(query)
+            return iter([{"identifier": "b"}, {"identifier": "a"}, {"identifier": "b"}])
+
+        fake_library(self, search_items=search_items)
+        members = []
+        with tempfile.TemporaryDirectory() as tmp, MockServer() as server:
+            counts = collections_v2.download_item("shelf", make_args(tmp), None, members=members)
+        self.assertEqual((members, queries, counts["collections"]), (["a", "b"], ["collection:shelf"], 1))
+        self.assertEqual(server.requests, [])
+
+    def test_search_matches_follow_the_listed_identifiers_once(self):
+        results = {"a": ["c", "b"], "b": ["d", "c"]}
+        fake_library(self, search_items=(
+            lambda query, fields=None, config=None, **kwargs: iter({"identifier": i} for i in results[query])))
+        args = make_args("unused", search=["a", "b"])
+        with contextlib.redirect_stdout(io.StringIO()) as out:
+            identifiers = collections_v2.add_search_matches(["b"], args, None)
+        self.assertEqual(identifiers, ["b", "c", "d"])
+        self.assertEqual(out.getvalue(), "a: 2 matching items, 1 already listed\nb: 2 matching items, 1 already listed\n")
+
+
+class NormalizeIdentifiersTest(unittest.TestCase):
+    def test_urls_and_paths_reduce_to_the_identifier(self):
+        for value in ("  ubuntu-22.04\t", "https://archive.org/details/ubuntu-22.04",
+                      "http://www.archive.org/details/ubuntu-22.04/page/n5?view=theater", "details/ubuntu-22.04",
+                      "/details/ubuntu-22.04/", "https://archive.org/download/ubuntu-22.04/disc.iso",
+                      "archive.org/details/ubuntu-22.04", "www.archive.org/download/ubuntu-22.04/disc.iso"):
+            self.assertEqual(collections_v2.normalize_identifier(value), "ubuntu-22.04", value)
+
+    def test_invalid_values_are_all_named(self):
+        for value in ("my item", "a/b", "-leading", "x" * 101, "https://archive.org/", ""):
+            with self.assertRaises(ValueError):
+                collections_v2.normalize_identifier(value)
+        with self.assertRaises(ValueError) as ctx:
+            collections_v2.normalize_identifiers(["ok", "my item", "bad!"])
+        self.assertIn("'my item', 'bad!'", str(ctx.exception))
+
+    def test_repeats_after_normalizing_are_dropped(self):
+        self.assertEqual(collections_v2.normalize_identifiers(["b", "https://archive.org/details/a", "a", " b"]),
+                         ["b", "a"])
+
+
+class ItemConcurrencyTest(unittest.TestCase):
+    def test_items_overlap_up_to_the_limit_and_members_are_queued(self):
+        lock = threading.Lock()
+        running, peak = [0], [0]
+
+        def fetch(identifier):
+            with lock:
+                running[0] += 1
+                peak[0] = max(peak[0], running[0])
+            time.sleep(0.05)
+            with lock:
+                running[0] -= 1
+            if identifier == "broken":
+                raise RuntimeError("no such item")
+            if identifier == "coll":
+                return Counter(collections=1), ["m1", "m2"]
+            return Counter(downloaded=2), None
+
+        queue = deque((identifier, 0) for identifier in ("a", "b", "coll", "broken"))
+        recorded = []
+
+        def record(identifier, depth, counts, members):
+            recorded.append(identifier)
+            queue.extend((member, depth + 1) for member in members or [])
+
+        with contextlib.redirect_stdout(io.StringIO()) as out, self.assertLogs(level="ERROR"):
+            processed, errors, interrupted = collections_v2.download_concurrently(queue, fetch, record, 2)
+        self.assertEqual((processed, errors, interrupted), (6, 1, False))
+        self.assertEqual(sorted(recorded), ["a", "b", "coll", "m1", "m2"])
+        self.assertEqual(peak[0], 2)
+        self.assertIn("coll: collection", out.getvalue())
+        self.assertIn("m2: 2 downloaded, 0 skipped", out.getvalue())
+
+
+class InteractiveTest(unittest.TestCase):
+    FILES = [{"name": "disc1.iso", "size": "700"}, {"name": "disc2.iso", "size": "700"},
+             {"name": "Manual.pdf", "size": "10"}, {"name": "cover.jpg"}]
+
+    def names(self, files):
+        return [f["name"] for f in files]
+
+    def test_numbers_ranges_all_and_words(self):
+        self.assertEqual(self.names(collections_v2.parse_selection("1, 3-4", self.FILES)),
+                         ["disc1.iso", "Manual.pdf", "cover.jpg"])
+        self.assertEqual(collections_v2.parse_selection("all", self.FILES), self.FILES)
+        self.assertEqual(self.names(collections_v2.parse_selection("manual", self.FILES)), ["Manual.pdf"])
+        self.assertEqual(self.names(collections_v2.parse_selection("disc 2", self.FILES)), ["disc2.iso"])
+        for bad in ("0", "2-9", "3-1"):
+            with self.assertRaises(ValueError):
+                collections_v2.parse_selection(bad, self.FILES)
+
+    def test_asks_again_until_something_is_chosen(self):
+        answers = iter(["7", "zip", "2"])
+        with unittest.mock.patch("builtins.input", lambda prompt: next(answers)), \
+                contextlib.redirect_stdout(io.StringIO()) as out:
+            chosen = collections_v2.choose_files("item", self.FILES)
+        self.assertEqual(self.names(chosen), ["disc2.iso"])
+        self.assertIn("7 is outside 1-4", out.getvalue())
+        self.assertIn("Nothing matches 'zip'", out.getvalue())
+
+    def test_dry_run_lists_only_the_chosen_files(self):
+        fake_library(self, get_item=lambda identifier, config=None: FakeItem(identifier, self.FILES))
+        with tempfile.TemporaryDirectory() as tmp, \
+                unittest.mock.patch("builtins.input", lambda prompt: "3"), \
+                contextlib.redirect_stdout(io.StringIO()) as out:
+            collections_v2.download_item("item", make_args(tmp, interactive=True, dry_run=True), None)
+        listing = out.getvalue().split("Chose 1 of 4 files")[1]
+        self.assertIn("Manual.pdf", listing)
+        self.assertNotIn("disc1.iso", listing)
+
+
+class ThumbnailTest(unittest.TestCase):
+    def test_saves_image_and_skips_it_next_time(self):
+        session = ia_common.build_session(5, 0, 0, None)
+        with tempfile.TemporaryDirectory() as tmp, MockServer() as server:
+            server.add("/services/img/item", Response(200, b"\xff\xd8jpeg"))
+            args = make_args(tmp)
+            counts = collections_v2.download_thumbnail(session, "item", args)
+            again = collections_v2.download_thumbnail(session, "item", args)
+            with open(os.path.join(tmp, "item.jpg"), "rb") as f:
+                self.assertEqual(f.read(), b"\xff\xd8jpeg")
+            self.assertEqual(os.listdir(tmp), ["item.jpg"])
+        self.assertEqual((counts["downloaded"], again["skipped"]), (1, 1))
+        self.assertEqual(len(server.requests), 1)
+
+    def test_missing_image_counts_as_failed(self):
+        session = ia_common.build_session(5, 0, 0, None)
+        with tempfile.TemporaryDirectory() as tmp, MockServer() as server:
+            server.add("/services/img/item", Response(404))
+            with self.assertLogs(level="ERROR"):
+                counts = collections_v2.download_thumbnail(session, "item", make_args(tmp))
+            self.assertEqual(os.listdir(tmp), [])
+        self.assertEqual(counts["failed"], 1)
+
+
+class NestedNamesTest(unittest.TestCase):
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+        files = [{"name": "disc1/track01.flac"}, {"name": "disc2/extras/cover.jpg"}]
+        fake_library(self, get_item=lambda identifier, config=None: FakeItem(identifier, files))
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def run_item(self, **overrides):
+        with MockServer() as server:
+            server.add("/download/item/disc1/track01.flac", Response(200, b"flac"))
+            server.add("/download/item/disc2/extras/cover.jpg", Response(200, b"jpg"))
+            return collections_v2.download_item("item", make_args(self.tmp.name, **overrides), None)
+
+    def test_subdirectories_are_created(self):
+        counts = self.run_item()
+        self.assertEqual(counts["downloaded"], 2)
+        self.assertTrue(os.path.isfile(os.path.join(self.tmp.name, "item", "disc1", "track01.flac")))
+        self.assertTrue(os.path.isfile(os.path.join(self.tmp.name, "item", "disc2", "extras", "cover.jpg")))
+
+    def test_flatten_joins_components(self):
+        counts = self.run_item(flatten=True)
+        self.assertEqual(counts["downloaded"], 2)
+        self.assertEqual(sorted(os.listdir(os.path.join(self.tmp.name, "item"))),
+                         ["disc1_track01.flac", "disc2_extras_cover.jpg"])
+
+
+class SelectFilesTest(unittest.TestCase):
+    FILES = [
+        {"name": "disc.iso", "format": "ISO Image"},
+        {"name": "item_files.xml", "format": "Metadata"},
+        {"name": "item_meta.sqlite", "format": "Metadata"},
+        {"name": "__ia_thumb.jpg", "format": "Item Tile"},
+        {"name": "notes.txt"},
+    ]
+
+    def names(self, **kwargs):
+        return [f["name"] for f in collections_v2.select_files(self.FILES, None, None, None, **kwargs)]
+
+    def test_derivatives_skipped_by_default(self):
+        self.assertEqual(self.names(), ["disc.iso", "notes.txt"])
+
+    def test_include_derivatives_keeps_everything(self):
+        self.assertEqual(self.names(include_derivatives=True), [f["name"] for f in self.FILES])
+
+    def test_originals_only_uses_source_field(self):
+        files = [{"name": "talk.flac", "source": "original"}, {"name": "talk.mp3", "source": "derivative"},
+                 {"name": "item_files.xml", "source": "original", "format": "Metadata"},
+                 {"name": "item_meta.xml", "source": "metadata"}, {"name": "unlabelled.bin"}]
+        selected = collections_v2.select_files(files, None, None, None, originals_only=True)
+        self.assertEqual([f["name"] for f in selected], ["talk.flac"])
+
+    def test_explicit_names_only(self):
+        self.assertEqual(self.names(names=["notes.txt", "item_files.xml"]), ["item_files.xml", "notes.txt"])
+
+    def test_explicit_names_union_with_glob(self):
+        selected = collections_v2.select_files(self.FILES, "*.iso", None, None, names=["notes.txt"])
+        self.assertEqual([f["name"] for f in selected], ["disc.iso", "notes.txt"])
+
+
+class ItemFilterTest(unittest.TestCase):
+    META = {"licenseurl": "https://creativecommons.org/licenses/by/4.0/", "subject": ["Linux", "Debian"],
+            "date": "2020-05-01", "language": ""}
+
+    def test_required_fields_must_be_non_empty(self):
+        self.assertIsNone(collections_v2.metadata_mismatch(self.META, ["licenseurl"], None, None))
+        self.assertEqual(collections_v2.metadata_mismatch(self.META, ["language"], None, None), "no 'language' metadata")
+
+    def test_field_globs_match_any_value(self):
+        self.assertIsNone(collections_v2.metadata_mismatch(self.META, None, [("subject", "debian")], None))
+        self.assertIsNotNone(collections_v2.metadata_mismatch(self.META, None, [("subject", "*bsd*")], None))
+
+    def test_year_falls_back_to_date(self):
+        self.assertIsNone(collections_v2.metadata_mismatch(self.META, None, None, 2020))
+        self.assertEqual(collections_v2.metadata_mismatch({"year": "1999"}, None, None, 2020), "year is 1999, not 2020")
+
+    def test_excluded_item_lists_no_files(self):
+        item = FakeItem("item", unittest.mock.Mock(side_effect=AssertionError("files read")))
+        fake_library(self, get_item=lambda identifier, config=None: item)
+        counts = collections_v2.download_item("item", make_args("unused", require_metadata=["licenseurl"]), None)
+        self.assertEqual(counts["excluded"], 1)
+
+
+class WebCaptureTest(unittest.TestCase):
+    CDX = b" CDX N b a m s k r M S V g\ncom,example)/ 20200101000000 http://example.com/ text/html 200 AAA - - 512 0 cap.warc.gz\n"
+
+    def test_only_warc_and_cdx_files_and_captured_urls_are_listed(self):
+        files = [{"name": "cap.warc.gz"}, {"name": "cap.cdx"}, {"name": "readme.txt"}]
+
+        def web_item(identifier, config=None):
+            item = FakeItem(identifier, files)
+            item.metadata["mediatype"] = "web"
+            return item
+        fake_library(self, get_item=web_item)
+        out = io.StringIO()
+        with tempfile.TemporaryDirectory() as tmp, MockServer() as server, contextlib.redirect_stdout(out):
+            server.add("/download/item/cap.warc.gz", Response(200, b"warc"))
+            server.add("/download/item/cap.cdx", Response(200, self.CDX))
+            counts = collections_v2.download_item("item", make_args(tmp, web_captures=True, list_captures=True), None)
+        self.assertEqual(counts["downloaded"], 2)
+        self.assertNotIn("/download/item/readme.txt", [path for _, path, _ in server.requests])
+        self.assertEqual(out.getvalue().splitlines(), ["http://example.com/"])
+
+
+class PlaylistTest(unittest.TestCase):
+    FILES = [
+        {"name": "gd77-05-08d1t02.flac", "track": "02", "title": "New Minglewood Blues", "length": "5:38"},
+        {"name": "gd77-05-08d1t01.flac", "track": "1/12", "title": "Promised Land", "creator": "Grateful Dead",
+         "length": "271.52"},
+        {"name": "gd77-05-08d1t01.mp3", "source": "derivative", "original": "gd77-05-08d1t01.flac", "track": "1"},
+        {"name": "encore.flac"},
+        {"name": "gd77-05-08.txt"},
+        {"name": "missing.flac", "track": "3"},
+    ]
+
+    def test_audio_files_on_disk_are_listed_in_track_order(self):
+        fake_library(self, get_item=lambda identifier, config=None: FakeItem(identifier, self.FILES))
+        with tempfile.TemporaryDirectory() as tmp, MockServer() as server:
+            for f in self.FILES:
+                if f["name"] != "missing.flac":
+                    server.add(f"/download/item/{f['name']}", Response(200, b"audio"))
+            counts = collections_v2.download_item("item", make_args(tmp, write_m3u=True), None)
+            with open(os.path.join(tmp, "item", "item.m3u8"), encoding="utf-8") as f:
+                playlist = f.read().splitlines()
+        self.assertEqual(counts["failed"], 1)
+        self.assertEqual(playlist, [
+            "#EXTM3U",
+            "#EXTINF:271,Grateful Dead - Promised Land", "gd77-05-08d1t01.flac",
+            "#EXTINF:338,New Minglewood Blues", "gd77-05-08d1t02.flac",
+            "#EXTINF:-1,encore", "encore.flac",
+        ])
+
+
+class VerifyItemTest(unittest.TestCase):
+    def test_reports_missing_corrupt_and_extra_files(self):
+        files = [
+            {"name": "disc.iso", "size": str(len(GOOD)), "md5": GOOD_MD5},
+            {"name": "sub/bad.iso", "size": str(len(GOOD)), "md5": GOOD_MD5},
+            {"name": "gone.iso", "size": "10"},
+        ]
+        fake_library(self, get_item=lambda identifier, config=None: FakeItem(identifier, files))
+        with tempfile.TemporaryDirectory() as tmp:
+            os.makedirs(os.path.join(tmp, "item", "sub"))
+            for name, data in (("disc.iso", GOOD), ("sub/bad.iso", b"x" * len(GOOD)), ("stray.txt", b"?")):
+                with open(os.path.join(tmp, "item", name), "wb") as f:
+                    f.write(data)
+            out = io.StringIO()
+            with contextlib.redirect_stdout(out):
+                counts = collections_v2.verify_item("item", make_args(tmp), None)
+        self.assertEqual((counts["ok"], counts["missing"], counts["corrupt"], counts["extra"]), (1, 1, 1, 1))
+        self.assertEqual(out.getvalue().splitlines(), [
+            "CORRUPT  sub/bad.iso (md5 mismatch)",
+            "MISSING  gone.iso",
+            f"EXTRA    {os.path.join('item', 'stray.txt')}",
+        ])
+
+    def test_sidecars_and_filtered_out_files_are_not_extra(self):
+        files = [{"name": "disc.iso", "size": str(len(GOOD))}, {"name": "notes.txt"}]
+        fake_library(self, get_item=lambda identifier, config=None: FakeItem(identifier, files))
+        with tempfile.TemporaryDirectory() as tmp:
+            os.makedirs(os.path.join(tmp, "item"))
+            for name in ("disc.iso", "notes.txt", "disc.iso.badsum", "disc.iso.bad", "next.iso.part",
+                         "disc.iso.head-65536", "stray.txt"):
+                with open(os.path.join(tmp, "item", name), "wb") as f:
+                    f.write(GOOD)
+            out = io.StringIO()
+            with contextlib.redirect_stdout(out):
+                counts = collections_v2.verify_item("item", make_args(tmp, exclude=re.compile(r"\.txt$", re.IGNORECASE)), None)
+        self.assertEqual((counts["ok"], counts["extra"]), (1, 1))
+        self.assertEqual(out.getvalue().splitlines(), [f"EXTRA    {os.path.join('item', 'stray.txt')}"])
+
+    def test_parallel_hashing_keeps_listing_order_and_counts_bad_items(self):
+        files = [{"name": f"f{i}.bin", "size": str(len(GOOD)), "md5": GOOD_MD5} for i in range(8)]
+        fake_library(self, get_item=lambda identifier, config=None: FakeItem(identifier, files))
+        with tempfile.TemporaryDirectory() as tmp:
+            for identifier in ("good", "bad"):
+                os.makedirs(os.path.join(tmp, identifier))
+                for i in range(8):
+                    data = b"x" * len(GOOD) if identifier == "bad" and i % 3 == 0 else GOOD
+                    with open(os.path.join(tmp, identifier, f"f{i}.bin"), "wb") as f:
+                        f.write(data)
+            out = io.StringIO()
+            with contextlib.redirect_stdout(out):
+                totals = collections_v2.verify_items(["good", "bad"], make_args(tmp, verify_concurrency=3), None)
+        self.assertEqual((totals["ok"], totals["corrupt"], totals["bad_items"]), (13, 3, 1))
+        self.assertEqual(out.getvalue().splitlines(), [
+            "CORRUPT  f0.bin (md5 mismatch)",
+            "CORRUPT  f3.bin (md5 mismatch)",
+            "CORRUPT  f6.bin (md5 mismatch)",
+            "Verified: 13 ok, 0 missing, 3 corrupt, 0 extra",
+        ])
+
+
+class ResolveOriginalsTest(unittest.TestCase):
+    FILES = [
+        {"name": "01.flac", "source": "original"},
+        {"name": "01.mp3", "source": "derivative", "original": "01.flac"},
+        {"name": "01.ogg", "source": "derivative", "original": "01.flac"},
+        {"name": "02.mp3", "source": "derivative", "original": "02.flac"},
+        {"name": "cover.jpg", "source": "original"},
+    ]
+
+    def test_derivatives_map_to_originals_once(self):
+        selected = [f for f in self.FILES if f["name"] in ("01.mp3", "01.ogg", "cover.jpg")]
+        resolved = collections_v2.resolve_originals(selected, self.FILES)
+        self.assertEqual([f["name"] for f in resolved], ["01.flac", "cover.jpg"])
+
+    def test_missing_original_keeps_derivative(self):
+        with self.assertLogs(level="WARNING"):
+            resolved = collections_v2.resolve_originals([self.FILES[3]], self.FILES)
+        self.assertEqual([f["name"] for f in resolved], ["02.mp3"])
+
+
+class TorrentTest(unittest.TestCase):
+    TORRENT = (b"d8:announce12:http://t/ann4:infod5:filesl"
+               b"d6:lengthi3e4:pathl5:a.isoee"
+               b"d6:lengthi5e4:pathl4:docs5:b.txteee"
+               b"4:name4:item12:piece lengthi16384eee")
+
+    def test_bdecode_values(self):
+        self.assertEqual(collections_v2.bdecode(b"li42e3:abcd1:ki-1eee"), [42, b"abc", {b"k": -1}])
+
+    def test_bdecode_rejects_truncated_input(self):
+        for data in (b"d4:info", b"5:ab", b"i1ei2e", b"<html>"):
+            with self.assertRaises(ValueError):
+                collections_v2.bdecode(data)
+
+    def test_torrent_files_lists_paths(self):
+        meta = collections_v2.bdecode(self.TORRENT)
+        self.assertEqual(collections_v2.torrent_files(meta), [("item/a.iso", 3), ("item/docs/b.txt", 5)])
+
+    def test_via_torrent_saves_validated_file(self):
+        files = [{"name": "item_archive.torrent", "format": "Archive BitTorrent"}]
+        fake_library(self, get_item=lambda identifier, config=None: FakeItem(identifier, files))
+        with tempfile.TemporaryDirectory() as tmp, MockServer() as server:
+            server.add("/download/item/item_archive.torrent", Response(200, self.TORRENT))
+            with contextlib.redirect_stdout(io.StringIO()) as out:
+                counts = collections_v2.download_via_torrent("item", make_args(tmp), None)
+            self.assertTrue(os.path.isfile(os.path.join(tmp, "item_archive.torrent")))
+        self.assertEqual(counts["downloaded"], 1)
+        self.assertIn("item/docs/b.txt\t5", out.getvalue())
+
+
+if __name__ == "__main__":
+    unittest.main()
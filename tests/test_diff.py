@@ -0,0 +1,60 @@
+import hashlib
+import os
+import re
+import tempfile
+import unittest
+
+from support import load_script
+
+ia_diff = load_script("IA-Diff.py")
+
+OLD = [
+    {"name": "disc.iso", "size": "3", "md5": hashlib.md5(b"iso").hexdigest(), "source": "original"},
+    {"name": "notes.txt", "size": "5", "md5": hashlib.md5(b"notes").hexdigest(), "source": "original"},
+    {"name": "old.img", "size": "3", "md5": hashlib.md5(b"img").hexdigest(), "source": "original"},
+    {"name": "item_files.xml", "md5": "1" * 32, "source": "original", "format": "Metadata"},
+]
+
+
+class DiffListingsTest(unittest.TestCase):
+    def test_reports_added_removed_and_changed_by_md5(self):
+        new = [dict(OLD[0]), dict(OLD[1], md5=hashlib.md5(b"NOTES").hexdigest()),
+               {"name": "new.img", "size": "3", "md5": "2" * 32}, dict(OLD[3], md5="3" * 32)]
+        diff = ia_diff.diff_listings(ia_diff.item_listing(OLD), ia_diff.item_listing(new))
+        self.assertEqual(diff, {"added": ["new.img"], "removed": ["old.img"], "changed": ["notes.txt"],
+                                "unchanged": 1})
+        with_generated = ia_diff.diff_listings(ia_diff.item_listing(OLD, True), ia_diff.item_listing(new, True))
+        self.assertEqual(with_generated["changed"], ["item_files.xml", "notes.txt"])
+
+    def test_local_copy_is_hashed_only_where_sizes_match(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            for name, data in (("disc.iso", b"iso"), ("notes.txt", b"longer notes"), ("extra/a.bin", b"a"),
+                               ("old.img.part", b"im")):
+                os.makedirs(os.path.dirname(os.path.join(tmp, name)), exist_ok=True)
+                with open(os.path.join(tmp, name), "wb") as f:
+                    f.write(data)
+            local = ia_diff.local_listing(tmp)
+            diff = ia_diff.diff_listings(ia_diff.item_listing(OLD), local)
+        self.assertEqual(diff, {"added": ["extra/a.bin"], "removed": ["old.img"], "changed": ["notes.txt"],
+                                "unchanged": 1})
+        self.assertEqual(local["disc.iso"]["md5"], hashlib.md5(b"iso").hexdigest())
+        self.assertIsNone(local["notes.txt"]["md5"])
+
+    def test_every_download_sidecar_is_ignored(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            for name in ("disc.iso", "disc.iso.bad", "disc.iso.badsum", "disc.iso.head-65536", "disc.iso.sha256"):
+                with open(os.path.join(tmp, name), "wb") as f:
+                    f.write(b"iso")
+            self.assertEqual(list(ia_diff.local_listing(tmp)), ["disc.iso"])
+
+    def test_files_the_download_skipped_are_not_removed(self):
+        files = OLD + [{"name": "item__ia_thumb.jpg", "source": "original"},
+                       {"name": "item_archive.torrent", "source": "metadata"}]
+        kept = ia_diff.downloaded_files(files, exclude=re.compile(r"\.txt$", re.IGNORECASE))
+        self.assertEqual([f["name"] for f in kept], ["disc.iso", "old.img"])
+        self.assertEqual([f["name"] for f in ia_diff.downloaded_files(files, include=re.compile("iso"))], ["disc.iso"])
+        self.assertEqual(len(ia_diff.downloaded_files(files, include_derivatives=True)), len(files))
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,777 @@
+import argparse
+import contextlib
+import gzip
+import hashlib
+import io
+import json
+import os
+import shlex
+import sys
+import tempfile
+import threading
+import time
+import unittest
+import unittest.mock
+
+import requests
+
+from support import MockServer, Response, ia_common, load_script, run_main, session_args
+
+dfj = load_script("Download-From-JSON.py")
+
+PAYLOAD = bytes(range(256)) * 2400  # 600 KiB, more than one CHUNK_SIZE
+
+
+def range_handler(payload):
+    """Serve payload, honouring `Range: bytes=N-` with a 206."""
+    def handle(request):
+        rng = request.headers.get("Range")
+        if rng and rng.startswith("bytes="):
+            start = int(rng[len("bytes="):].rstrip("-"))
+            return Response(206, payload[start:], {"Content-Range": f"bytes {start}-{len(payload) - 1}/{len(payload)}"})
+        return Response(200, payload)
+    return handle
+
+
+class DownloadFileTest(unittest.TestCase):
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+        self.dest = os.path.join(self.tmp.name, "disc.iso")
+        self.session = ia_common.session_from_args(session_args(retries=3))
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def download(self, url, **kwargs):
+        kwargs.setdefault("backoff", 0)
+        with contextlib.redirect_stdout(io.StringIO()):
+            return dfj.download_file(self.session, url, self.dest, **kwargs)
+
+    def read_dest(self):
+        with open(self.dest, "rb") as f:
+            return f.read()
+
+    def test_output_is_byte_identical(self):
+        # Odd-sized payload spanning several chunks with a short final chunk
+        payload = os.urandom(dfj.CHUNK_SIZE * 3 + 12345)
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, payload))
+            self.download(f"{server.url}/download/item/disc.iso")
+        self.assertEqual(hashlib.md5(self.read_dest()).hexdigest(), hashlib.md5(payload).hexdigest())
+        self.assertEqual(os.path.getsize(self.dest), len(payload))
+
+    def test_adaptive_buffer_output_is_byte_identical(self):
+        payload = os.urandom(dfj.ADAPTIVE_MIN_CHUNK * 40 + 777)
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, payload))
+            self.download(f"{server.url}/download/item/disc.iso", adaptive_buffer=True)
+        self.assertEqual(self.read_dest(), payload)
+
+    def test_pause_file_holds_transfer_until_removed(self):
+        sentinel = os.path.join(self.tmp.name, "ia.pause")
+        open(sentinel, "w").close()
+        threading.Timer(0.3, os.remove, (sentinel,)).start()
+        clock = time.monotonic()
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, b"iso"))
+            with self.assertLogs(level="WARNING") as logs:
+                self.download(f"{server.url}/download/item/disc.iso", pause=ia_common.PauseFile(sentinel, poll=0.05))
+        self.assertGreaterEqual(time.monotonic() - clock, 0.3)
+        self.assertEqual(self.read_dest(), b"iso")
+        self.assertEqual(len(logs.output), 2)
+        self.assertIn("Paused: ", logs.output[0])
+        self.assertIn("Resumed after", logs.output[1])
+
+    def test_adaptive_reads_grow_to_the_cap(self):
+        class Raw:
+            def __init__(self):
+                self.body, self.sizes = io.BytesIO(b"x" * 1000), []
+
+            def read(self, size, decode_content=True):
+                self.sizes.append(size)
+                return self.body.read(size)
+
+        raw = Raw()
+        self.assertEqual(b"".join(dfj._adaptive_chunks(raw, 10, 80)), b"x" * 1000)
+        self.assertEqual(raw.sizes[:6], [10, 20, 40, 80, 80, 80])
+
+    def test_retries_503_then_succeeds(self):
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(503), Response(503), Response(200, PAYLOAD))
+            self.download(f"{server.url}/download/item/disc.iso")
+        self.assertEqual(self.read_dest(), PAYLOAD)
+        self.assertEqual(len(server.requests), 3)
+
+    def test_honours_retry_after(self):
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(503, headers={"Retry-After": "1"}), Response(200, PAYLOAD))
+            started = time.monotonic()
+            self.download(f"{server.url}/download/item/disc.iso")
+            elapsed = time.monotonic() - started
+        self.assertEqual(self.read_dest(), PAYLOAD)
+        self.assertGreaterEqual(elapsed, 0.9)
+
+    def test_resumes_interrupted_body_with_range(self):
+        served = {"first": True}
+        full = range_handler(PAYLOAD)
+
+        def handle(request):
+            if served.pop("first", False):
+                return Response(200, PAYLOAD, truncate_at=300000)
+            return full(request)
+
+        with MockServer() as server:
+            server.routes["/download/item/disc.iso"] = handle
+            self.download(f"{server.url}/download/item/disc.iso")
+        self.assertEqual(self.read_dest(), PAYLOAD)
+        self.assertFalse(os.path.exists(self.dest + ia_common.PART_SUFFIX))
+        # Only whole chunks reach the .part file before the connection drops
+        self.assertEqual(server.requests[-1][2].get("Range"), f"bytes={dfj.CHUNK_SIZE}-")
+
+    def test_orig_length_header_catches_rewritten_content_length(self):
+        served = {"first": True}
+        full = range_handler(PAYLOAD)
+
+        def handle(request):
+            if served.pop("first", False):
+                # A complete-looking 200 whose body (and Content-Length) a proxy cut short
+                return Response(200, PAYLOAD[:5000], {dfj.ORIG_LENGTH_HEADER: str(len(PAYLOAD))})
+            return full(request)
+
+        with MockServer() as server:
+            server.routes["/download/item/disc.iso"] = handle
+            self.download(f"{server.url}/download/item/disc.iso")
+        self.assertEqual(self.read_dest(), PAYLOAD)
+        self.assertEqual(server.requests[-1][2].get("Range"), "bytes=5000-")
+
+    def test_smaller_orig_length_header_does_not_lower_the_expected_size(self):
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, PAYLOAD, {dfj.ORIG_LENGTH_HEADER: "5000"}))
+            self.download(f"{server.url}/download/item/disc.iso", retries=0)
+        self.assertEqual(self.read_dest(), PAYLOAD)
+        self.assertEqual(len(server.requests), 1)
+
+    def test_resume_continues_existing_part_file(self):
+        with open(self.dest + ia_common.PART_SUFFIX, "wb") as f:
+            f.write(PAYLOAD[:4096])
+        with MockServer() as server:
+            server.routes["/download/item/disc.iso"] = range_handler(PAYLOAD)
+            self.download(f"{server.url}/download/item/disc.iso", resume=True)
+        self.assertEqual(self.read_dest(), PAYLOAD)
+        self.assertEqual(len(server.requests), 1)
+        self.assertEqual(server.requests[0][2].get("Range"), "bytes=4096-")
+
+    def test_server_ignoring_range_restarts_from_zero(self):
+        with open(self.dest + ia_common.PART_SUFFIX, "wb") as f:
+            f.write(b"stale bytes")
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, PAYLOAD))
+            self.download(f"{server.url}/download/item/disc.iso", resume=True)
+        self.assertEqual(self.read_dest(), PAYLOAD)
+
+    def test_retry_answered_with_200_truncates_instead_of_appending(self):
+        # The retry after the drop carries a Range, which this server never honours
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, PAYLOAD, truncate_at=300000), Response(200, PAYLOAD))
+            self.download(f"{server.url}/download/item/disc.iso")
+        self.assertEqual(self.read_dest(), PAYLOAD)
+        self.assertEqual(server.requests[-1][2].get("Range"), f"bytes={dfj.CHUNK_SIZE}-")
+
+    def test_206_from_the_wrong_offset_is_not_appended(self):
+        with open(self.dest + ia_common.PART_SUFFIX, "wb") as f:
+            f.write(PAYLOAD[:4096])
+        wrong = Response(206, PAYLOAD, {"Content-Range": f"bytes 0-{len(PAYLOAD) - 1}/{len(PAYLOAD)}"})
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", wrong, Response(200, PAYLOAD))
+            self.download(f"{server.url}/download/item/disc.iso", resume=True)
+        self.assertEqual(self.read_dest(), PAYLOAD)
+        self.assertNotIn("Range", server.requests[-1][2])
+
+    def test_206_of_a_file_that_changed_upstream_is_not_appended(self):
+        with open(self.dest + ia_common.PART_SUFFIX, "wb") as f:
+            f.write(PAYLOAD[:4096])
+        grown = PAYLOAD + b"appended upstream"
+        stale = Response(206, grown[4096:], {"Content-Range": f"bytes 4096-{len(grown) - 1}/{len(grown)}"})
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", stale, Response(200, grown))
+            self.download(f"{server.url}/download/item/disc.iso", resume=True, expected_size=len(PAYLOAD))
+        self.assertEqual(self.read_dest(), grown)
+        self.assertEqual(server.requests[0][2].get("Range"), "bytes=4096-")
+        self.assertNotIn("Range", server.requests[-1][2])
+
+    def test_range_problems(self):
+        self.assertEqual(dfj.parse_content_range("bytes 10-19/20"), (10, 19, 20))
+        self.assertEqual(dfj.parse_content_range("bytes 10-19/*"), (10, 19, None))
+        self.assertIsNone(dfj.parse_content_range("bytes 19-10/20"))
+        self.assertIsNone(dfj.range_problem("bytes 10-19/20", 10, 10, 20))
+        self.assertIsNone(dfj.range_problem("bytes 10-19/*", 10, None, 20))
+        for content_range, length, expected_size in (("bytes 0-19/20", None, None), (None, None, None),
+                                                     ("bytes 10-19/20", 5, None), ("bytes 10-19/20", None, 30),
+                                                     ("bytes 10-14/20", None, None)):
+            self.assertIsNotNone(dfj.range_problem(content_range, 10, length, expected_size), content_range)
+
+    def test_max_rate_paces_the_stream(self):
+        # One second of burst is free, so the rest of the payload takes about another second
+        limiter = ia_common.RateLimiter(len(PAYLOAD) / 2)
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, PAYLOAD))
+            started = time.monotonic()
+            self.download(f"{server.url}/download/item/disc.iso", rate_limiter=limiter)
+            elapsed = time.monotonic() - started
+        self.assertEqual(self.read_dest(), PAYLOAD)
+        self.assertGreaterEqual(elapsed, 0.8)
+
+    def test_per_file_rate_caps_below_a_looser_global_rate(self):
+        limiter = ia_common.RateLimiter(len(PAYLOAD) * 100)
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, PAYLOAD))
+            started = time.monotonic()
+            self.download(f"{server.url}/download/item/disc.iso", rate_limiter=limiter, file_rate=len(PAYLOAD) / 2)
+            elapsed = time.monotonic() - started
+        self.assertEqual(self.read_dest(), PAYLOAD)
+        self.assertGreaterEqual(elapsed, 0.8)
+
+    def test_timeout_per_file_gives_up_and_keeps_part_file(self):
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, PAYLOAD))
+            started = time.monotonic()
+            with self.assertRaisesRegex(dfj.FileTimeout, "partial download kept"):
+                self.download(f"{server.url}/download/item/disc.iso", file_rate=len(PAYLOAD) / 4, time_limit=0.5)
+            elapsed = time.monotonic() - started
+        self.assertLess(elapsed, 1.5)
+        self.assertFalse(os.path.exists(self.dest))
+        part_size = os.path.getsize(self.dest + ia_common.PART_SUFFIX)
+        self.assertTrue(0 < part_size < len(PAYLOAD))
+
+    def test_permanent_client_errors_are_not_retried(self):
+        for response in (Response(404), Response(403), Response(413, headers={"Retry-After": "0"})):
+            with MockServer() as server:
+                server.add("/download/item/disc.iso", response, Response(200, PAYLOAD))
+                with self.assertRaises(requests.HTTPError):
+                    self.download(f"{server.url}/download/item/disc.iso")
+            self.assertEqual(len(server.requests), 1, response.status)
+
+    def test_rate_limiting_is_retried(self):
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(429), Response(200, PAYLOAD))
+            self.download(f"{server.url}/download/item/disc.iso")
+        self.assertEqual(len(server.requests), 2)
+
+    def test_timeouts_are_retried(self):
+        self.session = ia_common.session_from_args(session_args(retries=1, read_timeout=0.2))
+        answers = iter([0.6, 0])
+
+        def slow_then_fast(request):
+            time.sleep(next(answers))
+            return Response(200, PAYLOAD)
+        with MockServer() as server:
+            server.routes["/download/item/disc.iso"] = slow_then_fast
+            self.download(f"{server.url}/download/item/disc.iso")
+        self.assertEqual(self.read_dest(), PAYLOAD)
+        self.assertEqual(len(server.requests), 2)
+
+    def test_connection_errors_are_retried(self):
+        with MockServer() as server:
+            url = f"{server.url}/download/item/disc.iso"
+        with self.assertRaises(requests.ConnectionError) as caught:
+            self.download(url)
+        self.assertEqual(ia_common.describe_attempts(caught.exception), ", ".join(["connection error"] * 4))
+
+    def test_stale_data_node_url_falls_back_to_download_url(self):
+        with MockServer() as server:
+            server.add("/7/items/item/disc.iso", Response(404))
+            server.add("/download/item/disc.iso", Response(200, PAYLOAD))
+            _, served_url = self.download(f"{server.url}/7/items/item/disc.iso",
+                                          fallback_url=f"{server.url}/download/item/disc.iso")
+        self.assertEqual(self.read_dest(), PAYLOAD)
+        self.assertEqual(served_url, f"{server.url}/download/item/disc.iso")
+        self.assertEqual([path for _, path, _ in server.requests], ["/7/items/item/disc.iso", "/download/item/disc.iso"])
+
+    def test_part_file_kept_when_retries_exhausted(self):
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, PAYLOAD, truncate_at=1000))
+            with self.assertRaises(dfj.IncompleteDownload):
+                self.download(f"{server.url}/download/item/disc.iso", retries=1)
+        self.assertFalse(os.path.exists(self.dest))
+        self.assertTrue(os.path.exists(self.dest + ia_common.PART_SUFFIX))
+
+
+class ProcessItemCase(unittest.TestCase):
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+        self.dest = os.path.join(self.tmp.name, "disc.iso")
+        with open(self.dest, "wb") as f:
+            f.write(PAYLOAD[:1000])
+        self.session = ia_common.session_from_args(session_args(retries=0))
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def process(self, server, policy, entry=None, **overrides):
+        args = dfj.build_parser().parse_args(["-o", self.tmp.name, "--on-exists", policy, "--retries", "0",
+                                              "--backoff", "0", "--max-backoff", "0"])
+        # What main() sets after parsing
+        args.pause = None
+        for key, value in overrides.items():
+            setattr(args, key, value)
+        iso = {"identifier": "item", "file_name": "disc.iso", "download_url": f"{server.url}/download/item/disc.iso",
+               "size": str(len(PAYLOAD)), **(entry or {})}
+        with contextlib.redirect_stdout(io.StringIO()):
+            return dfj.process_item(self.session, args, iso, 1, 1)
+
+    def read(self, path):
+        with open(path, "rb") as f:
+            return f.read()
+
+
+class OnExistsTest(ProcessItemCase):
+    def test_skip_leaves_complete_file_alone(self):
+        with open(self.dest, "wb") as f:
+            f.write(PAYLOAD)
+        with MockServer() as server:
+            result = self.process(server, "skip")
+        self.assertEqual(result["status"], "skipped")
+        self.assertEqual(server.requests, [])
+
+    def test_refresh_older_than_fetches_stale_copies_again(self):
+        with open(self.dest, "wb") as f:
+            f.write(PAYLOAD)
+        with MockServer() as server:
+            server.routes["/download/item/disc.iso"] = range_handler(PAYLOAD)
+            result = self.process(server, "skip", refresh_older_than=3600)
+            self.assertEqual(result["status"], "skipped")
+            week_ago = time.time() - 7 * 86400
+            os.utime(self.dest, (week_ago, week_ago))
+            result = self.process(server, "skip", refresh_older_than=ia_common.parse_duration("2d"))
+        self.assertEqual(result["status"], "downloaded")
+        self.assertEqual(len(server.requests), 1)
+        self.assertNotIn("Range", server.requests[0][2])
+        self.assertGreater(os.path.getmtime(self.dest), week_ago)
+
+    def test_skip_resumes_file_shorter_than_listed_size(self):
+        with MockServer() as server:
+            server.routes["/download/item/disc.iso"] = range_handler(PAYLOAD)
+            result = self.process(server, "skip")
+        self.assertEqual(result["status"], "downloaded")
+        self.assertEqual(self.read(self.dest), PAYLOAD)
+        self.assertEqual(server.requests[0][2].get("Range"), "bytes=1000-")
+
+    def test_short_file_never_overwrites_an_existing_part(self):
+        with open(self.dest + ia_common.PART_SUFFIX, "wb") as f:
+            f.write(PAYLOAD[:5000])
+        with MockServer() as server:
+            server.routes["/download/item/disc.iso"] = range_handler(PAYLOAD)
+            result = self.process(server, "skip")
+        self.assertEqual(result["status"], "downloaded")
+        self.assertEqual(server.requests[0][2].get("Range"), "bytes=5000-")
+        self.assertEqual(self.read(self.dest), PAYLOAD)
+
+    def test_resume_sends_the_saved_validator_as_if_range(self):
+        with open(self.dest + dfj.META_SUFFIX, "w", encoding="utf-8") as f:
+            json.dump({"etag": '"v1"', "last_modified": "Mon, 01 Jan 2024 00:00:00 GMT"}, f)
+        with MockServer() as server:
+            server.routes["/download/item/disc.iso"] = range_handler(PAYLOAD)
+            self.process(server, "resume")
+        self.assertEqual(server.requests[0][2].get("If-Range"), '"v1"')
+
+    def test_resumed_file_failing_its_md5_is_deleted(self):
+        with open(self.dest, "wb") as f:
+            f.write(b"\0" * 1000)
+        with MockServer() as server:
+            server.routes["/download/item/disc.iso"] = range_handler(PAYLOAD)
+            result = self.process(server, "skip", {"md5": hashlib.md5(PAYLOAD).hexdigest()})
+        self.assertEqual(result["status"], "failed")
+        self.assertIn("md5 mismatch after resuming from byte 1000", result["error"])
+        self.assertFalse(os.path.exists(self.dest))
+
+    def test_overwrite_downloads_fresh_copy(self):
+        with MockServer() as server:
+            server.routes["/download/item/disc.iso"] = range_handler(PAYLOAD)
+            result = self.process(server, "overwrite")
+        self.assertEqual(result["status"], "downloaded")
+        self.assertEqual(self.read(self.dest), PAYLOAD)
+        self.assertNotIn("Range", server.requests[0][2])
+
+    def test_resume_continues_short_file(self):
+        with MockServer() as server:
+            server.routes["/download/item/disc.iso"] = range_handler(PAYLOAD)
+            result = self.process(server, "resume")
+        self.assertEqual(result["status"], "downloaded")
+        self.assertEqual(self.read(self.dest), PAYLOAD)
+        self.assertEqual(server.requests[0][2].get("Range"), "bytes=1000-")
+
+    def test_rename_keeps_both_files(self):
+        with MockServer() as server:
+            server.routes["/download/item/disc.iso"] = range_handler(PAYLOAD)
+            result = self.process(server, "rename")
+        renamed = os.path.join(self.tmp.name, "disc (1).iso")
+        self.assertEqual(result["dest_path"], os.path.abspath(renamed))
+        self.assertEqual(self.read(renamed), PAYLOAD)
+        self.assertEqual(self.read(self.dest), PAYLOAD[:1000])
+
+    def test_validators_name_the_url_that_served_the_file(self):
+        with MockServer() as server:
+            server.add("/7/items/item/disc.iso", Response(404))
+            server.add("/download/item/disc.iso", Response(200, PAYLOAD, {"ETag": '"v2"'}))
+            stale = f"{server.url}/7/items/item/disc.iso"
+            result = self.process(server, "overwrite", {"download_url": stale}, update=True)
+        self.assertEqual(result["status"], "downloaded")
+        with open(self.dest + dfj.META_SUFFIX, encoding="utf-8") as f:
+            meta = json.load(f)
+        self.assertEqual((meta["url"], meta["served_url"]), (stale, f"{server.url}/download/item/disc.iso"))
+
+
+class HeadBytesTest(ProcessItemCase):
+    def test_saves_prefix_under_its_own_name(self):
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(206, PAYLOAD[:100]), Response(200, PAYLOAD))
+            result = self.process(server, "skip", head_bytes=100)
+            self.assertEqual(result["status"], "downloaded")
+            # A server that ignores the Range is cut off after the requested bytes
+            again = self.process(server, "overwrite", head_bytes=100)
+        self.assertEqual(again["status"], "downloaded")
+        self.assertEqual(server.requests[0][2].get("Range"), "bytes=0-99")
+        self.assertEqual(self.read(self.dest + ".head-100"), PAYLOAD[:100])
+        # The file at the real name is left as it was
+        self.assertEqual(self.read(self.dest), PAYLOAD[:1000])
+        self.assertEqual(sorted(os.listdir(self.tmp.name)), ["disc.iso", "disc.iso.head-100"])
+
+
+class WriteChecksumsTest(ProcessItemCase):
+    def test_resumed_download_is_hashed_whole_and_gets_a_sidecar(self):
+        with MockServer() as server:
+            server.routes["/download/item/disc.iso"] = range_handler(PAYLOAD)
+            result = self.process(server, "resume", {"md5": hashlib.md5(PAYLOAD).hexdigest().upper()},
+                                  write_checksums=True)
+        self.assertEqual(result["status"], "downloaded")
+        self.assertEqual(server.requests[0][2].get("Range"), "bytes=1000-")
+        sha256 = hashlib.sha256(PAYLOAD).hexdigest()
+        self.assertEqual(result["sha256"], sha256)
+        with open(self.dest + dfj.CHECKSUM_SUFFIX, encoding="utf-8") as f:
+            self.assertEqual(f.read(), f"{sha256}  disc.iso\n")
+
+    def test_md5_mismatch_deletes_the_download(self):
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, PAYLOAD))
+            result = self.process(server, "overwrite", {"md5": "0" * 32}, write_checksums=True)
+        self.assertEqual(result["status"], "failed")
+        self.assertIn("md5 mismatch", result["error"])
+        self.assertEqual(os.listdir(self.tmp.name), [])
+
+    def test_md5_mismatch_under_update_leaves_no_validators_behind(self):
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, PAYLOAD, {"ETag": '"v2"'}))
+            result = self.process(server, "skip", {"md5": "0" * 32}, write_checksums=True, update=True)
+        self.assertEqual(result["status"], "failed")
+        self.assertEqual(os.listdir(self.tmp.name), [])
+
+
+
+class OnCompleteHookTest(ProcessItemCase):
+    def hook(self, code):
+        script = f"import sys; open(sys.argv[1] + '.hook', 'w').write(' '.join(sys.argv[2:])); sys.exit({code})"
+        return f"{shlex.quote(sys.executable)} -c {shlex.quote(script)} {{path}} {{identifier}} {{filename}}"
+
+    def test_hook_gets_placeholders_and_exit_status_is_recorded(self):
+        with MockServer() as server:
+            server.routes["/download/item/disc.iso"] = range_handler(PAYLOAD)
+            result = self.process(server, "overwrite", on_complete=self.hook(0))
+        self.assertEqual((result["status"], result["hook_exit"]), ("downloaded", 0))
+        with open(self.dest + ".hook") as f:
+            self.assertEqual(f.read(), "item disc.iso")
+
+    def test_failing_hook_only_fails_file_when_strict(self):
+        for strict, expected in ((False, "downloaded"), (True, "failed")):
+            with MockServer() as server:
+                server.routes["/download/item/disc.iso"] = range_handler(PAYLOAD)
+                result = self.process(server, "overwrite", on_complete=self.hook(3), hook_strict=strict)
+            self.assertEqual((result["status"], result["hook_exit"]), (expected, 3))
+
+    def test_template_is_validated(self):
+        self.assertIsNone(dfj.hook_template_error("scan '{path}'"))
+        self.assertIn("{path}", dfj.hook_template_error("scan {file}"))
+        self.assertIsNotNone(dfj.hook_template_error("scan 'unterminated"))
+
+
+class PartialEntriesTest(unittest.TestCase):
+    def test_matches_part_files_to_entries_and_reports_orphans(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            os.makedirs(os.path.join(tmp, "item"))
+            for name in ("item/a.iso", "item/orphan.iso"):
+                open(os.path.join(tmp, name + ia_common.PART_SUFFIX), "w").close()
+            open(os.path.join(tmp, "item", "b.iso"), "w").close()
+            args = dfj.build_parser().parse_args(["-o", tmp, "--output-template", "{identifier}/{file_name}"])
+            items = [{"identifier": "item", "file_name": name, "download_url": "u"} for name in ("b.iso", "a.iso", "c.iso")]
+            entries, orphans = dfj.partial_entries(args, items)
+        self.assertEqual([e["file_name"] for e in entries], ["a.iso"])
+        self.assertEqual(orphans, [os.path.join(os.path.abspath(tmp), "item", "orphan.iso" + ia_common.PART_SUFFIX)])
+
+
+class MediatypeFilterTest(unittest.TestCase):
+    def test_matches_case_insensitively_and_skips_entries_without_the_field(self):
+        wanted = ["texts", "audio"]
+        self.assertTrue(dfj.has_mediatype({"mediatype": "Texts"}, wanted))
+        self.assertTrue(dfj.has_mediatype({"mediatype": ["software", "audio"]}, wanted))
+        self.assertFalse(dfj.has_mediatype({"mediatype": "software"}, wanted))
+        self.assertFalse(dfj.has_mediatype({"file_name": "a.iso"}, wanted))
+
+
+class ShuffleTest(unittest.TestCase):
+    NAMES = [f"f{i}.iso" for i in range(10)]
+
+    def planned(self, *argv):
+        with tempfile.TemporaryDirectory() as tmp:
+            input_path, report = os.path.join(tmp, "in.json"), os.path.join(tmp, "report.json")
+            with open(input_path, "w", encoding="utf-8") as f:
+                json.dump([{"identifier": "item", "file_name": name, "download_url": f"http://127.0.0.1:9/{name}"}
+                           for name in self.NAMES], f)
+            code, _, _ = run_main(dfj, "-i", input_path, "-o", tmp, "--dry-run", "--report", report, *argv)
+            self.assertEqual(code, 0)
+            with open(report, encoding="utf-8") as f:
+                return [item["file_name"] for item in json.load(f)["items"]]
+
+    def test_max_takes_the_first_entries_of_the_seeded_order(self):
+        picked = self.planned("--shuffle", "--seed", "7", "--max", "3")
+        self.assertEqual(picked, ia_common.shuffled(self.NAMES, 7)[:3])
+        self.assertNotEqual(picked, self.NAMES[:3])
+        self.assertEqual(self.planned("--shuffle", "--seed", "7", "--max", "3"), picked)
+
+
+class ProgressStyleTest(unittest.TestCase):
+    def line(self, templates, downloaded, total):
+        out = io.StringIO()
+        with contextlib.redirect_stdout(out):
+            dfj._print_bar("[↓] disc.iso", downloaded, total, templates)
+        return out.getvalue()
+
+    def test_styles(self):
+        self.assertEqual(self.line(dfj.PROGRESS_STYLES["bar"], 512, 1024),
+                         "\r[↓] disc.iso [" + "#" * 20 + "-" * 20 + "]  50% (512 B/1.0 KiB)")
+        self.assertEqual(self.line(dfj.PROGRESS_STYLES["bytes"], 512, 1024), "\r[↓] disc.iso 512 B of 1.0 KiB ( 50%)")
+        self.assertEqual(self.line(dfj.PROGRESS_STYLES["minimal"], 512, 1024), "\r[↓] disc.iso  50%")
+        self.assertEqual(self.line(dfj.PROGRESS_STYLES["minimal"], 512, None), "\r[↓] disc.iso 512 B")
+        self.assertRegex(self.line(dfj.PROGRESS_STYLES["spinner"], 512, None), r"^\r\[↓\] disc\.iso [|/\\-] 512 B$")
+
+    def test_custom_template(self):
+        template = dfj.progress_template("{percent} {name}")
+        self.assertEqual(self.line((template, template), 0, None), "\r? [↓] disc.iso")
+        with self.assertRaises(argparse.ArgumentTypeError):
+            dfj.progress_template("{speed}")
+
+
+class ThroughputStatsTest(unittest.TestCase):
+    def test_aggregates_downloaded_files_only(self):
+        results = [
+            {"status": "downloaded", "transferred_bytes": 1000, "elapsed_seconds": 1.0},
+            {"status": "downloaded", "transferred_bytes": 3000, "elapsed_seconds": 1.0},
+            {"status": "downloaded", "transferred_bytes": 8000, "elapsed_seconds": 2.0},
+            {"status": "skipped"},
+        ]
+        stats = dfj.throughput_stats(results, elapsed=4.0)
+        self.assertEqual(stats["transferred_bytes"], 12000)
+        self.assertEqual(stats["average_bytes_per_sec"], 3000)
+        self.assertEqual((stats["min_file_bytes_per_sec"], stats["median_file_bytes_per_sec"],
+                          stats["max_file_bytes_per_sec"]), (1000, 3000, 4000))
+
+    def test_nothing_downloaded(self):
+        stats = dfj.throughput_stats([{"status": "skipped"}], elapsed=0.0)
+        self.assertEqual(stats["transferred_bytes"], 0)
+        self.assertIsNone(stats["average_bytes_per_sec"])
+        self.assertIsNone(stats["median_file_bytes_per_sec"])
+
+    def test_slowest_downloads_by_throughput_with_serving_host(self):
+        def downloaded(name, transferred, elapsed, served_url=None):
+            return {"status": "downloaded", "file_name": name, "download_url": f"https://archive.org/download/x/{name}",
+                    "served_url": served_url, "transferred_bytes": transferred, "elapsed_seconds": elapsed}
+
+        results = [
+            downloaded("fast.iso", 9000, 1.0),
+            # Longest, but not the slowest per byte
+            downloaded("big.iso", 60000, 20.0),
+            downloaded("slow.iso", 1000, 2.0, "https://ia801.us.archive.org/7/items/x/slow.iso"),
+            downloaded("resumed.iso", 0, None),
+            {"status": "failed", "file_name": "bad.iso"},
+        ]
+        slowest = dfj.slowest_downloads(results, 2)
+        self.assertEqual([s["file_name"] for s in slowest], ["slow.iso", "big.iso"])
+        self.assertEqual((slowest[0]["host"], slowest[0]["bytes_per_sec"]), ("ia801.us.archive.org", 500))
+        self.assertEqual(slowest[1]["url"], "https://archive.org/download/x/big.iso")
+
+
+class LoadItemsTest(unittest.TestCase):
+    GOOD = {"file_name": "a.iso", "download_url": "http://x/a.iso"}
+
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def write(self, name, text):
+        path = os.path.join(self.tmp.name, name)
+        with open(path, "w", encoding="utf-8") as f:
+            f.write(text)
+        return path
+
+    def test_reports_index_and_reason(self):
+        path = self.write("in.json", json.dumps([self.GOOD, {"file_name": "b.iso"}, "oops", {}]))
+        count, items, problems = dfj.load_items(path, ndjson=False)
+        self.assertEqual((count, list(items)), (1, [self.GOOD]))
+        self.assertEqual(problems, [
+            ("index 1", "missing download_url"),
+            ("index 2", "expected an object, got str"),
+            ("index 3", "missing file_name and download_url"),
+        ])
+
+    def test_failures_file_reads_back_as_input(self):
+        path = os.path.join(self.tmp.name, "failures.json")
+        bad = {"file_name": "b.iso", "download_url": "http://x/b.iso", "identifier": "b", "size": "10"}
+        dfj.write_failures(path, [(bad, "HTTP 404")])
+        count, items, problems = dfj.load_items(path, ndjson=False)
+        self.assertEqual((count, problems), (1, []))
+        self.assertEqual(list(items), [dict(bad, last_error="HTTP 404")])
+
+    def test_nested_fields_are_read_through_dotted_paths(self):
+        nested = {"name": "a.iso", "files": [{"url": "http://x/a.iso"}]}
+        path = self.write("in.json", json.dumps([nested, {"name": "b.iso", "files": []}]))
+        count, items, problems = dfj.load_items(path, ndjson=False, fields=("name", "files.0.url"))
+        self.assertEqual(list(items), [dict(nested, file_name="a.iso", download_url="http://x/a.iso")])
+        self.assertEqual(problems, [("index 1", "missing files.0.url")])
+
+    def test_object_instead_of_array_is_rejected(self):
+        path = self.write("in.json", json.dumps(self.GOOD))
+        with self.assertRaisesRegex(ValueError, "expected a JSON array"):
+            dfj.load_items(path, ndjson=False)
+
+    def test_ndjson_reports_line_numbers(self):
+        path = self.write("in.ndjson", json.dumps(self.GOOD) + "\n\n{broken\n" + json.dumps({"file_name": "x"}) + "\n")
+        count, items, problems = dfj.load_items(path, ndjson=True)
+        self.assertEqual((count, list(items)), (1, [self.GOOD]))
+        self.assertEqual([location for location, _ in problems], ["line 3", "line 4"])
+
+    def test_gzipped_ndjson_is_decompressed(self):
+        path = os.path.join(self.tmp.name, "in.jsonl.gz")
+        with gzip.open(path, "wt", encoding="utf-8") as f:
+            f.write(json.dumps(self.GOOD) + "\n")
+        count, items, problems = dfj.load_items(path, ndjson=True)
+        self.assertEqual((count, list(items), problems), (1, [self.GOOD], []))
+
+    def test_dash_reads_stdin(self):
+        for ndjson, text in ((False, json.dumps([self.GOOD])), (True, json.dumps(self.GOOD) + "\n")):
+            with unittest.mock.patch("sys.stdin", io.StringIO(text)):
+                count, items, problems = dfj.load_items("-", ndjson=ndjson)
+                self.assertEqual((count, list(items), problems), (1, [self.GOOD], []))
+
+
+class CheckItemTest(unittest.TestCase):
+    def setUp(self):
+        self.session = ia_common.session_from_args(session_args(retries=0))
+
+    def check(self, server, path, **fields):
+        iso = {"identifier": "item", "file_name": "disc.iso", "download_url": f"{server.url}{path}", **fields}
+        with contextlib.redirect_stdout(io.StringIO()):
+            return dfj.check_item(self.session, iso, 1, 1)
+
+    def test_reachable_link_reports_length(self):
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, PAYLOAD))
+            result = self.check(server, "/download/item/disc.iso")
+        self.assertEqual((result["status"], result["http_status"]), ("reachable", 200))
+        self.assertEqual(result["content_length"], len(PAYLOAD))
+        self.assertEqual(server.requests[0][0], "HEAD")
+
+    def test_size_differing_from_content_length_is_a_mismatch(self):
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, PAYLOAD))
+            matching = self.check(server, "/download/item/disc.iso", size=str(len(PAYLOAD)))
+            stale = self.check(server, "/download/item/disc.iso", size="1024")
+        self.assertEqual(matching["status"], "reachable")
+        self.assertEqual(stale["status"], "mismatch")
+        self.assertIn("1024", stale["error"])
+
+    def test_missing_link_is_broken(self):
+        with MockServer() as server:
+            result = self.check(server, "/download/item/gone.iso")
+        self.assertEqual((result["status"], result["http_status"]), ("broken", 404))
+
+    def test_head_not_allowed_falls_back_to_get(self):
+        with MockServer() as server:
+            server.routes["/download/item/disc.iso"] = (
+                lambda request: Response(405) if request.command == "HEAD" else Response(200, PAYLOAD))
+            result = self.check(server, "/download/item/disc.iso")
+        self.assertEqual(result["status"], "reachable")
+        self.assertEqual([method for method, _, _ in server.requests], ["HEAD", "GET"])
+
+
+class PreflightSizesTest(unittest.TestCase):
+    def test_fills_missing_sizes_from_head(self):
+        session = ia_common.session_from_args(session_args(retries=0))
+        with MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, PAYLOAD))
+            items = [
+                {"file_name": "disc.iso", "download_url": f"{server.url}/download/item/disc.iso"},
+                {"file_name": "listed.iso", "download_url": f"{server.url}/download/item/listed.iso", "size": "7"},
+                {"file_name": "gone.iso", "download_url": f"{server.url}/download/item/gone.iso"},
+            ]
+            unknown = dfj.preflight_sizes(session, items)
+        self.assertEqual(unknown, 1)
+        self.assertEqual([iso.get("size") for iso in items], [str(len(PAYLOAD)), "7", None])
+        # Entries that already list a size cost no request
+        self.assertEqual([(method, path) for method, path, _ in server.requests],
+                         [("HEAD", "/download/item/disc.iso"), ("HEAD", "/download/item/gone.iso")])
+
+    def test_only_entries_that_will_be_fetched_are_sent_a_head(self):
+        with tempfile.TemporaryDirectory() as tmp, MockServer() as server:
+            server.add("/download/item/new.iso", Response(200, PAYLOAD))
+            entries = [{"identifier": "item", "file_name": name, "download_url": f"{server.url}/download/item/{name}",
+                        "mediatype": mediatype}
+                       for name, mediatype in (("new.iso", "software"), ("have.iso", "software"),
+                                               ("other.mp3", "audio"))]
+            input_path, out_dir = os.path.join(tmp, "in.json"), os.path.join(tmp, "out")
+            with open(input_path, "w", encoding="utf-8") as f:
+                json.dump(entries, f)
+            os.makedirs(out_dir)
+            with open(os.path.join(out_dir, "have.iso"), "wb") as f:
+                f.write(b"already here")
+            code, _, _ = run_main(dfj, "-i", input_path, "-o", out_dir, "--preflight-sizes", "--mediatype", "software",
+                                  "--no-progress", "--retries", "0")
+        self.assertEqual(code, 0)
+        self.assertEqual([(method, path) for method, path, _ in server.requests],
+                         [("HEAD", "/download/item/new.iso"), ("GET", "/download/item/new.iso")])
+
+
+class LoggingTest(unittest.TestCase):
+    def test_each_result_line_names_the_run_and_its_request(self):
+        with tempfile.TemporaryDirectory() as tmp, MockServer() as server:
+            server.add("/download/item/disc.iso", Response(200, PAYLOAD))
+            entries = [{"identifier": "item", "file_name": name, "download_url": f"{server.url}/download/item/{name}"}
+                       for name in ("disc.iso", "gone.iso")]
+            input_path, log_path = os.path.join(tmp, "in.json"), os.path.join(tmp, "run.log")
+            with open(input_path, "w", encoding="utf-8") as f:
+                json.dump(entries, f)
+            code, _, err = run_main(dfj, "-i", input_path, "-o", os.path.join(tmp, "out"), "--log-file", log_path,
+                                    "--no-progress", "--retries", "0")
+            with open(log_path, encoding="utf-8") as f:
+                log = f.read().splitlines()
+        self.assertEqual(code, ia_common.EXIT_FAILED)
+        self.assertIn(f"Run ID {ia_common.RUN_ID}", err)
+        sent = [headers[ia_common.REQUEST_ID_HEADER] for _, _, headers in server.requests]
+        for status, name, request_id in (("downloaded", "disc.iso", sent[0]), ("failed", "gone.iso", sent[1])):
+            line = next(line for line in log if f"| {status} item/{name}" in line)
+            self.assertIn(f"| {ia_common.RUN_ID} |", line)
+            self.assertIn(f"({ia_common.REQUEST_ID_HEADER} {request_id})", line)
+
+    def test_very_verbose_logs_redirect_hops(self):
+        with tempfile.TemporaryDirectory() as tmp, MockServer() as server:
+            server.add("/download/item/disc.iso", Response(302, headers={"Location": "/data/disc.iso"}))
+            server.add("/data/disc.iso", Response(200, PAYLOAD))
+            input_path = os.path.join(tmp, "in.json")
+            with open(input_path, "w", encoding="utf-8") as f:
+                json.dump([{"file_name": "disc.iso", "download_url": f"{server.url}/download/item/disc.iso"}], f)
+            code, out, _ = run_main(dfj, "-i", input_path, "-o", tmp, "--no-progress", "-vv")
+        self.assertEqual(code, 0)
+        self.assertIn(f"Redirect 302: {server.url}/download/item/disc.iso -> /data/disc.iso", out)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,844 @@
+import argparse
+import contextlib
+import gzip
+import io
+import json
+import logging
+import os
+import signal
+import socket
+import tempfile
+import unittest
+import unittest.mock
+
+import requests
+from urllib3.util.retry import RequestHistory
+
+from support import MockServer, Response, ia_common, session_args
+
+
+class EncodePathSegmentTest(unittest.TestCase):
+    def test_spaces_are_encoded(self):
+        self.assertEqual(ia_common.encode_path_segment("my disc.iso"), "my%20disc.iso")
+
+    def test_hash_is_encoded(self):
+        self.assertEqual(ia_common.encode_path_segment("disc#1.iso"), "disc%231.iso")
+
+    def test_plus_is_encoded(self):
+        self.assertEqual(ia_common.encode_path_segment("c++.iso"), "c%2B%2B.iso")
+
+    def test_escape_like_names_are_encoded_literally(self):
+        # A file really named "my%20disc.iso" is stored under that name, not as "my disc.iso"
+        ia_common.session_from_args(session_args())
+        self.assertEqual(ia_common.encode_path_segment("my%20disc.iso"), "my%2520disc.iso")
+
+    def test_no_encode_keeps_escapes_already_in_the_name(self):
+        self.addCleanup(ia_common.set_encode_percent, True)
+        ia_common.session_from_args(session_args(encode=False))
+        self.assertEqual(ia_common.encode_path_segment("my%20disc.iso"), "my%20disc.iso")
+        self.assertEqual(ia_common.encode_path_segment("my disc.iso"), "my%20disc.iso")
+
+    def test_lone_percent_is_encoded(self):
+        self.assertEqual(ia_common.encode_path_segment("100%.iso"), "100%25.iso")
+
+    def test_slashes_are_kept(self):
+        self.assertEqual(ia_common.encode_path_segment("disc 1/track.flac"), "disc%201/track.flac")
+
+
+class BuildDownloadUrlTest(unittest.TestCase):
+    META = {"server": "ia800100.us.archive.org", "dir": "/7/items/item"}
+
+    def test_data_node_from_metadata(self):
+        self.assertEqual(ia_common.build_download_url("item", "a b.iso", self.META),
+                         "https://ia800100.us.archive.org/7/items/item/a%20b.iso")
+
+    def test_generic_url_without_server_or_with_base_url_override(self):
+        self.assertEqual(ia_common.build_download_url("item", "a.iso", {"dir": "/7/items/item"}),
+                         f"{ia_common.DEFAULT_BASE_URL}/download/item/a.iso")
+        with MockServer() as server:
+            self.assertEqual(ia_common.build_download_url("item", "a.iso", self.META), f"{server.url}/download/item/a.iso")
+
+
+class FilesXmlTest(unittest.TestCase):
+    XML = b"""<?xml version="1.0" encoding="UTF-8"?>
+<files>
+  <file name="disc.iso" source="original">
+    <size>2048</size>
+    <md5>0123456789abcdef0123456789abcdef</md5>
+    <crc32>deadbeef</crc32>
+    <sha1>da39a3ee5e6b4b0d3255bfef95601890afd80709</sha1>
+    <format>ISO Image</format>
+  </file>
+  <file name="disc.torrent" source="metadata"><format>Archive BitTorrent</format></file>
+</files>"""
+
+    def test_entries_match_the_json_shape(self):
+        files = ia_common.parse_files_xml(self.XML)
+        self.assertEqual(files[0], {"name": "disc.iso", "source": "original", "size": "2048",
+                                    "md5": "0123456789abcdef0123456789abcdef", "crc32": "deadbeef",
+                                    "sha1": "da39a3ee5e6b4b0d3255bfef95601890afd80709", "format": "ISO Image"})
+        self.assertEqual(files[1], {"name": "disc.torrent", "source": "metadata", "format": "Archive BitTorrent"})
+
+    def test_fetch_reports_broken_xml(self):
+        session = ia_common.session_from_args(session_args())
+        with MockServer() as server:
+            server.add("/download/item/item_files.xml", Response(200, b"<files><file"))
+            with self.assertRaisesRegex(RuntimeError, "Failed to parse item_files.xml"):
+                ia_common.fetch_files_xml(session, "item")
+
+
+class SafeFilenameTest(unittest.TestCase):
+    def test_long_names_keep_extension_and_stay_distinct(self):
+        first = ia_common.safe_filename("a" * 300 + "1.iso", 64)
+        second = ia_common.safe_filename("a" * 300 + "2.iso", 64)
+        self.assertEqual(len(first.encode("utf-8")), 64)
+        self.assertTrue(first.endswith(".iso"))
+        self.assertNotEqual(first, second)
+
+    def test_truncation_never_splits_a_character(self):
+        name = ia_common.safe_filename("é" * 200 + ".iso", 100)
+        self.assertLessEqual(len(name.encode("utf-8")), 100)
+        self.assertTrue(name.startswith("é"))
+
+    def test_short_names_are_untouched(self):
+        self.assertEqual(ia_common.safe_filename("disc.iso"), "disc.iso")
+
+    def test_windows_reserved_names_are_prefixed(self):
+        self.assertEqual(ia_common.safe_filename("CON"), "_CON")
+        self.assertEqual(ia_common.safe_filename("nul.tar.gz"), "_nul.tar.gz")
+        self.assertEqual(ia_common.safe_filename("console.iso"), "console.iso")
+
+    def test_windows_invalid_characters_are_replaced(self):
+        self.assertEqual(ia_common.safe_filename('a<b>c:d"e|f?g*h.iso', portable=True), "a_b_c_d_e_f_g_h.iso")
+        self.assertEqual(ia_common.safe_filename("tab\there.iso", replacement="-", portable=True), "tab-here.iso")
+        self.assertEqual(ia_common.safe_filename("notes. ", portable=True), "notes__")
+        self.assertEqual(ia_common.safe_filename("a:b.iso", replacement="", portable=True), "ab.iso")
+        self.assertEqual(ia_common.safe_filename("???", replacement="", portable=True), "_")
+
+    @unittest.skipIf(os.name == "nt", "Windows always applies its own rules")
+    def test_names_the_local_filesystem_accepts_are_kept(self):
+        # Linux and macOS accept these, so files already mirrored there keep their names
+        self.assertEqual(ia_common.safe_filename("a:b?.iso"), "a:b?.iso")
+        self.assertEqual(ia_common.safe_filename("nul\0byte.iso"), "nul_byte.iso")
+
+    def test_undecodable_names_become_creatable(self):
+        # os.fsdecode() leaves bytes that aren't UTF-8 behind as lone surrogates
+        name = ia_common.safe_filename(b"caf\xe9.iso".decode("utf-8", "surrogateescape"))
+        self.assertEqual(name, "caf_.iso")
+        name.encode("utf-8")
+
+    def test_replacement_must_be_allowed_itself(self):
+        self.assertEqual(ia_common.filename_replacement("-"), "-")
+        for value in (":", "/", "a?"):
+            with self.assertRaises(argparse.ArgumentTypeError):
+                ia_common.filename_replacement(value)
+
+    def test_original_names_merge_into_sidecar(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            names = {}
+            ia_common.record_original_name(names, tmp, os.path.join(tmp, "item", "a_b.iso"), "item", "a:b.iso")
+            ia_common.record_original_name(names, tmp, os.path.join(tmp, "item", "same.iso"), "item", "same.iso")
+            ia_common.save_original_names(tmp, names)
+            ia_common.save_original_names(tmp, {"other/c_.iso": {"identifier": "other", "name": "c?.iso"}})
+            with open(os.path.join(tmp, ia_common.ORIGINAL_NAMES_FILE), encoding="utf-8") as f:
+                saved = json.load(f)
+        self.assertEqual(saved, {"item/a_b.iso": {"identifier": "item", "name": "a:b.iso"},
+                                 "other/c_.iso": {"identifier": "other", "name": "c?.iso"}})
+
+    def test_applies_to_every_rendered_component(self):
+        path = ia_common.render_output_path("{identifier}/{file_name}", "item", "aux/" + "x" * 80 + ".iso", max_length=40)
+        parts = path.split(os.sep)
+        self.assertEqual(parts[:2], ["item", "_aux"])
+        self.assertEqual(len(parts[2]), 40 - len(ia_common.PART_SUFFIX))
+
+
+class FileExtensionTest(unittest.TestCase):
+    def test_compressed_extensions_keep_the_one_they_wrap(self):
+        for name, ext in (("src.tar.gz", ".tar.gz"), ("disc.IMG.xz", ".img.xz"), ("notes.gz", ".gz"),
+                          ("linux-5.10.gz", ".gz"), ("dump.sql.zst", ".sql.zst"), ("a.tar.gz.sig", ".sig"),
+                          ("disc.iso", ".iso"), ("README", "")):
+            self.assertEqual(ia_common.file_extension(name), ext, name)
+
+
+class ParseRateTest(unittest.TestCase):
+    def test_decimal_and_binary_units(self):
+        self.assertEqual(ia_common.parse_rate("2MB/s"), 2_000_000)
+        self.assertEqual(ia_common.parse_rate("500KiB/s"), 500 * 1024)
+        self.assertEqual(ia_common.parse_rate("1M"), 1024 * 1024)
+
+    def test_plain_bytes(self):
+        self.assertEqual(ia_common.parse_rate("750000"), 750000)
+
+    def test_rejects_garbage_and_zero(self):
+        for value in ("fast", "0", "2TB/s"):
+            with self.assertRaises(argparse.ArgumentTypeError):
+                ia_common.parse_rate(value)
+
+
+class PacerTest(unittest.TestCase):
+    def run_pacer(self, pacer, request_seconds):
+        """Starts of consecutive waits when each request takes request_seconds, on a fake clock."""
+        clock = [100.0]
+        starts = []
+        sleep = lambda seconds: clock.__setitem__(0, clock[0] + seconds)
+        with unittest.mock.patch.object(ia_common.time, "monotonic", lambda: clock[0]), \
+                unittest.mock.patch.object(ia_common.time, "sleep", sleep):
+            for _ in range(5):
+                pacer.wait()
+                starts.append(clock[0])
+                sleep(request_seconds)
+        return [b - a for a, b in zip(starts, starts[1:])]
+
+    def test_request_time_counts_towards_the_gap(self):
+        self.assertEqual(self.run_pacer(ia_common.Pacer(1.0), 0.4), [1.0] * 4)
+        # A request slower than the gap is followed straight away
+        self.assertEqual(self.run_pacer(ia_common.Pacer(1.0), 1.5), [1.5] * 4)
+
+    def test_jitter_stays_within_bounds(self):
+        gaps = self.run_pacer(ia_common.Pacer(0.5, 2.0), 0.0)
+        self.assertTrue(all(0.5 <= gap <= 2.0 for gap in gaps), gaps)
+        self.assertGreater(len(set(gaps)), 1)
+
+    def test_as_pacer_wraps_seconds(self):
+        pacer = ia_common.Pacer(1.0)
+        self.assertIs(ia_common.as_pacer(pacer), pacer)
+        self.assertEqual(ia_common.as_pacer(0.25).high, 0.25)
+
+    def test_adaptive_gap_doubles_on_429_and_creeps_back(self):
+        pacer = ia_common.AdaptivePacer(1.0, 0.5, 3.0)
+        pacer.throttled()
+        self.assertEqual(pacer.low, 2.0)
+        pacer.throttled()
+        self.assertEqual(pacer.low, 3.0)
+        for _ in range(200):
+            pacer.succeeded()
+        self.assertEqual((pacer.low, pacer.high), (0.5, 0.5))
+        unpaced = ia_common.AdaptivePacer(0, 0, 10)
+        unpaced.throttled()
+        self.assertEqual(unpaced.low, ia_common.ADAPTIVE_SLEEP_FIRST)
+
+    def test_session_429s_steer_an_adaptive_pacer(self):
+        args = argparse.Namespace(adaptive_sleep=True, adaptive_sleep_min=0.0, adaptive_sleep_max=60.0)
+        session = ia_common.session_from_args(session_args(retries=2, backoff=0))
+        pacer = ia_common.pacer_from_args(args, session, 1.0)
+        with MockServer() as server:
+            server.add("/search", Response(429), Response(429), Response(200, b"{}"))
+            self.assertEqual(session.get(f"{server.url}/search").status_code, 200)
+        # Two 429s doubled the gap twice, the 200 took a step back towards the minimum
+        self.assertLess(pacer.low, 4.0)
+        self.assertGreater(pacer.low, 3.0)
+        plain = ia_common.pacer_from_args(argparse.Namespace(adaptive_sleep=False), session, 0.5, 2.0)
+        self.assertEqual((type(plain), plain.low, plain.high), (ia_common.Pacer, 0.5, 2.0))
+
+
+class SizeTest(unittest.TestCase):
+    def test_parse_units(self):
+        self.assertEqual(ia_common.parse_size("0"), 0)
+        self.assertEqual(ia_common.parse_size("1023"), 1023)
+        self.assertEqual(ia_common.parse_size("1.5GB"), 1_500_000_000)
+        self.assertEqual(ia_common.parse_size("1.5GiB"), 3 * 512 * 1024 ** 2)
+        self.assertEqual(ia_common.parse_size(" 2 mb "), 2_000_000)
+
+    def test_parse_rejects_rates_and_garbage(self):
+        for value in ("", "-1MB", "2MB/s", "1.5.0GB", "huge"):
+            with self.assertRaises(argparse.ArgumentTypeError):
+                ia_common.parse_size(value)
+
+    def test_format_boundaries(self):
+        self.assertEqual(ia_common.format_size(0), "0 B")
+        self.assertEqual(ia_common.format_size(1023), "1023 B")
+        self.assertEqual(ia_common.format_size(1024), "1.0 KiB")
+        self.assertEqual(ia_common.format_size(int(1.5 * 1024 ** 3)), "1.5 GiB")
+        self.assertEqual(ia_common.format_size(2048 * 1024 ** 4), "2048.0 TiB")
+
+    def test_format_round_trips_parse(self):
+        self.assertEqual(ia_common.format_size(ia_common.parse_size("700MiB")), "700.0 MiB")
+
+
+class FilesTableTest(unittest.TestCase):
+    FILES = [{"name": f"part{i}.bin", "size": str(i), "format": "Data"} for i in range(5)]
+
+    def table(self, **kwargs):
+        with contextlib.redirect_stdout(io.StringIO()) as out:
+            ia_common.print_files_table(self.FILES, **kwargs)
+        return out.getvalue().splitlines()
+
+    def test_header_repeats_every_page(self):
+        lines = self.table(sort=None, page_rows=2)
+        self.assertEqual([i for i, line in enumerate(lines) if line.startswith("name")], [0, 4, 8])
+        self.assertEqual(lines[-1], "5 files, 10 B")
+
+    def test_labels_and_given_order(self):
+        labels = [f"label{i}" for i in range(5)]
+        lines = self.table(sort=None, labels=labels)
+        self.assertEqual([line.split()[0] for line in lines[2:-2]], labels)
+
+
+class CompressionTest(unittest.TestCase):
+    def test_accept_encoding_requests_gzip(self):
+        session = ia_common.session_from_args(session_args())
+        with MockServer() as server:
+            server.add("/metadata/item", Response(200, b"{}"))
+            session.get(ia_common.metadata_url("item"))
+        self.assertIn("gzip", server.requests[0][2].get("Accept-Encoding", ""))
+
+    def test_gzip_body_is_decoded(self):
+        body = {"files": [{"name": "disc.iso"}]}
+        session = ia_common.session_from_args(session_args())
+        with MockServer() as server:
+            server.add("/metadata/item", Response(200, gzip.compress(json.dumps(body).encode()),
+                                                  {"Content-Encoding": "gzip"}))
+            self.assertEqual(session.get(ia_common.metadata_url("item")).json(), body)
+
+    def test_no_compression_asks_for_identity(self):
+        session = ia_common.session_from_args(session_args(compression=False))
+        with MockServer() as server:
+            server.add("/metadata/item", Response(200, b"{}"))
+            session.get(ia_common.metadata_url("item"))
+        self.assertEqual(server.requests[0][2].get("Accept-Encoding"), "identity")
+
+
+class AttemptHistoryTest(unittest.TestCase):
+    def test_exhausted_status_retries_list_every_attempt(self):
+        session = ia_common.session_from_args(session_args(retries=2))
+        with MockServer() as server:
+            server.add("/metadata/item", Response(503), Response(429), Response(503))
+            resp = session.get(ia_common.metadata_url("item"))
+        self.assertEqual(ia_common.describe_attempts(resp), "503, 429, 503")
+        self.assertEqual(ia_common.with_attempts("failed", resp), "failed (attempts: 503, 429, 503)")
+
+    def test_connection_errors_are_listed(self):
+        session = ia_common.session_from_args(session_args(retries=1))
+        with MockServer() as server:
+            url = ia_common.metadata_url("item")
+        # Server is shut down now, so every attempt is refused
+        with self.assertRaises(requests.ConnectionError) as ctx:
+            session.get(url)
+        self.assertEqual(ia_common.describe_attempts(ctx.exception), "connection error, connection error")
+
+    def test_single_attempt_adds_nothing(self):
+        session = ia_common.session_from_args(session_args())
+        with MockServer() as server:
+            server.add("/metadata/item", Response(404))
+            resp = session.get(ia_common.metadata_url("item"))
+        self.assertEqual(ia_common.with_attempts("failed", resp), "failed")
+
+
+class LogFileTest(unittest.TestCase):
+    def test_rotates_at_max_size_and_keeps_backups(self):
+        logger = logging.getLogger("test.rotation")
+        logger.propagate = False
+        logger.setLevel(logging.DEBUG)
+        with tempfile.TemporaryDirectory() as tmp:
+            path = os.path.join(tmp, "sync.log")
+            handler = ia_common.log_file_handler(path, logging.WARNING, max_size=200, backups=2)
+            logger.addHandler(handler)
+            try:
+                logger.debug("not written: the file gets INFO and up")
+                for n in range(20):
+                    logger.info(f"entry {n}")
+            finally:
+                logger.removeHandler(handler)
+                handler.close()
+            self.assertEqual(sorted(os.listdir(tmp)), ["sync.log", "sync.log.1", "sync.log.2"])
+            with open(path, encoding="utf-8") as f:
+                last = f.read().splitlines()[-1]
+            for name in os.listdir(tmp):
+                with open(os.path.join(tmp, name), encoding="utf-8") as f:
+                    self.assertNotIn("not written", f.read())
+        self.assertRegex(last, rf"^\d{{4}}-\d\d-\d\d .* \| INFO     \| {ia_common.RUN_ID} \| test.rotation \| entry 19$")
+
+    def test_json_format_writes_one_object_per_line(self):
+        logger = logging.getLogger("test.json")
+        logger.propagate = False
+        with tempfile.TemporaryDirectory() as tmp:
+            path = os.path.join(tmp, "run.log")
+            handler = ia_common.log_file_handler(path, logging.INFO, log_format="json")
+            logger.addHandler(handler)
+            try:
+                logger.warning("disc | \"quoted\"")
+            finally:
+                logger.removeHandler(handler)
+                handler.close()
+            with open(path, encoding="utf-8") as f:
+                entry = json.loads(f.read())
+        self.assertEqual({k: entry[k] for k in ("level", "run_id", "logger", "message")},
+                         {"level": "WARNING", "run_id": ia_common.RUN_ID, "logger": "test.json",
+                          "message": 'disc | "quoted"'})
+        self.assertRegex(entry["time"], r"^\d{4}-\d\d-\d\d ")
+
+
+class UserAgentTest(unittest.TestCase):
+    def test_contact_is_appended_to_the_sent_user_agent(self):
+        session = ia_common.session_from_args(session_args(contact="ops@example.com"))
+        with MockServer() as server:
+            server.add("/metadata/item", Response(200, b"{}"))
+            session.get(ia_common.metadata_url("item"))
+        self.assertEqual(server.requests[0][2]["User-Agent"],
+                         f"{ia_common.DEFAULT_USER_AGENT} (contact: ops@example.com)")
+        self.assertIn(f"/{ia_common.VERSION} ", ia_common.DEFAULT_USER_AGENT)
+
+    def test_custom_headers_are_validated_and_win_over_built_in_ones(self):
+        headers = [ia_common.header_arg(value) for value in ("X-Trace: abc 123", "User-Agent: probe/1",
+                                                              "Accept-Encoding:")]
+        session = ia_common.session_from_args(session_args(header=headers))
+        with MockServer() as server:
+            server.add("/metadata/item", Response(200, b"{}"))
+            session.get(ia_common.metadata_url("item"))
+        sent = server.requests[0][2]
+        self.assertEqual((sent["X-Trace"], sent["User-Agent"]), ("abc 123", "probe/1"))
+        self.assertEqual(sent.get("Accept-Encoding"), "identity")
+        for value in ("No colon", "Bad Name: x", ": empty", "X-Split: a\r\nInjected: b"):
+            with self.assertRaises(argparse.ArgumentTypeError):
+                ia_common.header_arg(value)
+
+    def test_per_request_headers_of_a_download_win_over_custom_ones(self):
+        session = ia_common.session_from_args(session_args(header=[("Range", "bytes=5-")]))
+        with tempfile.TemporaryDirectory() as tmp, MockServer() as server:
+            server.add("/download/item/x.iso", Response(200, b"abcd"))
+            ia_common.fetch_head(session, f"{ia_common.download_base_url()}/item/x.iso", os.path.join(tmp, "x"), 2)
+        self.assertEqual(server.requests[0][2]["Range"], "bytes=0-1")
+
+    def test_requests_carry_the_run_id_and_their_own_number(self):
+        session = ia_common.session_from_args(session_args(retries=1, backoff=0))
+        with MockServer() as server:
+            server.add("/metadata/a", Response(503), Response(200, b"{}"))
+            server.add("/metadata/b", Response(200, b"{}"))
+            session.get(ia_common.metadata_url("a"))
+            session.get(ia_common.metadata_url("b"))
+        ids = [headers[ia_common.REQUEST_ID_HEADER] for _, _, headers in server.requests]
+        run, _, number = ids[0].rpartition("-")
+        self.assertEqual(run, ia_common.RUN_ID)
+        # The retry of a is the same request; b is the next one
+        self.assertEqual(ids, [ids[0], ids[0], f"{run}-{int(number) + 1}"])
+        custom = ia_common.session_from_args(session_args(header=[("X-Request-Id", "ticket-42")]))
+        with MockServer() as server:
+            server.add("/metadata/a", Response(200, b"{}"))
+            custom.get(ia_common.metadata_url("a"))
+        self.assertEqual(server.requests[0][2][ia_common.REQUEST_ID_HEADER], "ticket-42")
+
+    def test_large_run_warns_once_without_contact(self):
+        args = session_args()
+        with unittest.mock.patch.object(ia_common, "_contact_warned", False):
+            ia_common.warn_without_contact(args, ia_common.LARGE_RUN_REQUESTS)
+            with self.assertLogs(level="WARNING") as logs:
+                ia_common.warn_without_contact(args, 5000)
+                ia_common.warn_without_contact(args, 5000)
+            self.assertEqual(len(logs.output), 1)
+            self.assertIn("About 5000 requests ahead without a --contact", logs.output[0])
+
+
+class FriendlyErrorTest(unittest.TestCase):
+    def test_refused_connection_names_host(self):
+        session = ia_common.session_from_args(session_args())
+        with MockServer() as server:
+            url = ia_common.metadata_url("item")
+        with self.assertRaises(requests.ConnectionError) as ctx:
+            session.get(url)
+        with self.assertLogs(level="INFO") as logs:
+            message = ia_common.friendly_error(ctx.exception)
+        self.assertEqual(message, "127.0.0.1 refused the connection; the service may be down or the port blocked")
+        # The raw error stays available under -v
+        self.assertIn("ConnectionError(MaxRetryError(", logs.output[0])
+
+    def test_dns_failure_found_through_the_chain(self):
+        try:
+            try:
+                raise socket.gaierror(-2, "Name or service not known")
+            except socket.gaierror as e:
+                raise requests.ConnectionError("Max retries exceeded") from e
+        except requests.ConnectionError as e:
+            message = ia_common.friendly_error(e)
+        self.assertTrue(message.startswith("DNS resolution failed"))
+
+    def test_read_timeout(self):
+        error = requests.exceptions.ReadTimeout("timed out", request=requests.Request("GET", "https://archive.org/x"))
+        self.assertIn("archive.org accepted the connection but stopped responding", ia_common.friendly_error(error))
+
+    def test_redirect_loop_names_both_ends(self):
+        session = ia_common.session_from_args(session_args(max_redirects=3))
+        with MockServer() as server:
+            server.add("/download/a", Response(302, headers={"Location": "/download/b"}))
+            server.add("/download/b", Response(302, headers={"Location": "/download/a"}))
+            with self.assertLogs(level="DEBUG") as logs, self.assertRaises(requests.TooManyRedirects) as ctx:
+                session.get(f"{server.url}/download/a")
+        self.assertEqual(len(server.requests), 4)
+        hops = [line for line in logs.output if line.startswith("DEBUG:root:Redirect")]
+        self.assertEqual(len(hops), 4)
+        self.assertIn(f"Redirect 302: {server.url}/download/a -> /download/b", hops[0])
+        message = ia_common.friendly_error(ctx.exception)
+        self.assertIn(f"more than 3 redirects starting at {server.url}/download/a", message)
+        self.assertIn(f"the last went from {server.url}/download/b to /download/a", message)
+
+    def test_other_errors_pass_through(self):
+        self.assertEqual(ia_common.friendly_error(ValueError("bad")), "bad")
+
+
+class ProxyTest(unittest.TestCase):
+    def test_proxy_flag_routes_requests_through_proxy(self):
+        with MockServer() as proxy:
+            session = ia_common.session_from_args(session_args(proxy=proxy.url))
+            proxy.add("http://archive.invalid/metadata/item", Response(200, b"{}"))
+            self.assertEqual(session.get("http://archive.invalid/metadata/item").status_code, 200)
+        self.assertEqual(proxy.requests[0][1], "http://archive.invalid/metadata/item")
+
+    def test_proxy_flag_wins_over_environment(self):
+        dead = "http://127.0.0.1:9"
+        with MockServer() as proxy, unittest.mock.patch.dict(os.environ, {"HTTP_PROXY": dead, "HTTPS_PROXY": dead}):
+            session = ia_common.session_from_args(session_args(proxy=proxy.url, retries=0))
+            proxy.add("http://archive.invalid/metadata/item", Response(200, b"{}"))
+            self.assertEqual(session.get("http://archive.invalid/metadata/item").status_code, 200)
+            # The per-request proxies can't be polluted by the environment for the next request either
+            self.assertEqual(session.get("http://archive.invalid/metadata/item").status_code, 200)
+        self.assertEqual([path for _, path, _ in proxy.requests], ["http://archive.invalid/metadata/item"] * 2)
+
+    def test_no_proxy_ignores_environment(self):
+        with MockServer() as server, unittest.mock.patch.dict(os.environ, {"HTTP_PROXY": "http://127.0.0.1:9"}):
+            server.add("/metadata/item", Response(200, b"{}"))
+            session = ia_common.session_from_args(session_args(no_proxy=True))
+            self.assertEqual(session.get(ia_common.metadata_url("item")).status_code, 200)
+
+
+class ConnectionTuningTest(unittest.TestCase):
+    def adapter(self, session):
+        return session.get_adapter("https://archive.org/")
+
+    def test_tcp_keepalive_is_on_by_default(self):
+        options = self.adapter(ia_common.session_from_args(session_args())).poolmanager.connection_pool_kw[
+            "socket_options"]
+        self.assertIn((socket.SOL_SOCKET, socket.SO_KEEPALIVE, 1), options)
+
+    def test_zero_turns_tcp_keepalive_off(self):
+        options = self.adapter(ia_common.session_from_args(session_args(tcp_keepalive=0))).poolmanager.connection_pool_kw[
+            "socket_options"]
+        self.assertNotIn((socket.SOL_SOCKET, socket.SO_KEEPALIVE, 1), options)
+
+    def test_no_keepalive_closes_each_connection(self):
+        session = ia_common.session_from_args(session_args(keepalive=False))
+        self.assertEqual(session.headers["Connection"], "close")
+        self.assertEqual(ia_common.session_from_args(session_args()).headers["Connection"], "keep-alive")
+
+    def test_pool_size(self):
+        session = ia_common.session_from_args(session_args(pool_size=16))
+        self.assertEqual(self.adapter(session)._pool_maxsize, 16)
+        with self.assertRaises(argparse.ArgumentTypeError):
+            ia_common.pool_size("0")
+
+
+class ResolverTest(unittest.TestCase):
+    def tearDown(self):
+        ia_common.install_resolver()
+
+    def test_answers_are_reused_within_the_ttl_and_pins_skip_the_lookup(self):
+        lookups = []
+
+        def lookup(host, port, *args):
+            lookups.append(host)
+            return [(socket.AF_INET, socket.SOCK_STREAM, 6, "", ("192.0.2.1" if host != "10.0.0.9" else host, port))]
+
+        cache = ia_common.DnsCache(60, {"ia800101.us.archive.org": "10.0.0.9"}, lookup)
+        for _ in range(3):
+            cache.getaddrinfo("archive.org", 443)
+        self.assertEqual(cache.getaddrinfo("IA800101.us.archive.org", 443)[0][4], ("10.0.0.9", 443))
+        self.assertEqual(lookups, ["archive.org", "10.0.0.9"])
+        cache.ttl = 0
+        cache.getaddrinfo("archive.org", 443)
+        self.assertEqual(lookups.count("archive.org"), 2)
+
+    def test_resolve_pins_a_host_for_real_connections(self):
+        self.assertEqual(ia_common.resolve_pin("Example.org:[::1]"), ("example.org", "::1"))
+        for bad in ("archive.org", "archive.org:node1", ":127.0.0.1"):
+            with self.assertRaises(argparse.ArgumentTypeError):
+                ia_common.resolve_pin(bad)
+        with MockServer() as server:
+            server.add("/ping", Response(200, b"pong"))
+            session = ia_common.session_from_args(session_args(resolve=[("pinned.invalid", "127.0.0.1")], no_proxy=True))
+            port = server.url.rsplit(":", 1)[1]
+            self.assertEqual(session.get(f"http://pinned.invalid:{port}/ping").content, b"pong")
+
+
+class RetryJitterTest(unittest.TestCase):
+    def tearDown(self):
+        ia_common.seed_retry_jitter(None)
+
+    def delays(self, seed):
+        ia_common.seed_retry_jitter(seed)
+        retry = ia_common.RecordingRetry(total=5, backoff_factor=1.0, backoff_jitter=1.0, backoff_max=60)
+        retry = retry.new(history=(RequestHistory("GET", "/", None, 503, None),) * 3)
+        return [ia_common.backoff_delay(attempt, 1.0, 60) for attempt in (1, 2, 3)] + [retry.get_backoff_time()]
+
+    def test_seed_makes_delays_repeat(self):
+        self.assertEqual(self.delays(7), self.delays(7))
+        self.assertNotEqual(self.delays(7), self.delays(8))
+
+    def test_jitter_stays_within_backoff(self):
+        backoffs = self.delays(7)
+        for base, delay in zip((1, 2, 4, 4), backoffs):
+            self.assertGreaterEqual(delay, base)
+            self.assertLess(delay, base + 1)
+
+    def test_flag_seeds_session(self):
+        ia_common.session_from_args(session_args(retry_seed=3))
+        first = ia_common.backoff_delay(1, 1.0)
+        ia_common.session_from_args(session_args(retry_seed=3))
+        self.assertEqual(ia_common.backoff_delay(1, 1.0), first)
+
+
+class TLSTest(unittest.TestCase):
+    def test_verification_is_on_by_default(self):
+        self.assertIs(ia_common.session_from_args(session_args()).verify, True)
+
+    def test_ca_cert_is_added_to_default_roots(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            cert = os.path.join(tmp, "proxy-ca.pem")
+            with open(cert, "w") as f:
+                f.write("-----BEGIN CERTIFICATE-----\nproxy\n-----END CERTIFICATE-----\n")
+            session = ia_common.session_from_args(session_args(ca_cert=cert))
+        with open(session.verify) as f:
+            bundle = f.read()
+        with open(requests.certs.where()) as f:
+            self.assertTrue(bundle.startswith(f.read().rstrip("\n")))
+        self.assertTrue(bundle.endswith("proxy\n-----END CERTIFICATE-----\n"))
+
+    def test_insecure_disables_verification_loudly(self):
+        with self.assertLogs(level="WARNING") as logs:
+            session = ia_common.session_from_args(session_args(insecure=True))
+        self.assertIs(session.verify, False)
+        self.assertIn("DISABLED", logs.output[0])
+
+    def sent_verify(self, args, environ):
+        """The verify value the adapter is handed for one request against the mock server."""
+        send = requests.adapters.HTTPAdapter.send
+        with MockServer() as server, unittest.mock.patch.dict(os.environ, environ), \
+                unittest.mock.patch.object(requests.adapters.HTTPAdapter, "send", autospec=True,
+                                           side_effect=send) as spy:
+            server.add("/metadata/item", Response(200, b"{}"))
+            session = ia_common.session_from_args(args)
+            session.get(ia_common.metadata_url("item"))
+        return session, spy.call_args.kwargs["verify"]
+
+    def test_flags_win_over_environment_bundles(self):
+        bundles = {"REQUESTS_CA_BUNDLE": "/nonexistent/ca.pem", "CURL_CA_BUNDLE": "/nonexistent/curl.pem"}
+        with self.assertLogs(level="WARNING"):
+            _, verify = self.sent_verify(session_args(insecure=True), bundles)
+        self.assertIs(verify, False)
+        session, verify = self.sent_verify(session_args(ca_cert=__file__),
+                                           {"REQUESTS_CA_BUNDLE": "/nonexistent/ca.pem"})
+        self.assertEqual(verify, session.verify)
+        # Without either flag the environment's bundle is still honoured
+        _, verify = self.sent_verify(session_args(), {"REQUESTS_CA_BUNDLE": "/nonexistent/ca.pem"})
+        self.assertEqual(verify, "/nonexistent/ca.pem")
+
+    def test_flags_are_exclusive_and_cert_must_exist(self):
+        parser = argparse.ArgumentParser()
+        ia_common.add_tls_args(parser)
+        with contextlib.redirect_stderr(io.StringIO()):
+            with self.assertRaises(SystemExit):
+                parser.parse_args(["--ca-cert", __file__, "--insecure"])
+            with self.assertRaises(SystemExit):
+                parser.parse_args(["--ca-cert", "/nonexistent/ca.pem"])
+
+
+class UniquePathTest(unittest.TestCase):
+    def test_numbers_before_extension_and_skips_taken_names(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            path = os.path.join(tmp, "disc.iso")
+            self.assertEqual(ia_common.unique_path(path), os.path.join(tmp, "disc (1).iso"))
+            open(os.path.join(tmp, "disc (1).iso"), "w").close()
+            open(os.path.join(tmp, "disc (2).iso" + ia_common.PART_SUFFIX), "w").close()
+            self.assertEqual(ia_common.unique_path(path), os.path.join(tmp, "disc (3).iso"))
+
+
+class WriteJsonAtomicTest(unittest.TestCase):
+    def test_replaces_target_and_leaves_no_temp_files(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            path = os.path.join(tmp, "out.json")
+            ia_common.write_json_atomic(path, [1])
+            ia_common.write_json_atomic(path, {"a": 2})
+            with open(path, encoding="utf-8") as f:
+                self.assertEqual(json.load(f), {"a": 2})
+            self.assertEqual(os.listdir(tmp), ["out.json"])
+
+    def test_failed_write_keeps_previous_file(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            path = os.path.join(tmp, "out.json")
+            ia_common.write_json_atomic(path, [1])
+            with self.assertRaises(TypeError):
+                ia_common.write_json_atomic(path, {"bad": object()})
+            with open(path, encoding="utf-8") as f:
+                self.assertEqual(json.load(f), [1])
+            self.assertEqual(os.listdir(tmp), ["out.json"])
+
+
+class CompressedJsonTest(unittest.TestCase):
+    def round_trip(self, name):
+        with tempfile.TemporaryDirectory() as tmp:
+            path = os.path.join(tmp, name)
+            ia_common.write_json_atomic(path, [{"file_name": "é.iso"}], ensure_ascii=False)
+            with open(path, "rb") as f:
+                head = f.read(4)
+            with ia_common.open_text(path) as f:
+                self.assertEqual(json.load(f), [{"file_name": "é.iso"}])
+            self.assertEqual(os.listdir(tmp), [name])
+        return head
+
+    def test_gz_suffix_writes_gzip(self):
+        self.assertEqual(self.round_trip("out.json.gz")[:2], b"\x1f\x8b")
+
+    @unittest.skipIf(ia_common.zstandard is None, "zstandard not installed")
+    def test_zst_suffix_writes_zstd(self):
+        self.assertEqual(self.round_trip("out.json.zst"), b"\x28\xb5\x2f\xfd")
+
+    def test_plain_suffix_stays_uncompressed(self):
+        self.assertEqual(self.round_trip("out.json"), b'[{"f')
+
+    def test_missing_zstd_package_is_reported(self):
+        with unittest.mock.patch.object(ia_common, "zstandard", None):
+            with self.assertRaisesRegex(ValueError, "zstandard"):
+                ia_common.require_compression("out.json.zst")
+            ia_common.require_compression("out.json.gz")
+
+
+class FreeSpaceTest(unittest.TestCase):
+    def test_shortfall_counts_the_reserve(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            free = ia_common.free_space(os.path.join(tmp, "not", "yet", "created"))
+            self.assertIsNone(ia_common.space_shortfall(tmp, 0))
+            self.assertIsNone(ia_common.space_shortfall(tmp, free // 2, free // 4))
+            message = ia_common.space_shortfall(tmp, free // 2, free)
+            self.assertIn("needed plus a", message)
+
+
+class ColorTest(unittest.TestCase):
+    def tearDown(self):
+        ia_common.use_color("never")
+
+    def test_modes(self):
+        self.assertTrue(ia_common.use_color("always"))
+        self.assertEqual(ia_common.colored("[✔]", "green"), "\033[32m[✔]\033[0m")
+        self.assertFalse(ia_common.use_color("never"))
+        self.assertEqual(ia_common.colored("[✔]", "green"), "[✔]")
+
+    def test_auto_needs_a_tty_and_no_NO_COLOR(self):
+        tty = unittest.mock.Mock(isatty=lambda: True)
+        self.assertFalse(ia_common.use_color("auto", io.StringIO()))
+        with unittest.mock.patch.dict(os.environ):
+            os.environ.pop("NO_COLOR", None)
+            self.assertTrue(ia_common.use_color("auto", tty))
+        with unittest.mock.patch.dict(os.environ, {"NO_COLOR": "1"}):
+            self.assertFalse(ia_common.use_color("auto", tty))
+
+
+class TransferStatusTest(unittest.TestCase):
+    def test_snapshot_lists_counts_and_current_files(self):
+        status = ia_common.TransferStatus(total_files=3)
+        status.record("downloaded")
+        status.record("failed")
+        status.start("disc.iso", 2048)
+        status.advance("disc.iso", 1024)
+        line = status.snapshot()
+        self.assertTrue(line.startswith("[status] 2/3 files done (1 failed), 1.0 KiB at "), line)
+        self.assertTrue(line.endswith("; current: disc.iso 1.0 KiB of 2.0 KiB"), line)
+        status.end("disc.iso")
+        self.assertTrue(status.snapshot().endswith("; idle"))
+
+    def test_snapshot_shows_known_batch_total(self):
+        status = ia_common.TransferStatus(total_files=2, total_bytes=4096)
+        status.advance("disc.iso", 1024)
+        self.assertIn(" 1.0 KiB of 4.0 KiB at ", status.snapshot())
+
+    @unittest.skipUnless(hasattr(signal, "SIGUSR1"), "SIGUSR1 is Unix-only")
+    def test_sigusr1_prints_snapshot(self):
+        previous = signal.getsignal(signal.SIGUSR1)
+        self.addCleanup(signal.signal, signal.SIGUSR1, previous)
+        out = io.StringIO()
+        self.assertTrue(ia_common.install_status_signal(ia_common.TransferStatus(), out))
+        os.kill(os.getpid(), signal.SIGUSR1)
+        self.assertTrue(out.getvalue().startswith("[status] 0 files done"))
+
+    def test_metrics_file_holds_totals_by_outcome(self):
+        status = ia_common.TransferStatus(total_files=3)
+        status.record("downloaded")
+        status.record("downloaded")
+        status.record("failed")
+        status.advance("disc.iso", 4096)
+        with tempfile.TemporaryDirectory() as tmp:
+            path = os.path.join(tmp, "ia.prom")
+            writer = ia_common.MetricsWriter(path, status, interval=60).start()
+            writer.stop()
+            with open(path, encoding="utf-8") as f:
+                samples = dict(line.rsplit(" ", 1) for line in f.read().splitlines() if not line.startswith("#"))
+            self.assertEqual(os.listdir(tmp), ["ia.prom"])
+        self.assertEqual(samples["ia_download_bytes_total"], "4096")
+        self.assertEqual(samples['ia_download_files_total{status="downloaded"}'], "2")
+        self.assertEqual(samples['ia_download_files_total{status="failed"}'], "1")
+        self.assertEqual(samples['ia_download_files_total{status="skipped"}'], "0")
+        self.assertEqual(samples["ia_download_files_expected"], "3")
+
+
+class ExitCodeTest(unittest.TestCase):
+    def test_failures_beat_an_empty_run_which_beats_success(self):
+        self.assertEqual(ia_common.run_exit_code(1, 0), ia_common.EXIT_FAILED)
+        self.assertEqual(ia_common.run_exit_code(0, 0), ia_common.EXIT_NOTHING)
+        self.assertEqual(ia_common.run_exit_code(0, 3, 0), ia_common.EXIT_OK)
+        self.assertEqual(ia_common.run_exit_code(0, 3, 0, on_skip=True), ia_common.EXIT_NOTHING)
+        self.assertEqual(ia_common.run_exit_code(0, 3, 1, on_skip=True), ia_common.EXIT_OK)
+
+    def test_usage_and_config_errors_exit_with_the_usage_status(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            path = os.path.join(tmp, "ia-tools.toml")
+            with open(path, "w", encoding="utf-8") as f:
+                f.write("retries = ")
+            for argv in (["--retries", "many"], ["--config", path]):
+                parser = ia_common.ConfigArgumentParser()
+                parser.add_argument("--retries", type=int)
+                ia_common.add_exit_code_args(parser)
+                self.assertIn("Exit status:", parser.format_help())
+                with contextlib.redirect_stderr(io.StringIO()), self.assertRaises(SystemExit) as caught:
+                    ia_common.parse_args_with_config(parser, "tool", argv)
+                self.assertEqual(caught.exception.code, ia_common.EXIT_USAGE)
+
+
+class ConfigFileTest(unittest.TestCase):
+    def parse(self, config, argv=(), env=None):
+        parser = ia_common.ConfigArgumentParser()
+        parser.add_argument("--output-dir", default="out")
+        parser.add_argument("--on-exists", choices=["skip", "overwrite"], default="skip")
+        parser.add_argument("--query", action="append")
+        ia_common.add_session_args(parser)
+        with tempfile.TemporaryDirectory() as tmp:
+            path = os.path.join(tmp, "ia-tools.toml")
+            with open(path, "w", encoding="utf-8") as f:
+                f.write(config)
+            with unittest.mock.patch.dict(os.environ, env or {}):
+                return ia_common.parse_args_with_config(parser, "tool", ["--config", path, *argv])
+
+    def test_tool_table_overrides_top_level_and_flags_override_both(self):
+        config = 'retries = 9\noutput-dir = "shared"\n[tool]\noutput_dir = "mine"\n'
+        args = self.parse(config)
+        self.assertEqual((args.retries, args.output_dir), (9, "mine"))
+        args = self.parse(config, ["--output-dir", "flag", "--retries", "1"])
+        self.assertEqual((args.retries, args.output_dir), (1, "flag"))
+
+    def test_switches_lists_and_typed_values(self):
+        args = self.parse('no-compression = true\nquery = ["a", "b"]\nmax-backoff = "12"\n')
+        self.assertFalse(args.compression)
+        self.assertEqual(args.query, ["a", "b"])
+        self.assertEqual(args.max_backoff, 12.0)
+        self.assertEqual(self.parse('query = "a"', ["--query", "c"]).query, ["c"])
+        # Options in a mutually exclusive group are found too
+        self.assertTrue(self.parse("insecure = true").insecure)
+
+    def test_environment_beats_config(self):
+        args = self.parse('base-url = "http://config.invalid"', env={"IA_BASE_URL": "http://env.invalid"})
+        self.assertIsNone(args.base_url)
+
+    def test_invalid_values_and_files_are_usage_errors(self):
+        with unittest.mock.patch("sys.stderr"):
+            for config in ('on-exists = "rename"', "retries = ", "query = [1"):
+                with self.assertRaises(SystemExit):
+                    self.parse(config)
+
+
+if __name__ == "__main__":
+    unittest.main()
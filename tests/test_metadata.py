@@ -0,0 +1,84 @@
+import contextlib
+import io
+import unittest
+
+from support import MockServer, Response, ia_common, load_script, session_args
+
+metadata = load_script("IA-Metadata.py")
+
+FILES = [
+    {"name": "b.txt", "size": "10", "format": "Text"},
+    {"name": "a.iso", "size": "2048", "format": "ISO Image"},
+    {"name": "c_files.xml", "format": "Metadata"},
+]
+
+
+class FilesTableTest(unittest.TestCase):
+    def table_names(self, sort):
+        with contextlib.redirect_stdout(io.StringIO()) as out:
+            metadata.print_files_table(FILES, sort)
+        return [line.split()[0] for line in out.getvalue().splitlines()[2:-2]]
+
+    def test_sorts_by_size_descending_by_default(self):
+        self.assertEqual(self.table_names("size"), ["a.iso", "b.txt", "c_files.xml"])
+
+    def test_sort_by_name_and_format(self):
+        self.assertEqual(self.table_names("name"), ["a.iso", "b.txt", "c_files.xml"])
+        self.assertEqual(self.table_names("format"), ["a.iso", "c_files.xml", "b.txt"])
+
+    def test_sizes_are_human_readable(self):
+        with contextlib.redirect_stdout(io.StringIO()) as out:
+            metadata.print_files_table(FILES)
+        self.assertIn("2.0 KiB", out.getvalue())
+        self.assertIn("3 files, 2.0 KiB (1 without size)", out.getvalue())
+
+
+class GetMetadataTest(unittest.TestCase):
+    def test_empty_object_means_unknown_identifier(self):
+        session = ia_common.session_from_args(session_args())
+        with MockServer() as server:
+            server.add("/metadata/missing", Response(200, b"{}"))
+            with self.assertRaisesRegex(RuntimeError, "No metadata found"):
+                metadata.get_metadata(session, "missing")
+
+    def test_truncated_and_garbled_bodies_are_fetched_again(self):
+        body = b'{"metadata": {"identifier": "item"}, "files": []}'
+        session = ia_common.session_from_args(session_args(retries=2, backoff=0))
+        with MockServer() as server:
+            server.add("/metadata/item", Response(200, body, truncate_at=20), Response(200, body[:-5]),
+                       Response(200, body))
+            meta = metadata.get_metadata(session, "item")
+        self.assertEqual(meta["metadata"]["identifier"], "item")
+        self.assertEqual(len(server.requests), 3)
+
+    def test_body_that_never_parses_fails_after_the_retry_limit(self):
+        session = ia_common.session_from_args(session_args(retries=1, backoff=0))
+        with MockServer() as server:
+            server.add("/metadata/item", Response(200, b'{"metadata": {'))
+            with self.assertRaisesRegex(RuntimeError, "Failed to parse metadata JSON"):
+                metadata.get_metadata(session, "item")
+        self.assertEqual(len(server.requests), 2)
+
+
+class ListServersTest(unittest.TestCase):
+    META = {"server": "ia800101.us.archive.org", "d1": "ia800101.us.archive.org", "d2": "ia600101.us.archive.org",
+            "dir": "/7/items/item", "files": [{"name": "disc 1.iso"}]}
+
+    def test_nodes_are_listed_once_with_every_field_naming_them(self):
+        self.assertEqual(metadata.data_nodes(self.META), [("ia800101.us.archive.org", ["server", "d1"]),
+                                                          ("ia600101.us.archive.org", ["d2"])])
+        self.assertEqual(metadata.node_urls(self.META, "disc 1.iso"),
+                         ["https://ia800101.us.archive.org/7/items/item/disc%201.iso",
+                          "https://ia600101.us.archive.org/7/items/item/disc%201.iso"])
+
+    def test_ping_reports_status_or_failure(self):
+        session = ia_common.session_from_args(session_args(retries=0))
+        with MockServer() as server:
+            server.add("/7/items/item/disc.iso", Response(200))
+            result = metadata.ping_node(session, f"{server.url}/7/items/item/disc.iso")
+        self.assertRegex(result, r"^HTTP 200 in \d+ ms$")
+        self.assertTrue(metadata.ping_node(session, "http://127.0.0.1:9/").startswith("unreachable ("))
+
+
+if __name__ == "__main__":
+    unittest.main()
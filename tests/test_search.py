@@ -0,0 +1,916 @@
+import argparse
+import collections
+import contextlib
+import io
+import json
+import os
+import sqlite3
+import tempfile
+import unittest
+import unittest.mock
+from urllib.parse import parse_qs, urlparse
+
+from support import MockServer, Response, ia_common, load_script, run_main, session_args
+
+search = load_script("IA-Advanced-Search-v2.py")
+
+
+def search_handler(identifiers):
+    """Answer advancedsearch.php with the requested page of `identifiers`."""
+    def handle(request):
+        params = parse_qs(urlparse(request.path).query)
+        rows, page = int(params["rows"][0]), int(params["page"][0])
+        docs = [{"identifier": i} for i in identifiers[(page - 1) * rows:page * rows]]
+        body = {"response": {"numFound": len(identifiers), "start": (page - 1) * rows, "docs": docs}}
+        return Response(200, json.dumps(body), {"Content-Type": "application/json"})
+    return handle
+
+
+class QueryBuildingTest(unittest.TestCase):
+    def test_mediatype_is_anded_on(self):
+        self.assertEqual(search.with_mediatype("linux", "audio"), "(linux) AND mediatype:audio")
+        self.assertEqual(search.with_mediatype("linux", None), "linux")
+
+    def test_date_range_bounds(self):
+        self.assertEqual(search.with_date_range("q", "2020-01-01", None), "(q) AND publicdate:[2020-01-01 TO *]")
+        self.assertEqual(search.with_date_range("q", None, None), "q")
+
+
+class SearchUrlTest(unittest.TestCase):
+    URL = ("https://archive.org/advancedsearch.php?q=collection%3Adebian+AND+format%3AISO&fl%5B%5D=identifier"
+           "&fl%5B%5D=downloads&sort%5B%5D=downloads+desc&sort%5B%5D=&sort%5B%5D=&rows=50&page=1&output=json")
+
+    def test_browser_url_is_split_into_crawl_parameters(self):
+        self.assertEqual(search.search_url_params(self.URL), {
+            "query": "collection:debian AND format:ISO",
+            "fields": ["identifier", "downloads"],
+            "sort": ["downloads desc"],
+            "rows": 50,
+        })
+
+    def test_only_query_is_required(self):
+        self.assertEqual(search.search_url_params("https://archive.org/advancedsearch.php?q=linux"), {"query": "linux"})
+
+    def test_other_output_and_later_page_are_reported(self):
+        found = search.search_url_params("https://archive.org/advancedsearch.php?q=linux&output=xml&page=3")
+        self.assertEqual(found, {"query": "linux", "output": "xml", "page": 3})
+
+    def test_rows_of_the_url_conflict_with_rows(self):
+        code, _, err = run_main(search, "--from-url", self.URL, "--rows", "10")
+        self.assertEqual(code, ia_common.EXIT_USAGE)
+        self.assertIn("drop --rows", err)
+
+    def test_url_rows_are_used_and_ignored_parameters_warned_about(self):
+        seen = []
+
+        def handle(request):
+            seen.append(parse_qs(urlparse(request.path).query))
+            return Response(200, json.dumps({"response": {"numFound": 0, "docs": []}}))
+
+        with tempfile.TemporaryDirectory() as tmp, MockServer() as server:
+            server.routes["/advancedsearch.php"] = handle
+            code, out, err = run_main(search, "--from-url", self.URL.replace("page=1&output=json", "page=2&output=xml"),
+                                      "--sleep", "0", "--retries", "0", "-o", os.path.join(tmp, "out.json"))
+        self.assertEqual(code, ia_common.EXIT_NOTHING)
+        self.assertEqual(seen[0]["rows"], ["50"])
+        self.assertEqual(seen[0]["output"], ["json"])
+        self.assertIn("results are always fetched as JSON", out + err)
+        self.assertIn("the crawl walks every page from 1", out + err)
+
+    def test_rejects_other_pages_and_missing_query(self):
+        for url in ("https://archive.org/search?query=linux", "https://archive.org/advancedsearch.php?rows=5"):
+            with self.assertRaises(argparse.ArgumentTypeError):
+                search.search_url_params(url)
+
+
+class PageCountTest(unittest.TestCase):
+    def test_rounds_partial_page_up(self):
+        self.assertEqual(search.page_count(2500, 1000), 3)
+
+    def test_exact_multiple_has_no_extra_page(self):
+        self.assertEqual(search.page_count(2000, 1000), 2)
+        self.assertEqual(search.page_count(500, 500), 1)
+
+    def test_one_over_adds_one_page(self):
+        self.assertEqual(search.page_count(1001, 1000), 2)
+        self.assertEqual(search.page_count(1, 1000), 1)
+
+    def test_zero_results_means_zero_pages(self):
+        self.assertEqual(search.page_count(0, 1000), 0)
+
+    def test_max_pages_caps_count(self):
+        self.assertEqual(search.page_count(2500, 1000, max_pages=2), 2)
+        self.assertEqual(search.page_count(2500, 1000, max_pages=10), 3)
+
+
+class IterSearchPagesTest(unittest.TestCase):
+    def setUp(self):
+        self.session = ia_common.session_from_args(session_args(retries=2))
+
+    def collect(self, server, identifiers, rows, max_pages=None, workers=1, start_page=1):
+        server.routes["/advancedsearch.php"] = search_handler(identifiers)
+        pages = list(search.iter_search_pages(self.session, "collection:test", ["identifier"], rows, max_pages, 0,
+                                              workers=workers, start_page=start_page))
+        return [page for page, _ in pages], [d["identifier"] for _, docs in pages for d in docs]
+
+    def test_walks_every_page(self):
+        identifiers = [f"item{i}" for i in range(5)]
+        with MockServer() as server:
+            pages, found = self.collect(server, identifiers, rows=2)
+        self.assertEqual(pages, [1, 2, 3])
+        self.assertEqual(found, identifiers)
+
+    def test_saved_pages_replay_the_same_crawl(self):
+        identifiers = [f"item{i}" for i in range(5)]
+        with tempfile.TemporaryDirectory() as tmp:
+            with MockServer() as server:
+                server.routes["/advancedsearch.php"] = search_handler(identifiers)
+                pages = list(search.iter_search_pages(self.session, "q", ["identifier"], 2, None, 0, save_raw=tmp))
+            self.assertEqual(sorted(os.listdir(tmp)), ["page-1.json", "page-2.json", "page-3.json"])
+
+            def replay(request):
+                page = parse_qs(urlparse(request.path).query)["page"][0]
+                with open(os.path.join(tmp, f"page-{page}.json"), "rb") as f:
+                    return Response(200, f.read())
+
+            with MockServer() as server:
+                server.routes["/advancedsearch.php"] = replay
+                replayed = list(search.iter_search_pages(self.session, "q", ["identifier"], 2, None, 0))
+        self.assertEqual(replayed, pages)
+
+    def test_max_pages_stops_early(self):
+        identifiers = [f"item{i}" for i in range(5)]
+        with MockServer() as server:
+            pages, found = self.collect(server, identifiers, rows=2, max_pages=2)
+        self.assertEqual(pages, [1, 2])
+        self.assertEqual(found, identifiers[:4])
+
+    def test_concurrent_pages_arrive_in_order(self):
+        identifiers = [f"item{i}" for i in range(9)]
+        with MockServer() as server:
+            pages, found = self.collect(server, identifiers, rows=2, workers=3)
+        self.assertEqual(pages, [1, 2, 3, 4, 5])
+        self.assertEqual(found, identifiers)
+
+    def test_start_page_skips_earlier_pages(self):
+        identifiers = [f"item{i}" for i in range(7)]
+        for workers in (1, 2):
+            with MockServer() as server:
+                pages, found = self.collect(server, identifiers, rows=2, workers=workers, start_page=3)
+            self.assertEqual(pages, [3, 4])
+            self.assertEqual(found, identifiers[4:])
+            self.assertEqual(len(server.requests), 2)
+
+    def test_short_page_stops_concurrent_requests(self):
+        handler = search_handler([f"item{i}" for i in range(3)])
+
+        def shrunk(request):
+            # numFound still claims 20 results, but only 3 are left
+            resp = handler(request)
+            body = json.loads(resp.body)
+            body["response"]["numFound"] = 20
+            return Response(200, json.dumps(body))
+
+        with MockServer() as server:
+            server.routes["/advancedsearch.php"] = shrunk
+            pages = list(search.iter_search_pages(self.session, "q", ["identifier"], 2, None, 0, workers=2))
+        self.assertEqual([d["identifier"] for _, docs in pages for d in docs], ["item0", "item1", "item2"])
+        # Page 2 came back short, so only the page already in flight alongside it was requested
+        self.assertEqual(len(server.requests), 3)
+
+    def test_page_without_docs_is_requested_again(self):
+        identifiers = [f"item{i}" for i in range(5)]
+        handler = search_handler(identifiers)
+        blanks = collections.Counter()
+
+        def flaky(request):
+            page = parse_qs(urlparse(request.path).query)["page"][0]
+            if page == "2" and blanks[page] < 2:
+                blanks[page] += 1
+                return Response(200, json.dumps({"responseHeader": {"status": 0}}))
+            return handler(request)
+
+        for workers in (1, 2):
+            blanks.clear()
+            with MockServer() as server, self.assertLogs(level="WARNING") as logs:
+                server.routes["/advancedsearch.php"] = flaky
+                pages = list(search.iter_search_pages(self.session, "q", ["identifier"], 2, None, 0, workers=workers))
+            self.assertEqual(logs.output, [
+                f"WARNING:root:Page 2 came back without response.docs; requesting it again ({n}/{search.PAGE_RETRIES})"
+                for n in (1, 2)])
+            self.assertEqual([page for page, _ in pages], [1, 2, 3])
+            # Each page's docs exactly once, none carried over from the page before
+            self.assertEqual([d["identifier"] for _, docs in pages for d in docs], identifiers)
+            self.assertEqual(len(server.requests), 5)
+
+    def test_page_that_never_has_docs_stops_the_crawl(self):
+        handler = search_handler(["a", "b", "c"])
+
+        def broken(request):
+            if parse_qs(urlparse(request.path).query)["page"][0] == "2":
+                return Response(200, json.dumps({"response": None}))
+            return handler(request)
+
+        with MockServer() as server:
+            server.routes["/advancedsearch.php"] = broken
+            pages = search.iter_search_pages(self.session, "q", ["identifier"], 2, None, 0)
+            self.assertEqual(next(pages), (1, [{"identifier": "a"}, {"identifier": "b"}]))
+            with self.assertLogs(level="WARNING") as logs, self.assertRaises(RuntimeError) as ctx:
+                next(pages)
+        self.assertEqual(len(logs.output), search.PAGE_RETRIES + 1)
+        self.assertEqual(logs.output[-1], "WARNING:root:Page 2 came back without response.docs")
+        self.assertIn("Page 2 came back without response.docs", str(ctx.exception))
+        self.assertEqual(len(server.requests), 1 + search.PAGE_RETRIES + 1)
+
+    def test_short_page_stops_sequential_crawl(self):
+        handler = search_handler([f"item{i}" for i in range(3)])
+
+        def shrunk(request):
+            resp = handler(request)
+            body = json.loads(resp.body)
+            body["response"]["numFound"] = 20
+            return Response(200, json.dumps(body))
+
+        with MockServer() as server, self.assertLogs(level="WARNING") as logs:
+            server.routes["/advancedsearch.php"] = shrunk
+            pages = list(search.iter_search_pages(self.session, "q", ["identifier"], 2, None, 0))
+        self.assertIn("Page 2 returned 1 of 2 docs before the last page; the result set changed during the crawl",
+                      logs.output[0])
+        self.assertEqual([page for page, _ in pages], [1, 2])
+        self.assertEqual(len(server.requests), 2)
+
+    def test_sort_is_sent_with_every_page(self):
+        with MockServer() as server:
+            server.routes["/advancedsearch.php"] = search_handler(["a", "b", "c"])
+            list(search.iter_search_pages(self.session, "q", ["identifier"], 2, None, 0, sort=["downloads desc"]))
+        sorts = [parse_qs(urlparse(path).query)["sort[]"] for _, path, _ in server.requests]
+        self.assertEqual(sorts, [["downloads desc"], ["downloads desc"]])
+
+    def test_error_response_is_reported(self):
+        with MockServer() as server:
+            server.add("/advancedsearch.php", Response(200, json.dumps({"error": "bad query"})))
+            with self.assertRaises(RuntimeError):
+                list(search.iter_search_pages(self.session, "(", ["identifier"], 50, None, 0))
+
+    def test_error_beside_empty_results_fails_with_server_message(self):
+        body = {"error": {"msg": "org.apache.solr.search.SyntaxError: Cannot parse '('", "code": 400},
+                "response": {"numFound": 0, "start": 0, "docs": []}}
+        with MockServer() as server:
+            server.add("/advancedsearch.php", Response(200, json.dumps(body)))
+            with self.assertRaises(RuntimeError) as ctx:
+                list(search.iter_search_pages(self.session, "(", ["identifier"], 50, None, 0))
+        self.assertEqual(str(ctx.exception), "Advanced search rejected the query: "
+                                             "org.apache.solr.search.SyntaxError: Cannot parse '('")
+
+    def test_retries_503_on_search(self):
+        identifiers = ["only"]
+        handler = search_handler(identifiers)
+        calls = []
+
+        def flaky(request):
+            calls.append(request.path)
+            return Response(503) if len(calls) == 1 else handler(request)
+
+        with MockServer() as server:
+            server.routes["/advancedsearch.php"] = flaky
+            found = [d["identifier"] for _, docs in
+                     search.iter_search_pages(self.session, "q", ["identifier"], 50, None, 0) for d in docs]
+        self.assertEqual(found, identifiers)
+        self.assertEqual(len(calls), 2)
+
+    def test_no_results_yields_nothing(self):
+        with MockServer() as server:
+            pages, found = self.collect(server, [], rows=50)
+        self.assertEqual((pages, found), ([], []))
+        self.assertEqual(len(server.requests), 1)
+
+
+class MakeEntryTest(unittest.TestCase):
+    def test_requested_search_fields_are_carried_into_entry(self):
+        item = {"identifier": "distro", "title": "Distro 1.0", "mediatype": "software", "date": "2020-01-01",
+                "creator": ["A", "B"], "downloads": 42}
+        entry = search.make_entry(item, {"name": "distro.iso", "size": "123"})
+        self.assertEqual(entry["file_name"], "distro.iso")
+        self.assertEqual(entry["size"], "123")
+        for key in ("mediatype", "date", "creator", "downloads"):
+            self.assertEqual(entry[key], item[key])
+
+    def test_file_keys_win_over_same_named_search_fields(self):
+        entry = search.make_entry({"identifier": "distro", "size": "item-level"}, {"name": "distro.iso", "size": "1"})
+        self.assertEqual(entry["size"], "1")
+
+    def test_download_url_stays_generic_when_metadata_names_a_data_node(self):
+        meta = {"server": "ia800100.us.archive.org", "dir": "/7/items/distro"}
+        entry = search.make_entry({"identifier": "distro"}, {"name": "distro.iso"}, meta)
+        self.assertEqual(entry["download_url"], "https://archive.org/download/distro/distro.iso")
+
+    def test_mediatype_comes_from_item_metadata_when_not_requested(self):
+        meta = {"metadata": {"mediatype": "texts"}}
+        entry = search.make_entry({"identifier": "scan"}, {"name": "scan.pdf"}, meta)
+        self.assertEqual(entry["mediatype"], "texts")
+        entry = search.make_entry({"identifier": "scan", "mediatype": "image"}, {"name": "scan.pdf"}, meta)
+        self.assertEqual(entry["mediatype"], "image")
+
+
+class CapFilesTest(unittest.TestCase):
+    FILES = [{"name": "c.iso", "size": "10"}, {"name": "a.iso"}, {"name": "b.iso", "size": "900"},
+             {"name": "d.iso", "size": "900"}]
+
+    def test_keeps_the_largest_in_listed_order(self):
+        self.assertEqual([f["name"] for f in search.cap_files(self.FILES, 2)], ["b.iso", "d.iso"])
+        self.assertEqual([f["name"] for f in search.cap_files(self.FILES, 3)], ["c.iso", "b.iso", "d.iso"])
+
+    def test_first_keeps_the_head_of_the_listing(self):
+        self.assertEqual([f["name"] for f in search.cap_files(self.FILES, 2, "first")], ["c.iso", "a.iso"])
+        self.assertIs(search.cap_files(self.FILES, 4), self.FILES)
+
+
+class PickPrimaryTest(unittest.TestCase):
+    FILES = [{"name": "small.iso", "size": "10"}, {"name": "big.img", "size": "900"},
+             {"name": "big.iso", "size": "500"}, {"name": "nosize.iso"}]
+
+    def test_largest_wins_without_preferences(self):
+        self.assertEqual(search.pick_primary(self.FILES)["name"], "big.img")
+
+    def test_preferred_extension_beats_size(self):
+        self.assertEqual(search.pick_primary(self.FILES, (".iso", ".img"))["name"], "big.iso")
+
+    def test_ties_go_to_first_name(self):
+        files = [{"name": "b.iso", "size": "5"}, {"name": "A.iso", "size": "5"}]
+        self.assertEqual(search.pick_primary(files)["name"], "A.iso")
+
+    def test_strict_chain_falls_through_then_skips(self):
+        files = [{"name": "b.zip", "size": "900"}, {"name": "a.img", "size": "5"}]
+        self.assertEqual(search.pick_primary(files, (".iso", ".img", ".zip"), strict=True)["name"], "a.img")
+        self.assertIsNone(search.pick_primary(files, (".iso",), strict=True))
+        self.assertEqual(search.pick_primary(files, (".iso",))["name"], "b.zip")
+
+    def test_unknown_size_ranks_last(self):
+        files = [{"name": "a.iso"}, {"name": "b.iso", "size": "0"}]
+        self.assertEqual(search.pick_primary(files)["name"], "b.iso")
+
+
+class IdentifierFilterTest(unittest.TestCase):
+    DOCS = [{"identifier": "debian-12"}, {"identifier": "Debian-11"}, {"identifier": "ubuntu-debian-remix"}, {}]
+
+    def test_case_insensitive_by_default(self):
+        pattern = search.identifier_filter("^debian-")
+        self.assertEqual(search.matching_docs(self.DOCS, pattern), self.DOCS[:2])
+
+    def test_case_sensitive_toggle(self):
+        pattern = search.identifier_filter("^debian-", case_sensitive=True)
+        self.assertEqual(search.matching_docs(self.DOCS, pattern), self.DOCS[:1])
+
+    def test_no_pattern_keeps_everything(self):
+        self.assertIs(search.matching_docs(self.DOCS, search.identifier_filter(None)), self.DOCS)
+
+    def test_invalid_pattern_is_reported(self):
+        with self.assertRaises(ValueError):
+            search.identifier_filter("debian-(")
+
+    def test_min_downloads_drops_less_popular_and_uncounted_docs(self):
+        docs = [{"identifier": "debian-1", "downloads": 500}, {"identifier": "debian-2", "downloads": "40"},
+                {"identifier": "debian-3"}]
+        self.assertEqual(search.matching_docs(docs, None, min_downloads=100), docs[:1])
+        self.assertEqual(search.matching_docs(docs, search.identifier_filter("-2$"), min_downloads=0), docs[1:2])
+
+
+class SearchFieldsTest(unittest.TestCase):
+    def test_typos_get_a_suggestion(self):
+        warnings = search.unknown_fields(["identifier", "titel", "titel", "zzz"])
+        self.assertEqual(len(warnings), 2)
+        self.assertIn("did you mean 'title'?", warnings[0])
+        self.assertIn("see --list-fields", warnings[1])
+
+    def test_sorter_and_date_fields_are_known(self):
+        self.assertEqual(search.unknown_fields(["titleSorter", "creatorSorter", "addeddate", "updatedate"]), [])
+
+    def test_presets_only_use_known_fields(self):
+        for fields in search.FIELD_PRESETS.values():
+            self.assertEqual(search.unknown_fields(fields), [])
+
+
+class SortSpecTest(unittest.TestCase):
+    def test_direction_defaults_to_ascending(self):
+        self.assertEqual(search.sort_spec("downloads"), "downloads asc")
+        self.assertEqual(search.sort_spec("downloads DESC"), "downloads desc")
+
+    def test_rejects_unknown_direction(self):
+        with self.assertRaises(argparse.ArgumentTypeError):
+            search.sort_spec("downloads sideways")
+
+
+class FacetTest(unittest.TestCase):
+    DOCS = [
+        {"identifier": "a", "year": 2020, "format": ["ISO Image", "Metadata"]},
+        {"identifier": "b", "year": 2021, "format": "ISO Image"},
+        {"identifier": "c", "format": []},
+        {"identifier": "a", "year": 2020, "format": ["ISO Image"]},
+    ]
+
+    def test_buckets_count_each_item_once_per_value(self):
+        counts = search.count_facets([(1, self.DOCS[:2]), (2, self.DOCS[2:])], ["year", "format"])
+        self.assertEqual(counts["year"], {"2020": 1, "2021": 1, "(none)": 1})
+        self.assertEqual(counts["format"], {"ISO Image": 2, "Metadata": 1, "(none)": 1})
+
+    def test_json_output_is_sorted_by_count(self):
+        counts = search.count_facets([(1, self.DOCS)], ["format"])
+        out = io.StringIO()
+        with contextlib.redirect_stdout(out):
+            search.print_facets(counts, as_json=True)
+        self.assertEqual(list(json.loads(out.getvalue())["format"]), ["ISO Image", "(none)", "Metadata"])
+
+
+class CountResultsTest(unittest.TestCase):
+    def test_single_request_with_zero_rows(self):
+        session = ia_common.session_from_args(session_args())
+        with MockServer() as server:
+            server.routes["/advancedsearch.php"] = search_handler([f"item{i}" for i in range(1234)])
+            self.assertEqual(search.count_results(session, "collection:test"), 1234)
+        self.assertEqual(len(server.requests), 1)
+        self.assertEqual(parse_qs(urlparse(server.requests[0][1]).query)["rows"], ["0"])
+
+    def test_validate_query_exits_with_failure_when_a_query_is_rejected(self):
+        good = search_handler(["a", "b"])
+
+        def handle(request):
+            if "broken" in parse_qs(urlparse(request.path).query)["q"][0]:
+                return Response(200, json.dumps({"error": "Cannot parse 'broken AND': Encountered <EOF>"}))
+            return good(request)
+
+        with MockServer() as server:
+            server.routes["/advancedsearch.php"] = handle
+            code, out, _ = run_main(search, "--validate-query", "-q", "collection:test", "-q", "broken AND",
+                                    "--retries", "0")
+        self.assertEqual(code, ia_common.EXIT_FAILED)
+        lines = out.splitlines()
+        self.assertEqual(lines[0], "[q1] OK (2 results): collection:test")
+        self.assertTrue(lines[1].startswith("[q2] Invalid: broken AND"))
+        self.assertIn("Cannot parse", out)
+
+
+class CrawlEstimateTest(unittest.TestCase):
+    def test_extrapolates_average_item_size(self):
+        docs = [{"item_size": 100}, {"item_size": 300}, {"identifier": "no-size"}]
+        self.assertEqual(search.estimate_total_size(10, docs), 2000)
+        self.assertIsNone(search.estimate_total_size(10, [{"identifier": "x"}]))
+
+    def test_confirmation_only_above_threshold(self):
+        self.assertTrue(search.confirm_crawl(10, 10, assume_yes=False))
+        self.assertTrue(search.confirm_crawl(11, 10, assume_yes=True))
+        with unittest.mock.patch("builtins.input", return_value="n"):
+            self.assertFalse(search.confirm_crawl(11, 10, assume_yes=False))
+        with unittest.mock.patch("builtins.input", return_value="y"):
+            self.assertTrue(search.confirm_crawl(11, 10, assume_yes=False))
+
+
+class MetadataCacheTest(unittest.TestCase):
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def test_round_trip_within_ttl(self):
+        cache = search.MetadataCache(self.tmp.name, ttl=60)
+        cache.put("item", {"files": []})
+        self.assertEqual(cache.get("item"), {"files": []})
+
+    def test_expired_and_missing_entries_miss(self):
+        cache = search.MetadataCache(self.tmp.name, ttl=-1)
+        cache.put("item", {"files": []})
+        self.assertIsNone(cache.get("item"))
+        self.assertIsNone(cache.get("other"))
+
+
+class CrawlStateTest(unittest.TestCase):
+    SETTINGS = {"queries": ["collection:test"], "rows": 100}
+
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+        self.path = os.path.join(self.tmp.name, "crawl.state")
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def test_round_trip_and_clear(self):
+        state = search.CrawlState(self.path, dict(self.SETTINGS))
+        self.assertIsNone(state.load())
+        self.assertTrue(state.due())
+        state.save({"query": 1, "page": 430, "entries": 1}, [{"identifier": "a"}, {"identifier": "next page"}])
+        self.assertFalse(state.due())
+        progress = search.CrawlState(self.path, dict(self.SETTINGS)).load()
+        self.assertEqual((progress["page"], progress["entries"]), (430, [{"identifier": "a"}]))
+        state.clear()
+        self.assertEqual(os.listdir(self.tmp.name), [])
+
+    def test_saves_only_append_new_entries(self):
+        state = search.CrawlState(self.path, dict(self.SETTINGS))
+        entries = [{"identifier": "a"}]
+        state.save({"query": 1, "page": 1, "entries": 1}, entries)
+        entries.append({"identifier": "b"})
+        state.save({"query": 1, "page": 2, "entries": 2}, entries)
+        with open(state.entries_path, encoding="utf-8") as f:
+            self.assertEqual([json.loads(line) for line in f], entries)
+
+    def test_entries_appended_after_the_last_save_are_dropped(self):
+        state = search.CrawlState(self.path, dict(self.SETTINGS))
+        state.save({"query": 1, "page": 1, "entries": 1}, [{"identifier": "a"}])
+        # A crash between appending and rewriting the state file leaves extra lines behind
+        with open(state.entries_path, "a", encoding="utf-8") as f:
+            f.write(json.dumps({"identifier": "b"}) + "\n")
+        resumed = search.CrawlState(self.path, dict(self.SETTINGS))
+        self.assertEqual(resumed.load()["entries"], [{"identifier": "a"}])
+        resumed.save({"query": 1, "page": 2, "entries": 2}, [{"identifier": "a"}, {"identifier": "c"}])
+        self.assertEqual([e["identifier"] for e in search.CrawlState(self.path, dict(self.SETTINGS)).load()["entries"]],
+                         ["a", "c"])
+
+    def test_different_crawl_is_refused(self):
+        search.CrawlState(self.path, dict(self.SETTINGS)).save({"query": 1, "page": 2, "entries": 0}, [])
+        other = search.CrawlState(self.path, dict(self.SETTINGS, queries=["collection:other"]))
+        with self.assertRaisesRegex(ValueError, "queries"):
+            other.load()
+
+
+class StateFileRunTest(unittest.TestCase):
+    IDENTIFIERS = [f"item{i}" for i in range(5)]
+
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+        self.state = os.path.join(self.tmp.name, "crawl.state")
+        self.out = os.path.join(self.tmp.name, "out.json")
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def crawl(self, interrupt_at=None):
+        real_fetch = search.fetch_metadata
+        pages = []
+
+        def fetch(session, identifier):
+            if identifier == interrupt_at:
+                raise KeyboardInterrupt
+            return real_fetch(session, identifier)
+
+        def handle(request):
+            params = parse_qs(urlparse(request.path).query)
+            rows, page = int(params["rows"][0]), int(params["page"][0])
+            pages.append(page)
+            docs = [{"identifier": i} for i in self.IDENTIFIERS]
+            return Response(200, json.dumps({"response": {"numFound": len(docs),
+                                                          "docs": docs[(page - 1) * rows:page * rows]}}))
+
+        with MockServer() as server, unittest.mock.patch.object(search, "fetch_metadata", fetch):
+            server.routes["/advancedsearch.php"] = handle
+            for identifier in self.IDENTIFIERS:
+                server.add(f"/metadata/{identifier}",
+                           Response(200, json.dumps({"files": [{"name": f"{identifier}.iso"}]})))
+            code, out, _ = run_main(search, "-q", "collection:test", "--rows", "2", "--sleep", "0", "--retries", "0",
+                                    "--state-file", self.state, "-o", self.out)
+        return code, out, pages
+
+    def test_interrupted_crawl_resumes_after_the_saved_page(self):
+        code, out, _ = self.crawl(interrupt_at="item3")
+        self.assertEqual(code, ia_common.EXIT_INTERRUPTED)
+        self.assertIn("progress is saved in", out)
+        self.assertFalse(os.path.exists(self.out))
+
+        code, out, pages = self.crawl()
+        self.assertEqual(code, 0)
+        self.assertIn("q1 after page 1, 2 entries collected so far", out)
+        # Page 1 isn't fetched again; page 2 is, as item3 on it was never processed
+        self.assertEqual(pages, [2, 3])
+        with open(self.out, encoding="utf-8") as f:
+            self.assertEqual([e["identifier"] for e in json.load(f)], self.IDENTIFIERS)
+        self.assertEqual(sorted(os.listdir(self.tmp.name)), ["out.json"])
+
+
+class ListFormatsTest(unittest.TestCase):
+    def test_tallies_extensions_and_sizes(self):
+        tally = {}
+        search.tally_formats(tally, [{"name": "disc.ISO", "size": "700"}, {"name": "src.tar.gz", "size": "50"},
+                                     {"name": "README"}, {"name": "notes.gz", "size": "5"}])
+        search.tally_formats(tally, [{"name": "other.iso", "size": "300"}])
+        self.assertEqual(tally, {".iso": [2, 1000], ".tar.gz": [1, 50], "(none)": [1, 0], ".gz": [1, 5]})
+        out = io.StringIO()
+        search.print_formats(tally, out)
+        lines = out.getvalue().splitlines()
+        self.assertEqual([line.split()[0] for line in lines[2:]], [".iso", ".tar.gz", ".gz", "(none)", "total"])
+        self.assertEqual(lines[-1].split()[1], "5")
+
+    def test_wrapped_extensions_and_unreadable_sizes(self):
+        tally = {}
+        search.tally_formats(tally, [{"name": "disc/disc.img.xz", "size": "10"}, {"name": "linux-5.10.gz"},
+                                     {"name": "a.tar.gz", "size": "n/a"}, {}])
+        self.assertEqual(tally, {".img.xz": [1, 10], ".gz": [1, 0], ".tar.gz": [1, 0], "(none)": [1, 0]})
+
+
+class ChangesTest(unittest.TestCase):
+    PAGES = {
+        None: {"changes": [{"identifier": "a"}, {"identifier": "b"}], "next_token": "t1", "estimated_tokens_remaining": 2},
+        "t1": {"changes": [{"identifier": "b"}, {"identifier": "c"}], "next_token": "t2", "estimated_tokens_remaining": 1},
+        "t2": {"changes": [{"identifier": "d"}], "next_token": "t3", "estimated_tokens_remaining": 0},
+    }
+
+    def setUp(self):
+        self.session = ia_common.session_from_args(session_args(retries=0))
+
+    def handle(self, request):
+        params = parse_qs(urlparse(request.path).query)
+        token = params.get("token", [None])[0]
+        if token is None:
+            self.assertEqual(params["start_date"], ["20240101"])
+        return Response(200, json.dumps(self.PAGES[token]))
+
+    def test_follows_tokens_until_none_remain(self):
+        with MockServer() as server:
+            server.routes["/services/changes/v1"] = self.handle
+            identifiers, token = search.fetch_changes(self.session, None, "2024-01-01", None, 0)
+            self.assertEqual((identifiers, token), (["a", "b", "c", "d"], "t3"))
+            identifiers, token = search.fetch_changes(self.session, "t1", None, 1, 0)
+        self.assertEqual((identifiers, token), (["b", "c"], "t2"))
+
+    def test_collection_filter_keeps_change_order(self):
+        with MockServer() as server:
+            server.add("/advancedsearch.php", Response(200, json.dumps(
+                {"response": {"numFound": 2, "docs": [{"identifier": "c"}, {"identifier": "a"}]}})))
+            members = search.in_collection(self.session, ["a", "b", "c"], "linux", 0)
+        self.assertEqual(members, ["a", "c"])
+        query = parse_qs(urlparse(server.requests[0][1]).query)["q"][0]
+        self.assertEqual(query, 'collection:linux AND identifier:("a" OR "b" OR "c")')
+
+    def test_state_round_trip(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            path = os.path.join(tmp, "changes.json")
+            self.assertIsNone(search.read_changes_state(path))
+            search.write_changes_state(path, "t3")
+            self.assertEqual(search.read_changes_state(path), "t3")
+            with open(path, "w", encoding="utf-8") as f:
+                json.dump({"only_new_since": "2024-01-01T00:00:00Z"}, f)
+            with self.assertRaises(ValueError):
+                search.read_changes_state(path)
+
+
+class NewSinceTest(unittest.TestCase):
+    def test_timestamps_normalize_to_utc_seconds(self):
+        self.assertEqual(search.timestamp("2024-03-01"), "2024-03-01T00:00:00Z")
+        self.assertEqual(search.timestamp("2024-03-01T10:20:30"), "2024-03-01T10:20:30Z")
+        with self.assertRaises(argparse.ArgumentTypeError):
+            search.timestamp("March 2024")
+
+    def test_filters_and_tracks_newest(self):
+        new_since = search.NewSince("2024-03-01T00:00:00Z")
+        self.assertEqual(new_since.query("collection:x"), "(collection:x) AND publicdate:[2024-03-01T00:00:00Z TO *]")
+        docs = [{"identifier": "old", "publicdate": "2024-03-01T00:00:00Z"},
+                {"identifier": "new", "publicdate": "2024-05-02T08:00:00Z"}, {"identifier": "undated"}]
+        new_since.see(docs)
+        self.assertEqual([d["identifier"] for d in docs if new_since.is_new(d)], ["new", "undated"])
+        self.assertEqual(new_since.newest, "2024-05-02T08:00:00Z")
+
+    def test_state_file_feeds_the_next_run(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            path = os.path.join(tmp, "since.json")
+            args = argparse.Namespace(only_new_since=None, since_state=path)
+            self.assertIsNone(search.new_since_from_args(args).threshold)
+            search.write_since_state(path, "2024-05-02T08:00:00Z")
+            self.assertEqual(search.new_since_from_args(args).threshold, "2024-05-02T08:00:00Z")
+            # An explicit timestamp wins over the stored one
+            args.only_new_since = "2020-01-01T00:00:00Z"
+            self.assertEqual(search.new_since_from_args(args).threshold, "2020-01-01T00:00:00Z")
+            with open(path, "w") as f:
+                f.write("{}")
+            args.only_new_since = None
+            with self.assertRaises(ValueError):
+                search.new_since_from_args(args)
+
+
+class SinceStateRunTest(unittest.TestCase):
+    DATES = ["2024-01-05T00:00:00Z", "2024-03-01T00:00:00Z", "2024-02-10T00:00:00Z", "2024-01-20T00:00:00Z"]
+
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+        self.addCleanup(self.tmp.cleanup)
+        self.state = os.path.join(self.tmp.name, "since.json")
+
+    def crawl(self, *argv, broken=()):
+        def handle(request):
+            params = parse_qs(urlparse(request.path).query)
+            rows, page = int(params["rows"][0]), int(params["page"][0])
+            docs = [{"identifier": f"item{i}", "publicdate": date} for i, date in enumerate(self.DATES)]
+            body = {"response": {"numFound": len(docs), "docs": docs[(page - 1) * rows:page * rows]}}
+            return Response(200, json.dumps(body))
+
+        with MockServer() as server:
+            server.routes["/advancedsearch.php"] = handle
+            for i in range(len(self.DATES)):
+                if f"item{i}" not in broken:
+                    server.add(f"/metadata/item{i}", Response(200, json.dumps({"files": [{"name": f"{i}.iso"}]})))
+            return run_main(search, "-q", "collection:test", "--rows", "2", "--sleep", "0", "--retries", "0",
+                            "--since-state", self.state, "-o", os.path.join(self.tmp.name, "out.json"), *argv)
+
+    def stored(self):
+        with open(self.state, encoding="utf-8") as f:
+            return json.load(f)["only_new_since"]
+
+    def test_complete_crawl_stores_the_newest_publicdate(self):
+        self.assertEqual(self.crawl()[0], 0)
+        self.assertEqual(self.stored(), "2024-03-01T00:00:00Z")
+
+    def test_max_pages_leaves_the_mark_alone(self):
+        search.write_since_state(self.state, "2024-01-01T00:00:00Z")
+        code, out, _ = self.crawl("--max-pages", "1")
+        self.assertEqual(code, 0)
+        self.assertIn("--max-pages 1 leaves 2 results unvisited", out)
+        # item2 and item3, older than the newest seen, would otherwise never come back
+        self.assertEqual(self.stored(), "2024-01-01T00:00:00Z")
+
+    def test_failed_metadata_fetch_leaves_the_mark_alone(self):
+        self.crawl(broken={"item3"})
+        self.assertFalse(os.path.exists(self.state))
+
+
+class MultipleQueriesTest(unittest.TestCase):
+    HITS = {"first": ["a", "shared"], "second": ["shared", "b"]}
+
+    def test_overlapping_queries_keep_each_file_once(self):
+        fetched = collections.Counter()
+
+        def search_page(request):
+            query = parse_qs(urlparse(request.path).query)["q"][0]
+            docs = [{"identifier": i} for key, ids in self.HITS.items() if key in query for i in ids]
+            return Response(200, json.dumps({"response": {"numFound": len(docs), "docs": docs}}))
+
+        def shared_metadata(request):
+            fetched["shared"] += 1
+            # The first query's fetch fails, so only the second query can collect its files
+            if fetched["shared"] == 1:
+                return Response(503, b"busy")
+            return Response(200, json.dumps({"files": [{"name": "one.iso"}, {"name": "two.iso"}]}))
+
+        with tempfile.TemporaryDirectory() as tmp, MockServer() as server:
+            server.routes["/advancedsearch.php"] = search_page
+            server.routes["/metadata/shared"] = shared_metadata
+            for identifier in ("a", "b"):
+                server.add(f"/metadata/{identifier}", Response(200, json.dumps({"files": [{"name": "x.iso"}]})))
+            out_path = os.path.join(tmp, "out.json")
+            code, _, _ = run_main(search, "-q", "first", "-q", "second", "--sleep", "0", "--retries", "0",
+                                  "-o", out_path)
+            with open(out_path, encoding="utf-8") as f:
+                pairs = [(e["identifier"], e["file_name"]) for e in json.load(f)]
+        self.assertEqual(code, 0)
+        self.assertEqual(sorted(pairs), [("a", "x.iso"), ("b", "x.iso"), ("shared", "one.iso"), ("shared", "two.iso")])
+
+
+class MergeResultsTest(unittest.TestCase):
+    def setUp(self):
+        self.tmp = tempfile.TemporaryDirectory()
+
+    def tearDown(self):
+        self.tmp.cleanup()
+
+    def write(self, name, entries):
+        path = os.path.join(self.tmp.name, name)
+        ia_common.write_json_atomic(path, entries)
+        return path
+
+    def test_dedupes_by_identifier_and_file_name_and_sorts(self):
+        first = self.write("a.json", [{"identifier": "b", "file_name": "x.iso", "source": "a"},
+                                      {"identifier": "a", "file_name": "y.iso"}])
+        second = self.write("b.json.gz", [{"identifier": "b", "file_name": "x.iso", "source": "b"},
+                                          {"identifier": "a", "file_name": "b.iso"}])
+        entries, duplicates = search.merge_results([first, second])
+        self.assertEqual([(e["identifier"], e["file_name"]) for e in entries],
+                         [("a", "b.iso"), ("a", "y.iso"), ("b", "x.iso")])
+        self.assertEqual(entries[2]["source"], "a")
+        self.assertEqual(duplicates, 1)
+
+    def test_rejects_non_results_file(self):
+        path = self.write("state.json", {"settings": {}})
+        with self.assertRaisesRegex(ValueError, "expected a JSON array"):
+            search.merge_results([path])
+
+    def test_spilled_runs_merge_like_the_in_memory_sort(self):
+        first = self.write("a.json", [{"identifier": f"i{n % 7}", "file_name": f"f{n % 5}.iso", "source": "a"}
+                                      for n in range(40)])
+        second = os.path.join(self.tmp.name, "b.ndjson")
+        with open(second, "w", encoding="utf-8") as f:
+            for n in range(30):
+                f.write(json.dumps({"identifier": f"i{n % 9}", "file_name": f"f{n % 4}.iso", "source": "b"}) + "\n")
+        expected, duplicates = search.merge_results([first, second])
+        spill = os.path.join(self.tmp.name, "spill")
+        os.makedirs(spill)
+        stats = collections.Counter()
+        entries = list(search.iter_merged([first, second], stats, spill, chunk_entries=8))
+        self.assertEqual(entries, expected)
+        self.assertEqual((stats["entries"], stats["duplicates"]), (len(expected), duplicates))
+        self.assertEqual(os.listdir(spill), [])
+        # An identifier+file pair in both files keeps the copy of the file listed first
+        self.assertEqual({e["source"] for e in entries if e["identifier"] == "i0" and e["file_name"] == "f0.iso"}, {"a"})
+
+    def test_ndjson_errors_name_the_line(self):
+        path = os.path.join(self.tmp.name, "bad.jsonl")
+        with open(path, "w", encoding="utf-8") as f:
+            f.write('{"identifier": "a"}\n\n[1]\n')
+        with self.assertRaisesRegex(ValueError, "bad.jsonl:3"):
+            search.merge_results([path])
+
+
+class StableOutputTest(unittest.TestCase):
+    def test_same_results_in_any_order_serialize_identically(self):
+        first = [{"identifier": "b", "file_name": "x.iso", "size": "1", "downloads": 5, "title": "B",
+                  "download_url": "u2"},
+                 {"size": "2", "identifier": "a", "title": "A", "file_name": "y.iso", "download_url": "u1"}]
+        second = [{key: entry[key] for key in reversed(list(entry))} for entry in reversed(first)]
+        self.assertEqual(json.dumps(search.stable_entries(first)), json.dumps(search.stable_entries(second)))
+        self.assertEqual(list(search.stable_entries(first)[1]),
+                         ["identifier", "title", "file_name", "download_url", "size", "downloads"])
+
+    def test_gzip_output_carries_no_timestamp(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            path = os.path.join(tmp, "out.json.gz")
+            ia_common.write_json_atomic(path, [{"a": 1}])
+            with open(path, "rb") as f:
+                self.assertEqual(f.read()[4:8], b"\0\0\0\0")
+
+
+class OutputFormatTest(unittest.TestCase):
+    ENTRIES = [{"identifier": "a", "file_name": "x.iso", "download_url": "https://archive.org/download/a/x.iso"},
+               {"identifier": "b", "file_name": "y.iso", "download_url": "https://archive.org/download/b/y.iso"},
+               {"identifier": "c", "file_name": "z.iso"}]
+
+    def test_urls_are_written_one_per_line(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            path = os.path.join(tmp, "urls.txt")
+            search.write_entries(self.ENTRIES, path, "urls")
+            with open(path, encoding="utf-8") as f:
+                self.assertEqual(f.read().splitlines(), [self.ENTRIES[0]["download_url"],
+                                                         self.ENTRIES[1]["download_url"]])
+
+    def test_results_on_stdout_leave_logs_and_status_to_stderr(self):
+        with MockServer() as server:
+            server.routes["/advancedsearch.php"] = search_handler(["a"])
+            server.add("/metadata/a", Response(200, json.dumps({"files": [{"name": "x.iso", "size": "3"}]})))
+            code, out, err = run_main(search, "-q", "collection:test", "-o", "-", "-v", "--sleep", "0",
+                                      "--retries", "0")
+        self.assertEqual(code, 0)
+        self.assertEqual([(e["identifier"], e["file_name"]) for e in json.loads(out)], [("a", "x.iso")])
+        self.assertIn("Found 1 ISO-like files.", err)
+        self.assertIn("| INFO     |", err)
+
+    def test_url_list_without_out_is_not_named_json(self):
+        with tempfile.TemporaryDirectory() as tmp, MockServer() as server:
+            server.routes["/advancedsearch.php"] = search_handler(["a"])
+            server.add("/metadata/a", Response(200, json.dumps({"files": [{"name": "x.iso", "size": "3"}]})))
+            cwd = os.getcwd()
+            os.chdir(tmp)
+            try:
+                code, _, _ = run_main(search, "-q", "collection:test", "--format", "urls", "--sleep", "0",
+                                      "--retries", "0")
+            finally:
+                os.chdir(cwd)
+            self.assertEqual(code, 0)
+            self.assertEqual(os.listdir(tmp), ["pear.txt"])
+
+    def test_urls_to_stdout_carry_no_json(self):
+        with contextlib.redirect_stdout(io.StringIO()) as out:
+            search.write_entries(self.ENTRIES[:1], "-", "urls")
+        self.assertEqual(out.getvalue(), "https://archive.org/download/a/x.iso\n")
+
+    def test_aria2_input_names_each_file_and_its_directory(self):
+        self.assertEqual(search.format_aria2(self.ENTRIES, "isos/{identifier}").splitlines(), [
+            "https://archive.org/download/a/x.iso", " out=x.iso", " dir=isos/a",
+            "https://archive.org/download/b/y.iso", " out=y.iso", " dir=isos/b",
+        ])
+        self.assertEqual(search.format_aria2(self.ENTRIES[:1]), "https://archive.org/download/a/x.iso\n out=x.iso\n")
+
+    def test_aria2_names_cannot_add_options_or_leave_the_tree(self):
+        entries = [{"identifier": "../a", "file_name": "../../x.iso\non-download-complete=/bin/sh",
+                    "download_url": "https://archive.org/download/a/x.iso"},
+                   {"identifier": "b", "file_name": "y.iso",
+                    "download_url": "https://archive.org/download/b/y.iso\n dir=/etc"}]
+        with self.assertLogs(level="WARNING") as logs:
+            lines = search.format_aria2(entries, "isos/{identifier}").splitlines()
+        self.assertEqual(lines, ["https://archive.org/download/a/x.iso",
+                                 " out=x.iso_on-download-complete=/bin/sh", " dir=isos/.._a"])
+        self.assertIn("line break", logs.output[0])
+
+    def test_sqlite_rows_are_upserted_by_identifier_and_file_name(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            path = os.path.join(tmp, "results.db")
+            search.write_entries([dict(e, size="10") for e in self.ENTRIES[:2]], path, "sqlite")
+            # A rerun finds y.iso again with a new size, and one new file
+            search.write_entries([dict(self.ENTRIES[1], size="20", creator="someone"), self.ENTRIES[2]], path,
+                                 "sqlite")
+            conn = sqlite3.connect(path)
+            try:
+                rows = conn.execute("SELECT identifier, file_name, size, json_extract(entry, '$.creator') "
+                                    "FROM files ORDER BY identifier").fetchall()
+                indexes = [row[1] for row in conn.execute("PRAGMA index_list(files)")]
+            finally:
+                conn.close()
+        self.assertEqual(rows, [("a", "x.iso", 10, None), ("b", "y.iso", 20, "someone"), ("c", "z.iso", None, None)])
+        self.assertIn("files_file_name", indexes)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,184 @@
+import contextlib
+import io
+import json
+import os
+import tempfile
+import threading
+import time
+import unittest
+
+from support import MockServer, Response, ia_common, load_script, run_main, session_args
+
+sync = load_script("IA-Sync.py")
+
+
+def make_args(output_dir, **overrides):
+    args = sync.build_parser().parse_args(["-q", "linux", "-o", output_dir, "--rows", "50", "--sleep", "0", "--jobs", "2",
+                                           "--extensions", ".iso", "--retries", "0", "--backoff", "0", "--max-backoff", "0"])
+    # What main() sets after parsing
+    args.update, args.head_bytes, args.progress, args.pause = False, None, False, None
+    for key, value in overrides.items():
+        setattr(args, key, value)
+    return args
+
+
+class FileMatchesTest(unittest.TestCase):
+    def matches(self, f, **overrides):
+        args = make_args("out", **overrides)
+        return sync.file_matches(f, args, None, None)
+
+    def test_extension_and_size_bounds(self):
+        self.assertTrue(self.matches({"name": "a.ISO", "size": "500"}))
+        self.assertFalse(self.matches({"name": "a.zip", "size": "500"}))
+        self.assertFalse(self.matches({"name": "a.iso", "size": "500"}, min_size=1000))
+        self.assertFalse(self.matches({"name": "a.iso", "size": "500"}, max_size=100))
+        self.assertTrue(self.matches({"name": "a.iso"}, min_size=1000))
+
+    def test_compound_extensions_match_whole(self):
+        tar_gz = ia_common.parse_extensions("tar.gz")
+        self.assertTrue(self.matches({"name": "src.TAR.GZ"}, extensions=tar_gz))
+        self.assertFalse(self.matches({"name": "notes.gz"}, extensions=tar_gz))
+        gz = ia_common.parse_extensions(".gz")
+        self.assertTrue(self.matches({"name": "notes.gz"}, extensions=gz))
+        self.assertFalse(self.matches({"name": "src.tar.gz"}, extensions=gz))
+        self.assertFalse(self.matches({"name": "disc.img.xz"}, extensions=gz))
+        self.assertTrue(self.matches({"name": "disc.img.xz"}, extensions=ia_common.parse_extensions("img.xz,iso")))
+        self.assertFalse(self.matches({"name": "disc.img.xz"}, extensions=ia_common.parse_extensions("img")))
+
+
+class SyncTest(unittest.TestCase):
+    def test_invalid_filter_pattern_is_a_usage_error(self):
+        code, _, err = run_main(sync, "-q", "x", "--dry-run", "--include", "(")
+        self.assertEqual(code, ia_common.EXIT_USAGE)
+        self.assertIn("invalid --include/--exclude pattern", err)
+
+    def test_search_results_stream_into_downloads(self):
+        docs = [{"identifier": "one"}, {"identifier": "two"}]
+        search_body = {"response": {"numFound": 2, "start": 0, "docs": docs}}
+        with tempfile.TemporaryDirectory() as tmp, MockServer() as server:
+            server.add("/advancedsearch.php", Response(200, json.dumps(search_body)))
+            for identifier in ("one", "two"):
+                files = [{"name": f"{identifier}.iso", "size": "4"}, {"name": "notes.txt", "size": "1"}]
+                server.add(f"/metadata/{identifier}", Response(200, json.dumps({"files": files})))
+                server.add(f"/download/{identifier}/{identifier}.iso", Response(200, b"data"))
+            session = ia_common.session_from_args(session_args())
+            args = make_args(tmp)
+            with contextlib.redirect_stdout(io.StringIO()):
+                results, interrupted = sync.run_downloads(session, args, sync.iter_matching_entries(session, args))
+            self.assertFalse(interrupted)
+            self.assertEqual([(r["file_name"], r["status"]) for r in results],
+                             [("one.iso", "downloaded"), ("two.iso", "downloaded")])
+            self.assertTrue(os.path.isfile(os.path.join(tmp, "two", "two.iso")))
+        self.assertNotIn("/download/one/notes.txt", [path for _, path, _ in server.requests])
+
+    def test_concurrent_outcomes_are_counted_exactly(self):
+        # Every third file is missing upstream and every third is already on disk
+        outcomes = ["failed", "downloaded", "skipped"] * 20
+        with tempfile.TemporaryDirectory() as tmp, MockServer() as server:
+            entries = []
+            for i, outcome in enumerate(outcomes):
+                name = f"f{i}.iso"
+                server.add(f"/download/item/{name}", Response(404) if outcome == "failed" else Response(200, b"data"))
+                if outcome == "skipped":
+                    os.makedirs(os.path.join(tmp, "item"), exist_ok=True)
+                    with open(os.path.join(tmp, "item", name), "wb") as f:
+                        f.write(b"data")
+                entries.append({"identifier": "item", "file_name": name, "size": "4",
+                                "download_url": f"{server.url}/download/item/{name}"})
+            session = ia_common.session_from_args(session_args(retries=0))
+            status = ia_common.TransferStatus()
+            with contextlib.redirect_stdout(io.StringIO()):
+                results, _ = sync.run_downloads(session, make_args(tmp, jobs=8), entries, status=status)
+        counts = sync.dfj.count_statuses(results)
+        self.assertEqual((counts["downloaded"], counts["skipped"], counts["failed"]), (20, 20, 20))
+        self.assertEqual([r["status"] for r in results], outcomes)
+        self.assertEqual((status.done, status.failed), (60, 20))
+
+    def test_pipeline_reports_backpressure_from_slow_downloads(self):
+        def slow(request):
+            time.sleep(0.05)
+            return Response(200, b"data")
+
+        with tempfile.TemporaryDirectory() as tmp, MockServer() as server:
+            server.routes["/download/item/f.iso"] = slow
+            entries = [{"identifier": "item", "file_name": f"f{i}.iso", "size": "4",
+                        "download_url": f"{server.url}/download/item/f.iso"} for i in range(12)]
+            session = ia_common.session_from_args(session_args(retries=0))
+            pipeline = sync.Pipeline(2, 4)
+            with contextlib.redirect_stdout(io.StringIO()), self.assertLogs(level="INFO") as logs:
+                sync.run_downloads(session, make_args(tmp, jobs=2), entries, pipeline=pipeline)
+        self.assertEqual((pipeline.queued, pipeline.running, pipeline.done), (0, 0, 12))
+        # The entries are all at hand, so only the search side ever waited
+        self.assertGreater(pipeline.blocked, 0.1)
+        self.assertLess(pipeline.starved, 0.05)
+        self.assertIn("Pipeline: 0 queued, 0 running, 12 done (queue holds 4)", logs.output[-1])
+
+
+class JobTunerTest(unittest.TestCase):
+    def test_ramps_up_while_throughput_improves_then_settles_on_the_best(self):
+        tuner = sync.JobTuner(1, 1, 8, ia_common.TransferStatus())
+        with self.assertLogs(level="INFO"):
+            for rate in (100, 190, 260, 270):
+                tuner.sample(rate)
+        # The fourth job added only 4%, so the tuner goes back to three and stops probing
+        self.assertEqual((tuner.limit, tuner.settled), (3, True))
+        tuner.sample(1000)
+        self.assertEqual(tuner.limit, 3)
+
+    def test_idle_intervals_are_not_judged(self):
+        tuner = sync.JobTuner(2, 1, 8, ia_common.TransferStatus())
+        tuner.sample(0, busy=False)
+        self.assertEqual((tuner.limit, tuner.settled), (2, False))
+
+    def test_429_halves_the_limit_and_caps_later_probing(self):
+        tuner = sync.JobTuner(6, 1, 8, ia_common.TransferStatus())
+        session = ia_common.session_from_args(session_args(retries=1, backoff=0))
+        tuner.attach(session)
+        with MockServer() as server, self.assertLogs(level="INFO") as logs:
+            server.add("/download/item/f.iso", Response(429), Response(200, b"data"))
+            session.get(f"{server.url}/download/item/f.iso")
+            for rate in (100, 200, 300):
+                tuner.sample(rate)
+        self.assertIn("--auto-jobs: 3 jobs (429 Too Many Requests)", logs.output[0])
+        self.assertEqual((tuner.limit, tuner.high, tuner.settled), (5, 5, True))
+
+    def test_a_burst_of_429s_from_many_threads_cuts_once(self):
+        tuner = sync.JobTuner(6, 1, 8, ia_common.TransferStatus())
+        barrier = threading.Barrier(16)
+
+        def hit():
+            barrier.wait()
+            tuner.throttled()
+
+        threads = [threading.Thread(target=hit) for _ in range(16)]
+        with self.assertLogs(level="INFO") as logs:
+            for thread in threads:
+                thread.start()
+            for thread in threads:
+                thread.join()
+        self.assertEqual((tuner.limit, tuner.high), (3, 5))
+        self.assertEqual(len(logs.output), 1)
+
+    def test_limit_bounds_the_transfers_running_at_once(self):
+        tuner = sync.JobTuner(2, 1, 8, ia_common.TransferStatus())
+        peak, lock = [0], threading.Lock()
+
+        def slow(request):
+            with lock:
+                peak[0] = max(peak[0], tuner.running)
+            time.sleep(0.02)
+            return Response(200, b"data")
+
+        with tempfile.TemporaryDirectory() as tmp, MockServer() as server:
+            server.routes["/download/item/f.iso"] = slow
+            entries = [{"identifier": "item", "file_name": f"f{i}.iso", "size": "4",
+                        "download_url": f"{server.url}/download/item/f.iso"} for i in range(10)]
+            session = ia_common.session_from_args(session_args(retries=0))
+            with contextlib.redirect_stdout(io.StringIO()):
+                results, _ = sync.run_downloads(session, make_args(tmp, jobs=6), entries, tuner=tuner)
+        self.assertEqual(len(results), 10)
+        self.assertEqual(peak[0], 2)
+
+
+if __name__ == "__main__":
+    unittest.main()